@@ -12,14 +12,17 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::process;
+use std::sync::atomic::{AtomicU16, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
 use std::time::Duration;
 
 use actix::io::SinkWrite;
 use actix::prelude::*;
 use actix_codec::Framed;
+use actix_web::dev::Service;
 use actix_web::web::Bytes;
 use awc::{
     error::WsProtocolError,
@@ -27,8 +30,13 @@ use awc::{
     BoxedSocket,
 };
 
+use futures::future::{Either, FutureExt};
 use futures::stream::{SplitSink, StreamExt};
 
+use rand::Rng;
+
+use rerobots::client::TokenClaims;
+
 extern crate serde;
 extern crate serde_json;
 use serde::{Deserialize, Serialize};
@@ -36,33 +44,42 @@ use serde::{Deserialize, Serialize};
 use crate::camera;
 use crate::control;
 use crate::control::{CWorkerCommand, TunnelInfo};
+use crate::error::HardshareError;
 use crate::mgmt;
 use crate::mgmt::WDeployment;
+use crate::proxy;
+use crate::util;
 
-struct ClientError {
-    msg: String,
+pub fn error<T, S>(msg: S) -> Result<T, HardshareError>
+where
+    S: ToString,
+{
+    Err(HardshareError::Other(msg.to_string()))
 }
-impl std::error::Error for ClientError {}
 
-impl std::fmt::Display for ClientError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.msg)
-    }
+fn auth_error<T, S>(msg: S) -> Result<T, HardshareError>
+where
+    S: ToString,
+{
+    Err(HardshareError::Auth(msg.to_string()))
 }
 
-impl std::fmt::Debug for ClientError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.msg)
-    }
+fn config_error<T, S>(msg: S) -> Result<T, HardshareError>
+where
+    S: ToString,
+{
+    Err(HardshareError::Config(msg.to_string()))
 }
 
-pub fn error<T, S>(msg: S) -> Result<T, Box<dyn std::error::Error>>
+fn invalid<T, S>(msg: S) -> Result<T, HardshareError>
 where
     S: ToString,
 {
-    Err(Box::new(ClientError {
-        msg: msg.to_string(),
-    }))
+    Err(HardshareError::Invalid(msg.to_string()))
+}
+
+fn not_found<T>() -> Result<T, HardshareError> {
+    Err(HardshareError::NotFound("not found".into()))
 }
 
 #[derive(Serialize, Deserialize)]
@@ -71,10 +88,162 @@ pub struct AccessRule {
     date_created: String,
     pub id: u16,
     param: Option<serde_json::Value>,
+
+    // Human-readable form of the time window in param, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    schedule: Option<String>,
+
     pub user: String,
     pub wdeployment_id: String,
 }
 
+const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+// Time window during which an access rule applies, for example,
+// "Mon-Fri 09:00-17:00 UTC-08:00". The time zone is UTC or a fixed offset
+// from it, so that it is unambiguous without a time zone database. If the
+// time zone is omitted, then it is UTC.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Schedule {
+    days: Vec<String>,
+    start: String,
+    end: String,
+    tz: String,
+}
+
+impl Schedule {
+    // The core API stores the `param` object of a rule (in the body of
+    // POST /deployment/{id}/rule) as given and returns it in GET
+    // /deployment/{id}/rules. The time window is in its `schedule` key, e.g.,
+    // {"schedule": {"days": ["Mon", "Tue"], "start": "09:00", "end": "17:00", "tz": "UTC"}}
+    pub fn to_param(&self) -> serde_json::Value {
+        json!({ "schedule": self })
+    }
+
+    fn from_param(param: &serde_json::Value) -> Option<Self> {
+        serde_json::from_value(param["schedule"].clone()).ok()
+    }
+}
+
+fn parse_weekday(s: &str) -> Result<usize, String> {
+    match WEEKDAYS.iter().position(|d| d.eq_ignore_ascii_case(s)) {
+        Some(i) => Ok(i),
+        None => Err(format!("unknown day: {}", s)),
+    }
+}
+
+// Time zone must be UTC or of the form UTC+HH:MM or UTC-HH:MM
+fn parse_time_zone(s: &str) -> Result<(), String> {
+    if s == "UTC" {
+        return Ok(());
+    }
+    if let Some(offset) = s.strip_prefix("UTC+").or_else(|| s.strip_prefix("UTC-")) {
+        if parse_clock_time(offset).is_ok() && offset <= "14:00" {
+            return Ok(());
+        }
+    }
+    Err(format!(
+        "time zone must be UTC or an offset from it, e.g., UTC-08:00; got {}",
+        s
+    ))
+}
+
+fn parse_clock_time(s: &str) -> Result<(), String> {
+    let parts: Vec<&str> = s.split(':').collect();
+    if parts.len() == 2 && parts[0].len() == 2 && parts[1].len() == 2 {
+        if let (Ok(h), Ok(m)) = (parts[0].parse::<u8>(), parts[1].parse::<u8>()) {
+            if h < 24 && m < 60 {
+                return Ok(());
+            }
+        }
+    }
+    Err(format!(
+        "time must be of the form HH:MM, e.g., 09:00; got {}",
+        s
+    ))
+}
+
+impl std::str::FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        if parts.len() != 2 && parts.len() != 3 {
+            return Err("schedule must be of the form DAYS HH:MM-HH:MM [TIMEZONE], e.g., Mon-Fri 09:00-17:00 UTC-08:00".into());
+        }
+
+        let mut day_indices = vec![];
+        for days_part in parts[0].split(',') {
+            match days_part.split_once('-') {
+                Some((first, last)) => {
+                    let first = parse_weekday(first)?;
+                    let last = parse_weekday(last)?;
+                    if first > last {
+                        return Err(format!("day range is backwards: {}", days_part));
+                    }
+                    day_indices.extend(first..=last);
+                }
+                None => day_indices.push(parse_weekday(days_part)?),
+            }
+        }
+        day_indices.sort_unstable();
+        day_indices.dedup();
+
+        let (start, end) = match parts[1].split_once('-') {
+            Some(x) => x,
+            None => {
+                return Err(format!(
+                    "time range must be of the form HH:MM-HH:MM; got {}",
+                    parts[1]
+                ))
+            }
+        };
+        parse_clock_time(start)?;
+        parse_clock_time(end)?;
+        if start >= end {
+            return Err(format!("time range must end after it starts: {}", parts[1]));
+        }
+
+        let tz = if parts.len() == 3 {
+            parse_time_zone(parts[2])?;
+            parts[2]
+        } else {
+            "UTC"
+        };
+
+        Ok(Schedule {
+            days: day_indices
+                .iter()
+                .map(|i| WEEKDAYS[*i].to_string())
+                .collect(),
+            start: start.into(),
+            end: end.into(),
+            tz: tz.into(),
+        })
+    }
+}
+
+impl std::fmt::Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let indices: Vec<usize> = self
+            .days
+            .iter()
+            .filter_map(|d| parse_weekday(d).ok())
+            .collect();
+        let is_range = indices.len() > 2 && indices.windows(2).all(|w| w[1] == w[0] + 1);
+        let days = if is_range {
+            format!(
+                "{}-{}",
+                WEEKDAYS[indices[0]],
+                WEEKDAYS[indices[indices.len() - 1]]
+            )
+        } else {
+            self.days.join(",")
+        };
+        write!(f, "{} {}-{} {}", days, self.start, self.end, self.tz)
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct AccessRules {
     pub rules: Vec<AccessRule>,
@@ -92,12 +261,79 @@ impl std::fmt::Display for AccessRules {
 #[derive(PartialEq, Debug, Clone)]
 pub enum AddOn {
     MistyProxy,
+    Webrtc,
+}
+
+// Names of add-ons as used by the API. To add an add-on, add a variant to
+// AddOn and an entry here.
+const ADDON_NAMES: [(AddOn, &str); 2] =
+    [(AddOn::MistyProxy, "mistyproxy"), (AddOn::Webrtc, "webrtc")];
+
+// STUN or TURN server, as in RTCIceServer of the WebRTC API
+#[derive(Serialize, Debug, Clone)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+}
+
+impl IceServer {
+    pub fn new(url: &str) -> Result<Self, String> {
+        let scheme = match url.find(':') {
+            Some(sep) => &url[..sep],
+            None => "",
+        };
+        if !["stun", "stuns", "turn", "turns"].contains(&scheme) || url.len() == scheme.len() + 1 {
+            return Err(format!(
+                "expected URL of STUN or TURN server, e.g., stun:stun.example.com:3478; got {}",
+                url
+            ));
+        }
+        Ok(IceServer {
+            urls: vec![url.into()],
+            username: None,
+            credential: None,
+        })
+    }
+
+    pub fn is_turn(&self) -> bool {
+        self.urls.iter().any(|u| u.starts_with("turn"))
+    }
+}
+
+// Configuration of the webrtc add-on, which browsers use to negotiate a
+// media channel with a streamer that runs separately from hardshare
+#[derive(Serialize, Debug, Clone)]
+pub struct WebrtcConfig {
+    #[serde(rename = "iceServers")]
+    pub ice_servers: Vec<IceServer>,
 }
 
 impl std::fmt::Display for AddOn {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            AddOn::MistyProxy => write!(f, "mistyproxy"),
+        let (_, name) = ADDON_NAMES.iter().find(|(a, _)| a == self).unwrap();
+        write!(f, "{}", name)
+    }
+}
+
+impl std::str::FromStr for AddOn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match ADDON_NAMES.iter().find(|(_, name)| *name == s) {
+            Some((addon, _)) => Ok(addon.clone()),
+            None => {
+                let known: Vec<&str> = ADDON_NAMES.iter().map(|(_, name)| *name).collect();
+                Err(format!(
+                    "unknown add-on: {}; known add-ons: {}",
+                    s,
+                    known.join(", ")
+                ))
+            }
         }
     }
 }
@@ -137,12 +373,44 @@ impl std::str::FromStr for CameraDimensions {
 
 pub type CameraCrop = HashMap<String, Vec<u16>>;
 
+// Camera stream started on this host, as found from its pid file
+#[derive(Serialize, Debug)]
+pub struct CameraStatus {
+    pub id: String,
+    pub pid: String,
+    pub alive: bool,
+    pub wdeployments: Vec<String>,
+
+    // Whether the server still lists this camera
+    pub registered: bool,
+
+    pub stats: Option<camera::StreamStats>,
+}
+
+// Instance as reported by the core API server, e.g., for `hardshare test-launch`
+#[derive(Deserialize, Debug)]
+pub struct InstanceInfo {
+    pub status: String,
+
+    // Address for ssh, present when the instance is READY
+    #[serde(default)]
+    pub fwd: Option<InstanceFwd>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InstanceFwd {
+    pub ipv4: String,
+    pub port: u16,
+}
+
 #[derive(Clone)]
 pub struct HSAPIClient {
     local_config: Option<mgmt::Config>,
     cached_api_token: Option<String>,
     origin: String,
     wdid_tab: Option<HashMap<String, Addr<MainActor>>>,
+    metrics_tab: HashMap<String, Arc<control::Metrics>>,
+    runtime: ClientRuntime,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -153,6 +421,12 @@ pub struct DaemonStatus {
     ad_deployments: Vec<String>,
 }
 
+impl DaemonStatus {
+    pub fn ad_deployments(&self) -> &[String] {
+        &self.ad_deployments
+    }
+}
+
 impl std::fmt::Display for DaemonStatus {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "advertised deployments:")?;
@@ -167,24 +441,367 @@ impl std::fmt::Display for DaemonStatus {
     }
 }
 
+type RuntimeJob =
+    Box<dyn FnOnce() -> std::pin::Pin<Box<dyn std::future::Future<Output = ()>>> + Send>;
+
+// Blocking API calls of a client (and its clones) run on one thread, which
+// has its own actix System and, per API token, one awc::Client, so that
+// connections to the API server are reused across calls. Because the calls
+// do not run on the calling thread, they can be made from any thread. The
+// runtime thread is started at the first call and exits when the last clone
+// of the client is dropped.
+#[derive(Clone, Default)]
+struct ClientRuntime {
+    jobs: Arc<Mutex<Option<mpsc::Sender<RuntimeJob>>>>,
+}
+
+thread_local! {
+    // Only used on runtime threads of ClientRuntime
+    static CLIENTS: RefCell<HashMap<String, awc::Client>> = RefCell::new(HashMap::new());
+}
+
+impl ClientRuntime {
+    // Run the future created by `make_future` on the runtime thread, and
+    // block until it is done
+    fn block_on<F, Fut, T>(&self, make_future: F) -> T
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = T> + 'static,
+        T: Send + 'static,
+    {
+        let (result_tx, result_rx) = mpsc::channel();
+        let job: RuntimeJob = Box::new(move || {
+            Box::pin(async move {
+                let _ = result_tx.send(make_future().await);
+            })
+        });
+        self.sender()
+            .send(job)
+            .expect("runtime thread of API client stopped");
+        result_rx
+            .recv()
+            .expect("runtime thread of API client stopped")
+    }
+
+    fn sender(&self) -> mpsc::Sender<RuntimeJob> {
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.get_or_insert_with(|| {
+            let (jobs_tx, jobs_rx) = mpsc::channel::<RuntimeJob>();
+            std::thread::spawn(move || {
+                let sys = System::new();
+                for job in jobs_rx {
+                    sys.block_on(job());
+                }
+                // Clients are dropped before the System that they use
+                CLIENTS.with(|clients| clients.borrow_mut().clear());
+            });
+            jobs_tx
+        })
+        .clone()
+    }
+}
+
+// Check that an API origin is an http or https URL without a path
+pub fn validate_origin(origin: &str) -> Result<(), String> {
+    let uri: awc::http::Uri = match origin.parse() {
+        Ok(u) => u,
+        Err(_) => return Err(format!("not a valid URL: {}", origin)),
+    };
+    match uri.scheme_str() {
+        Some("http") | Some("https") => {}
+        _ => return Err(format!("API origin must be http or https: {}", origin)),
+    }
+    if uri.host().is_none() {
+        return Err(format!("API origin has no host: {}", origin));
+    }
+    if !matches!(uri.path(), "" | "/") || uri.query().is_some() {
+        return Err(format!("API origin must not have a path: {}", origin));
+    }
+    Ok(())
+}
+
+// Origin of the rerobots API, also used for WebSocket URLs. REROBOTS_ORIGIN
+// at run time (or `hardshare --api-origin`, which sets it) takes precedence
+// over REROBOTS_ORIGIN at build time.
+fn resolve_origin(runtime: Option<String>) -> String {
+    let default_origin = option_env!("REROBOTS_ORIGIN").unwrap_or("https://api.rerobots.net");
+    let origin = match runtime {
+        Some(origin) if !origin.is_empty() => match validate_origin(&origin) {
+            Ok(()) => origin,
+            Err(err) => {
+                warn!("ignoring REROBOTS_ORIGIN: {}", err);
+                default_origin.to_string()
+            }
+        },
+        _ => default_origin.to_string(),
+    };
+    origin.trim_end_matches('/').to_string()
+}
+
+fn validate_webhook_url(url: &str) -> Result<(), HardshareError> {
+    let uri: awc::http::Uri = match url.parse() {
+        Ok(u) => u,
+        Err(_) => return invalid(format!("not a valid URL: {}", url)),
+    };
+    match uri.scheme_str() {
+        Some("http") | Some("https") => {}
+        _ => return invalid(format!("webhook URL must be http or https: {}", url)),
+    }
+    if uri.host().is_none() {
+        return invalid(format!("webhook URL does not have a host: {}", url));
+    }
+    Ok(())
+}
+
+// Address of a Misty robot must be an IPv4 address or a resolvable host name
+fn validate_mistyproxy_addr(addr: &str) -> Result<(), HardshareError> {
+    if addr.is_empty() {
+        return invalid("address of robot is empty");
+    }
+    if addr.trim() != addr || addr.contains(char::is_whitespace) {
+        return invalid(format!("address of robot contains whitespace: {:?}", addr));
+    }
+    if addr.parse::<std::net::Ipv4Addr>().is_ok() {
+        return Ok(());
+    }
+    if addr.contains('/') {
+        return invalid(format!(
+            "expected IPv4 address of robot, not network: {}",
+            addr
+        ));
+    }
+    // Else it must be a host name, not an incomplete or out of range address
+    if addr.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        return invalid(format!("not a valid IPv4 address: {}", addr));
+    }
+    use std::net::ToSocketAddrs;
+    match (addr, 0).to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(_)) => Ok(()),
+        _ => invalid(format!("cannot resolve host name: {}", addr)),
+    }
+}
+
+fn max_request_attempts() -> u32 {
+    match std::env::var("HARDSHARE_API_ATTEMPTS") {
+        Ok(x) => match x.parse::<u32>() {
+            Ok(n) => std::cmp::max(n, 1),
+            Err(_) => {
+                warn!("ignoring invalid HARDSHARE_API_ATTEMPTS: {}", x);
+                DEFAULT_REQUEST_ATTEMPTS
+            }
+        },
+        Err(_) => DEFAULT_REQUEST_ATTEMPTS,
+    }
+}
+
+const DEFAULT_REQUEST_ATTEMPTS: u32 = 3;
+
+const DEFAULT_TOKEN_EXPIRY_WARNING: i64 = 86400;
+
+const DEFAULT_REQUEST_TIMEOUT: u64 = 30;
+
+// Maximum duration (seconds) of connecting and of waiting for a response, for
+// every outbound request. It can be changed with HARDSHARE_TIMEOUT (or
+// `hardshare --timeout`, which sets it).
+pub fn request_timeout() -> Duration {
+    let timeout = match std::env::var("HARDSHARE_TIMEOUT") {
+        Ok(x) => match x.parse::<u64>() {
+            Ok(t) if t > 0 => t,
+            _ => {
+                warn!("ignoring invalid HARDSHARE_TIMEOUT: {}", x);
+                DEFAULT_REQUEST_TIMEOUT
+            }
+        },
+        Err(_) => DEFAULT_REQUEST_TIMEOUT,
+    };
+    Duration::from_secs(timeout)
+}
+
+// Duration (seconds) for which a cached listing from get_remote_config() is
+// used; can be changed with HARDSHARE_REMOTE_CONFIG_TTL
+const DEFAULT_REMOTE_CONFIG_TTL: u64 = 30;
+
+// Send the request made by make_req, retrying with jittered backoff if the
+// server responds 5xx or the request fails at the transport level. Only use
+// this for requests that are safe to repeat.
+async fn send_with_retry<F>(make_req: F) -> <awc::SendClientRequest as std::future::Future>::Output
+where
+    F: Fn() -> awc::ClientRequest,
+{
+    let max_attempts = max_request_attempts();
+    let mut attempt = 1;
+    loop {
+        let res = make_req().send().await;
+        let retry_reason = match &res {
+            Ok(resp) if resp.status().is_server_error() => format!("{}", resp.status()),
+            Ok(_) => return res,
+            Err(err) => format!("{}", err),
+        };
+        if attempt >= max_attempts {
+            return res;
+        }
+        let backoff = 500 * 2u64.pow(attempt - 1) + rand::thread_rng().gen_range(0..250);
+        warn!(
+            "request failed ({}); retrying in {} ms (attempt {} of {})",
+            retry_reason,
+            backoff,
+            attempt + 1,
+            max_attempts
+        );
+        actix::clock::sleep(Duration::from_millis(backoff)).await;
+        attempt += 1;
+    }
+}
+
+// Error for a response that does not indicate success. If the body has an
+// `error_message`, it is included; for status 400, it is returned alone
+// because it describes what was wrong with the request.
+async fn error_from_response<T, S>(
+    context: &str,
+    resp: &mut awc::ClientResponse<S>,
+) -> Result<T, HardshareError>
+where
+    S: futures::Stream<Item = Result<Bytes, awc::error::PayloadError>> + Unpin,
+{
+    let status = resp.status();
+    let error_message = match resp.body().await {
+        Ok(body) => serde_json::from_slice::<serde_json::Value>(body.as_ref())
+            .ok()
+            .and_then(|payload| payload["error_message"].as_str().map(String::from)),
+        Err(_) => None,
+    };
+    let message = match error_message {
+        Some(msg) if status == 400 => msg,
+        Some(msg) => format!("{}: {}: {}", context, status, msg),
+        None => format!("{}: {}", context, status),
+    };
+    Err(match status.as_u16() {
+        401 | 403 => HardshareError::Auth(message),
+        404 => HardshareError::NotFound(message),
+        status => HardshareError::Api { status, message },
+    })
+}
+
+// Each daemon has its own shared secret, so that several can run on one host
+fn get_daemon_token_path(port: u16) -> Option<std::path::PathBuf> {
+    mgmt::get_base_path().map(|base_path| base_path.join(format!("daemon-{}.token", port)))
+}
+
+fn new_daemon_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+// Save the shared secret for requests to the local daemon at the given port,
+// where only this user can read it
+fn save_daemon_token(port: u16, token: &str) -> Result<(), HardshareError> {
+    let path = match get_daemon_token_path(port) {
+        Some(p) => p,
+        None => {
+            return config_error(
+                "cannot determine home directory; set HOME or HARDSHARE_CONFIG_DIR",
+            )
+        }
+    };
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut f = options.open(&path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        f.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    std::io::Write::write_all(&mut f, token.as_bytes())?;
+    Ok(())
+}
+
+// A daemon that is started with port 0, i.e., on a port chosen by the
+// operating system, records the port in the name of a file daemon-PORT.port
+fn get_daemon_port_path(port: u16) -> Option<std::path::PathBuf> {
+    mgmt::get_base_path().map(|base_path| base_path.join(format!("daemon-{}.port", port)))
+}
+
+fn parse_port_file_name(name: &str) -> Option<u16> {
+    match name
+        .strip_prefix("daemon-")?
+        .strip_suffix(".port")?
+        .parse::<u16>()
+    {
+        Ok(port) if port > 0 => Some(port),
+        _ => None,
+    }
+}
+
+fn list_port_files(base_path: &std::path::Path) -> Vec<u16> {
+    let mut ports = vec![];
+    if let Ok(entries) = std::fs::read_dir(base_path) {
+        for entry in entries.flatten() {
+            if let Some(port) = entry.file_name().to_str().and_then(parse_port_file_name) {
+                ports.push(port);
+            }
+        }
+    }
+    ports.sort_unstable();
+    ports
+}
+
+// Ports recorded by daemons that were started with port 0. Some may be stale
+// if a daemon did not exit cleanly.
+pub fn list_daemon_ports() -> Vec<u16> {
+    match mgmt::get_base_path() {
+        Some(base_path) => list_port_files(&base_path),
+        None => vec![],
+    }
+}
+
+// Client for requests to the local daemon at the given address, with the
+// shared secret if known
+fn daemon_client(daemonaddr: &str) -> awc::Client {
+    let builder = awc::Client::builder().timeout(request_timeout());
+    let token_path = daemonaddr
+        .parse::<std::net::SocketAddr>()
+        .ok()
+        .and_then(|addr| get_daemon_token_path(addr.port()));
+    match token_path.and_then(|path| std::fs::read_to_string(path).ok()) {
+        Some(token) => builder
+            .add_default_header(("Authorization", format!("Bearer {}", token.trim())))
+            .finish(),
+        None => builder.finish(),
+    }
+}
+
+fn is_daemon_request_authorized(req: &actix_web::dev::ServiceRequest, token: &str) -> bool {
+    match req.headers().get("Authorization") {
+        Some(v) => v.as_bytes() == format!("Bearer {}", token).as_bytes(),
+        None => false,
+    }
+}
+
 async fn get_access_rules_a(
     client: &awc::Client,
     origin: &str,
     wdid: &str,
-) -> Result<AccessRules, Box<dyn std::error::Error>> {
+) -> Result<AccessRules, HardshareError> {
     let url = format!("{}/deployment/{}/rules", origin, wdid);
-    let mut resp = client.get(url).send().await?;
+    let mut resp = send_with_retry(|| client.get(url.as_str())).await?;
     if resp.status() == 200 {
-        let payload: AccessRules = serde_json::from_slice(resp.body().await?.as_ref())?;
+        let mut payload: AccessRules = serde_json::from_slice(resp.body().await?.as_ref())?;
+        for rule in payload.rules.iter_mut() {
+            if let Some(param) = &rule.param {
+                rule.schedule = Schedule::from_param(param).map(|x| x.to_string());
+            }
+        }
         Ok(payload)
-    } else if resp.status() == 400 {
-        let payload: serde_json::Value = serde_json::from_slice(resp.body().await?.as_ref())?;
-        error(payload["error_message"].as_str().unwrap())
     } else {
-        error(format!(
-            "error contacting core API server: {}",
-            resp.status()
-        ))
+        error_from_response("error contacting core API server", &mut resp).await
     }
 }
 
@@ -199,12 +816,12 @@ impl HSAPIClient {
             cached_api_token: None,
             origin,
             wdid_tab: None,
+            metrics_tab: HashMap::new(),
+            runtime: ClientRuntime::default(),
         };
 
         #[cfg(not(test))]
-        let origin = option_env!("REROBOTS_ORIGIN")
-            .unwrap_or("https://api.rerobots.net")
-            .to_string();
+        let origin = resolve_origin(std::env::var("REROBOTS_ORIGIN").ok());
 
         #[cfg(not(test))]
         let mut hsclient = match mgmt::get_local_config(false, false) {
@@ -213,6 +830,8 @@ impl HSAPIClient {
                 cached_api_token: None,
                 origin,
                 wdid_tab: None,
+                metrics_tab: HashMap::new(),
+                runtime: ClientRuntime::default(),
             },
             Err(_) => {
                 return HSAPIClient {
@@ -220,6 +839,8 @@ impl HSAPIClient {
                     cached_api_token: None,
                     origin,
                     wdid_tab: None,
+                    metrics_tab: HashMap::new(),
+                    runtime: ClientRuntime::default(),
                 }
             }
         };
@@ -241,96 +862,196 @@ impl HSAPIClient {
         hsclient
     }
 
-    fn reload_config(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    // Use the API token of the given organization, without changing default_org
+    pub fn use_org(&mut self, org_name: &str) -> Result<(), HardshareError> {
+        let local_config = match &self.local_config {
+            Some(local_config) => local_config,
+            None => return config_error("No local configuration"),
+        };
+        match local_config.api_tokens.get(org_name) {
+            Some(paths) if !paths.is_empty() => {
+                let raw_tok = std::fs::read(&paths[0])?;
+                let tok = match String::from_utf8(raw_tok) {
+                    Ok(t) => t.trim().to_string(),
+                    Err(_) => return auth_error("API token is not valid UTF-8"),
+                };
+                self.cached_api_token = Some(tok);
+                Ok(())
+            }
+            _ => auth_error(format!("No valid API tokens found for org {}", org_name)),
+        }
+    }
+
+    fn reload_config(&mut self) -> Result<(), HardshareError> {
         let local_config = mgmt::get_local_config(false, false)?;
         self.local_config = Some(local_config);
         Ok(())
     }
 
-    fn create_client_generator(
-        &self,
-    ) -> Result<impl FnOnce() -> awc::Client, Box<dyn std::error::Error>> {
+    fn create_client_generator(&self) -> Result<impl FnOnce() -> awc::Client, HardshareError> {
         let api_token = match &self.cached_api_token {
             Some(tok) => tok.clone(),
             None => match &self.local_config {
                 Some(local_config) => {
                     return match &local_config.default_org {
                         Some(default_org) => {
-                            error(format!("No valid API tokens found for org {}", default_org))
+                            auth_error(format!("No valid API tokens found for org {}", default_org))
                         }
-                        None => error("No valid API tokens found (no default org)"),
+                        None => auth_error("No valid API tokens found (no default org)"),
                     }
                 }
-                None => return error("No valid API tokens found"),
+                None => return auth_error("No valid API tokens found"),
             },
         };
 
         Ok(Box::new(move || {
-            awc::Client::builder()
-                .add_default_header(("Authorization", format!("Bearer {}", api_token)))
-                .finish()
+            CLIENTS.with(|clients| {
+                clients
+                    .borrow_mut()
+                    .entry(api_token.clone())
+                    .or_insert_with(|| {
+                        proxy::client(format!("Bearer {}", api_token), request_timeout())
+                    })
+                    .clone()
+            })
         }))
     }
 
+    // If the API token in use expires within the warning threshold, return the
+    // number of seconds remaining. The threshold defaults to 1 day and can be
+    // changed with the environment variable HARDSHARE_TOKEN_EXPIRY_WARNING.
+    pub fn token_expiring_soon(&self) -> Option<i64> {
+        let local_config = self.local_config.as_ref()?;
+        let org_name = match &local_config.default_org {
+            Some(default_org) => default_org.as_str(),
+            None => "()",
+        };
+        let claims = local_config.api_tokens_data.get(org_name)?.first()?;
+        let expiration = claims.expiration? as i64;
+
+        let threshold = match std::env::var("HARDSHARE_TOKEN_EXPIRY_WARNING") {
+            Ok(x) => match x.parse::<i64>() {
+                Ok(t) => t,
+                Err(_) => {
+                    warn!("ignoring invalid HARDSHARE_TOKEN_EXPIRY_WARNING: {}", x);
+                    DEFAULT_TOKEN_EXPIRY_WARNING
+                }
+            },
+            Err(_) => DEFAULT_TOKEN_EXPIRY_WARNING,
+        };
+
+        let remaining = expiration - chrono::Utc::now().timestamp();
+        if remaining < threshold {
+            Some(remaining)
+        } else {
+            None
+        }
+    }
+
+    pub fn get_token_claims(&self) -> Result<TokenClaims, HardshareError> {
+        match &self.cached_api_token {
+            Some(tok) => match TokenClaims::new(tok) {
+                Ok(claims) => Ok(claims),
+                Err(err) => auth_error(err),
+            },
+            None => auth_error("No valid API tokens found."),
+        }
+    }
+
+    // With `with_instances`, each workspace deployment in the result has an
+    // `instances` array of its active instances. With `use_cache`, a recent
+    // result for the same API token and options is returned without a request.
     pub fn get_remote_config(
         &self,
         include_dissolved: bool,
-    ) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        with_instances: bool,
+        use_cache: bool,
+    ) -> Result<serde_json::Value, HardshareError> {
         let client = self.create_client_generator()?;
+        let cache_path = get_cache_dir().map(|cache_dir| {
+            remote_config_cache_path(
+                &cache_dir,
+                &self.origin,
+                self.cached_api_token.as_deref().unwrap_or(""),
+                include_dissolved,
+                with_instances,
+            )
+        });
+        if use_cache {
+            if let Some(cache_path) = &cache_path {
+                if let Some(rc) = read_fresh_cache(cache_path, get_remote_config_ttl()) {
+                    debug!("using cached remote config: {}", cache_path.display());
+                    return Ok(rc);
+                }
+            }
+        }
+
         let origin = self.origin.clone();
-        let sys = System::new();
-        actix::SystemRunner::block_on(&sys, async move {
-            let listurl_path = if include_dissolved {
-                "/hardshare/list?with_dissolved"
+        let rc: serde_json::Value = self.runtime.block_on(move || async move {
+            let mut params = vec![];
+            if include_dissolved {
+                params.push("with_dissolved");
+            }
+            if with_instances {
+                params.push("with_instances");
+            }
+            let url = if params.is_empty() {
+                format!("{}/hardshare/list", origin)
             } else {
-                "/hardshare/list"
+                format!("{}/hardshare/list?{}", origin, params.join("&"))
             };
-            let url = format!("{}{}", origin, listurl_path);
 
             let client = client();
-            let mut resp = client.get(url).send().await?;
+            let mut resp = send_with_retry(|| client.get(url.as_str())).await?;
             if resp.status() == 200 {
                 Ok(serde_json::from_slice(resp.body().await?.as_ref())?)
-            } else if resp.status() == 400 {
-                let payload: serde_json::Value =
-                    serde_json::from_slice(resp.body().await?.as_ref())?;
-                error(String::from(payload["error_message"].as_str().unwrap()))
             } else {
-                error(format!(
-                    "error contacting core API server: {}",
-                    resp.status()
-                ))
+                error_from_response("error contacting core API server", &mut resp).await
             }
-        })
+        })?;
+
+        // Servers that do not support `with_instances` ignore it
+        if with_instances {
+            if let Some(wds) = rc["wdeployments"].as_array() {
+                if wds.iter().any(|wd| wd.get("instances").is_none()) {
+                    return error("server does not support listing instances of deployments");
+                }
+            }
+        }
+
+        if let Some(cache_path) = &cache_path {
+            if let Err(err) = write_cache(cache_path, &rc) {
+                warn!("failed to cache remote config: {}", err);
+            }
+        }
+        Ok(rc)
     }
 
-    pub fn get_access_rules(&self, wdid: &str) -> Result<AccessRules, Box<dyn std::error::Error>> {
+    pub fn get_access_rules(&self, wdid: &str) -> Result<AccessRules, HardshareError> {
         let client = self.create_client_generator()?;
         let origin = self.origin.clone();
         let wdid = wdid.to_string();
-        let sys = System::new();
-        actix::SystemRunner::block_on(&sys, async move {
-            get_access_rules_a(&client(), &origin, &wdid).await
-        })
+        self.runtime
+            .block_on(move || async move { get_access_rules_a(&client(), &origin, &wdid).await })
     }
 
-    pub fn drop_access_rules(&self, wdid: &str) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn drop_access_rules(&self, wdid: &str) -> Result<(), HardshareError> {
+        invalidate_remote_config_cache();
         let client = self.create_client_generator()?;
         let origin = self.origin.clone();
         let wdid = wdid.to_string();
-        let sys = System::new();
-        actix::SystemRunner::block_on(&sys, async move {
+        self.runtime.block_on(move || async move {
             let client = client();
             let ruleset = get_access_rules_a(&client, &origin, &wdid).await?;
             for rule in ruleset.rules.iter() {
                 let url = format!("{}/deployment/{}/rule/{}", origin, wdid, rule.id);
-                let resp = client.delete(url).send().await?;
+                let mut resp = send_with_retry(|| client.delete(url.as_str())).await?;
                 if resp.status() != 200 {
-                    return error(format!(
-                        "error deleting rule {}: {}",
-                        rule.id,
-                        resp.status()
-                    ));
+                    return error_from_response(
+                        &format!("error deleting rule {}", rule.id),
+                        &mut resp,
+                    )
+                    .await;
                 }
             }
 
@@ -342,131 +1063,220 @@ impl HSAPIClient {
         &self,
         wdid: &str,
         to_user: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+        param: Option<serde_json::Value>,
+    ) -> Result<(), HardshareError> {
+        invalidate_remote_config_cache();
         let client = self.create_client_generator()?;
-        let td = std::time::Duration::new(10, 0);
         let origin = self.origin.clone();
         let wdid = wdid.to_string();
         let to_user = to_user.to_string();
-        let sys = System::new();
-        actix::SystemRunner::block_on(&sys, async move {
-            let mut body = HashMap::new();
-            body.insert("cap", "CAP_INSTANTIATE");
-            body.insert("user", to_user.as_str());
+        self.runtime.block_on(move || async move {
+            let mut body = json!({
+                "cap": "CAP_INSTANTIATE",
+                "user": to_user,
+            });
+            if let Some(param) = param {
+                body["param"] = param;
+            }
 
             let url = format!("{}/deployment/{}/rule", origin, wdid);
             let client = client();
-            let client_req = client.post(url).timeout(td);
+            let client_req = client.post(url);
+            let mut resp = client_req.send_json(&body).await?;
+            if resp.status() == 404 {
+                return not_found();
+            } else if resp.status() != 200 {
+                return error_from_response("server indicated error", &mut resp).await;
+            }
+
+            // Without its schedule, the new rule would permit access at all times
+            if body.get("param").is_some() {
+                let ruleset = get_access_rules_a(&client, &origin, &wdid).await?;
+                if !ruleset
+                    .rules
+                    .iter()
+                    .any(|rule| rule.user == to_user && rule.param.as_ref() == body.get("param"))
+                {
+                    return error(
+                        "server did not keep the schedule of the new rule, so it may permit access at all times; check with `hardshare rules -l`",
+                    );
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    pub fn toggle_lockout(&self, wdid: &str, make_locked: bool) -> Result<(), HardshareError> {
+        invalidate_remote_config_cache();
+        let client = self.create_client_generator()?;
+        let origin = self.origin.clone();
+        let wdid = wdid.to_string();
+        self.runtime.block_on(move || async move {
+            let client = client();
+            let url = format!("{}/deployment/{}/lockout", origin, wdid);
+            let mut resp = if make_locked {
+                client.post(url).send().await?
+            } else {
+                send_with_retry(|| client.delete(url.as_str())).await?
+            };
+            if resp.status() != 200 {
+                return error_from_response("error changing lock-out", &mut resp).await;
+            }
+
+            Ok(())
+        })
+    }
+
+    pub fn send_alert(&self, wdid: &str, message: &str) -> Result<(), HardshareError> {
+        let client = self.create_client_generator()?;
+        let origin = self.origin.clone();
+        let wdid = wdid.to_string();
+        let message = message.to_string();
+        self.runtime.block_on(move || async move {
+            let mut body = HashMap::new();
+            body.insert("msg", message);
+
+            let url = format!("{}/hardshare/alert/{}", origin, wdid);
+            let client = client();
+            let client_req = client.post(url);
             let mut resp = client_req.send_json(&body).await?;
-            if resp.status() == 400 {
-                let payload: serde_json::Value =
-                    serde_json::from_slice(resp.body().await?.as_ref())?;
-                return error(payload["error_message"].as_str().unwrap());
-            } else if resp.status() == 404 {
-                return error("not found".to_string());
+            if resp.status() == 404 {
+                return not_found();
             } else if resp.status() != 200 {
-                return error(format!("server indicated error: {}", resp.status()));
+                return error_from_response("server indicated error", &mut resp).await;
             }
 
             Ok(())
         })
     }
 
-    pub fn toggle_lockout(
-        &self,
-        wdid: &str,
-        make_locked: bool,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    // Request a new instance of the workspace deployment, as a user would.
+    // The result is the instance ID and the private key for ssh.
+    pub fn new_instance(&self, wdid: &str) -> Result<(String, String), HardshareError> {
+        invalidate_remote_config_cache();
         let client = self.create_client_generator()?;
         let origin = self.origin.clone();
         let wdid = wdid.to_string();
-        let sys = System::new();
-        actix::SystemRunner::block_on(&sys, async move {
+        self.runtime.block_on(move || async move {
+            let url = format!("{}/new", origin);
             let client = client();
-            let url = format!("{}/deployment/{}/lockout", origin, wdid);
-            let resp = if make_locked {
-                client.post(url).send().await?
-            } else {
-                client.delete(url).send().await?
-            };
+            let mut resp = client
+                .post(url)
+                .send_json(&json!({ "wds": [wdid] }))
+                .await?;
             if resp.status() != 200 {
-                return error(format!("error changing lock-out: {}", resp.status()));
+                return error_from_response("failed to create instance", &mut resp).await;
             }
+            let payload: serde_json::Value = serde_json::from_slice(resp.body().await?.as_ref())?;
+            match (payload["id"].as_str(), payload["sshkey"].as_str()) {
+                (Some(id), Some(sshkey)) => Ok((id.to_string(), sshkey.to_string())),
+                _ => error("response to new instance request is missing `id` or `sshkey`"),
+            }
+        })
+    }
 
-            Ok(())
+    pub fn get_instance_info(&self, instance_id: &str) -> Result<InstanceInfo, HardshareError> {
+        let client = self.create_client_generator()?;
+        let origin = self.origin.clone();
+        let instance_id = instance_id.to_string();
+        self.runtime.block_on(move || async move {
+            let url = format!("{}/instance/{}", origin, instance_id);
+            let client = client();
+            let mut resp = send_with_retry(|| client.get(url.as_str())).await?;
+            if resp.status() == 200 {
+                Ok(serde_json::from_slice(resp.body().await?.as_ref())?)
+            } else {
+                error_from_response("error contacting core API server", &mut resp).await
+            }
+        })
+    }
+
+    pub fn terminate_instance(&self, instance_id: &str) -> Result<(), HardshareError> {
+        invalidate_remote_config_cache();
+        let client = self.create_client_generator()?;
+        let origin = self.origin.clone();
+        let instance_id = instance_id.to_string();
+        self.runtime.block_on(move || async move {
+            let url = format!("{}/terminate/{}", origin, instance_id);
+            let client = client();
+            let mut resp = client.post(url).send().await?;
+            if resp.status() == 200 {
+                Ok(())
+            } else {
+                error_from_response("failed to terminate instance", &mut resp).await
+            }
         })
     }
 
-    pub fn send_alert(&self, wdid: &str, message: &str) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn register_hook_emails(&self, wdid: &str, addr: Vec<&str>) -> Result<(), HardshareError> {
+        invalidate_remote_config_cache();
         let client = self.create_client_generator()?;
         let origin = self.origin.clone();
         let wdid = wdid.to_string();
-        let message = message.to_string();
-        let sys = System::new();
-        actix::SystemRunner::block_on(&sys, async move {
+        let addr: Vec<String> = addr.iter().map(|x| x.to_string()).collect();
+        self.runtime.block_on(move || async move {
             let mut body = HashMap::new();
-            body.insert("msg", message);
+            body.insert("emails", addr);
 
-            let url = format!("{}/hardshare/alert/{}", origin, wdid);
+            let url = format!("{}/hardshare/hook/email/{}", origin, wdid);
             let client = client();
             let client_req = client.post(url);
             let mut resp = client_req.send_json(&body).await?;
-            if resp.status() == 400 {
-                let payload: serde_json::Value =
-                    serde_json::from_slice(resp.body().await?.as_ref())?;
-                return error(payload["error_message"].as_str().unwrap());
-            } else if resp.status() == 404 {
-                return error("not found".to_string());
+            if resp.status() == 404 {
+                return not_found();
             } else if resp.status() != 200 {
-                return error(format!("server indicated error: {}", resp.status()));
+                return error_from_response("server indicated error", &mut resp).await;
             }
 
             Ok(())
         })
     }
 
-    pub fn register_hook_emails(
+    pub fn register_hook_webhooks(
         &self,
         wdid: &str,
-        addr: Vec<&str>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+        urls: Vec<&str>,
+    ) -> Result<(), HardshareError> {
+        invalidate_remote_config_cache();
+        for url in urls.iter() {
+            validate_webhook_url(url)?;
+        }
         let client = self.create_client_generator()?;
         let origin = self.origin.clone();
         let wdid = wdid.to_string();
-        let addr: Vec<String> = addr.iter().map(|x| x.to_string()).collect();
-        let sys = System::new();
-        actix::SystemRunner::block_on(&sys, async move {
+        let urls: Vec<String> = urls.iter().map(|x| x.to_string()).collect();
+        self.runtime.block_on(move || async move {
             let mut body = HashMap::new();
-            body.insert("emails", addr);
+            body.insert("urls", urls);
 
-            let url = format!("{}/hardshare/hook/email/{}", origin, wdid);
+            let url = format!("{}/hardshare/hook/webhook/{}", origin, wdid);
             let client = client();
             let client_req = client.post(url);
             let mut resp = client_req.send_json(&body).await?;
-            if resp.status() == 400 {
-                let payload: serde_json::Value =
-                    serde_json::from_slice(resp.body().await?.as_ref())?;
-                return error(payload["error_message"].as_str().unwrap());
-            } else if resp.status() == 404 {
-                return error("not found".to_string());
+            if resp.status() == 404 {
+                return not_found();
             } else if resp.status() != 200 {
-                return error(format!("server indicated error: {}", resp.status()));
+                return error_from_response("server indicated error", &mut resp).await;
             }
 
             Ok(())
         })
     }
 
-    pub fn dissolve_wdeployment(&mut self, wdid: &str) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn dissolve_wdeployment(&mut self, wdid: &str) -> Result<(), HardshareError> {
+        invalidate_remote_config_cache();
         let local_config = match &self.local_config {
             Some(local_config) => {
                 if local_config.wdeployments.is_empty() {
-                    return error("Unexpected dissolve request: local configuration is empty");
+                    return config_error(
+                        "Unexpected dissolve request: local configuration is empty",
+                    );
                 }
                 local_config
             }
             None => {
-                return error("cannot dissolve without local configuration");
+                return config_error("cannot dissolve without local configuration");
             }
         };
 
@@ -482,13 +1292,12 @@ impl HSAPIClient {
         let client = self.create_client_generator()?;
         let origin = self.origin.clone();
         let url = format!("{}/hardshare/dis/{}", origin, wdid);
-        let sys = System::new();
-        actix::SystemRunner::block_on(&sys, async move {
+        self.runtime.block_on(move || async move {
             let client = client();
 
-            let resp = client.post(url).send().await?;
+            let mut resp = client.post(url).send().await?;
             if resp.status() != 200 {
-                return error(format!("error dissolving: {}", resp.status()));
+                return error_from_response("error dissolving", &mut resp).await;
             }
 
             Ok(())
@@ -505,16 +1314,15 @@ impl HSAPIClient {
         &self,
         wdid: &str,
         addon: &AddOn,
-    ) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    ) -> Result<serde_json::Value, HardshareError> {
         let client = self.create_client_generator()?;
         let origin = self.origin.clone();
         let wdid = wdid.to_string();
         let addon = addon.clone();
-        let sys = System::new();
-        actix::SystemRunner::block_on(&sys, async move {
+        self.runtime.block_on(move || async move {
             let url = format!("{}/deployment/{}", origin, wdid);
             let client = client();
-            let mut resp = client.get(url).send().await?;
+            let mut resp = send_with_retry(|| client.get(url.as_str())).await?;
             if resp.status() == 200 {
                 let mut payload: serde_json::Value =
                     serde_json::from_slice(resp.body().await?.as_ref())?;
@@ -528,34 +1336,22 @@ impl HSAPIClient {
                 } else {
                     Ok(payload["addons_config"][addon.to_string()].take())
                 }
-            } else if resp.status() == 400 {
-                let payload: serde_json::Value =
-                    serde_json::from_slice(resp.body().await?.as_ref())?;
-                error(payload["error_message"].as_str().unwrap())
             } else {
-                error(format!(
-                    "error contacting core API server: {}",
-                    resp.status()
-                ))
+                error_from_response("error contacting core API server", &mut resp).await
             }
         })
     }
 
-    pub fn remove_addon(
-        &self,
-        wdid: &str,
-        addon: &AddOn,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn remove_addon(&self, wdid: &str, addon: &AddOn) -> Result<(), HardshareError> {
+        invalidate_remote_config_cache();
         let client = self.create_client_generator()?;
-        let td = std::time::Duration::new(10, 0);
         let origin = self.origin.clone();
         let wdid = wdid.to_string();
         let addon = addon.clone();
-        let sys = System::new();
-        actix::SystemRunner::block_on(&sys, async move {
+        self.runtime.block_on(move || async move {
             let url = format!("{}/deployment/{}", origin, wdid);
             let client = client();
-            let mut resp = client.get(url).send().await?;
+            let mut resp = send_with_retry(|| client.get(url.as_str())).await?;
             if resp.status() == 200 {
                 let mut payload: serde_json::Value =
                     serde_json::from_slice(resp.body().await?.as_ref())?;
@@ -577,57 +1373,36 @@ impl HSAPIClient {
                         }
                         update_payload.insert("supported_addons".into(), supported_addons.into());
                         let url = format!("{}/hardshare/wd/{}", origin, wdid);
-                        let resp = client
-                            .post(url)
-                            .timeout(td)
-                            .send_json(&update_payload)
-                            .await?;
+                        let mut resp = client.post(url).send_json(&update_payload).await?;
                         if resp.status() == 200 {
                             Ok(())
                         } else {
-                            error(format!(
-                                "error contacting hardshare server: {}",
-                                resp.status()
-                            ))
+                            error_from_response("error contacting hardshare server", &mut resp)
+                                .await
                         }
                     }
                     None => Ok(()),
                 }
-            } else if resp.status() == 400 {
-                let payload: serde_json::Value =
-                    serde_json::from_slice(resp.body().await?.as_ref())?;
-                error(payload["error_message"].as_str().unwrap())
             } else {
-                error(format!(
-                    "error contacting core API server: {}",
-                    resp.status()
-                ))
+                error_from_response("error contacting core API server", &mut resp).await
             }
         })
     }
 
-    fn is_locked_out(&self, wdid: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    pub fn is_locked_out(&self, wdid: &str) -> Result<bool, HardshareError> {
         let client = self.create_client_generator()?;
         let origin = self.origin.clone();
         let wdid = wdid.to_string();
-        let sys = System::new();
-        actix::SystemRunner::block_on(&sys, async move {
+        self.runtime.block_on(move || async move {
             let url = format!("{}/deployment/{}", origin, wdid);
             let client = client();
-            let mut resp = client.get(url).send().await?;
+            let mut resp = send_with_retry(|| client.get(url.as_str())).await?;
             if resp.status() == 200 {
                 let payload: serde_json::Value =
                     serde_json::from_slice(resp.body().await?.as_ref())?;
                 Ok(payload["lockout"].as_bool().unwrap_or(false))
-            } else if resp.status() == 400 {
-                let payload: serde_json::Value =
-                    serde_json::from_slice(resp.body().await?.as_ref())?;
-                error(payload["error_message"].as_str().unwrap())
             } else {
-                error(format!(
-                    "error contacting core API server: {}",
-                    resp.status()
-                ))
+                error_from_response("error contacting core API server", &mut resp).await
             }
         })
     }
@@ -637,17 +1412,16 @@ impl HSAPIClient {
         wdid: &str,
         addon: &AddOn,
         config: Option<serde_json::Value>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<(), HardshareError> {
+        invalidate_remote_config_cache();
         let client = self.create_client_generator()?;
-        let td = std::time::Duration::new(10, 0);
         let origin = self.origin.clone();
         let wdid = wdid.to_string();
         let addon = addon.clone();
-        let sys = System::new();
-        actix::SystemRunner::block_on(&sys, async move {
+        self.runtime.block_on(move || async move {
             let url = format!("{}/deployment/{}", origin, wdid);
             let client = client();
-            let mut resp = client.get(url).send().await?;
+            let mut resp = send_with_retry(|| client.get(url.as_str())).await?;
             if resp.status() == 200 {
                 let mut payload: serde_json::Value =
                     serde_json::from_slice(resp.body().await?.as_ref())?;
@@ -676,71 +1450,69 @@ impl HSAPIClient {
                                 .insert(this_addon, this_addon_config);
                         }
                         None => {
-                            let addons_config = json!({
-                                "addons_config": {
-                                    this_addon: this_addon_config
-                                }
-                            });
+                            let addons_config = json!({ this_addon: this_addon_config });
                             update_payload.insert("addons_config".into(), addons_config);
                         }
                     }
                 }
 
                 let url = format!("{}/hardshare/wd/{}", origin, wdid);
-                let resp = client
-                    .post(url)
-                    .timeout(td)
-                    .send_json(&update_payload)
-                    .await?;
+                let mut resp = client.post(url).send_json(&update_payload).await?;
                 if resp.status() == 200 {
                     Ok(())
                 } else {
-                    error(format!(
-                        "error contacting hardshare server: {}",
-                        resp.status()
-                    ))
+                    error_from_response("error contacting hardshare server", &mut resp).await
                 }
-            } else if resp.status() == 400 {
-                let payload: serde_json::Value =
-                    serde_json::from_slice(resp.body().await?.as_ref())?;
-                error(payload["error_message"].as_str().unwrap())
             } else {
-                error(format!(
-                    "error contacting core API server: {}",
-                    resp.status()
-                ))
+                error_from_response("error contacting core API server", &mut resp).await
             }
         })
     }
 
-    pub fn add_mistyproxy(&self, wdid: &str, addr: &str) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn add_mistyproxy(&self, wdid: &str, addr: &str) -> Result<(), HardshareError> {
+        validate_mistyproxy_addr(addr)?;
         let mistyproxy_config = json!({ "ip": addr });
-        self.upsert_addon(wdid, &AddOn::MistyProxy, Some(mistyproxy_config))
+        self.set_addon(wdid, &AddOn::MistyProxy, mistyproxy_config)
+    }
+
+    pub fn add_webrtc(&self, wdid: &str, config: &WebrtcConfig) -> Result<(), HardshareError> {
+        if config.ice_servers.is_empty() {
+            return invalid("at least one STUN or TURN server is required");
+        }
+        self.set_addon(wdid, &AddOn::Webrtc, serde_json::to_value(config)?)
+    }
+
+    // Add the add-on if needed, and replace its configuration
+    pub fn set_addon(
+        &self,
+        wdid: &str,
+        addon: &AddOn,
+        config: serde_json::Value,
+    ) -> Result<(), HardshareError> {
+        if !config.is_object() {
+            return invalid(format!("configuration of {} must be a JSON object", addon));
+        }
+        self.upsert_addon(wdid, addon, Some(config))
     }
 
-    pub fn stop(&self, wdid: &str, bindaddr: &str) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn stop(&self, wdid: &str, bindaddr: &str) -> Result<(), HardshareError> {
         let url = format!("http://{}/stop/{}", bindaddr, wdid);
-        let sys = System::new();
-        actix::SystemRunner::block_on(&sys, async {
-            awc::Client::new()
-                .post(url)
-                .send()
-                .await
-                .or_else(error)
-                .and_then(|resp| {
-                    if resp.status() == 200 {
-                        Ok(())
-                    } else {
-                        error(format!("{}", resp.status()))
-                    }
-                })
+        let bindaddr = bindaddr.to_string();
+        self.runtime.block_on(move || async move {
+            let mut resp = daemon_client(&bindaddr).post(url).send().await?;
+            if resp.status() == 200 {
+                Ok(())
+            } else {
+                error_from_response("error contacting daemon", &mut resp).await
+            }
         })
     }
 
     async fn ad(
         ac: &Arc<Mutex<HSAPIClient>>,
         wdid: String,
-    ) -> Result<Addr<MainActor>, Box<dyn std::error::Error>> {
+        once: bool,
+    ) -> Result<Addr<MainActor>, HardshareError> {
         let authheader;
         let url;
         let wd;
@@ -755,10 +1527,14 @@ impl HSAPIClient {
             wd = Arc::new(local_config.wdeployments[wd_index].clone());
         }
 
+        let metrics = Arc::new(control::Metrics::default());
         let (cworker_tx, cworker_rx) = mpsc::channel();
+        let ma_metrics = Arc::clone(&metrics);
         let main_actor_addr = MainActor::create(|_ctx| MainActor {
             worker_req: cworker_tx,
             wsclient_addr: None,
+            ws_opened: false,
+            metrics: ma_metrics,
         });
 
         let addr = open_websocket(&url, &authheader, &main_actor_addr, None)
@@ -766,8 +1542,15 @@ impl HSAPIClient {
             .unwrap();
         main_actor_addr.do_send(NewWS(Some(addr)));
 
+        {
+            let mut ac_inner = ac.lock().unwrap();
+            ac_inner.metrics_tab.insert(wdid, Arc::clone(&metrics));
+        }
+
         let ma_addr_for_cworker = main_actor_addr.clone();
-        std::thread::spawn(move || control::cworker(cworker_rx, ma_addr_for_cworker, wd));
+        std::thread::spawn(move || {
+            control::cworker(cworker_rx, ma_addr_for_cworker, wd, metrics, once)
+        });
 
         Ok(main_actor_addr)
     }
@@ -817,7 +1600,7 @@ impl HSAPIClient {
             }
         }
 
-        let addr = match HSAPIClient::ad(&ac, wdid_expanded.clone()).await {
+        let addr = match HSAPIClient::ad(&ac, wdid_expanded.clone(), false).await {
             Ok(a) => a,
             Err(err) => {
                 error!("{}", err);
@@ -839,10 +1622,12 @@ impl HSAPIClient {
         wdid: actix_web::web::Path<String>,
         ac: actix_web::web::Data<Arc<Mutex<HSAPIClient>>>,
     ) -> actix_web::HttpResponse {
-        let mut ac_inner = ac.lock().unwrap();
+        let mut ac_guard = ac.lock().unwrap();
+        let ac_inner = &mut *ac_guard;
         if let Some(wdid_tab) = &mut ac_inner.wdid_tab {
             match wdid_tab.remove(&*wdid) {
                 Some(addr) => {
+                    ac_inner.metrics_tab.remove(&*wdid);
                     if wdid_tab.is_empty() {
                         addr.do_send(MainActorCommand("STOP DAEMON".into()));
                     } else {
@@ -872,70 +1657,179 @@ impl HSAPIClient {
         actix_web::HttpResponse::Ok().json(daemon_status)
     }
 
-    pub fn run(&self, wdid: &str, bindaddr: &str) -> Result<(), Box<dyn std::error::Error>> {
+    async fn http_get_metrics(
+        ac: actix_web::web::Data<Arc<Mutex<HSAPIClient>>>,
+    ) -> actix_web::HttpResponse {
+        let ac_inner = ac.lock().unwrap();
+        actix_web::HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(control::render_metrics(&ac_inner.metrics_tab))
+    }
+
+    // Advertise each of the given workspace deployments in one daemon
+    pub fn run(
+        &self,
+        wdids: &[String],
+        bindaddr: &str,
+        serve_metrics: bool,
+        once: bool,
+    ) -> Result<(), HardshareError> {
         if self.cached_api_token.is_none() {
-            return error("No valid API tokens found.");
+            return auth_error("No valid API tokens found.");
+        }
+
+        if let Some(remaining) = self.token_expiring_soon() {
+            warn!("********");
+            warn!(
+                "API token expires in {} minutes; advertising will stop when it does",
+                remaining / 60
+            );
+            warn!("get a new token at https://rerobots.net/tokens");
+            warn!("********");
         }
 
-        match self.is_locked_out(wdid) {
-            Ok(locked_out) => {
-                if locked_out {
-                    warn!("advertised deployment {} is locked out", wdid);
-                    warn!("new instances will be rejected until unlock");
+        for wdid in wdids {
+            match self.is_locked_out(wdid) {
+                Ok(locked_out) => {
+                    if locked_out {
+                        warn!("advertised deployment {} is locked out", wdid);
+                        warn!("new instances will be rejected until unlock");
+                    }
+                }
+                Err(err) => {
+                    error!("failed to determine whether deployment is locked out");
+                    return Err(err);
                 }
-            }
-            Err(err) => {
-                error!("failed to determine whether deployment is locked out");
-                return Err(err);
             }
         }
 
-        // Try to start via daemon, if exists
-        let url = format!("http://{}/start/{}", bindaddr, wdid);
-        let sys = System::new();
-        let res = actix::SystemRunner::block_on(&sys, async {
-            awc::Client::new().post(url).send().await
-        });
-        match res {
-            Ok(res) => {
-                if res.status() == 403 {
-                    warn!("ignoring because daemon already advertising {}", wdid);
-                } else {
-                    info!("started via existing daemon");
-                }
+        // If the port is 0, then a new daemon is started on a port chosen by
+        // the operating system, so there is no existing daemon to contact.
+        let bindaddr: std::net::SocketAddr = match bindaddr.parse() {
+            Ok(a) => a,
+            Err(err) => return Err(HardshareError::Invalid(format!("{}: {}", err, bindaddr))),
+        };
+        if bindaddr.port() != 0 {
+            // The existing daemon would keep running after the instance is done
+            if once && self.get_local_status(&bindaddr.to_string()).is_ok() {
+                return error("cannot advertise once because a daemon is already running");
+            }
+
+            // Try to start via daemon, if exists
+            let mut existing_daemon = false;
+            for wdid in wdids {
+                let url = format!("http://{}/start/{}", bindaddr, wdid);
+                let res = self.runtime.block_on(move || async move {
+                    match daemon_client(&bindaddr.to_string()).post(url).send().await {
+                        Ok(resp) => Ok(resp.status()),
+                        Err(err) => Err(HardshareError::from(err)),
+                    }
+                });
+                match res {
+                    Ok(status) => {
+                        existing_daemon = true;
+                        if status == 403 {
+                            warn!("ignoring because daemon already advertising {}", wdid);
+                        } else if status == 401 {
+                            return auth_error(
+                                "existing daemon rejected request: missing or wrong token",
+                            );
+                        } else if status != 200 {
+                            return error(format!(
+                                "existing daemon failed to advertise {}: {}",
+                                wdid, status
+                            ));
+                        } else {
+                            info!("started {} via existing daemon", wdid);
+                        }
+                    }
+                    Err(err) => {
+                        info!("no existing daemon: {}", err);
+                        break;
+                    }
+                };
+            }
+            if existing_daemon {
                 return Ok(());
             }
-            Err(err) => info!("no existing daemon: {}", err),
-        };
+        }
 
         // Else, start new daemon
         info!("starting new daemon");
-        let bindaddr: std::net::SocketAddr = bindaddr.parse()?;
-        let wdid = String::from(wdid);
+        let wdids = wdids.to_vec();
+        let bound_port = Arc::new(AtomicU16::new(0));
+        let bound_port_in_sys = Arc::clone(&bound_port);
 
         let sys = System::new();
         let (err_notify, err_rx) = mpsc::channel();
         let ac = Arc::new(Mutex::new(self.clone()));
         sys.runtime().spawn(async move {
-            let addr = match HSAPIClient::ad(&ac, wdid.clone()).await {
-                Ok(a) => a,
-                Err(err) => {
-                    err_notify.send(format!("{}", err)).unwrap();
-                    System::current().stop_with_code(1);
-                    return;
-                }
-            };
-            let mut wdid_tab = HashMap::new();
-            wdid_tab.insert(wdid.clone(), addr.clone());
+            let mut wdid_tab: HashMap<String, Addr<MainActor>> = HashMap::new();
+            for wdid in wdids {
+                let addr = match HSAPIClient::ad(&ac, wdid.clone(), once).await {
+                    Ok(a) => a,
+                    Err(err) => {
+                        err_notify.send(format!("{}: {}", wdid, err)).unwrap();
+                        for addr in wdid_tab.values() {
+                            addr.do_send(MainActorCommand("STOP".into()));
+                        }
+                        System::current().stop_with_code(1);
+                        return;
+                    }
+                };
+                wdid_tab.insert(wdid, addr);
+            }
             {
                 let mut ac_inner = ac.lock().unwrap();
                 ac_inner.wdid_tab = Some(wdid_tab);
             }
 
+            let daemon_token = new_daemon_token();
+            let daemon_token_to_save = daemon_token.clone();
+
+            let ac_for_signals = Arc::clone(&ac);
+            actix::spawn(async move {
+                if let Err(err) = wait_for_termination().await {
+                    error!("failed to listen for termination signals: {}", err);
+                    return;
+                }
+                info!("received termination signal; stopping...");
+                let addrs: Vec<Addr<MainActor>> = match &ac_for_signals.lock().unwrap().wdid_tab {
+                    Some(wdid_tab) => wdid_tab.values().cloned().collect(),
+                    None => vec![],
+                };
+                let stopped = futures::future::join_all(
+                    addrs
+                        .iter()
+                        .map(|addr| addr.send(MainActorCommand("TEARDOWN".into()))),
+                )
+                .await;
+                for res in stopped {
+                    if let Err(err) = res {
+                        warn!("failed to stop main actor: {}", err);
+                    }
+                }
+                System::current().stop();
+            });
+
             let mut manip = actix_web::HttpServer::new(move || {
                 let ac = Arc::clone(&ac);
-                actix_web::App::new()
+                let daemon_token = daemon_token.clone();
+                let app = actix_web::App::new()
                     .app_data(ac)
+                    .wrap_fn(move |req, srv| {
+                        if is_daemon_request_authorized(&req, &daemon_token) {
+                            Either::Left(
+                                srv.call(req).map(|res| res.map(|r| r.map_into_left_body())),
+                            )
+                        } else {
+                            warn!("rejected unauthorized request: {}", req.path());
+                            let resp = req
+                                .into_response(actix_web::HttpResponse::Unauthorized().finish())
+                                .map_into_right_body();
+                            Either::Right(futures::future::ok(resp))
+                        }
+                    })
                     .wrap(actix_web::middleware::Logger::default())
                     .route(
                         "/status",
@@ -952,9 +1846,18 @@ impl HSAPIClient {
                     .route(
                         "/reload",
                         actix_web::web::post().to(HSAPIClient::http_post_reload_config),
+                    );
+                if serve_metrics {
+                    app.route(
+                        "/metrics",
+                        actix_web::web::get().to(HSAPIClient::http_get_metrics),
                     )
+                } else {
+                    app
+                }
             })
-            .workers(1);
+            .workers(1)
+            .disable_signals();
             manip = match manip.bind(bindaddr) {
                 Ok(s) => s,
                 Err(err) => {
@@ -965,6 +1868,36 @@ impl HSAPIClient {
                     return;
                 }
             };
+            let port = match manip.addrs().first() {
+                Some(addr) => addr.port(),
+                None => {
+                    err_notify
+                        .send(format!("failed to bind to {}", bindaddr))
+                        .unwrap();
+                    System::current().stop_with_code(1);
+                    return;
+                }
+            };
+            if let Err(err) = save_daemon_token(port, &daemon_token_to_save) {
+                err_notify
+                    .send(format!("failed to create daemon token: {}", err))
+                    .unwrap();
+                System::current().stop_with_code(1);
+                return;
+            }
+            if bindaddr.port() == 0 {
+                info!("daemon listening on port {}", port);
+                // The base path is known because the daemon token was saved there
+                let path = get_daemon_port_path(port).unwrap();
+                if let Err(err) = std::fs::write(path, format!("{}\n", process::id())) {
+                    err_notify
+                        .send(format!("failed to write daemon port file: {}", err))
+                        .unwrap();
+                    System::current().stop_with_code(1);
+                    return;
+                }
+                bound_port_in_sys.store(port, Ordering::Relaxed);
+            }
             match manip.run().await {
                 Ok(()) => (),
                 Err(err) => {
@@ -975,63 +1908,70 @@ impl HSAPIClient {
                 }
             }
         });
-        match sys.run() {
+        let res = sys.run();
+        let port = bound_port.load(Ordering::Relaxed);
+        if port != 0 {
+            if let Some(path) = get_daemon_port_path(port) {
+                if let Err(err) = std::fs::remove_file(path) {
+                    warn!("failed to remove daemon port file: {}", err);
+                }
+            }
+        }
+        match res {
             Ok(()) => Ok(()),
-            Err(_) => error(err_rx.recv()?),
+            Err(err) => match err_rx.recv() {
+                Ok(msg) => error(msg),
+                Err(_) => Err(err.into()),
+            },
         }
     }
 
-    pub fn get_local_status(
-        &self,
-        bindaddr: &str,
-    ) -> Result<DaemonStatus, Box<dyn std::error::Error>> {
+    pub fn get_local_status(&self, bindaddr: &str) -> Result<DaemonStatus, HardshareError> {
         let url = format!("http://{}/status", bindaddr);
-        let sys = System::new();
-        actix::SystemRunner::block_on(&sys, async {
-            let mut resp = awc::Client::new().get(url).send().await?;
+        let bindaddr = bindaddr.to_string();
+        self.runtime.block_on(move || async move {
+            let mut resp = daemon_client(&bindaddr).get(url).send().await?;
             if resp.status() == 200 {
                 let r: DaemonStatus = serde_json::from_slice(resp.body().await?.as_ref())?;
                 Ok(r)
             } else {
-                error(format!("error contacting daemon: {}", resp.status()))
+                error_from_response("error contacting daemon", &mut resp).await
             }
         })
     }
 
-    pub fn req_reload_config(&self, bindaddr: &str) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn req_reload_config(&self, bindaddr: &str) -> Result<(), HardshareError> {
         let url = format!("http://{}/reload", bindaddr);
-        let sys = System::new();
-        actix::SystemRunner::block_on(&sys, async {
-            let resp = awc::Client::new().post(url).send().await?;
+        let bindaddr = bindaddr.to_string();
+        self.runtime.block_on(move || async move {
+            let mut resp = daemon_client(&bindaddr).post(url).send().await?;
             if resp.status() == 200 {
                 Ok(())
             } else {
-                error(format!("error contacting daemon: {}", resp.status()))
+                error_from_response("error contacting daemon", &mut resp).await
             }
         })
     }
 
-    pub fn register_new(&mut self, at_most_1: bool) -> Result<String, Box<dyn std::error::Error>> {
+    pub fn register_new(&mut self, at_most_1: bool) -> Result<String, HardshareError> {
+        invalidate_remote_config_cache();
+        let url = format!("{}/hardshare/register", self.origin);
+        let client = self.create_client_generator()?;
+
         let local_config = match &mut self.local_config {
             Some(local_config) => {
                 if at_most_1 && !local_config.wdeployments.is_empty() {
-                    return error("local configuration already declares a workspace deployment (to register more, `hardshare register --permit-more`)");
+                    return config_error("local configuration already declares a workspace deployment (to register more, `hardshare register --permit-more`)");
                 }
                 local_config
             }
             None => {
-                return error("cannot register without initial local configuration. (try `hardshare config --create`)");
+                return config_error("cannot register without initial local configuration. (try `hardshare config --create`)");
             }
         };
 
-        let url = format!("{}/hardshare/register", self.origin);
-        let authheader = format!("Bearer {}", self.cached_api_token.as_ref().unwrap());
-
-        let sys = System::new();
-        let res = actix::SystemRunner::block_on(&sys, async {
-            let client = awc::Client::builder()
-                .add_default_header(("Authorization", authheader))
-                .finish();
+        let res = self.runtime.block_on(move || async move {
+            let client = client();
             let mut resp = client.post(url).send().await?;
             if resp.status() == 200 {
                 let payload: serde_json::Value =
@@ -1040,12 +1980,8 @@ impl HSAPIClient {
                 new_wd.insert("id".into(), json!(payload["id"].as_str().unwrap()));
                 new_wd.insert("owner".into(), json!(payload["owner"].as_str().unwrap()));
                 Ok(new_wd)
-            } else if resp.status() == 400 {
-                let payload: serde_json::Value =
-                    serde_json::from_slice(resp.body().await?.as_ref())?;
-                error(String::from(payload["error_message"].as_str().unwrap()))
             } else {
-                error(format!("server indicated error: {}", resp.status()))
+                error_from_response("server indicated error", &mut resp).await
             }
         });
         let new_wd = res?;
@@ -1060,26 +1996,25 @@ impl HSAPIClient {
         Ok(local_config.wdeployments.last().unwrap().id.clone())
     }
 
-    pub fn declare_existing(&mut self, wdid: &str) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn declare_existing(&mut self, wdid: &str) -> Result<(), HardshareError> {
+        invalidate_remote_config_cache();
         if let Some(local_config) = &self.local_config {
             for wd in local_config.wdeployments.iter() {
                 if wd.id == wdid {
-                    return error("attempted to declare workspace deployment that is already declared in local configuration");
+                    return config_error("attempted to declare workspace deployment that is already declared in local configuration");
                 }
             }
         } else {
-            return error("cannot declare existing without initial local configuration. (try `hardshare config --create`)");
+            return config_error("cannot declare existing without initial local configuration. (try `hardshare config --create`)");
         }
 
         let client = self.create_client_generator()?;
         let origin = self.origin.clone();
         let url = format!("{}/hardshare/list", origin);
-        let sys = System::new();
-
         let wdid = wdid.to_string();
-        let res = actix::SystemRunner::block_on(&sys, async move {
+        let res = self.runtime.block_on(move || async move {
             let client = client();
-            let mut resp = client.get(url).send().await?;
+            let mut resp = send_with_retry(|| client.get(url.as_str())).await?;
             if resp.status() == 200 {
                 let body = resp.body().await?;
                 let parsed_body: serde_json::Value = serde_json::from_slice(body.as_ref())?;
@@ -1095,22 +2030,17 @@ impl HSAPIClient {
                     }
                 }
                 Ok(None)
-            } else if resp.status() == 400 {
-                let payload: serde_json::Value =
-                    serde_json::from_slice(resp.body().await?.as_ref())?;
-                error(String::from(payload["error_message"].as_str().unwrap()))
             } else {
-                error(format!(
-                    "error contacting core API server: {}",
-                    resp.status()
-                ))
+                error_from_response("error contacting core API server", &mut resp).await
             }
         });
 
         let matched_wd = match res? {
             Some(matched_wd) => matched_wd,
             None => {
-                return error("no previously registered workspace deployments found with given ID")
+                return Err(HardshareError::NotFound(
+                    "no previously registered workspace deployments found with given ID".into(),
+                ))
             }
         };
 
@@ -1129,12 +2059,32 @@ impl HSAPIClient {
         &self,
         camera_path: &str,
         wds: &Vec<String>,
-        dim: &Option<CameraDimensions>,
         crop: &Option<CameraCrop>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let api_token = self.cached_api_token.as_ref().ok_or("no valid API token")?;
+        capture: camera::CaptureOptions,
+        duration: Option<Duration>,
+    ) -> Result<(), HardshareError> {
+        invalidate_remote_config_cache();
+        let api_token = match &self.cached_api_token {
+            Some(tok) => tok,
+            None => return auth_error("no valid API token"),
+        };
+
+        // Prepare local files before registering the camera with the server
+        let camera_dir = get_camera_dir(true)?;
+        for stale in remove_stale_camera_pids(&camera_dir)? {
+            info!(
+                "removed pid file of camera {} that is no longer running",
+                stale
+            );
+        }
+
+        if capture.dimensions.is_some()
+            && capture.on_unsupported_res == camera::ResolutionPolicy::Fail
+        {
+            // Fail before registering the camera with the server
+            camera::check_camera(camera_path, &capture.dimensions, capture.on_unsupported_res)?;
+        }
         let client = self.create_client_generator()?;
-        let td = std::time::Duration::new(10, 0);
         let origin = self.origin.clone();
 
         let mut opts = json!({ "wds": wds });
@@ -1142,61 +2092,133 @@ impl HSAPIClient {
             opts["crop"] = json!(crop);
         }
 
-        let sys = System::new();
-        let res = actix::SystemRunner::block_on(&sys, async move {
+        let res = self.runtime.block_on(move || async move {
             let client = client();
             let url = format!("{}/hardshare/cam", origin);
-            let client_req = client.post(url).timeout(td);
+            let client_req = client.post(url);
             let mut resp = client_req.send_json(&opts).await?;
             if resp.status() == 200 {
                 let payload: serde_json::Value =
                     serde_json::from_slice(resp.body().await?.as_ref())?;
                 Ok(payload["id"].as_str().unwrap().to_string())
             } else {
-                error(format!("server indicated error: {}", resp.status()))
+                error_from_response("server indicated error", &mut resp).await
             }
         });
         let hscamera_id = res?;
         debug!("registered new hscamera: {}", hscamera_id);
 
-        let base_path = mgmt::get_base_path().unwrap();
-        let path = base_path.join("camera");
-        if !path.exists() {
-            std::fs::create_dir(&path)?
-        }
-        let path = path.join(format!("{}.pid", hscamera_id));
+        let stats_path = camera_dir.join(format!("{}.stats", hscamera_id));
+        let path = camera_dir.join(format!("{}.pid", hscamera_id));
         let pid = process::id();
-        std::fs::write(&path, pid.to_string())?;
+        if let Err(err) = std::fs::write(&path, pid.to_string()) {
+            return error(format!("cannot write to {}: {}", path.display(), err));
+        }
 
         debug!("starting camera stream...");
-        let exit_result =
-            camera::stream_websocket(&self.origin, api_token, &hscamera_id, camera_path, dim);
-
-        if exit_result.is_err() {
-            std::fs::remove_file(path)?;
-            let client = self.create_client_generator()?;
-            let origin = self.origin.clone();
-            actix::SystemRunner::block_on(&sys, async move {
-                let client = client();
-                let url = format!("{}/hardshare/cam/{}", origin, hscamera_id);
-                let resp = client.delete(url).send().await?;
-                if resp.status() != 200 {
-                    return error(format!(
-                        "error stopping camera {}: {}",
-                        hscamera_id,
-                        resp.status()
-                    ));
+        let exit_result = camera::stream_websocket(
+            &self.origin,
+            api_token,
+            &hscamera_id,
+            camera_path,
+            capture,
+            Some(stats_path.clone()),
+            duration,
+        );
+
+        // The stream only ends due to error or after the given duration
+        std::fs::remove_file(path)?;
+        if stats_path.exists() {
+            std::fs::remove_file(stats_path)?;
+        }
+        let client = self.create_client_generator()?;
+        let origin = self.origin.clone();
+        self.runtime.block_on(move || async move {
+            let client = client();
+            let url = format!("{}/hardshare/cam/{}", origin, hscamera_id);
+            let mut resp = send_with_retry(|| client.delete(url.as_str())).await?;
+            if resp.status() != 200 {
+                return error_from_response(
+                    &format!("error stopping camera {}", hscamera_id),
+                    &mut resp,
+                )
+                .await;
+            }
+            Ok(())
+        })?;
+
+        Ok(exit_result?)
+    }
+
+    pub fn camera_status(&self) -> Result<Vec<CameraStatus>, HardshareError> {
+        let path = get_camera_dir(false)?;
+        let mut cameras = Vec::new();
+        if path.exists() {
+            for entry in std::fs::read_dir(path)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    continue;
                 }
-                Ok(())
-            })?;
+                let entry = entry.path();
+                if entry.extension().map(|x| x == "pid") != Some(true) {
+                    continue;
+                }
+
+                let pid = String::from_utf8_lossy(&std::fs::read(&entry)?)
+                    .trim()
+                    .to_string();
+                let stats_path = entry.with_extension("stats");
+                let stats = if stats_path.exists() {
+                    camera::read_stats(&stats_path).ok()
+                } else {
+                    None
+                };
+                cameras.push(CameraStatus {
+                    id: entry.file_stem().unwrap().to_string_lossy().to_string(),
+                    alive: process_is_alive(&pid),
+                    pid,
+                    wdeployments: vec![],
+                    registered: false,
+                    stats,
+                });
+            }
         }
+        if cameras.is_empty() {
+            return Ok(cameras);
+        }
+        cameras.sort_by(|a, b| a.id.cmp(&b.id));
 
-        exit_result
+        let client = self.create_client_generator()?;
+        let url = format!("{}/hardshare/cam", self.origin);
+        self.runtime.block_on(move || async move {
+            let client = client();
+            let mut resp = send_with_retry(|| client.get(url.as_str())).await?;
+            if resp.status() != 200 {
+                return error_from_response("error enumerating cameras", &mut resp).await;
+            }
+            let payload: serde_json::Value = serde_json::from_slice(resp.body().await?.as_ref())?;
+            let hscameras = match payload.as_object() {
+                Some(h) => h,
+                None => return error("unexpected response when enumerating cameras"),
+            };
+            for cam in cameras.iter_mut() {
+                if let Some(assoc) = hscameras.get(&cam.id) {
+                    cam.registered = true;
+                    if let Some(assoc) = assoc.as_array() {
+                        cam.wdeployments = assoc
+                            .iter()
+                            .filter_map(|x| x.as_str().map(|x| x.to_string()))
+                            .collect();
+                    }
+                }
+            }
+            Ok(cameras)
+        })
     }
 
-    pub fn stop_cameras(&self, all: bool) -> Result<(), Box<dyn std::error::Error>> {
-        let base_path = mgmt::get_base_path().unwrap();
-        let path = base_path.join("camera");
+    pub fn stop_cameras(&self, all: bool) -> Result<(), HardshareError> {
+        invalidate_remote_config_cache();
+        let path = get_camera_dir(false)?;
         let mut stopped_via_pids = Vec::new();
         if path.exists() {
             for entry in std::fs::read_dir(path)? {
@@ -1214,34 +2236,42 @@ impl HSAPIClient {
                         .trim()
                         .to_string();
 
-                    #[cfg(target_os = "windows")]
-                    let kresult = process::Command::new("taskkill")
-                        .args(["/pid", &pid])
-                        .status();
-                    #[cfg(any(target_os = "linux", target_os = "macos"))]
-                    let kresult = process::Command::new("kill").arg(&pid).status();
-
-                    match kresult {
-                        Ok(r) => {
-                            if !r.success() {
+                    if process_is_alive(&pid) {
+                        #[cfg(target_os = "windows")]
+                        let kresult = process::Command::new("taskkill")
+                            .args(["/pid", &pid])
+                            .status();
+                        #[cfg(any(target_os = "linux", target_os = "macos"))]
+                        let kresult = process::Command::new("kill").arg(&pid).status();
+
+                        // Exiting between the liveness check and kill is as good as stopped
+                        let kerr = match kresult {
+                            Ok(r) if !r.success() => Some(r.to_string()),
+                            Ok(_) => None,
+                            Err(err) => Some(err.to_string()),
+                        };
+                        if let Some(kerr) = kerr {
+                            if process_is_alive(&pid) {
                                 return error(format!(
                                     "failed to terminate local process {} for camera {}: {}",
                                     pid,
                                     stopped_via_pids.last().unwrap(),
-                                    r
+                                    kerr
                                 ));
                             }
                         }
-                        Err(err) => {
-                            return error(format!(
-                                "failed to terminate local process {} for camera {}: {}",
-                                pid,
-                                stopped_via_pids.last().unwrap(),
-                                err
-                            ));
-                        }
+                    } else {
+                        debug!(
+                            "process {} of camera {} already gone",
+                            pid,
+                            stopped_via_pids.last().unwrap()
+                        );
                     }
 
+                    let stats_path = entry.with_extension("stats");
+                    if stats_path.exists() {
+                        std::fs::remove_file(stats_path)?;
+                    }
                     std::fs::remove_file(entry)?;
                 }
             }
@@ -1255,10 +2285,9 @@ impl HSAPIClient {
         let client = self.create_client_generator()?;
         let origin = self.origin.clone();
         let url = format!("{}/hardshare/cam", origin);
-        let sys = System::new();
-        actix::SystemRunner::block_on(&sys, async move {
+        self.runtime.block_on(move || async move {
             let client = client();
-            let mut resp = client.get(url).send().await?;
+            let mut resp = send_with_retry(|| client.get(url.as_str())).await?;
             if resp.status() == 200 {
                 let payload: serde_json::Value =
                     serde_json::from_slice(resp.body().await?.as_ref())?;
@@ -1279,24 +2308,24 @@ impl HSAPIClient {
                             .map(|x| x.as_str().unwrap().to_string())
                             .collect();
 
-                        if !nonempty_intersection(&local_wdeployments, &assoc) {
+                        if !util::intersects(&local_wdeployments, &assoc) {
                             continue;
                         }
                     }
 
                     let url = format!("{}/hardshare/cam/{}", origin, hscamera_id);
-                    let resp = client.delete(url).send().await?;
+                    let mut resp = send_with_retry(|| client.delete(url.as_str())).await?;
                     if resp.status() != 200 {
-                        return error(format!(
-                            "error stopping camera {}: {}",
-                            hscamera_id,
-                            resp.status()
-                        ));
+                        return error_from_response(
+                            &format!("error stopping camera {}", hscamera_id),
+                            &mut resp,
+                        )
+                        .await;
                     }
                 }
                 Ok(())
             } else {
-                error(format!("error enumerating cameras: {}", resp.status()))
+                error_from_response("error enumerating cameras", &mut resp).await
             }
         })
     }
@@ -1308,22 +2337,20 @@ async fn open_websocket(
     authheader: &str,
     main_actor_addr: &Addr<MainActor>,
     timeout: Option<Duration>,
-) -> Result<Addr<WSClient>, Box<dyn std::error::Error>> {
+) -> Result<Addr<WSClient>, HardshareError> {
     let sleep_time = std::time::Duration::from_secs(1);
     let now = std::time::Instant::now();
 
     loop {
         let authheader_dup = String::from(authheader);
         let url_dup = String::from(url);
-        let client = awc::Client::builder()
-            .add_default_header(("Authorization", authheader))
-            .finish();
+        let client = proxy::client(authheader.to_string(), request_timeout());
 
         let (_, framed) = match client.ws(url).connect().await {
             Ok(c) => c,
             Err(err) => {
                 if timeout.is_some() && Some(now.elapsed()) > timeout {
-                    return Err(Box::new(err));
+                    return Err(err.into());
                 } else {
                     warn!("failed to open WebSocket: {}", err);
                     std::thread::sleep(sleep_time);
@@ -1343,6 +2370,8 @@ async fn open_websocket(
                 ws_sink: SinkWrite::new(sink, ctx),
                 recent_rx_instant: std::time::Instant::now(), // First instant at first connect
                 main_actor_addr: ma_addr_for_wsclient,
+                closing: false,
+                on_closed: None,
             }
         }));
     }
@@ -1354,21 +2383,38 @@ pub struct WSClient {
     ws_sink: SinkWrite<Message, SplitSink<Framed<BoxedSocket, Codec>, Message>>,
     recent_rx_instant: std::time::Instant,
     main_actor_addr: Addr<MainActor>,
+    closing: bool,
+
+    // Notified when the actor stops after CloseWS
+    on_closed: Option<tokio::sync::oneshot::Sender<()>>,
 }
 
+// Interval between PINGs sent to the server, plus up to HEARTBEAT_JITTER_MS
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
+const HEARTBEAT_JITTER_MS: u64 = 2000;
+
 #[derive(Message)]
 #[rtype(result = "()")]
 struct WSSend(String);
 
+// Close the WebSocket after sending all queued messages
+#[derive(Message)]
+#[rtype(result = "()")]
+struct CloseWS;
+
 impl Actor for WSClient {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Context<Self>) {
         self.check_receive_timeout(ctx);
+        self.send_heartbeat(ctx);
     }
 
     fn stopped(&mut self, _ctx: &mut Context<Self>) {
         debug!("WSClient actor stopped");
+        if let Some(on_closed) = self.on_closed.take() {
+            let _ = on_closed.send(());
+        }
     }
 }
 
@@ -1377,6 +2423,7 @@ impl WSClient {
         ctx.run_later(Duration::new(60, 0), |act, ctx| {
             if act.recent_rx_instant.elapsed() > Duration::new(45, 0) {
                 debug!("timeout waiting for server");
+                act.closing = true;
                 match act.ws_sink.write(Message::Close(None)) {
                     Ok(()) => (),
                     Err(err) => error!("caught while attempting to close WebSocket: {:?}", err),
@@ -1387,6 +2434,21 @@ impl WSClient {
             }
         });
     }
+
+    fn send_heartbeat(&self, ctx: &mut Context<Self>) {
+        let jitter = Duration::from_millis(rand::thread_rng().gen_range(0..HEARTBEAT_JITTER_MS));
+        ctx.run_later(HEARTBEAT_INTERVAL + jitter, |act, ctx| {
+            if act.closing {
+                return;
+            }
+            debug!("sending PING");
+            match act.ws_sink.write(Message::Ping(Bytes::from_static(b""))) {
+                Ok(()) => (),
+                Err(err) => error!("caught while sending WebSocket ping: {:?}", err),
+            }
+            act.send_heartbeat(ctx);
+        });
+    }
 }
 
 impl Handler<WSSend> for WSClient {
@@ -1403,6 +2465,92 @@ impl Handler<WSSend> for WSClient {
     }
 }
 
+impl Handler<CloseWS> for WSClient {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, _msg: CloseWS, _ctx: &mut Context<Self>) -> Self::Result {
+        self.closing = true;
+        if let Err(err) = self.ws_sink.write(Message::Close(None)) {
+            error!("caught while attempting to close WebSocket: {:?}", err);
+        }
+        // When the sink is closed, WriteHandler::finished stops this actor
+        self.ws_sink.close();
+        let (on_closed, closed) = tokio::sync::oneshot::channel();
+        self.on_closed = Some(on_closed);
+        Box::pin(async move {
+            let _ = closed.await;
+        })
+    }
+}
+
+// Parse a control message from the server, without panicking on missing fields
+fn parse_command(payload: &serde_json::Value) -> Result<CWorkerCommand, String> {
+    let message_ver = match payload["v"].as_i64() {
+        Some(v) => v,
+        None => return Err("received message with no version declaration".into()),
+    };
+    if message_ver != 0 {
+        return Err(format!(
+            "received message of unknown format version: {}",
+            message_ver
+        ));
+    }
+
+    let cmd = match payload["cmd"].as_str() {
+        Some(c) => c,
+        None => return Err("received message without `cmd` field".into()),
+    };
+
+    let field = |name: &str| match payload[name].as_str() {
+        Some(x) => Ok(x),
+        None => Err(format!("received {} message without `{}` field", cmd, name)),
+    };
+
+    match cmd {
+        "INSTANCE_LAUNCH" => Ok(CWorkerCommand::launch_instance(
+            field("id")?,
+            field("mi")?,
+            control::ConnType::SshTun,
+            field("pr")?,
+            payload["repo"].as_str(),
+            payload["repo_path"].as_str(),
+        )),
+        "INSTANCE_STATUS" => Ok(CWorkerCommand::get_status(field("id")?, field("mi")?)),
+        "INSTANCE_DESTROY" => Ok(CWorkerCommand::destroy_instance(field("id")?, field("mi")?)),
+        "CREATE_SSHTUN_DONE" => {
+            let tunnelinfo: TunnelInfo = match serde_json::from_value(payload.clone()) {
+                Ok(x) => {
+                    debug!("received tunnel info: {:?}", x);
+                    x
+                }
+                Err(err) => {
+                    return Err(format!(
+                        "failed to parse tunnel info from {}: {}",
+                        payload, err
+                    ))
+                }
+            };
+            Ok(CWorkerCommand::create_sshtun_done(
+                field("id")?,
+                field("mi")?,
+                &tunnelinfo,
+            ))
+        }
+        _ => Err(format!("unknown command: {}", cmd)),
+    }
+}
+
+impl WSClient {
+    // Dispatch a control message, whether it arrived as JSON text or as binary MessagePack
+    fn handle_payload(&mut self, payload: serde_json::Value) {
+        debug!("received: {}", serde_json::to_string(&payload).unwrap());
+        match parse_command(&payload) {
+            Ok(m) => self.main_actor_addr.do_send(ClientCommand(m)),
+            Err(err) => error!("{}", err),
+        }
+    }
+}
+
 impl StreamHandler<Result<Frame, WsProtocolError>> for WSClient {
     fn handle(&mut self, msg: Result<Frame, WsProtocolError>, _ctx: &mut Context<Self>) {
         self.recent_rx_instant = std::time::Instant::now();
@@ -1415,83 +2563,31 @@ impl StreamHandler<Result<Frame, WsProtocolError>> for WSClient {
                     return;
                 }
             };
-            debug!("received: {}", serde_json::to_string(&payload).unwrap());
-
-            let message_ver = match payload["v"].as_i64() {
-                Some(v) => v,
-                None => {
-                    error!("received message with no version declaration");
-                    return;
-                }
-            };
-            if message_ver != 0 {
-                error!(
-                    "received message of unknown format version: {}",
-                    message_ver
-                );
-                return;
-            }
-
-            let cmd = match payload["cmd"].as_str() {
-                Some(c) => c,
-                None => {
-                    error!("received message without `cmd` field");
-                    return;
-                }
-            };
-
-            let m = match cmd {
-                "INSTANCE_LAUNCH" => CWorkerCommand::launch_instance(
-                    payload["id"].as_str().unwrap(),
-                    payload["mi"].as_str().unwrap(),
-                    control::ConnType::SshTun,
-                    payload["pr"].as_str().unwrap(),
-                    payload["repo"].as_str(),
-                    payload["repo_path"].as_str(),
-                ),
-                "INSTANCE_STATUS" => CWorkerCommand::get_status(
-                    payload["id"].as_str().unwrap(),
-                    payload["mi"].as_str().unwrap(),
-                ),
-                "INSTANCE_DESTROY" => CWorkerCommand::destroy_instance(
-                    payload["id"].as_str().unwrap(),
-                    payload["mi"].as_str().unwrap(),
-                ),
-                "CREATE_SSHTUN_DONE" => {
-                    let tunnelinfo: TunnelInfo = match serde_json::from_slice(txt.as_ref()) {
-                        Ok(x) => {
-                            debug!("received tunnel info: {:?}", x);
-                            x
-                        }
-                        Err(err) => {
-                            error!("failed to parse tunnel info from {:?}: {}", txt, err);
-                            return;
-                        }
-                    };
-                    CWorkerCommand::create_sshtun_done(
-                        payload["id"].as_str().unwrap(),
-                        payload["mi"].as_str().unwrap(),
-                        &tunnelinfo,
-                    )
-                }
-                _ => {
-                    error!("unknown command: {}", cmd);
+            self.handle_payload(payload);
+        } else if let Ok(Frame::Binary(bin)) = msg {
+            let payload: serde_json::Value = match rmp_serde::from_slice(bin.as_ref()) {
+                Ok(p) => p,
+                Err(err) => {
+                    warn!("failed to parse binary message {:?}: {}", bin, err);
                     return;
                 }
             };
-            self.main_actor_addr.do_send(ClientCommand(m));
+            self.handle_payload(payload);
         } else if let Ok(Frame::Ping(_)) = msg {
             debug!("received PING; sending PONG");
             match self.ws_sink.write(Message::Pong(Bytes::from_static(b""))) {
                 Ok(()) => (),
                 Err(err) => error!("caught while responding to WebSocket ping: {:?}", err),
             }
+        } else if let Ok(Frame::Pong(_)) = msg {
+            debug!("received PONG");
         } else {
             warn!("unrecognized WebSocket message: {:?}", msg);
         }
     }
 
     fn finished(&mut self, ctx: &mut Context<Self>) {
+        self.closing = true;
         self.ws_sink.close();
 
         let authheader = self.ws_auth.clone();
@@ -1515,6 +2611,53 @@ impl actix::io::WriteHandler<WsProtocolError> for WSClient {}
 pub struct MainActor {
     worker_req: mpsc::Sender<CWorkerCommand>,
     wsclient_addr: Option<Addr<WSClient>>,
+    ws_opened: bool,
+    metrics: Arc<control::Metrics>,
+}
+
+// Maximum time to wait for the client worker to destroy the instance before exit
+const TEARDOWN_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Maximum time to wait for final messages to be sent before closing the WebSocket
+const WS_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl MainActor {
+    // Ask the client worker to destroy any active instance, and then close the
+    // WebSocket. The event loop is not blocked meanwhile, so messages from the
+    // client worker, e.g., the TERMINATING status, are still sent to the server.
+    fn teardown(&mut self) -> ResponseActFuture<Self, ()> {
+        let (done_tx, mut done_rx) = futures::channel::mpsc::unbounded();
+        if self
+            .worker_req
+            .send(CWorkerCommand::shutdown(done_tx))
+            .is_err()
+        {
+            debug!("client worker already stopped");
+            return Box::pin(actix::fut::ready(()));
+        }
+        Box::pin(
+            async move {
+                if actix::clock::timeout(TEARDOWN_TIMEOUT, done_rx.next())
+                    .await
+                    .is_err()
+                {
+                    warn!("timed out waiting for client worker to tear down instance");
+                }
+            }
+            .into_actor(self)
+            .then(|_, act, ctx| {
+                // Through the mailbox, so that it is handled after any WsSend
+                // that the client worker sent before it was done
+                let closed = ctx.address().send(CloseWS);
+                async move {
+                    if let Err(err) = closed.await {
+                        warn!("failed to close WebSocket: {}", err);
+                    }
+                }
+                .into_actor(act)
+            }),
+        )
+    }
 }
 
 impl Actor for MainActor {
@@ -1551,6 +2694,10 @@ impl Handler<NewWS> for MainActor {
         match msg.0 {
             Some(ws) => {
                 info!("new WebSocket");
+                if self.ws_opened {
+                    self.metrics.count_ws_reconnect();
+                }
+                self.ws_opened = true;
                 self.wsclient_addr = Some(ws);
             }
             None => {
@@ -1561,28 +2708,51 @@ impl Handler<NewWS> for MainActor {
     }
 }
 
+impl Handler<CloseWS> for MainActor {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, msg: CloseWS, _ctx: &mut Context<Self>) -> Self::Result {
+        let wsclient_addr = self.wsclient_addr.take();
+        Box::pin(async move {
+            if let Some(wa) = wsclient_addr {
+                if actix::clock::timeout(WS_CLOSE_TIMEOUT, wa.send(msg))
+                    .await
+                    .is_err()
+                {
+                    warn!("timed out waiting for WebSocket to close");
+                }
+            }
+        })
+    }
+}
+
 impl Handler<MainActorCommand> for MainActor {
-    type Result = ();
+    type Result = ResponseActFuture<Self, ()>;
 
-    fn handle(&mut self, msg: MainActorCommand, ctx: &mut Context<Self>) {
+    fn handle(&mut self, msg: MainActorCommand, ctx: &mut Context<Self>) -> Self::Result {
         debug!("received client command: {}", msg.0);
         if msg.0 == "STOP" {
             ctx.stop();
         } else if msg.0 == "STOP DAEMON" {
-            ctx.stop();
-            System::current().stop();
+            return Box::pin(self.teardown().map(|_, _act, ctx| {
+                ctx.stop();
+                System::current().stop();
+            }));
+        } else if msg.0 == "TEARDOWN" {
+            return Box::pin(self.teardown().map(|_, _act, ctx| ctx.stop()));
         } else if msg.0 == "RESTART WEBSOCKET" {
             self.wsclient_addr = None;
         } else {
             warn!("unknown client command: {}", msg.0);
         }
+        Box::pin(actix::fut::ready(()))
     }
 }
 
 impl Handler<ClientWorkerMessage> for MainActor {
     type Result = ();
 
-    fn handle(&mut self, msg: ClientWorkerMessage, _ctx: &mut Context<Self>) {
+    fn handle(&mut self, msg: ClientWorkerMessage, ctx: &mut Context<Self>) {
         debug!("received client worker message: {:?}", msg);
         match msg.mtype {
             control::CWorkerMessageType::WsSend => match &self.wsclient_addr {
@@ -1593,6 +2763,12 @@ impl Handler<ClientWorkerMessage> for MainActor {
                     error!("received WsSend when no WSClient");
                 }
             },
+            control::CWorkerMessageType::StopDaemon => {
+                ctx.spawn(self.teardown().map(|_, _act, ctx| {
+                    ctx.stop();
+                    System::current().stop();
+                }));
+            }
         }
     }
 }
@@ -1609,27 +2785,223 @@ impl Handler<ClientCommand> for MainActor {
     }
 }
 
-fn nonempty_intersection<T>(u: &[T], v: &[T]) -> bool
-where
-    T: PartialEq,
-{
-    for x in u.iter() {
-        for y in v.iter() {
-            if x == y {
-                return true;
+async fn wait_for_termination() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sigterm = signal(SignalKind::terminate())?;
+        let ctrl_c = Box::pin(tokio::signal::ctrl_c());
+        let term = Box::pin(sigterm.recv());
+        let res = match futures::future::select(ctrl_c, term).await {
+            futures::future::Either::Left((res, _)) => res,
+            futures::future::Either::Right(_) => Ok(()),
+        };
+        res
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await
+    }
+}
+
+// Directory of pid and stats files of camera streams started on this host
+fn get_camera_dir(create: bool) -> Result<std::path::PathBuf, HardshareError> {
+    let base_path = match mgmt::get_base_path() {
+        Some(p) => p,
+        None => {
+            return config_error(
+                "cannot determine home directory; set HOME or HARDSHARE_CONFIG_DIR",
+            )
+        }
+    };
+    let path = base_path.join("camera");
+    if create && !path.exists() {
+        if !base_path.exists() {
+            return config_error(format!(
+                "{} does not exist; run `hardshare init` first",
+                base_path.display()
+            ));
+        }
+        if let Err(err) = std::fs::create_dir(&path) {
+            return error(format!("cannot write to {}: {}", base_path.display(), err));
+        }
+    }
+    Ok(path)
+}
+
+// Directory of cached responses from the server. Tests do not use it, so that
+// each request is sent to the mock server.
+fn get_cache_dir() -> Option<std::path::PathBuf> {
+    if cfg!(test) {
+        None
+    } else {
+        mgmt::get_base_path().map(|base_path| base_path.join("cache"))
+    }
+}
+
+fn get_remote_config_ttl() -> Duration {
+    let ttl = match std::env::var("HARDSHARE_REMOTE_CONFIG_TTL") {
+        Ok(x) => match x.parse::<u64>() {
+            Ok(t) => t,
+            Err(_) => {
+                warn!("ignoring invalid HARDSHARE_REMOTE_CONFIG_TTL: {}", x);
+                DEFAULT_REMOTE_CONFIG_TTL
+            }
+        },
+        Err(_) => DEFAULT_REMOTE_CONFIG_TTL,
+    };
+    Duration::from_secs(ttl)
+}
+
+// Listings differ by API origin, API token (user or organization), and options,
+// so each combination is cached separately. The token itself is not in the name.
+fn remote_config_cache_path(
+    cache_dir: &std::path::Path,
+    origin: &str,
+    api_token: &str,
+    include_dissolved: bool,
+    with_instances: bool,
+) -> std::path::PathBuf {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    origin.hash(&mut hasher);
+    api_token.hash(&mut hasher);
+    cache_dir.join(format!(
+        "remote-config-{:016x}-{}{}.json",
+        hasher.finish(),
+        include_dissolved as u8,
+        with_instances as u8
+    ))
+}
+
+// Remove all cached listings. This is done before any change of remote
+// configuration, which may be reflected in the listing of any API token.
+fn invalidate_remote_config_cache() {
+    if let Some(cache_dir) = get_cache_dir() {
+        remove_remote_config_cache(&cache_dir);
+    }
+}
+
+fn remove_remote_config_cache(cache_dir: &std::path::Path) {
+    let entries = match std::fs::read_dir(cache_dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with("remote-config-")
+        {
+            if let Err(err) = std::fs::remove_file(entry.path()) {
+                warn!("failed to remove cached remote config: {}", err);
+            }
+        }
+    }
+}
+
+fn read_fresh_cache(path: &std::path::Path, ttl: Duration) -> Option<serde_json::Value> {
+    let age = std::fs::metadata(path)
+        .ok()?
+        .modified()
+        .ok()?
+        .elapsed()
+        .ok()?;
+    if age > ttl {
+        return None;
+    }
+    serde_json::from_slice(&std::fs::read(path).ok()?).ok()
+}
+
+fn write_cache(path: &std::path::Path, data: &serde_json::Value) -> Result<(), HardshareError> {
+    if let Some(cache_dir) = path.parent() {
+        std::fs::create_dir_all(cache_dir)?;
+    }
+    std::fs::write(path, serde_json::to_string(data)?)?;
+    Ok(())
+}
+
+// Remove pid files (and stats) of camera streams whose process no longer
+// exists. Return the hscamera ids of the removed files.
+fn remove_stale_camera_pids(path: &std::path::Path) -> Result<Vec<String>, HardshareError> {
+    let mut removed = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            continue;
+        }
+        let entry = entry.path();
+        if entry.extension().map(|x| x == "pid") != Some(true) {
+            continue;
+        }
+        let pid = String::from_utf8_lossy(&std::fs::read(&entry)?)
+            .trim()
+            .to_string();
+        if process_is_alive(&pid) {
+            continue;
+        }
+        let stats_path = entry.with_extension("stats");
+        if stats_path.exists() {
+            std::fs::remove_file(stats_path)?;
+        }
+        std::fs::remove_file(&entry)?;
+        removed.push(entry.file_stem().unwrap().to_string_lossy().to_string());
+    }
+    Ok(removed)
+}
+
+// Check whether a local process with the given pid exists
+pub fn process_is_alive(pid: &str) -> bool {
+    if pid.is_empty() || !pid.chars().all(|c| c.is_ascii_digit()) {
+        return false;
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        match process::Command::new("tasklist")
+            .args(["/FI", &format!("PID eq {}", pid), "/NH", "/FO", "CSV"])
+            .output()
+        {
+            Ok(out) => {
+                out.status.success()
+                    && String::from_utf8_lossy(&out.stdout).contains(&format!("\"{}\"", pid))
             }
+            Err(_) => false,
+        }
+    }
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    {
+        match process::Command::new("kill")
+            .args(["-0", pid])
+            .stderr(process::Stdio::null())
+            .status()
+        {
+            Ok(r) => r.success(),
+            Err(_) => false,
         }
     }
-    false
 }
 
 #[cfg(test)]
 mod tests {
     use mockito::mock;
 
+    use super::is_daemon_request_authorized;
     use super::mgmt;
+    use super::parse_command;
+    use super::process_is_alive;
+    use super::remove_stale_camera_pids;
+    use super::validate_mistyproxy_addr;
     use super::AddOn;
     use super::HSAPIClient;
+    use super::HardshareError;
+    use super::Schedule;
+    use super::{list_port_files, parse_port_file_name};
+    use super::{
+        read_fresh_cache, remote_config_cache_path, remove_remote_config_cache, write_cache,
+    };
+    use super::{resolve_origin, validate_origin};
+    use super::{IceServer, WebrtcConfig};
 
     #[test]
     fn list_no_rules() {
@@ -1648,6 +3020,159 @@ mod tests {
         assert_eq!(ruleset.rules.len(), 0)
     }
 
+    #[test]
+    fn retry_on_server_error() {
+        let wdid = "0d4f5a2e-2a6b-4f9c-9a3c-4f2b5e0b6d1a";
+        let path = format!("/deployment/{}/rules", wdid);
+        let m = mock("GET", path.as_str())
+            .with_status(503)
+            .expect(3)
+            .create();
+
+        let mut ac = HSAPIClient::new();
+        ac.cached_api_token = Some("fake".to_string());
+        assert!(ac.get_access_rules(wdid).is_err());
+        m.assert();
+    }
+
+    #[test]
+    fn error_message_on_conflict() {
+        let wdid = "5c0f3a8e-8f7e-4c59-9d1e-2f4f3e1b7a90";
+        let path = format!("/deployment/{}/lockout", wdid);
+        let _m = mock("POST", path.as_str())
+            .with_status(409)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"error_message": "already locked out"}"#)
+            .create();
+
+        let mut ac = HSAPIClient::new();
+        ac.cached_api_token = Some("fake".to_string());
+        let err = ac.toggle_lockout(wdid, true).unwrap_err();
+        assert!(format!("{}", err).contains("already locked out"));
+        match err {
+            HardshareError::Api { status, .. } => assert_eq!(status, 409),
+            _ => panic!("expected API error"),
+        }
+    }
+
+    #[test]
+    fn register_webhooks() {
+        let wdid = "5c0f3a8e-8f7e-4c59-9d1e-2f4f3e1b7a90";
+        let path = format!("/hardshare/hook/webhook/{}", wdid);
+        let _m = mock("POST", path.as_str())
+            .match_body(mockito::Matcher::Json(
+                json!({"urls": ["https://example.com/hook"]}),
+            ))
+            .with_status(200)
+            .create();
+
+        let mut ac = HSAPIClient::new();
+        ac.cached_api_token = Some("fake".to_string());
+        ac.register_hook_webhooks(wdid, vec!["https://example.com/hook"])
+            .unwrap();
+
+        assert!(ac
+            .register_hook_webhooks(wdid, vec!["example.com/hook"])
+            .is_err());
+        assert!(ac
+            .register_hook_webhooks(wdid, vec!["ftp://example.com"])
+            .is_err());
+    }
+
+    #[test]
+    fn set_addon_config() {
+        let wdid = "5c0f3a8e-8f7e-4c59-9d1e-2f4f3e1b7a90";
+        let path = format!("/deployment/{}", wdid);
+        let _m = mock("GET", path.as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(json!({"id": wdid, "supported_addons": []}).to_string())
+            .create();
+        let path = format!("/hardshare/wd/{}", wdid);
+        let _m_update = mock("POST", path.as_str())
+            .match_body(mockito::Matcher::Json(json!({
+                "supported_addons": ["mistyproxy"],
+                "addons_config": {"mistyproxy": {"ip": "10.0.0.2"}},
+            })))
+            .with_status(200)
+            .create();
+
+        let mut ac = HSAPIClient::new();
+        ac.cached_api_token = Some("fake".to_string());
+        ac.set_addon(wdid, &AddOn::MistyProxy, json!({"ip": "10.0.0.2"}))
+            .unwrap();
+        assert!(ac
+            .set_addon(wdid, &AddOn::MistyProxy, json!(["10.0.0.2"]))
+            .is_err());
+    }
+
+    #[test]
+    fn addon_names() {
+        for (addon, name) in super::ADDON_NAMES.iter() {
+            assert_eq!(&addon.to_string(), name);
+            assert_eq!(&name.parse::<AddOn>().unwrap(), addon);
+        }
+        assert!("vnc".parse::<AddOn>().is_err());
+        assert!("MistyProxy".parse::<AddOn>().is_err());
+    }
+
+    #[test]
+    fn webrtc_config() {
+        assert!(IceServer::new("stun:stun.example.com:3478").is_ok());
+        assert!(IceServer::new("turns:turn.example.com").unwrap().is_turn());
+        assert!(IceServer::new("stun:").is_err());
+        assert!(IceServer::new("https://stun.example.com").is_err());
+        assert!(IceServer::new("stun.example.com").is_err());
+
+        let mut turn = IceServer::new("turn:turn.example.com:3478").unwrap();
+        turn.username = Some("scott".into());
+        turn.credential = Some("secret".into());
+        let config = WebrtcConfig {
+            ice_servers: vec![IceServer::new("stun:stun.example.com").unwrap(), turn],
+        };
+        assert_eq!(
+            serde_json::to_value(&config).unwrap(),
+            json!({"iceServers": [
+                {"urls": ["stun:stun.example.com"]},
+                {"urls": ["turn:turn.example.com:3478"], "username": "scott", "credential": "secret"},
+            ]})
+        );
+    }
+
+    #[test]
+    fn mistyproxy_addr() {
+        assert!(validate_mistyproxy_addr("192.168.1.10").is_ok());
+        assert!(validate_mistyproxy_addr("localhost").is_ok());
+        for bad in [
+            "",
+            "192.168.1.10 ",
+            " 192.168.1.10",
+            "192.168.1.0/24",
+            "192.168.1",
+            "256.1.1.1",
+            "no such host.invalid",
+        ] {
+            assert!(validate_mistyproxy_addr(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn daemon_request_authorization() {
+        let token = "Ar9pRT0pVcaDl0gnR4LGxZ4uHAp8WpCs";
+        let req = actix_web::test::TestRequest::default()
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_srv_request();
+        assert!(is_daemon_request_authorized(&req, token));
+
+        let req = actix_web::test::TestRequest::default()
+            .insert_header(("Authorization", "Bearer wrong"))
+            .to_srv_request();
+        assert!(!is_daemon_request_authorized(&req, token));
+
+        let req = actix_web::test::TestRequest::default().to_srv_request();
+        assert!(!is_daemon_request_authorized(&req, token));
+    }
+
     #[test]
     fn get_mistyproxy_config() {
         let wdid = "68a1be97-9365-4007-b726-14c56bd69eef";
@@ -1718,4 +3243,282 @@ mod tests {
         assert_eq!(res.unwrap(), expected_new_wdids[1]);
         assert_eq!(ac.local_config.unwrap().wdeployments.len(), 2);
     }
+
+    #[test]
+    fn malformed_instance_launch() {
+        let payload = json!({
+            "v": 0,
+            "cmd": "INSTANCE_LAUNCH",
+            "id": "8ad4d4b3-1f1b-4f6e-a5b2-2f1e0e8f5e43",
+        });
+        let err = parse_command(&payload).err().unwrap();
+        assert!(err.contains("`mi`"));
+
+        let payload = json!({
+            "v": 0,
+            "cmd": "INSTANCE_LAUNCH",
+            "id": "8ad4d4b3-1f1b-4f6e-a5b2-2f1e0e8f5e43",
+            "mi": "9f2e4e27-38b4-4c1b-9bd6-3a3c2ec2a6a1",
+        });
+        let err = parse_command(&payload).err().unwrap();
+        assert!(err.contains("`pr`"));
+
+        let payload = json!({
+            "v": 0,
+            "cmd": "INSTANCE_LAUNCH",
+            "id": "8ad4d4b3-1f1b-4f6e-a5b2-2f1e0e8f5e43",
+            "mi": "9f2e4e27-38b4-4c1b-9bd6-3a3c2ec2a6a1",
+            "pr": "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIKs6M5fOzLQ4 example",
+        });
+        assert!(parse_command(&payload).is_ok());
+    }
+
+    #[test]
+    fn parse_schedule() {
+        let schedule: Schedule = "Mon-Fri 09:00-17:00 UTC-08:00".parse().unwrap();
+        assert_eq!(
+            schedule.to_param(),
+            json!({"schedule": {
+                "days": ["Mon", "Tue", "Wed", "Thu", "Fri"],
+                "start": "09:00",
+                "end": "17:00",
+                "tz": "UTC-08:00",
+            }})
+        );
+        assert_eq!(schedule.to_string(), "Mon-Fri 09:00-17:00 UTC-08:00");
+
+        let schedule: Schedule = "sat,Sun 10:30-12:00".parse().unwrap();
+        assert_eq!(schedule.to_string(), "Sat,Sun 10:30-12:00 UTC");
+
+        assert!("Fri-Mon 09:00-17:00".parse::<Schedule>().is_err());
+        assert!("Mon-Fri 17:00-09:00".parse::<Schedule>().is_err());
+        assert!("Mon-Fri 9:00-17:00".parse::<Schedule>().is_err());
+        assert!("Mon-Fri 09:00-24:00".parse::<Schedule>().is_err());
+        assert!("Someday 09:00-17:00".parse::<Schedule>().is_err());
+        assert!("Mon-Fri".parse::<Schedule>().is_err());
+        assert!("Mon-Fri 09:00-17:00 America/Los_Angeles"
+            .parse::<Schedule>()
+            .is_err());
+        assert!("Mon-Fri 09:00-17:00 UTC+15:00".parse::<Schedule>().is_err());
+        assert!("Mon-Fri 09:00-17:00 UTC+5".parse::<Schedule>().is_err());
+        assert!("Mon-Fri 09:00-17:00 UTC+05:30".parse::<Schedule>().is_ok());
+    }
+
+    #[test]
+    fn schedule_not_kept_by_server() {
+        let wdid = "3e9b2c71-5d4a-4f0e-8c6b-9a1d7e2f4b53";
+        let m_add = mock("POST", format!("/deployment/{}/rule", wdid).as_str())
+            .with_status(200)
+            .create();
+        let _m_list = mock("GET", format!("/deployment/{}/rules", wdid).as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({"rules": [{
+                    "capability": "CAP_INSTANTIATE",
+                    "date_created": "2026-10-16 00:00:00",
+                    "id": 1,
+                    "param": null,
+                    "user": "*",
+                    "wdeployment_id": wdid,
+                }]})
+                .to_string(),
+            )
+            .create();
+
+        let mut ac = HSAPIClient::new();
+        ac.cached_api_token = Some("fake".to_string());
+        let schedule: Schedule = "Mon-Fri 09:00-17:00".parse().unwrap();
+        let err = ac
+            .add_access_rule(wdid, "*", Some(schedule.to_param()))
+            .unwrap_err();
+        assert!(format!("{}", err).contains("did not keep the schedule"));
+        m_add.assert();
+    }
+
+    #[test]
+    fn process_liveness() {
+        assert!(process_is_alive(&std::process::id().to_string()));
+        assert!(!process_is_alive(""));
+        assert!(!process_is_alive("-1"));
+        assert!(!process_is_alive("not-a-pid"));
+    }
+
+    #[test]
+    fn stale_camera_pids() {
+        let td = tempfile::tempdir().unwrap();
+        let live = td.path().join("live.pid");
+        std::fs::write(&live, std::process::id().to_string()).unwrap();
+        let stale = td.path().join("stale.pid");
+        std::fs::write(&stale, "not-a-pid").unwrap();
+        std::fs::write(td.path().join("stale.stats"), "{}").unwrap();
+
+        assert_eq!(remove_stale_camera_pids(td.path()).unwrap(), vec!["stale"]);
+        assert!(live.exists());
+        assert!(!stale.exists());
+        assert!(!td.path().join("stale.stats").exists());
+    }
+
+    #[test]
+    fn launch_and_terminate_instance() {
+        let wdid = "68a1be97-9365-4007-b726-14c56bd69eef";
+        let instance_id = "2d5c0c52-3b0d-4a9e-8f21-6a5a1e7bd0c4";
+        let _m_new = mock("POST", "/new")
+            .match_body(mockito::Matcher::Json(json!({ "wds": [wdid] })))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(json!({"id": instance_id, "sshkey": "KEY"}).to_string())
+            .create();
+        let _m_info = mock("GET", format!("/instance/{}", instance_id).as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({
+                    "id": instance_id,
+                    "status": "READY",
+                    "fwd": {"ipv4": "147.75.70.51", "port": 2210},
+                })
+                .to_string(),
+            )
+            .create();
+        let m_terminate = mock("POST", format!("/terminate/{}", instance_id).as_str())
+            .with_status(200)
+            .create();
+
+        let mut ac = HSAPIClient::new();
+        ac.cached_api_token = Some("fake".to_string());
+        let (id, sshkey) = ac.new_instance(wdid).unwrap();
+        assert_eq!(id, instance_id);
+        assert_eq!(sshkey, "KEY");
+        let info = ac.get_instance_info(&id).unwrap();
+        assert_eq!(info.status, "READY");
+        assert_eq!(info.fwd.unwrap().port, 2210);
+        ac.terminate_instance(&id).unwrap();
+        m_terminate.assert();
+    }
+
+    #[test]
+    fn list_with_instances() {
+        let _m = mock("GET", "/hardshare/list")
+            .match_query(mockito::Matcher::Regex("^with_instances$".into()))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({
+                    "wdeployments": [{
+                        "id": "68a1be97-9365-4007-b726-14c56bd69eef",
+                        "instances": [{"id": "2d5c0c52-3b0d-4a9e-8f21-6a5a1e7bd0c4", "status": "READY"}],
+                    }],
+                })
+                .to_string(),
+            )
+            .create();
+
+        let mut ac = HSAPIClient::new();
+        ac.cached_api_token = Some("fake".to_string());
+        let rc = ac.get_remote_config(false, true, false).unwrap();
+        assert_eq!(rc["wdeployments"][0]["instances"][0]["status"], "READY");
+    }
+
+    #[test]
+    fn list_with_instances_unsupported() {
+        let _m = mock("GET", "/hardshare/list")
+            .match_query(mockito::Matcher::Regex("^with_instances$".into()))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({
+                    "wdeployments": [{"id": "68a1be97-9365-4007-b726-14c56bd69eef"}],
+                })
+                .to_string(),
+            )
+            .create();
+
+        let mut ac = HSAPIClient::new();
+        ac.cached_api_token = Some("fake".to_string());
+        let err = ac.get_remote_config(false, true, false).unwrap_err();
+        assert!(format!("{}", err).contains("does not support listing instances"));
+    }
+
+    #[test]
+    fn remote_config_cache() {
+        let td = tempfile::tempdir().unwrap();
+        let cache_dir = td.path().join("cache");
+        let origin = "https://api.rerobots.net";
+        let path = remote_config_cache_path(&cache_dir, origin, "tok", false, false);
+        assert_ne!(
+            path,
+            remote_config_cache_path(&cache_dir, origin, "tok", true, false)
+        );
+        assert_ne!(
+            path,
+            remote_config_cache_path(&cache_dir, origin, "tok", false, true)
+        );
+        assert_ne!(
+            path,
+            remote_config_cache_path(&cache_dir, origin, "other", false, false)
+        );
+        assert_ne!(
+            path,
+            remote_config_cache_path(&cache_dir, "http://127.0.0.1:8080", "tok", false, false)
+        );
+        assert!(!path.to_string_lossy().contains("tok"));
+
+        let ttl = std::time::Duration::from_secs(60);
+        assert!(read_fresh_cache(&path, ttl).is_none());
+        let rc = json!({"wdeployments": []});
+        write_cache(&path, &rc).unwrap();
+        assert_eq!(read_fresh_cache(&path, ttl), Some(rc));
+        assert!(read_fresh_cache(&path, std::time::Duration::from_secs(0)).is_none());
+
+        let other_file = cache_dir.join("other.json");
+        std::fs::write(&other_file, "{}").unwrap();
+        remove_remote_config_cache(&cache_dir);
+        assert!(!path.exists());
+        assert!(other_file.exists());
+    }
+
+    #[test]
+    fn origin_override() {
+        let default_origin = resolve_origin(None);
+        assert_eq!(resolve_origin(Some("".into())), default_origin);
+        assert_eq!(
+            resolve_origin(Some("https://staging.example.com/".into())),
+            "https://staging.example.com"
+        );
+        assert_eq!(
+            resolve_origin(Some("http://127.0.0.1:8080".into())),
+            "http://127.0.0.1:8080"
+        );
+        assert_eq!(
+            resolve_origin(Some("ftp://example.com".into())),
+            default_origin
+        );
+
+        assert!(validate_origin("https://api.example.com").is_ok());
+        assert!(validate_origin("api.example.com").is_err());
+        assert!(validate_origin("https://example.com/api").is_err());
+    }
+
+    #[test]
+    fn daemon_port_file() {
+        assert_eq!(parse_port_file_name("daemon-40123.port"), Some(40123));
+        assert_eq!(parse_port_file_name("daemon-0.port"), None);
+        assert_eq!(parse_port_file_name("daemon-40123.token"), None);
+        assert_eq!(parse_port_file_name("daemon.port"), None);
+
+        let td = tempfile::tempdir().unwrap();
+        assert!(list_port_files(td.path()).is_empty());
+        for name in [
+            "daemon-40124.port",
+            "daemon-40123.port",
+            "daemon-40123.token",
+        ]
+        .iter()
+        {
+            std::fs::write(td.path().join(name), "").unwrap();
+        }
+        assert_eq!(list_port_files(td.path()), vec![40123, 40124]);
+        assert!(list_port_files(&td.path().join("missing")).is_empty());
+    }
 }
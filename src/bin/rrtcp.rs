@@ -0,0 +1,105 @@
+// Copyright (C) 2026 rerobots, Inc.
+//
+// rrtcp relays TCP connections to a target without filtering. It is meant for
+// devices that do not speak HTTP and so cannot be placed behind rrhttp.
+
+#[macro_use]
+extern crate clap;
+use clap::Arg;
+
+#[macro_use]
+extern crate log;
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Builder;
+use tokio::{signal, time};
+
+async fn main_per(mut ingress: TcpStream, mut egress: TcpStream) {
+    let ingress_peer_addr = ingress.peer_addr().unwrap();
+    let egress_peer_addr = egress.peer_addr().unwrap();
+    debug!(
+        "started relaying {} to {}",
+        ingress_peer_addr, egress_peer_addr
+    );
+    match tokio::io::copy_bidirectional(&mut ingress, &mut egress).await {
+        Ok((to_egress, to_ingress)) => {
+            debug!(
+                "done; wrote {} bytes to {} and {} bytes to {}",
+                to_egress, egress_peer_addr, to_ingress, ingress_peer_addr
+            );
+        }
+        Err(err) => {
+            warn!(
+                "{} to {}: error: {}",
+                ingress_peer_addr, egress_peer_addr, err
+            );
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::init();
+
+    let matches = clap::App::new("rrtcp")
+        .max_term_width(80)
+        .arg(
+            Arg::with_name("TARGET")
+                .required(true)
+                .help("target HOST:PORT"),
+        )
+        .version(crate_version!())
+        .get_matches();
+
+    let targetaddr = String::from(matches.value_of("TARGET").unwrap());
+
+    let rt = Builder::new_current_thread()
+        .enable_io()
+        .enable_time()
+        .build()?;
+    rt.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        println!("{}", listener.local_addr()?);
+
+        tokio::spawn(async move {
+            loop {
+                let (ingress, _) = match listener.accept().await {
+                    Ok(x) => x,
+                    Err(err) => {
+                        error!(
+                            "error on accept connection: {}; sleeping and looping...",
+                            err
+                        );
+                        time::sleep(std::time::Duration::from_millis(1000)).await;
+                        continue;
+                    }
+                };
+                match ingress.set_nodelay(true) {
+                    Ok(()) => (),
+                    Err(err) => {
+                        warn!("unable to set TCP NODELAY on ingress: {}", err)
+                    }
+                };
+
+                let egress = match TcpStream::connect(targetaddr.clone()).await {
+                    Ok(c) => c,
+                    Err(err) => {
+                        error!("unable to connect to target: {}", err);
+                        continue;
+                    }
+                };
+                match egress.set_nodelay(true) {
+                    Ok(()) => (),
+                    Err(err) => {
+                        warn!("unable to set TCP NODELAY on egress: {}", err)
+                    }
+                };
+
+                tokio::spawn(main_per(ingress, egress));
+            }
+        });
+
+        signal::ctrl_c().await?;
+
+        Ok(())
+    })
+}
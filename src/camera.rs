@@ -27,9 +27,12 @@ use awc::{
 
 use base64::engine::{general_purpose as base64_engine, Engine as _};
 use futures::stream::{SplitSink, StreamExt};
+use serde::{Deserialize, Serialize};
 
 use crate::api::{self, CameraDimensions};
 use crate::check::Error as CheckError;
+use crate::error::HardshareError;
+use crate::proxy;
 
 pub fn get_default_dev() -> String {
     #[cfg(target_os = "linux")]
@@ -38,8 +41,252 @@ pub fn get_default_dev() -> String {
     return "0".into();
 }
 
-pub fn check_camera(camera_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    verify_capture_ability(camera_path, None)
+// Image size that the camera driver accepted, and sizes that it supports
+pub struct CaptureFormats {
+    pub negotiated: CameraDimensions,
+    pub available: Vec<CameraDimensions>,
+}
+
+// What to do if the camera does not support the requested width and height
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub enum ResolutionPolicy {
+    // Use whatever size the camera driver offers instead
+    #[default]
+    Fallback,
+    // Do not capture at all
+    Fail,
+}
+
+impl std::str::FromStr for ResolutionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fallback" => Ok(ResolutionPolicy::Fallback),
+            "fail" => Ok(ResolutionPolicy::Fail),
+            _ => Err(format!(
+                "unknown policy for unsupported resolution: {}; expected fail or fallback",
+                s
+            )),
+        }
+    }
+}
+
+// Mirroring of captured images, e.g., for cameras that are mounted upside down
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub enum Flip {
+    #[default]
+    None,
+    Horizontal,
+    Vertical,
+}
+
+impl std::str::FromStr for Flip {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Flip::None),
+            "h" => Ok(Flip::Horizontal),
+            "v" => Ok(Flip::Vertical),
+            _ => Err(format!("unknown flip: {}; expected none, h, or v", s)),
+        }
+    }
+}
+
+// Settings of image capture for a camera stream
+#[derive(Default)]
+pub struct CaptureOptions {
+    pub dimensions: Option<CameraDimensions>,
+    pub on_unsupported_res: ResolutionPolicy,
+    pub recorder: Option<FrameRecorder>,
+
+    // Clockwise rotation in degrees: 0, 90, 180, or 270
+    pub rotate: u16,
+    pub flip: Flip,
+}
+
+impl CaptureOptions {
+    fn reorients(&self) -> bool {
+        self.rotate != 0 || self.flip != Flip::None
+    }
+}
+
+// Rotation is applied before flip
+fn orient(img: image::DynamicImage, rotate: u16, flip: Flip) -> image::DynamicImage {
+    let img = match rotate {
+        90 => img.rotate90(),
+        180 => img.rotate180(),
+        270 => img.rotate270(),
+        _ => img,
+    };
+    match flip {
+        Flip::None => img,
+        Flip::Horizontal => img.fliph(),
+        Flip::Vertical => img.flipv(),
+    }
+}
+
+fn encode_jpeg(img: &image::DynamicImage) -> Result<Vec<u8>, image::ImageError> {
+    let mut jpg: Vec<u8> = Vec::new();
+    img.write_to(
+        &mut std::io::Cursor::new(&mut jpg),
+        image::ImageFormat::Jpeg,
+    )?;
+    Ok(jpg)
+}
+
+// Frames from MJPG capture must be decoded to be rotated or flipped, and then
+// encoded again, which is much more CPU per frame than sending them as-is.
+fn reorient_jpeg(jpg: &[u8], rotate: u16, flip: Flip) -> Result<Vec<u8>, image::ImageError> {
+    let img = image::load_from_memory_with_format(jpg, image::ImageFormat::Jpeg)?;
+    encode_jpeg(&orient(img, rotate, flip))
+}
+
+// Compare size accepted by the camera driver with the requested size, if any
+fn check_negotiated(
+    requested: &Option<CameraDimensions>,
+    width: u32,
+    height: u32,
+    policy: ResolutionPolicy,
+) -> Result<(), String> {
+    if let Some(d) = requested {
+        if d.width != width || d.height != height {
+            if policy == ResolutionPolicy::Fail {
+                return Err(format!(
+                    "camera does not support requested size ({}, {}); it offers ({}, {})",
+                    d.width, d.height, width, height
+                ));
+            }
+            warn!(
+                "requested size not feasible; falling back to ({}, {})",
+                width, height
+            );
+        }
+    }
+    Ok(())
+}
+
+pub fn check_camera(
+    camera_path: &str,
+    dimensions: &Option<CameraDimensions>,
+    policy: ResolutionPolicy,
+) -> Result<CaptureFormats, Box<dyn std::error::Error>> {
+    verify_capture_ability(camera_path, dimensions.clone(), policy)
+}
+
+fn push_unique(resolutions: &mut Vec<CameraDimensions>, width: u32, height: u32) {
+    if !resolutions
+        .iter()
+        .any(|d| d.width == width && d.height == height)
+    {
+        resolutions.push(CameraDimensions { width, height });
+    }
+}
+
+// Camera device found during enumeration, for diagnostics
+pub struct CameraDevice {
+    // Path or index to use as PATH argument of attach-camera
+    pub path: String,
+    pub name: String,
+    pub resolutions: Vec<CameraDimensions>,
+}
+
+impl std::fmt::Display for CameraDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.path)?;
+        writeln!(f, "\tname: {}", self.name)?;
+        if self.resolutions.is_empty() {
+            write!(f, "\tresolutions: (unknown)")
+        } else {
+            let resolutions: Vec<String> = self
+                .resolutions
+                .iter()
+                .map(|d| format!("{}x{}", d.width, d.height))
+                .collect();
+            write!(f, "\tresolutions: {}", resolutions.join(", "))
+        }
+    }
+}
+
+// How often to summarize data sent on the camera stream
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+// Data sent on a camera stream, written to the stats file next to the pid file
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct StreamStats {
+    pub bytes_sent: u64,
+    pub frames_sent: u64,
+
+    // Rates over the most recent interval
+    pub fps: f64,
+    pub bytes_per_second: f64,
+
+    // Unix time of most recent update
+    pub updated: u64,
+}
+
+impl StreamStats {
+    fn update(&mut self, frames: u64, bytes: u64, elapsed: Duration) {
+        self.frames_sent += frames;
+        self.bytes_sent += bytes;
+        let secs = elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.fps = frames as f64 / secs;
+            self.bytes_per_second = bytes as f64 / secs;
+        }
+        self.updated = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+    }
+}
+
+// Default bound on number of frames written by FrameRecorder
+pub const DEFAULT_RECORD_MAX_FRAMES: u64 = 100;
+
+// Writes captured JPEG frames to a directory, for debugging the capture pipeline
+pub struct FrameRecorder {
+    dir: std::path::PathBuf,
+    max_frames: u64,
+    count: u64,
+}
+
+impl FrameRecorder {
+    pub fn new(
+        dir: &std::path::Path,
+        max_frames: u64,
+    ) -> Result<FrameRecorder, Box<dyn std::error::Error>> {
+        std::fs::create_dir_all(dir)?;
+        Ok(FrameRecorder {
+            dir: dir.to_path_buf(),
+            max_frames,
+            count: 0,
+        })
+    }
+
+    fn record(&mut self, jpg: &[u8]) {
+        if self.count >= self.max_frames {
+            return;
+        }
+        let path = self.dir.join(format!("frame-{:06}.jpg", self.count));
+        if let Err(err) = std::fs::write(&path, jpg) {
+            warn!("failed to record frame to {}: {}", path.display(), err);
+            return;
+        }
+        self.count += 1;
+        if self.count == self.max_frames {
+            info!(
+                "recorded {} frames in {}; not recording more",
+                self.count,
+                self.dir.display()
+            );
+        }
+    }
+}
+
+pub fn read_stats(path: &std::path::Path) -> Result<StreamStats, Box<dyn std::error::Error>> {
+    Ok(serde_json::from_slice(&std::fs::read(path)?)?)
 }
 
 pub fn stream_websocket(
@@ -47,18 +294,17 @@ pub fn stream_websocket(
     api_token: &str,
     hscamera_id: &str,
     camera_path: &str,
-    dimensions: &Option<CameraDimensions>,
+    capture: CaptureOptions,
+    stats_path: Option<std::path::PathBuf>,
+    duration: Option<Duration>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let camera_path = String::from(camera_path);
-    let dimensions = dimensions.as_ref().cloned();
     let authheader = format!("Bearer {}", api_token);
     let url = format!("{}/hardshare/cam/{}/upload", origin, hscamera_id);
     let sys = System::new();
     let (err_notify, err_rx) = mpsc::channel();
     sys.runtime().spawn(async move {
-        let client = awc::Client::builder()
-            .add_default_header(("Authorization", authheader))
-            .finish();
+        let client = proxy::client(authheader, api::request_timeout());
 
         debug!("opening camera websocket...");
         let (_, framed) = match client.ws(url).connect().await {
@@ -76,19 +322,27 @@ pub fn stream_websocket(
         let (sink, stream) = framed.split();
 
         let (capture_tx, capture_rx) = mpsc::channel();
+        let err_notify = err_notify.clone();
         let addr = WSClient::create(|ctx| {
             WSClient::add_stream(stream, ctx);
             WSClient {
                 ws_sink: SinkWrite::new(sink, ctx),
                 recent_txrx_instant: std::time::Instant::now(), // First instant at first connect
                 capture: capture_tx,
+                stats: StreamStats::default(),
+                stats_path,
+                window_start: std::time::Instant::now(),
+                window_frames: 0,
+                window_bytes: 0,
+                duration,
+                err_notify,
             }
         });
-        std::thread::spawn(move || video_capture(&camera_path, dimensions, addr, capture_rx));
+        std::thread::spawn(move || video_capture(&camera_path, capture, addr, capture_rx));
     });
     match sys.run() {
         Ok(()) => Ok(()),
-        Err(_) => api::error(err_rx.recv()?),
+        Err(_) => Err(Box::new(HardshareError::Other(err_rx.recv()?))),
     }
 }
 
@@ -99,11 +353,38 @@ enum CaptureCommand {
     Quit,  // Return from (close) the thread
 }
 
+#[cfg(target_os = "macos")]
+pub fn list_devices() -> Result<Vec<CameraDevice>, Box<dyn std::error::Error>> {
+    use openpnp_capture::Device;
+
+    let mut found = vec![];
+    for (index, device_id) in Device::enumerate().into_iter().enumerate() {
+        let dev = match Device::new(device_id) {
+            Some(d) => d,
+            None => {
+                warn!("failed to open camera device {}", index);
+                continue;
+            }
+        };
+        let mut resolutions = vec![];
+        for format in dev.formats.iter() {
+            push_unique(&mut resolutions, format.width, format.height);
+        }
+        found.push(CameraDevice {
+            path: index.to_string(),
+            name: dev.name.clone(),
+            resolutions,
+        });
+    }
+    Ok(found)
+}
+
 #[cfg(target_os = "macos")]
 fn verify_capture_ability(
     camera_path: &str,
     dimensions: Option<CameraDimensions>,
-) -> Result<(), Box<dyn std::error::Error>> {
+    policy: ResolutionPolicy,
+) -> Result<CaptureFormats, Box<dyn std::error::Error>> {
     use openpnp_capture::{Device, Format, Stream};
 
     let camera_index: usize = match camera_path.parse() {
@@ -132,7 +413,7 @@ fn verify_capture_ability(
         }
     };
 
-    let (mut width, mut height) = match dimensions {
+    let (mut width, mut height) = match &dimensions {
         Some(d) => (d.width, d.height),
         None => (1280, 720),
     };
@@ -146,26 +427,30 @@ fn verify_capture_ability(
     };
     if stream.format().width != width || stream.format().height != height {
         (width, height) = (stream.format().width, stream.format().height);
-        warn!(
-            "requested format not feasible; falling back to ({}, {})",
-            width, height
-        );
+        check_negotiated(&dimensions, width, height, policy).map_err(CheckError::new)?;
     }
 
-    Ok(())
+    let mut available = vec![];
+    for format in dev.formats.iter() {
+        push_unique(&mut available, format.width, format.height);
+    }
+    Ok(CaptureFormats {
+        negotiated: CameraDimensions { width, height },
+        available,
+    })
 }
 
 #[cfg(target_os = "macos")]
 fn video_capture(
     camera_path: &str,
-    dimensions: Option<CameraDimensions>,
+    capture: CaptureOptions,
     wsclient_addr: Addr<WSClient>,
     cap_command: mpsc::Receiver<CaptureCommand>,
 ) {
-    use std::io::Cursor;
-
     use openpnp_capture::{Device, Format, Stream};
 
+    let dimensions = capture.dimensions;
+    let mut recorder = capture.recorder;
     let camera_index: usize = match camera_path.parse() {
         Ok(c) => c,
         Err(err) => {
@@ -185,7 +470,7 @@ fn video_capture(
         }
     };
 
-    let (mut width, mut height) = match dimensions {
+    let (mut width, mut height) = match &dimensions {
         Some(d) => (d.width, d.height),
         None => (1280, 720),
     };
@@ -210,10 +495,15 @@ fn video_capture(
                             (width, height) = (s.format().width, s.format().height);
                             buf_capacity = (width as usize) * (height as usize) * 3;
                             format = Format::default().width(width).height(height);
-                            warn!(
-                                "requested format not feasible; falling back to ({}, {})",
-                                width, height
-                            );
+                            if let Err(err) = check_negotiated(
+                                &dimensions,
+                                width,
+                                height,
+                                capture.on_unsupported_res,
+                            ) {
+                                error!("{}", err);
+                                return;
+                            }
                         }
 
                         stream = Some(s);
@@ -244,9 +534,15 @@ fn video_capture(
 
             match image::ImageBuffer::<image::Rgb<u8>, Vec<u8>>::from_vec(width, height, data) {
                 Some(img) => {
-                    let mut jpg: Vec<u8> = Vec::new();
-                    img.write_to(&mut Cursor::new(&mut jpg), image::ImageFormat::Jpeg)
-                        .unwrap();
+                    let img = orient(
+                        image::DynamicImage::ImageRgb8(img),
+                        capture.rotate,
+                        capture.flip,
+                    );
+                    let jpg = encode_jpeg(&img).unwrap();
+                    if let Some(r) = &mut recorder {
+                        r.record(&jpg);
+                    }
 
                     let b64data = base64_engine::STANDARD.encode(jpg);
                     if let Err(err) = wsclient_addr
@@ -263,28 +559,83 @@ fn video_capture(
     }
 }
 
+#[cfg(target_os = "windows")]
+pub fn list_devices() -> Result<Vec<CameraDevice>, Box<dyn std::error::Error>> {
+    return Err(CheckError::new("cameras not supported on Windows"));
+}
+
 #[cfg(target_os = "windows")]
 fn verify_capture_ability(
     camera_path: &str,
     dimensions: Option<CameraDimensions>,
-) -> Result<(), Box<dyn std::error::Error>> {
+    policy: ResolutionPolicy,
+) -> Result<CaptureFormats, Box<dyn std::error::Error>> {
     return Err(CheckError::new("cameras not supported on Windows"));
 }
 
 #[cfg(target_os = "windows")]
 fn video_capture(
     camera_path: &str,
-    dimensions: Option<CameraDimensions>,
+    capture: CaptureOptions,
     wsclient_addr: Addr<WSClient>,
     cap_command: mpsc::Receiver<CaptureCommand>,
 ) {
 }
 
+// Only MJPG is used for capture, so only list sizes in that format
+#[cfg(target_os = "linux")]
+fn mjpg_resolutions(dev: &v4l::Device) -> Vec<CameraDimensions> {
+    use v4l::framesize::FrameSizeEnum;
+    use v4l::video::Capture;
+
+    let mut resolutions = vec![];
+    if let Ok(framesizes) = dev.enum_framesizes(v4l::FourCC::new(b"MJPG")) {
+        for framesize in framesizes {
+            match framesize.size {
+                FrameSizeEnum::Discrete(d) => push_unique(&mut resolutions, d.width, d.height),
+                FrameSizeEnum::Stepwise(s) => {
+                    push_unique(&mut resolutions, s.min_width, s.min_height);
+                    push_unique(&mut resolutions, s.max_width, s.max_height);
+                }
+            }
+        }
+    }
+    resolutions
+}
+
+#[cfg(target_os = "linux")]
+pub fn list_devices() -> Result<Vec<CameraDevice>, Box<dyn std::error::Error>> {
+    let mut found = vec![];
+    for node in v4l::context::enum_devices() {
+        let path = node.path().to_string_lossy().to_string();
+        let dev = match v4l::Device::with_path(node.path()) {
+            Ok(d) => d,
+            Err(err) => {
+                warn!("failed to open camera device {}: {}", path, err);
+                continue;
+            }
+        };
+        let name = match dev.query_caps() {
+            Ok(caps) => caps.card,
+            Err(_) => node.name().unwrap_or_else(|| "(unknown)".into()),
+        };
+        let resolutions = mjpg_resolutions(&dev);
+        found.push(CameraDevice {
+            path,
+            name,
+            resolutions,
+        });
+    }
+    found.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(found)
+}
+
 #[cfg(target_os = "linux")]
 fn verify_capture_ability(
     camera_path: &str,
     dimensions: Option<CameraDimensions>,
-) -> Result<(), Box<dyn std::error::Error>> {
+    policy: ResolutionPolicy,
+) -> Result<CaptureFormats, Box<dyn std::error::Error>> {
     use v4l::prelude::*;
     use v4l::video::Capture;
 
@@ -305,16 +656,9 @@ fn verify_capture_ability(
         format.width = d.width;
         format.height = d.height;
     }
-    match dev.set_format(&format) {
+    let format = match dev.set_format(&format) {
         Ok(f) => {
-            if let Some(d) = dimensions {
-                if f.width != d.width || f.height != d.height {
-                    warn!(
-                        "requested size not feasible; falling back to ({}, {})",
-                        f.width, f.height
-                    );
-                }
-            }
+            check_negotiated(&dimensions, f.width, f.height, policy).map_err(CheckError::new)?;
             debug!("set format: {}", f);
             f
         }
@@ -336,13 +680,19 @@ fn verify_capture_ability(
         }
     };
 
-    Ok(())
+    Ok(CaptureFormats {
+        negotiated: CameraDimensions {
+            width: format.width,
+            height: format.height,
+        },
+        available: mjpg_resolutions(&dev),
+    })
 }
 
 #[cfg(target_os = "linux")]
 fn video_capture(
     camera_path: &str,
-    dimensions: Option<CameraDimensions>,
+    capture: CaptureOptions,
     wsclient_addr: Addr<WSClient>,
     cap_command: mpsc::Receiver<CaptureCommand>,
 ) {
@@ -350,6 +700,9 @@ fn video_capture(
     use v4l::prelude::*;
     use v4l::video::Capture;
 
+    let reorients = capture.reorients();
+    let dimensions = capture.dimensions;
+    let mut recorder = capture.recorder;
     let buffer_count = 4;
     debug!("opening camera {}", camera_path);
     let dev = match v4l::Device::with_path(camera_path) {
@@ -367,22 +720,19 @@ fn video_capture(
     }
     match dev.set_format(&format) {
         Ok(f) => {
-            if let Some(d) = dimensions {
-                if f.width != d.width || f.height != d.height {
-                    warn!(
-                        "requested size not feasible; falling back to ({}, {})",
-                        f.width, f.height
-                    );
-                }
+            if let Err(err) =
+                check_negotiated(&dimensions, f.width, f.height, capture.on_unsupported_res)
+            {
+                error!("{}", err);
+                return;
             }
             debug!("set format: {}", f);
-            f
         }
         Err(err) => {
             error!("failed to set camera format MJPG: {}", err);
             return;
         }
-    };
+    }
     let mut stream = None;
 
     loop {
@@ -438,7 +788,16 @@ fn video_capture(
                 metadata.flags,
                 buf.len()
             );
-            let data = buf.to_vec();
+            let mut data = buf.to_vec();
+            if reorients {
+                match reorient_jpeg(&data, capture.rotate, capture.flip) {
+                    Ok(d) => data = d,
+                    Err(err) => warn!("failed to rotate or flip camera image: {}", err),
+                }
+            }
+            if let Some(r) = &mut recorder {
+                r.record(&data);
+            }
             let b64data = base64_engine::STANDARD.encode(data);
             debug!("sending frame");
             if let Err(err) =
@@ -459,6 +818,15 @@ struct WSClient {
     ws_sink: SinkWrite<Message, SplitSink<Framed<BoxedSocket, Codec>, Message>>,
     recent_txrx_instant: std::time::Instant,
     capture: mpsc::Sender<CaptureCommand>,
+    stats: StreamStats,
+    stats_path: Option<std::path::PathBuf>,
+    window_start: std::time::Instant,
+    window_frames: u64,
+    window_bytes: u64,
+
+    // If given, stop streaming after this long
+    duration: Option<Duration>,
+    err_notify: mpsc::Sender<String>,
 }
 
 #[derive(Message)]
@@ -470,6 +838,10 @@ impl Actor for WSClient {
 
     fn started(&mut self, ctx: &mut Context<Self>) {
         self.check_receive_timeout(ctx);
+        ctx.run_interval(STATS_INTERVAL, |act, _ctx| act.report_stats());
+        if let Some(duration) = self.duration {
+            ctx.run_later(duration, |act, ctx| act.finish_duration(ctx));
+        }
     }
 
     fn stopped(&mut self, _ctx: &mut Context<Self>) {
@@ -478,6 +850,55 @@ impl Actor for WSClient {
 }
 
 impl WSClient {
+    fn report_stats(&mut self) {
+        self.stats.update(
+            self.window_frames,
+            self.window_bytes,
+            self.window_start.elapsed(),
+        );
+        self.window_start = std::time::Instant::now();
+        self.window_frames = 0;
+        self.window_bytes = 0;
+        if self.stats.fps > 0.0 {
+            info!(
+                "camera stream: {:.1} frames/s, {:.1} KiB/s; total {} frames, {} KiB",
+                self.stats.fps,
+                self.stats.bytes_per_second / 1024.0,
+                self.stats.frames_sent,
+                self.stats.bytes_sent / 1024
+            );
+        }
+        if let Some(path) = &self.stats_path {
+            if let Err(err) = std::fs::write(path, serde_json::to_string(&self.stats).unwrap()) {
+                warn!(
+                    "failed to write camera stats to {}: {}",
+                    path.display(),
+                    err
+                );
+            }
+        }
+    }
+
+    fn finish_duration(&mut self, ctx: &mut Context<Self>) {
+        debug!("camera stream duration elapsed");
+        self.report_stats();
+        if let Err(err) = self.ws_sink.write(Message::Close(None)) {
+            error!(
+                "caught while attempting to close camera WebSocket: {:?}",
+                err
+            );
+        }
+        ctx.stop();
+        if self.stats.frames_sent == 0 {
+            self.err_notify
+                .send("no frames were sent; did the server request the camera stream?".into())
+                .unwrap();
+            System::current().stop_with_code(1);
+        } else {
+            System::current().stop();
+        }
+    }
+
     fn check_receive_timeout(&self, ctx: &mut Context<Self>) {
         ctx.run_later(Duration::new(60, 0), |act, ctx| {
             if act.recent_txrx_instant.elapsed() > Duration::new(45, 0) {
@@ -540,8 +961,12 @@ impl Handler<WSSend> for WSClient {
     type Result = ();
 
     fn handle(&mut self, msg: WSSend, _ctx: &mut Context<Self>) {
+        let length = msg.0.len() as u64;
         match self.ws_sink.write(Message::Text(msg.0.into())) {
-            Ok(()) => (),
+            Ok(()) => {
+                self.window_frames += 1;
+                self.window_bytes += length;
+            }
             Err(err) => error!(
                 "caught while attempting to send message via camera WebSocket: {:?}",
                 err
@@ -552,3 +977,92 @@ impl Handler<WSSend> for WSClient {
 }
 
 impl actix::io::WriteHandler<WsProtocolError> for WSClient {}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        check_negotiated, orient, reorient_jpeg, Flip, FrameRecorder, ResolutionPolicy, StreamStats,
+    };
+    use crate::api::CameraDimensions;
+
+    #[test]
+    fn stream_rates() {
+        let mut stats = StreamStats::default();
+        stats.update(20, 40960, Duration::from_secs(10));
+        assert_eq!(stats.frames_sent, 20);
+        assert!((stats.fps - 2.0).abs() < 1e-9);
+        assert!((stats.bytes_per_second - 4096.0).abs() < 1e-9);
+
+        stats.update(0, 0, Duration::from_secs(10));
+        assert_eq!(stats.frames_sent, 20);
+        assert_eq!(stats.bytes_sent, 40960);
+        assert_eq!(stats.fps, 0.0);
+        assert!(stats.updated > 0);
+    }
+
+    #[test]
+    fn record_bounded_frames() {
+        let td = tempfile::tempdir().unwrap();
+        let dir = td.path().join("frames");
+        let mut recorder = FrameRecorder::new(&dir, 2).unwrap();
+        recorder.record(b"first");
+        recorder.record(b"second");
+        recorder.record(b"third");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        assert_eq!(
+            std::fs::read(dir.join("frame-000001.jpg")).unwrap(),
+            b"second"
+        );
+    }
+
+    #[test]
+    fn unsupported_resolution_policy() {
+        let requested = Some(CameraDimensions {
+            width: 1920,
+            height: 1080,
+        });
+        assert!(check_negotiated(&requested, 1920, 1080, ResolutionPolicy::Fail).is_ok());
+        assert!(check_negotiated(&requested, 1280, 720, ResolutionPolicy::Fallback).is_ok());
+        assert!(check_negotiated(&requested, 1280, 720, ResolutionPolicy::Fail).is_err());
+        assert!(check_negotiated(&None, 1280, 720, ResolutionPolicy::Fail).is_ok());
+
+        assert_eq!(
+            "fail".parse::<ResolutionPolicy>().unwrap(),
+            ResolutionPolicy::Fail
+        );
+        assert_eq!(ResolutionPolicy::default(), ResolutionPolicy::Fallback);
+        assert!("strict".parse::<ResolutionPolicy>().is_err());
+    }
+
+    #[test]
+    fn rotate_and_flip() {
+        let mut img = image::RgbImage::new(2, 1);
+        img.put_pixel(0, 0, image::Rgb([255, 0, 0]));
+        img.put_pixel(1, 0, image::Rgb([0, 0, 255]));
+        let img = image::DynamicImage::ImageRgb8(img);
+
+        let rotated = orient(img.clone(), 90, Flip::None).to_rgb8();
+        assert_eq!(rotated.dimensions(), (1, 2));
+        assert_eq!(rotated.get_pixel(0, 0), &image::Rgb([255, 0, 0]));
+
+        let flipped = orient(img.clone(), 0, Flip::Horizontal).to_rgb8();
+        assert_eq!(flipped.get_pixel(0, 0), &image::Rgb([0, 0, 255]));
+
+        let upside_down = orient(img, 180, Flip::Vertical).to_rgb8();
+        assert_eq!(upside_down.get_pixel(0, 0), &image::Rgb([0, 0, 255]));
+
+        assert_eq!("h".parse::<Flip>().unwrap(), Flip::Horizontal);
+        assert!("x".parse::<Flip>().is_err());
+    }
+
+    #[test]
+    fn reorient_mjpg_frame() {
+        let img = image::DynamicImage::ImageRgb8(image::RgbImage::new(8, 4));
+        let jpg = super::encode_jpeg(&img).unwrap();
+        let rotated = reorient_jpeg(&jpg, 270, Flip::None).unwrap();
+        let rotated = image::load_from_memory(&rotated).unwrap();
+        assert_eq!((rotated.width(), rotated.height()), (4, 8));
+    }
+}
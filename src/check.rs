@@ -12,8 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::net::{TcpStream, ToSocketAddrs};
 use std::process::{Command, Stdio};
 
+use crate::api::CameraDimensions;
 use crate::mgmt::{self, CProvider, Config, WDeployment};
 use crate::{api, camera, control, monitor};
 
@@ -47,37 +49,70 @@ impl Error {
     }
 }
 
-fn check_docker(rootless: bool) -> Result<(), String> {
-    info!(
-        "checking availability of docker{}",
-        if rootless { " (rootless)" } else { "" }
-    );
-    let output = match Command::new("docker").arg("version").output() {
+// Oldest versions of container runtimes that are known to work
+const MIN_DOCKER_VERSION: (u32, u32) = (20, 10);
+const MIN_PODMAN_VERSION: (u32, u32) = (3, 0);
+
+// Major and minor numbers from a version string like "24.0.7" or "4.9.3-dev"
+fn parse_version(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.trim().trim_start_matches('v').split('.').map(|p| {
+        p.chars()
+            .take_while(|c| c.is_ascii_digit())
+            .collect::<String>()
+            .parse::<u32>()
+    });
+    let major = parts.next()?.ok()?;
+    let minor = parts.next().unwrap_or(Ok(0)).unwrap_or(0);
+    Some((major, minor))
+}
+
+fn check_container_runtime(cprovider: &CProvider) -> Result<(), String> {
+    let execname = match cprovider.get_execname() {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    let min_version = if execname == "docker" {
+        MIN_DOCKER_VERSION
+    } else {
+        MIN_PODMAN_VERSION
+    };
+    info!("checking availability of {}", cprovider);
+    let output = match Command::new(&execname)
+        .args(["version", "--format", "{{.Client.Version}}"])
+        .output()
+    {
         Ok(x) => x,
-        Err(err) => return Err(format!("error calling `docker version`: {}", err)),
+        Err(err) => return Err(format!("error calling `{} version`: {}", execname, err)),
     };
     if !output.status.success() {
         return Err(format!(
-            "`docker version` failed with return code: {:?}",
+            "`{} version` failed with return code: {:?}",
+            execname,
             output.status.code()
         ));
     }
-    Ok(())
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    println!("{} version: {}", execname, version);
+    match parse_version(&version) {
+        Some(v) if v >= min_version => Ok(()),
+        Some(_) => Err(format!(
+            "{} version {} is older than the minimum supported {}.{}",
+            execname, version, min_version.0, min_version.1
+        )),
+        None => Err(format!("cannot parse version of {}: {}", execname, version)),
+    }
 }
 
-fn check_podman() -> Result<(), String> {
-    info!("checking availability of podman");
-    let output = match Command::new("podman").arg("version").output() {
-        Ok(x) => x,
-        Err(err) => return Err(format!("error calling `podman version`: {}", err)),
+fn check_executable_in_path(name: &str) -> Result<(), String> {
+    let path = match std::env::var_os("PATH") {
+        Some(p) => p,
+        None => return Err("PATH is not defined".into()),
     };
-    if !output.status.success() {
-        return Err(format!(
-            "`podman version` failed with return code: {:?}",
-            output.status.code()
-        ));
+    if std::env::split_paths(&path).any(|dir| dir.join(name).is_file()) {
+        Ok(())
+    } else {
+        Err(format!("{} not found in PATH", name))
     }
-    Ok(())
 }
 
 fn check_lxd() -> Result<(), String> {
@@ -97,7 +132,229 @@ fn check_lxd() -> Result<(), String> {
     Ok(())
 }
 
-pub fn check_proxy(wd: &WDeployment) -> Result<(), String> {
+// LXD images may be given with a remote, e.g., images:ubuntu/22.04. They are
+// copied to the local image store with an alias that omits the remote.
+fn lxd_local_alias(image: &str) -> &str {
+    match image.find(':') {
+        Some(sep) => &image[(sep + 1)..],
+        None => image,
+    }
+}
+
+pub fn image_exists(cprovider: &CProvider, image: &str) -> Result<bool, String> {
+    let argv = match cprovider {
+        CProvider::Podman | CProvider::PodmanRootful => ["podman", "image", "exists", image],
+        CProvider::Docker | CProvider::DockerRootless => ["docker", "image", "inspect", image],
+        CProvider::Lxd => ["lxc", "image", "show", lxd_local_alias(image)],
+        CProvider::Proxy => return Err("cprovider `proxy` does not use images".into()),
+    };
+    debug!("exec: {:?}", argv);
+    let status = match Command::new(argv[0])
+        .args(&argv[1..])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+    {
+        Ok(s) => s,
+        Err(err) => return Err(format!("error calling `{}`: {}", argv.join(" "), err)),
+    };
+    debug!("exit status: {:?}", status);
+    Ok(status.success())
+}
+
+// Digests by which the registry knows the local image, e.g.,
+// rerobots/hs-generic@sha256:... These are what a tag currently resolves to.
+pub fn resolve_image_digests(cprovider: &CProvider, image: &str) -> Result<Vec<String>, String> {
+    let execname = match cprovider {
+        CProvider::Docker
+        | CProvider::DockerRootless
+        | CProvider::Podman
+        | CProvider::PodmanRootful => cprovider.get_execname().unwrap(),
+        _ => {
+            return Err(format!(
+                "cprovider `{}` does not have image digests",
+                cprovider
+            ))
+        }
+    };
+    let argv = [
+        execname.as_str(),
+        "image",
+        "inspect",
+        "--format",
+        "{{json .RepoDigests}}",
+        image,
+    ];
+    debug!("exec: {:?}", argv);
+    let output = match Command::new(argv[0]).args(&argv[1..]).output() {
+        Ok(o) => o,
+        Err(err) => return Err(format!("error calling `{}`: {}", argv.join(" "), err)),
+    };
+    if !output.status.success() {
+        return Err(format!(
+            "`{}` failed with return code: {:?}",
+            argv.join(" "),
+            output.status.code()
+        ));
+    }
+    match serde_json::from_slice::<Option<Vec<String>>>(&output.stdout) {
+        Ok(digests) => Ok(digests.unwrap_or_default()),
+        Err(err) => Err(format!("failed to parse image digests: {}", err)),
+    }
+}
+
+fn pull_image(cprovider: &CProvider, image: &str) -> Result<(), String> {
+    let argv: Vec<String> = match cprovider {
+        CProvider::Lxd => {
+            // Images without a remote are assumed to be from the default "images:" server
+            let source = if image.contains(':') {
+                image.to_string()
+            } else {
+                format!("images:{}", image)
+            };
+            let alias = lxd_local_alias(image);
+            vec![
+                "lxc".into(),
+                "image".into(),
+                "copy".into(),
+                source,
+                "local:".into(),
+                "--alias".into(),
+                alias.into(),
+            ]
+        }
+        _ => match cprovider.get_execname() {
+            Some(execname) => vec![execname, "pull".into(), image.into()],
+            None => return Err(format!("cannot pull images for cprovider `{}`", cprovider)),
+        },
+    };
+    println!("pulling image {} ...", image);
+    debug!("exec: {:?}", argv);
+    // Output is not captured, so progress is shown as the image is pulled
+    let status = match Command::new(&argv[0]).args(&argv[1..]).status() {
+        Ok(s) => s,
+        Err(err) => return Err(format!("error calling `{}`: {}", argv.join(" "), err)),
+    };
+    if !status.success() {
+        return Err(format!(
+            "`{}` failed with return code: {:?}",
+            argv.join(" "),
+            status.code()
+        ));
+    }
+    Ok(())
+}
+
+fn check_image(wd: &WDeployment, pull: bool) -> Result<(), String> {
+    if wd.cprovider == CProvider::Proxy {
+        return Ok(());
+    }
+    let image = match &wd.image {
+        Some(i) => i,
+        None => return Err("no image in configuration".into()),
+    };
+    if let Err(err) = mgmt::validate_image_ref(image, &wd.cprovider) {
+        return Err(format!("image {}: {}", image, err));
+    }
+    info!("checking that image {} is present", image);
+    if image_exists(&wd.cprovider, image)? {
+        report_image_digest(&wd.cprovider, image);
+        return Ok(());
+    }
+    if pull {
+        pull_image(&wd.cprovider, image)?;
+        report_image_digest(&wd.cprovider, image);
+        Ok(())
+    } else {
+        Err(format!(
+            "image {} is not present locally\nPull it with the following command:\n\n    hardshare check --pull\n",
+            image
+        ))
+    }
+}
+
+fn report_image_digest(cprovider: &CProvider, image: &str) {
+    if *cprovider == CProvider::Lxd {
+        return;
+    }
+    if let Some(digest) = mgmt::image_digest(image) {
+        println!("image is pinned to digest {}", digest);
+        return;
+    }
+    match resolve_image_digests(cprovider, image) {
+        Ok(digests) => {
+            if digests.is_empty() {
+                println!("image {} has no registry digest (built locally?)", image);
+            } else {
+                println!(
+                    "image {} resolves to {}\nTo pin it, try `hardshare config --assign-image {}`",
+                    image,
+                    digests.join(", "),
+                    digests[0]
+                );
+            }
+        }
+        Err(err) => warn!("failed to resolve digest of image {}: {}", image, err),
+    }
+}
+
+fn check_camera_formats(dimensions: &Option<CameraDimensions>) -> Result<(), String> {
+    let formats = match camera::check_camera(
+        &camera::get_default_dev(),
+        dimensions,
+        camera::ResolutionPolicy::Fallback,
+    ) {
+        Ok(f) => f,
+        Err(err) => return Err(format!("caught while checking camera: {}", err)),
+    };
+    if let Some(d) = dimensions {
+        if formats.negotiated.width != d.width || formats.negotiated.height != d.height {
+            let available: Vec<String> = formats
+                .available
+                .iter()
+                .map(|a| format!("{}x{}", a.width, a.height))
+                .collect();
+            return Err(format!(
+                "camera does not support {}x{}; it would fall back to {}x{}\nsupported: {}",
+                d.width,
+                d.height,
+                formats.negotiated.width,
+                formats.negotiated.height,
+                if available.is_empty() {
+                    "(unknown)".into()
+                } else {
+                    available.join(", ")
+                }
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Options for the simulated instance launch
+#[derive(Default)]
+pub struct LaunchOptions {
+    // Pull the image if it is not present locally
+    pub pull: bool,
+
+    // Clone into the test container, as if it were given with a launch request
+    pub repo: Option<control::RepoInfo>,
+}
+
+fn setup_test_repo(wd: &WDeployment, cname: &str, launch: &LaunchOptions) -> Result<(), String> {
+    if let Some(repo) = &launch.repo {
+        info!("simulating repository setup ...");
+        if let Err(err) = control::CurrentInstance::setup_repo(wd, cname, repo) {
+            return Err(format!(
+                "caught while setting up repository in test container: {}",
+                err
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn check_proxy_command(wd: &WDeployment) -> Result<(), String> {
     if wd.cargs.is_empty() {
         return Err(
             "Proxy is not configured. Try `hardshare config --assign-proxy-command`".into(),
@@ -118,14 +375,52 @@ pub fn check_proxy(wd: &WDeployment) -> Result<(), String> {
     Ok(())
 }
 
+fn check_proxy_target(wd: &WDeployment) -> Result<(), String> {
+    if wd.cargs.len() < 2 {
+        return Err(
+            "Proxy target is not configured. Try `hardshare config --assign-proxy-command`".into(),
+        );
+    }
+    let target = &wd.cargs[1];
+    info!("checking reachability of proxy target {}", target);
+    let addrs = match target.to_socket_addrs() {
+        Ok(a) => a,
+        Err(err) => return Err(format!("invalid proxy target {}: {}", target, err)),
+    };
+    let timeout = std::time::Duration::from_secs(3);
+    let mut last_err = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(_) => return Ok(()),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    match last_err {
+        Some(err) => Err(format!("proxy target {} is not reachable: {}", target, err)),
+        None => Err(format!("proxy target {} has no addresses", target)),
+    }
+}
+
+pub fn check_proxy(wd: &WDeployment) -> Result<(), String> {
+    check_proxy_command(wd)?;
+    check_proxy_target(wd)
+}
+
 fn check_cprovider(wd: &WDeployment) -> Result<(), String> {
     match wd.cprovider {
-        CProvider::Podman => check_podman(),
-        CProvider::Docker | CProvider::DockerRootless => {
-            check_docker(wd.cprovider == CProvider::DockerRootless)
-        }
+        CProvider::Podman
+        | CProvider::PodmanRootful
+        | CProvider::Docker
+        | CProvider::DockerRootless => check_container_runtime(&wd.cprovider),
         CProvider::Lxd => check_lxd(),
-        CProvider::Proxy => check_proxy(wd),
+        CProvider::Proxy => {
+            if let Some(command) = wd.cargs.first() {
+                if !command.contains(std::path::MAIN_SEPARATOR) {
+                    check_executable_in_path(command)?;
+                }
+            }
+            check_proxy_command(wd)
+        }
     }
 }
 
@@ -149,9 +444,11 @@ fn check_deployment_in_remote(
 pub fn config(
     local_config: &Config,
     check_camera: bool,
+    camera_dims: &Option<CameraDimensions>,
     id: &str,
     remote_config: Option<&serde_json::Value>,
     fail_fast: bool,
+    launch: &LaunchOptions,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let wd_index = match mgmt::find_id_prefix(local_config, Some(id)) {
         Ok(wi) => wi,
@@ -180,7 +477,7 @@ pub fn config(
         }
         None => {
             let ac = api::HSAPIClient::new();
-            match ac.get_remote_config(false) {
+            match ac.get_remote_config(false, false, false) {
                 Ok(rc) => {
                     let res = check_deployment_in_remote(id, &rc);
                     if let Err(err) = res {
@@ -204,8 +501,7 @@ pub fn config(
     }
 
     if check_camera {
-        if let Err(err) = camera::check_camera(&camera::get_default_dev()) {
-            let msg = format!("caught while checking camera: {}", err);
+        if let Err(msg) = check_camera_formats(camera_dims) {
             if fail_fast {
                 return Err(Error::new(&msg));
             }
@@ -283,6 +579,17 @@ pub fn config(
         }
     }
 
+    if local_config.wdeployments[wd_index].cprovider == CProvider::Proxy {
+        if let Err(err) = check_proxy_target(&local_config.wdeployments[wd_index]) {
+            let msg = format!("caught while checking proxy target: {}", err);
+            if fail_fast {
+                return Err(Error::new(&msg));
+            }
+            at_least_one_error = true;
+            println!("{}", msg);
+        }
+    }
+
     if let Err(err) = check_cprovider(&local_config.wdeployments[wd_index]) {
         return Err(Error::new(format!(
             "{}\nIs {} installed correctly?",
@@ -290,6 +597,14 @@ pub fn config(
         )));
     }
 
+    if let Err(msg) = check_image(&local_config.wdeployments[wd_index], launch.pull) {
+        if fail_fast {
+            return Err(Error::new(&msg));
+        }
+        at_least_one_error = true;
+        println!("{}", msg);
+    }
+
     monitor::run_dry(local_config, wd_index)?;
 
     info!("simulating instance launch ...");
@@ -298,6 +613,7 @@ pub fn config(
         &local_config.wdeployments[wd_index],
         cname,
         "checkkey",
+        &|phase| info!("launch phase: {}", phase),
     ) {
         let mut msg = format!("caught while creating test container: {}", err);
         if fail_fast {
@@ -311,12 +627,20 @@ pub fn config(
         }
         at_least_one_error = true;
         println!("{}", msg);
+    } else if let Err(msg) = setup_test_repo(&local_config.wdeployments[wd_index], cname, launch) {
+        if fail_fast {
+            return Err(Error::new(&msg));
+        }
+        at_least_one_error = true;
+        println!("{}", msg);
     }
 
     info!("simulating instance terminate ...");
-    if let Err(err) =
-        control::CurrentInstance::destroy_container(&local_config.wdeployments[wd_index], cname)
-    {
+    if let Err(err) = control::CurrentInstance::destroy_container(
+        &local_config.wdeployments[wd_index],
+        cname,
+        None,
+    ) {
         let msg = format!("caught while destroying test container: {}", err);
         if fail_fast {
             return Err(Error::new(&msg));
@@ -335,12 +659,14 @@ pub fn config(
 pub fn all_configurations(
     local_config: &Config,
     check_camera: bool,
+    camera_dims: &Option<CameraDimensions>,
     fail_fast: bool,
+    launch: &LaunchOptions,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let mut at_least_one_error = false;
 
     let ac = api::HSAPIClient::new();
-    let remote_config = match ac.get_remote_config(false) {
+    let remote_config = match ac.get_remote_config(false, false, false) {
         Ok(rc) => Some(rc),
         Err(err) => {
             let msg = format!("caught while checking registration on server: {}", err);
@@ -354,8 +680,7 @@ pub fn all_configurations(
     };
 
     if check_camera {
-        if let Err(err) = camera::check_camera(&camera::get_default_dev()) {
-            let msg = format!("caught while checking camera: {}", err);
+        if let Err(msg) = check_camera_formats(camera_dims) {
             if fail_fast {
                 return Err(Error::new(&msg));
             }
@@ -368,9 +693,11 @@ pub fn all_configurations(
         if let Err(err) = config(
             local_config,
             false,
+            &None,
             &wd.id,
             remote_config.as_ref(),
             fail_fast,
+            launch,
         ) {
             let msg = format!("{}: {}", &wd.id, err);
             if fail_fast {
@@ -387,14 +714,18 @@ pub fn all_configurations(
     }
 }
 
-pub fn defaults(check_camera: bool, fail_fast: bool) -> Result<(), Box<dyn std::error::Error>> {
+pub fn defaults(
+    check_camera: bool,
+    camera_dims: &Option<CameraDimensions>,
+    fail_fast: bool,
+    launch: &LaunchOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
     let mut at_least_one_error = false;
 
     let wdeployment = WDeployment::new_min("68a1be97-9365-4007-b726-14c56bd69eef", "owner");
 
     if check_camera {
-        if let Err(err) = camera::check_camera(&camera::get_default_dev()) {
-            let msg = format!("caught while checking camera: {}", err);
+        if let Err(msg) = check_camera_formats(camera_dims) {
             if fail_fast {
                 return Err(Error::new(&msg));
             }
@@ -410,19 +741,37 @@ pub fn defaults(check_camera: bool, fail_fast: bool) -> Result<(), Box<dyn std::
         )));
     }
 
+    if let Err(msg) = check_image(&wdeployment, launch.pull) {
+        if fail_fast {
+            return Err(Error::new(&msg));
+        }
+        at_least_one_error = true;
+        println!("{}", msg);
+    }
+
     info!("simulating instance launch ...");
     let cname = "check";
-    if let Err(err) = control::CurrentInstance::launch_container(&wdeployment, cname, "checkkey") {
+    if let Err(err) =
+        control::CurrentInstance::launch_container(&wdeployment, cname, "checkkey", &|phase| {
+            info!("launch phase: {}", phase)
+        })
+    {
         let msg = format!("caught while creating test container: {}", err);
         if fail_fast {
             return Err(Error::new(&msg));
         }
         at_least_one_error = true;
         println!("{}", msg);
+    } else if let Err(msg) = setup_test_repo(&wdeployment, cname, launch) {
+        if fail_fast {
+            return Err(Error::new(&msg));
+        }
+        at_least_one_error = true;
+        println!("{}", msg);
     }
 
     info!("simulating instance terminate ...");
-    if let Err(err) = control::CurrentInstance::destroy_container(&wdeployment, cname) {
+    if let Err(err) = control::CurrentInstance::destroy_container(&wdeployment, cname, None) {
         let msg = format!("caught while destroying test container: {}", err);
         if fail_fast {
             return Err(Error::new(&msg));
@@ -437,3 +786,19 @@ pub fn defaults(check_camera: bool, fail_fast: bool) -> Result<(), Box<dyn std::
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::parse_version;
+
+    #[test]
+    fn runtime_versions() {
+        assert_eq!(parse_version("24.0.7"), Some((24, 0)));
+        assert_eq!(parse_version("20.10.24+dfsg1\n"), Some((20, 10)));
+        assert_eq!(parse_version("4.9.3-dev"), Some((4, 9)));
+        assert_eq!(parse_version("5"), Some((5, 0)));
+        assert_eq!(parse_version(""), None);
+        assert!(parse_version("3.4.4").unwrap() >= super::MIN_PODMAN_VERSION);
+        assert!(parse_version("19.03.1").unwrap() < super::MIN_DOCKER_VERSION);
+    }
+}
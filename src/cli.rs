@@ -15,19 +15,81 @@
 use std::convert::TryFrom;
 use std::io::prelude::*;
 use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use serde::Serialize;
 
 use chrono::{TimeZone, Utc};
 
-use clap::{Arg, SubCommand};
+use clap::{AppSettings, Arg, SubCommand};
 
 use rerobots::client::TokenClaims;
 
 use crate::api::{CameraCrop, CameraDimensions};
 use crate::camera;
+use crate::error::HardshareError;
 use crate::mgmt::CProvider;
-use crate::{api, check, mgmt, monitor};
+use crate::{api, check, control, mgmt, monitor, proxy, tls};
+
+// Exit codes of the hardshare command:
+//
+//   0  success
+//   1  general failure
+//   2  usage error, e.g., unknown option, missing switch, or invalid value
+//   3  authentication failure, e.g., missing, expired, or rejected API token
+//   4  not found, e.g., unknown workspace deployment
+//   5  network failure, e.g., cannot connect to rerobots servers or local daemon
+//   6  conflict, e.g., the operation conflicts with current state on the server
+//   7  ambiguous, e.g., given id prefix matches more than 1 workspace deployment
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_AUTH: i32 = 3;
+pub const EXIT_NOT_FOUND: i32 = 4;
+pub const EXIT_NETWORK: i32 = 5;
+pub const EXIT_CONFLICT: i32 = 6;
+pub const EXIT_AMBIGUOUS: i32 = 7;
+
+// Choose exit code according to the class of error; if not recognized, use `fallback`.
+fn classify_exit_code(err: &(dyn std::error::Error + 'static), fallback: i32) -> i32 {
+    if let Some(herr) = HardshareError::find(err) {
+        return match herr {
+            HardshareError::Auth(_) => EXIT_AUTH,
+            HardshareError::NotFound(_) => EXIT_NOT_FOUND,
+            HardshareError::Api { status: 409, .. } => EXIT_CONFLICT,
+            HardshareError::Ambiguous { .. } => EXIT_AMBIGUOUS,
+            HardshareError::Invalid(_) => EXIT_USAGE,
+            HardshareError::Network(_) | HardshareError::Timeout => EXIT_NETWORK,
+            HardshareError::Io(ioerr) => classify_exit_code(ioerr, fallback),
+            _ => fallback,
+        };
+    }
+    if err.is::<awc::error::SendRequestError>() || err.is::<awc::error::WsClientError>() {
+        return EXIT_NETWORK;
+    }
+    if let Some(ioerr) = err.downcast_ref::<std::io::Error>() {
+        return match ioerr.kind() {
+            std::io::ErrorKind::ConnectionRefused
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::NotConnected
+            | std::io::ErrorKind::AddrNotAvailable
+            | std::io::ErrorKind::TimedOut => EXIT_NETWORK,
+            _ => fallback,
+        };
+    }
+    fallback
+}
+
+// Set by --quiet
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+// Guidance that is not part of the result of a command. It is printed to
+// stderr, so that stdout has only results, and not at all if --quiet.
+fn note(msg: &str) {
+    if !QUIET.load(Ordering::Relaxed) {
+        eprintln!("{}", msg);
+    }
+}
 
 pub struct CliError {
     pub msg: Option<String>,
@@ -53,11 +115,39 @@ impl std::fmt::Debug for CliError {
     }
 }
 
+// Message for an error; requests that time out are described as such, with
+// how to allow more time
+fn describe_error(err: &(dyn std::error::Error + 'static)) -> String {
+    let timed_out = matches!(HardshareError::find(err), Some(HardshareError::Timeout))
+        || matches!(
+            err.downcast_ref::<awc::error::SendRequestError>(),
+            Some(awc::error::SendRequestError::Timeout)
+                | Some(awc::error::SendRequestError::Connect(
+                    awc::error::ConnectError::Timeout
+                ))
+        );
+    if timed_out {
+        format!(
+            "request timed out after {} s (to wait longer, try `hardshare --timeout SECONDS`)",
+            api::request_timeout().as_secs()
+        )
+    } else {
+        format!("{}", err)
+    }
+}
+
 impl From<Box<dyn std::error::Error>> for CliError {
     fn from(value: Box<dyn std::error::Error>) -> Self {
-        let disp = format!("{}", value);
+        let disp = describe_error(value.as_ref());
         let msg = if disp.is_empty() { None } else { Some(disp) };
-        CliError { msg, exitcode: 1 }
+        let exitcode = classify_exit_code(value.as_ref(), EXIT_FAILURE);
+        CliError { msg, exitcode }
+    }
+}
+
+impl From<HardshareError> for CliError {
+    fn from(value: HardshareError) -> Self {
+        CliError::from(Box::<dyn std::error::Error>::from(value))
     }
 }
 
@@ -72,17 +162,21 @@ impl CliError {
         })
     }
 
-    fn new_std(err: Box<dyn std::error::Error>, exitcode: i32) -> Result<(), CliError> {
+    fn new_std<E>(err: E, exitcode: i32) -> Result<(), CliError>
+    where
+        E: Into<Box<dyn std::error::Error>>,
+    {
+        let err = err.into();
         Err(CliError {
-            msg: Some(format!("{}", err)),
-            exitcode,
+            msg: Some(describe_error(err.as_ref())),
+            exitcode: classify_exit_code(err.as_ref(), exitcode),
         })
     }
 
     fn new_stdio(err: std::io::Error, exitcode: i32) -> Result<(), CliError> {
         Err(CliError {
             msg: Some(format!("{}", err)),
-            exitcode,
+            exitcode: classify_exit_code(&err, exitcode),
         })
     }
 
@@ -101,23 +195,31 @@ enum PrintingFormat {
     Json,
 }
 
-fn confirm(prompt: &str) -> Result<(), CliError> {
+fn ask_yes_no(prompt: &str) -> Result<bool, CliError> {
     let mut confirmation = String::new();
     loop {
         print!("{}", prompt);
         std::io::stdout().flush().expect("failed to flush stdout");
+        confirmation.clear();
         match std::io::stdin().read_line(&mut confirmation) {
             Ok(n) => n,
-            Err(err) => return CliError::new_stdio(err, 1),
+            Err(err) => return CliError::new_stdio(err, 1).map(|_| false),
         };
-        confirmation = confirmation.trim().to_lowercase();
-        if confirmation == "y" || confirmation == "yes" {
-            break;
-        } else if confirmation.is_empty() || confirmation == "n" || confirmation == "no" {
-            return CliError::newrc(1);
+        let answer = confirmation.trim().to_lowercase();
+        if answer == "y" || answer == "yes" {
+            return Ok(true);
+        } else if answer.is_empty() || answer == "n" || answer == "no" {
+            return Ok(false);
         }
     }
-    Ok(())
+}
+
+fn confirm(prompt: &str) -> Result<(), CliError> {
+    if ask_yes_no(prompt)? {
+        Ok(())
+    } else {
+        CliError::newrc(1)
+    }
 }
 
 fn print_config(
@@ -125,6 +227,7 @@ fn print_config(
     remote: &Option<serde_json::Value>,
     pformat: PrintingFormat,
     show_all_remote: bool,
+    include_secrets: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
     print_config_w(
         &mut std::io::stdout(),
@@ -132,6 +235,7 @@ fn print_config(
         remote,
         pformat,
         show_all_remote,
+        include_secrets,
     )?;
     Ok(())
 }
@@ -142,6 +246,7 @@ fn print_config_w<T: Write>(
     remote: &Option<serde_json::Value>,
     pformat: PrintingFormat,
     show_all_remote: bool,
+    include_secrets: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
     if pformat != PrintingFormat::Default {
         fn serializer<T: Serialize>(x: &T, pformat: PrintingFormat) -> String {
@@ -153,6 +258,22 @@ fn print_config_w<T: Write>(
             }
         }
 
+        // Paths of the SSH key and API tokens are omitted unless requested
+        let mut local = serde_json::to_value(local)?;
+        if !include_secrets {
+            if let Some(local) = local.as_object_mut() {
+                local.remove("ssh_key");
+                local.remove("api_tokens");
+                if let Some(wds) = local.get_mut("wdeployments").and_then(|w| w.as_array_mut()) {
+                    for wd in wds.iter_mut() {
+                        if let Some(wd) = wd.as_object_mut() {
+                            wd.remove("ssh_key");
+                        }
+                    }
+                }
+            }
+        }
+
         if let Some(remote) = remote {
             let combined = json!({
                 "local": local,
@@ -172,10 +293,13 @@ fn print_config_w<T: Write>(
     } else {
         for wd in local.wdeployments.iter() {
             local_ids.push(wd.id.as_str());
+            writeln!(f, "{}", wd.id)?;
+            if let Some(label) = &wd.label {
+                writeln!(f, "\tlabel: {}", label)?;
+            }
             writeln!(
                 f,
-                "{}\n\turl: {}\n\towner: {}\n\tcprovider: {}\n\tcargs: {}",
-                wd.id,
+                "\turl: {}\n\towner: {}\n\tcprovider: {}\n\tcargs: {}",
                 wd.url.clone().unwrap(),
                 wd.owner,
                 wd.cprovider,
@@ -184,16 +308,25 @@ fn print_config_w<T: Write>(
             if wd.cprovider == CProvider::Docker
                 || wd.cprovider == CProvider::DockerRootless
                 || wd.cprovider == CProvider::Podman
+                || wd.cprovider == CProvider::PodmanRootful
                 || wd.cprovider == CProvider::Lxd
             {
                 match &wd.image {
                     Some(img) => {
                         writeln!(f, "\timg: {img}")?;
+                        if let Some(digest) = mgmt::image_digest(img) {
+                            writeln!(f, "\timg digest: {}", digest)?;
+                        }
                     }
                     None => {
                         writeln!(f, "\timg: (none)")?;
                     }
                 }
+                writeln!(f, "\tcontainer name: {}", wd.container_name)?;
+                writeln!(f, "\tssh user: {}", wd.get_ssh_user())?;
+                if let Some(network) = &wd.network {
+                    writeln!(f, "\tnetwork: {}", network)?;
+                }
             }
             if !wd.init_inside.is_empty() {
                 writeln!(f, "\tinit inside:")?;
@@ -207,9 +340,30 @@ fn print_config_w<T: Write>(
                     writeln!(f, "\t\t{}", terminate_p)?;
                 }
             }
+            if let Some(r) = &wd.ready_check {
+                writeln!(f, "\tready check: {}", r)?;
+            }
+            if let Some(secs) = wd.ready_timeout_secs {
+                writeln!(f, "\tready timeout: {} s", secs)?;
+            }
+            if let Some(r) = &wd.pre_launch {
+                writeln!(f, "\tpre-launch: {}", r)?;
+            }
+            if let Some(r) = &wd.on_ready {
+                writeln!(f, "\ton ready: {}", r)?;
+            }
             if let Some(m) = &wd.monitor {
                 writeln!(f, "\tmonitor: {}", m)?;
             }
+            if let Some(secs) = wd.max_session_secs {
+                writeln!(f, "\tmax session: {} s", secs)?;
+            }
+            if let Some(secs) = wd.idle_timeout_secs {
+                writeln!(f, "\tidle timeout: {} s", secs)?;
+            }
+            if wd.ssh_tunnel != mgmt::SshTunnelOptions::default() {
+                writeln!(f, "\tssh tunnel: {}", wd.ssh_tunnel)?;
+            }
         }
     }
 
@@ -306,6 +460,17 @@ fn print_config_w<T: Write>(
                 if locked_out {
                     writeln!(f, "\tlock-out: {}", locked_out)?;
                 }
+                if let Some(instances) = wd["instances"].as_array() {
+                    writeln!(f, "\tactive instances: {}", instances.len())?;
+                    for instance in instances.iter() {
+                        writeln!(
+                            f,
+                            "\t\t{}: {}",
+                            instance["id"].as_str().unwrap_or("(unknown)"),
+                            instance["status"].as_str().unwrap_or("(unknown)")
+                        )?;
+                    }
+                }
             }
         }
     }
@@ -316,7 +481,10 @@ fn print_config_w<T: Write>(
 fn list_subcommand(matches: &clap::ArgMatches, pformat: PrintingFormat) -> Result<(), CliError> {
     let only_local_config = matches.is_present("onlylocalconfig");
     let include_dissolved = matches.is_present("includedissolved");
+    let with_instances = matches.is_present("with_instances");
+    let refresh = matches.is_present("refresh");
     let show_all_remote = matches.is_present("list_all");
+    let include_secrets = matches.is_present("include_secrets");
 
     let mut local_config = match mgmt::get_local_config(false, true) {
         Ok(lc) => lc,
@@ -326,25 +494,102 @@ fn list_subcommand(matches: &clap::ArgMatches, pformat: PrintingFormat) -> Resul
 
     let mut remote_config = None;
     if !only_local_config {
-        let ac = api::HSAPIClient::new();
-        remote_config = Some(match ac.get_remote_config(include_dissolved) {
-            Ok(rc) => rc,
-            Err(err) => {
-                let err_message = format!(
-                    "{}\nTo get only the local configuration, do\n\n    hardshare list --local",
-                    err
-                );
-                return CliError::new(err_message.as_str(), 1);
+        let mut ac = api::HSAPIClient::new();
+        if let Some(org_name) = matches.value_of("org") {
+            if let Err(err) = ac.use_org(org_name) {
+                return CliError::new_std(err, 1);
             }
-        });
+            if pformat == PrintingFormat::Default {
+                note(&format!("listing for organization: {}\n", org_name));
+            }
+        }
+        remote_config = Some(
+            match ac.get_remote_config(include_dissolved, with_instances, !refresh) {
+                Ok(rc) => rc,
+                Err(err) => {
+                    let err_message = format!(
+                        "{}\nTo get only the local configuration, do\n\n    hardshare list --local",
+                        err
+                    );
+                    return CliError::new(err_message.as_str(), 1);
+                }
+            },
+        );
     }
 
-    match print_config(&local_config, &remote_config, pformat, show_all_remote) {
+    match print_config(
+        &local_config,
+        &remote_config,
+        pformat,
+        show_all_remote,
+        include_secrets,
+    ) {
         Ok(()) => Ok(()),
         Err(err) => CliError::new_std(err, 1),
     }
 }
 
+fn assign_cprovider(wd: &mut mgmt::WDeployment, selected_cprovider: CProvider) {
+    if selected_cprovider == CProvider::Proxy {
+        wd.image = None;
+    } else {
+        if wd.cprovider == CProvider::Proxy {
+            wd.cargs = vec![];
+        }
+        // LXD image names are not meaningful to Docker or Podman, and vice versa
+        let same_images =
+            (wd.cprovider == CProvider::Lxd) == (selected_cprovider == CProvider::Lxd);
+        if wd.image.is_none() || !same_images {
+            let default_img = selected_cprovider.default_image().unwrap();
+            if wd.image.is_some() {
+                note(&format!(
+                    "image changed to {}; to use another, try `hardshare config --assign-image`",
+                    default_img
+                ));
+            }
+            wd.image = Some(default_img.into());
+        }
+    }
+    wd.cprovider = selected_cprovider;
+}
+
+fn check_image(cprovider: &CProvider, image: &str) -> Result<(), CliError> {
+    if cprovider == &CProvider::Proxy {
+        let errmessage = format!("cannot --assign-image for cprovider `{}`", cprovider);
+        return CliError::new(errmessage.as_str(), 1);
+    }
+    if let Err(err) = mgmt::validate_image_ref(image, cprovider) {
+        return CliError::new_std(err, EXIT_USAGE);
+    }
+    match check::image_exists(cprovider, image) {
+        Ok(true) => Ok(()),
+        Ok(false) if mgmt::image_digest(image).is_some() => CliError::new(
+            "no local image has the given digest; pull it first, e.g., `docker pull NAME@sha256:DIGEST`",
+            1,
+        ),
+        Ok(false) => CliError::new("given image name is not recognized by cprovider", 1),
+        Err(err) => CliError::new(err, 1),
+    }
+}
+
+fn check_proxy_command(command: &str) -> Result<(), CliError> {
+    let parts: Vec<&str> = command.split(' ').collect();
+    match parts[0] {
+        "rrhttp" => {
+            if parts.len() != 2 {
+                return CliError::new("Usage: rrhttp TARGET", EXIT_USAGE);
+            }
+        }
+        "rrtcp" => {
+            if parts.len() != 2 {
+                return CliError::new("Usage: rrtcp TARGET", EXIT_USAGE);
+            }
+        }
+        _ => return CliError::new("unknown proxy command", 1),
+    };
+    Ok(())
+}
+
 fn config_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
     if let Some(new_token_path) = matches.value_of("new_api_token") {
         let mut local_config = match mgmt::get_local_config(false, true) {
@@ -382,6 +627,39 @@ fn config_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
             Ok(()) => {}
             Err(err) => return CliError::new_std(err, 1),
         }
+    } else if matches.is_present("show_ssh_key") {
+        let local_config = match mgmt::get_local_config(false, false) {
+            Ok(lc) => lc,
+            Err(err) => return CliError::new_std(err, 1),
+        };
+        let public_key =
+            match std::fs::read_to_string(mgmt::get_ssh_public_path(&local_config.ssh_key)) {
+                Ok(k) => k,
+                Err(err) => return CliError::new_stdio(err, 1),
+            };
+        let fingerprint = match mgmt::get_ssh_fingerprint(&local_config.ssh_key) {
+            Ok(f) => f,
+            Err(err) => return CliError::new_std(err, 1),
+        };
+        println!("path: {}", local_config.ssh_key);
+        println!("public key: {}", public_key.trim());
+        println!("fingerprint: {}", fingerprint);
+    } else if let Some(export_path) = matches.value_of("export_path") {
+        if let Err(err) = mgmt::export_config(export_path, matches.is_present("with_secrets")) {
+            return CliError::new_std(err, 1);
+        }
+    } else if let Some(import_path) = matches.value_of("import_path") {
+        if !matches.is_present("force") {
+            if let Ok(local_config) = mgmt::get_local_config(false, false) {
+                confirm(&format!(
+                    "Do you want to replace the local configuration ({} workspace deployments)? [y/N] ",
+                    local_config.wdeployments.len()
+                ))?;
+            }
+        }
+        if let Err(err) = mgmt::import_config(import_path) {
+            return CliError::new_std(err, 1);
+        }
     } else if let Some(declared_wdeployment_id) = matches.value_of("declare_wdeployment_id") {
         let mut ac = api::HSAPIClient::new();
         match ac.declare_existing(declared_wdeployment_id) {
@@ -412,93 +690,82 @@ fn config_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
             if local_config.wdeployments[wd_index].cprovider == selected_cprovider {
                 return Ok(());
             }
+            assign_cprovider(&mut local_config.wdeployments[wd_index], selected_cprovider);
 
-            if selected_cprovider == CProvider::Proxy {
-                local_config.wdeployments[wd_index].image = None;
-            } else {
-                if local_config.wdeployments[wd_index].cprovider == CProvider::Proxy {
-                    local_config.wdeployments[wd_index].cargs = vec![];
-                }
-                let default_img = "rerobots/hs-generic";
-                if local_config.wdeployments[wd_index].image.is_none() {
-                    local_config.wdeployments[wd_index].image = Some(default_img.into());
-                }
+            return match mgmt::modify_local(&local_config) {
+                Err(err) => CliError::new_std(err, 1),
+                Ok(()) => Ok(()),
+            };
+        } else if let Some(src_prefix) = matches.value_of("clone_src") {
+            let src_index = match mgmt::find_id_prefix(&local_config, Some(src_prefix)) {
+                Ok(wi) => wi,
+                Err(err) => return CliError::new_std(err, 1),
+            };
+            if src_index == wd_index {
+                return CliError::new("source and destination of --clone are the same", 1);
+            }
+            let src = local_config.wdeployments[src_index].clone();
+            let dst = &mut local_config.wdeployments[wd_index];
+            if src.cprovider != dst.cprovider && !matches.is_present("force") {
+                let errmessage = format!(
+                    "cprovider of source is `{}`, but destination is `{}`; to clone anyway, use --force",
+                    src.cprovider, dst.cprovider
+                );
+                return CliError::new(errmessage.as_str(), 1);
             }
-            local_config.wdeployments[wd_index].cprovider = selected_cprovider;
+            dst.cprovider = src.cprovider;
+            dst.image = src.image;
+            dst.cargs = src.cargs;
+            dst.container_name = src.container_name;
+            dst.init_inside = src.init_inside;
+            dst.terminate = src.terminate;
+            dst.monitor = src.monitor;
+            dst.ssh_user = src.ssh_user;
+            dst.ready_check = src.ready_check;
+            dst.ready_timeout_secs = src.ready_timeout_secs;
+            dst.on_ready = src.on_ready;
+            dst.pre_launch = src.pre_launch;
+            dst.max_session_secs = src.max_session_secs;
+            dst.idle_timeout_secs = src.idle_timeout_secs;
+            dst.ssh_tunnel = src.ssh_tunnel;
+            dst.network = src.network;
 
             return match mgmt::modify_local(&local_config) {
                 Err(err) => CliError::new_std(err, 1),
                 Ok(()) => Ok(()),
             };
         } else if let Some(new_image) = matches.value_of("cprovider_img") {
-            match local_config.wdeployments[wd_index].cprovider {
-                CProvider::Podman => {
-                    let argv = vec!["podman", "image", "exists", new_image];
-                    let mut prog = Command::new(argv[0]);
-
-                    debug!("exec: {:?}", argv);
-                    let status = prog.args(&argv[1..]).status();
-                    let status = match status {
-                        Ok(s) => s,
-                        Err(err) => return CliError::new_stdio(err, 1),
-                    };
-                    debug!("exit status: {:?}", status);
+            check_image(&local_config.wdeployments[wd_index].cprovider, new_image)?;
 
-                    if !status.success() {
-                        return CliError::new("given image name is not recognized by cprovider", 1);
-                    }
-                }
-                CProvider::Docker | CProvider::DockerRootless => {
-                    let argv = vec!["docker", "image", "inspect", new_image];
-                    let mut prog = Command::new(argv[0]);
-
-                    debug!("exec: {:?}", argv);
-                    let status = prog
-                        .args(&argv[1..])
-                        .stdout(Stdio::null())
-                        .stderr(Stdio::null())
-                        .status();
-                    let status = match status {
-                        Ok(s) => s,
-                        Err(err) => return CliError::new_stdio(err, 1),
-                    };
-                    debug!("exit status: {:?}", status);
+            local_config.wdeployments[wd_index].image = Some(new_image.into());
 
-                    if !status.success() {
-                        return CliError::new("given image name is not recognized by cprovider", 1);
-                    }
-                }
-                CProvider::Lxd => {
-                    let argv = vec!["lxc", "image", "show", new_image];
-                    let mut prog = Command::new(argv[0]);
-
-                    debug!("exec: {:?}", argv);
-                    let status = prog
-                        .args(&argv[1..])
-                        .stdout(Stdio::null())
-                        .stderr(Stdio::null())
-                        .status();
-                    let status = match status {
-                        Ok(s) => s,
-                        Err(err) => return CliError::new_stdio(err, 1),
-                    };
-                    debug!("exit status: {:?}", status);
+            return match mgmt::modify_local(&local_config) {
+                Err(err) => CliError::new_std(err, 1),
+                Ok(()) => Ok(()),
+            };
+        } else if let Some(container_name) = matches.value_of("container_name") {
+            if let Err(err) = mgmt::validate_container_name(
+                container_name,
+                &local_config.wdeployments[wd_index].cprovider,
+            ) {
+                return CliError::new_std(err, 1);
+            }
+            local_config.wdeployments[wd_index].container_name = container_name.into();
 
-                    if !status.success() {
-                        return CliError::new("given image name is not recognized by cprovider", 1);
-                    }
-                }
-                _ => {
-                    let errmessage = format!(
-                        "cannot --assign-image for cprovider `{}`",
-                        local_config.wdeployments[wd_index].cprovider
-                    );
-                    return CliError::new(errmessage.as_str(), 1);
+            return match mgmt::modify_local(&local_config) {
+                Err(err) => CliError::new_std(err, 1),
+                Ok(()) => Ok(()),
+            };
+        } else if let Some(ssh_user) = matches.value_of("ssh_user") {
+            if ssh_user == "root" {
+                local_config.wdeployments[wd_index].ssh_user = None;
+            } else {
+                if let Err(err) = mgmt::validate_ssh_user(ssh_user) {
+                    return CliError::new_std(err, 1);
                 }
+                local_config.wdeployments[wd_index].ssh_user = Some(ssh_user.into());
             }
 
-            local_config.wdeployments[wd_index].image = Some(new_image.into());
-
             return match mgmt::modify_local(&local_config) {
                 Err(err) => CliError::new_std(err, 1),
                 Ok(()) => Ok(()),
@@ -506,17 +773,9 @@ fn config_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
         } else if let Some(new_command) = matches.value_of("cprovider_cmd") {
             match local_config.wdeployments[wd_index].cprovider {
                 CProvider::Proxy => {
-                    let parts: Vec<&str> = new_command.split(' ').collect();
-                    match parts[0] {
-                        "rrhttp" => {
-                            if parts.len() != 2 {
-                                return CliError::new("Usage: rrhttp TARGET", 1);
-                            }
-                        }
-                        _ => return CliError::new("unknown proxy command", 1),
-                    };
+                    check_proxy_command(new_command)?;
                     local_config.wdeployments[wd_index].cargs =
-                        parts.iter().map(|x| x.to_string()).collect();
+                        new_command.split(' ').map(|x| x.to_string()).collect();
                 }
                 _ => {
                     let errmessage = format!(
@@ -542,6 +801,7 @@ fn config_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
             let device_path = device_path.to_str().unwrap();
             if local_config.wdeployments[wd_index].cprovider == CProvider::Docker
                 || local_config.wdeployments[wd_index].cprovider == CProvider::Podman
+                || local_config.wdeployments[wd_index].cprovider == CProvider::PodmanRootful
             {
                 let new_carg = format!("--device={device_path}:{device_path}");
                 if local_config.wdeployments[wd_index]
@@ -562,6 +822,7 @@ fn config_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
         } else if let Some(device_path) = matches.value_of("remove_raw_device_path") {
             if local_config.wdeployments[wd_index].cprovider == CProvider::Docker
                 || local_config.wdeployments[wd_index].cprovider == CProvider::Podman
+                || local_config.wdeployments[wd_index].cprovider == CProvider::PodmanRootful
             {
                 let mut carg = format!("--device={device_path}:{device_path}");
                 if !local_config.wdeployments[wd_index].cargs.contains(&carg) {
@@ -585,6 +846,27 @@ fn config_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
                 return CliError::new("adding/removing devices not supported by this cprovider", 1);
             }
 
+            return match mgmt::modify_local(&local_config) {
+                Err(err) => CliError::new_std(err, 1),
+                Ok(()) => Ok(()),
+            };
+        } else if matches.is_present("list_cargs") {
+            for (i, carg) in local_config.wdeployments[wd_index].cargs.iter().enumerate() {
+                println!("{}\t{}", i, carg);
+            }
+            return Ok(());
+        } else if matches.is_present("clear_cargs") {
+            let wd = &mut local_config.wdeployments[wd_index];
+            if wd.cargs.is_empty() {
+                return Ok(());
+            }
+            confirm(&format!(
+                "Remove all {} cargs of {}? [y/N] ",
+                wd.cargs.len(),
+                wd.id
+            ))?;
+            wd.cargs.clear();
+
             return match mgmt::modify_local(&local_config) {
                 Err(err) => CliError::new_std(err, 1),
                 Ok(()) => Ok(()),
@@ -637,6 +919,82 @@ fn config_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
                 }
                 None => CliError::new("no matching program found", 1),
             };
+        } else if let Some(label) = matches.value_of("label") {
+            if label == "-" {
+                local_config.wdeployments[wd_index].label = None;
+            } else {
+                local_config.wdeployments[wd_index].label = Some(label.into());
+            }
+            return match mgmt::modify_local(&local_config) {
+                Err(err) => CliError::new_std(err, 1),
+                Ok(()) => Ok(()),
+            };
+        } else if let Some(idle_timeout) = matches.value_of("idle_timeout") {
+            if idle_timeout == "-" {
+                local_config.wdeployments[wd_index].idle_timeout_secs = None;
+            } else {
+                if !control::observes_activity(&local_config.wdeployments[wd_index]) {
+                    return CliError::new(
+                        "--idle-timeout requires cprovider proxy with rrhttp, which reports traffic",
+                        1,
+                    );
+                }
+                match idle_timeout.parse::<u64>() {
+                    Ok(secs) if secs > 0 => {
+                        local_config.wdeployments[wd_index].idle_timeout_secs = Some(secs);
+                    }
+                    _ => {
+                        return CliError::new(
+                            "--idle-timeout must be a positive integer (seconds) or `-`",
+                            EXIT_USAGE,
+                        )
+                    }
+                }
+            }
+            return match mgmt::modify_local(&local_config) {
+                Err(err) => CliError::new_std(err, 1),
+                Ok(()) => Ok(()),
+            };
+        } else if let Some(max_session) = matches.value_of("max_session") {
+            if max_session == "-" {
+                local_config.wdeployments[wd_index].max_session_secs = None;
+            } else {
+                match max_session.parse::<u64>() {
+                    Ok(secs) if secs > 0 => {
+                        local_config.wdeployments[wd_index].max_session_secs = Some(secs);
+                    }
+                    _ => {
+                        return CliError::new(
+                            "--max-session must be a positive integer (seconds) or `-`",
+                            EXIT_USAGE,
+                        )
+                    }
+                }
+            }
+            return match mgmt::modify_local(&local_config) {
+                Err(err) => CliError::new_std(err, 1),
+                Ok(()) => Ok(()),
+            };
+        } else if let Some(ready_timeout) = matches.value_of("ready_timeout") {
+            if ready_timeout == "-" {
+                local_config.wdeployments[wd_index].ready_timeout_secs = None;
+            } else {
+                match ready_timeout.parse::<u64>() {
+                    Ok(secs) if secs > 0 => {
+                        local_config.wdeployments[wd_index].ready_timeout_secs = Some(secs);
+                    }
+                    _ => {
+                        return CliError::new(
+                            "--ready-timeout must be a positive integer (seconds) or `-`",
+                            EXIT_USAGE,
+                        )
+                    }
+                }
+            }
+            return match mgmt::modify_local(&local_config) {
+                Err(err) => CliError::new_std(err, 1),
+                Ok(()) => Ok(()),
+            };
         } else if let Some(program) = matches.value_of("monitor_prog") {
             if program == "-" {
                 local_config.wdeployments[wd_index].monitor = None;
@@ -647,6 +1005,57 @@ fn config_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
                 Err(err) => CliError::new_std(err, 1),
                 Ok(()) => Ok(()),
             };
+        } else if let Some(program) = matches.value_of("ready_check") {
+            if program == "-" {
+                local_config.wdeployments[wd_index].ready_check = None;
+            } else {
+                local_config.wdeployments[wd_index].ready_check = Some(program.into());
+            }
+            return match mgmt::modify_local(&local_config) {
+                Err(err) => CliError::new_std(err, 1),
+                Ok(()) => Ok(()),
+            };
+        } else if let Some(program) = matches.value_of("on_ready") {
+            if program == "-" {
+                local_config.wdeployments[wd_index].on_ready = None;
+            } else {
+                local_config.wdeployments[wd_index].on_ready = Some(program.into());
+            }
+            return match mgmt::modify_local(&local_config) {
+                Err(err) => CliError::new_std(err, 1),
+                Ok(()) => Ok(()),
+            };
+        } else if let Some(network) = matches.value_of("network") {
+            if network == "-" {
+                local_config.wdeployments[wd_index].network = None;
+            } else {
+                local_config.wdeployments[wd_index].network = Some(network.into());
+            }
+            return match mgmt::modify_local(&local_config) {
+                Err(err) => CliError::new_std(err, 1),
+                Ok(()) => Ok(()),
+            };
+        } else if let Some(assignment) = matches.value_of("ssh_tunnel_option") {
+            if let Err(err) = local_config.wdeployments[wd_index]
+                .ssh_tunnel
+                .set(assignment)
+            {
+                return CliError::new(err, EXIT_USAGE);
+            }
+            return match mgmt::modify_local(&local_config) {
+                Err(err) => CliError::new_std(err, 1),
+                Ok(()) => Ok(()),
+            };
+        } else if let Some(program) = matches.value_of("pre_launch") {
+            if program == "-" {
+                local_config.wdeployments[wd_index].pre_launch = None;
+            } else {
+                local_config.wdeployments[wd_index].pre_launch = Some(program.into());
+            }
+            return match mgmt::modify_local(&local_config) {
+                Err(err) => CliError::new_std(err, 1),
+                Ok(()) => Ok(()),
+            };
         } else if let Some(raw_addr) = matches.value_of("hook_emails") {
             let addr = if raw_addr == "-" {
                 vec![]
@@ -658,9 +1067,20 @@ fn config_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
                 Ok(()) => Ok(()),
                 Err(err) => CliError::new_std(err, 1),
             };
+        } else if let Some(raw_urls) = matches.value_of("hook_webhooks") {
+            let urls = if raw_urls == "-" {
+                vec![]
+            } else {
+                raw_urls.split(',').collect()
+            };
+            let ac = api::HSAPIClient::new();
+            return match ac.register_hook_webhooks(&local_config.wdeployments[wd_index].id, urls) {
+                Ok(()) => Ok(()),
+                Err(err) => CliError::new_std(err, 1),
+            };
         } else {
             let errmessage = "Use `hardshare config` with a switch. To get a help message, enter\n\n    hardshare help config";
-            return CliError::new(errmessage, 1);
+            return CliError::new(errmessage, EXIT_USAGE);
         }
     }
 
@@ -682,9 +1102,11 @@ fn config_addon_subcommand(
     };
 
     let addon = match matches.value_of("addon") {
-        Some("mistyproxy") => api::AddOn::MistyProxy,
-        Some(_) => return CliError::new("unknown add-on", 1),
-        _ => return CliError::new("add-on must be specified with `-a`", 1),
+        Some(name) => match name.parse::<api::AddOn>() {
+            Ok(a) => a,
+            Err(err) => return CliError::new(err, 1),
+        },
+        None => return CliError::new("add-on must be specified with `-a`", 1),
     };
 
     let ac = api::HSAPIClient::new();
@@ -704,6 +1126,14 @@ fn config_addon_subcommand(
         } else {
             println!("{}", serde_yaml::to_string(&addon_config).unwrap())
         }
+    } else if let Some(raw_config) = matches.value_of("set_config") {
+        let addon_config: serde_json::Value = match serde_json::from_str(raw_config) {
+            Ok(c) => c,
+            Err(err) => return CliError::new(format!("invalid JSON given to --set: {}", err), 1),
+        };
+        if let Err(err) = ac.set_addon(wdid, &addon, addon_config) {
+            return CliError::new_std(err, 1);
+        }
     } else if addon == api::AddOn::MistyProxy {
         if matches.is_present("ipv4") {
             if let Err(err) = ac.add_mistyproxy(wdid, matches.value_of("ipv4").unwrap()) {
@@ -712,6 +1142,36 @@ fn config_addon_subcommand(
         } else {
             return CliError::new("No command. Try `hardshare help config-addon`", 1);
         }
+    } else if addon == api::AddOn::Webrtc {
+        if !matches.is_present("stun") && !matches.is_present("turn") {
+            return CliError::new("No command. Try `hardshare help config-addon`", 1);
+        }
+        let mut ice_servers = vec![];
+        for url in matches.values_of("stun").into_iter().flatten() {
+            let server = match api::IceServer::new(url) {
+                Ok(s) => s,
+                Err(err) => return CliError::new(err, 1),
+            };
+            if server.is_turn() {
+                return CliError::new("give TURN servers with --turn", 1);
+            }
+            ice_servers.push(server);
+        }
+        if let Some(url) = matches.value_of("turn") {
+            let mut server = match api::IceServer::new(url) {
+                Ok(s) => s,
+                Err(err) => return CliError::new(err, 1),
+            };
+            if !server.is_turn() {
+                return CliError::new("give STUN servers with --stun", 1);
+            }
+            server.username = matches.value_of("turn_username").map(String::from);
+            server.credential = matches.value_of("turn_credential").map(String::from);
+            ice_servers.push(server);
+        }
+        if let Err(err) = ac.add_webrtc(wdid, &api::WebrtcConfig { ice_servers }) {
+            return CliError::new_std(err, 1);
+        }
     }
 
     Ok(())
@@ -728,6 +1188,22 @@ fn rules_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
         Err(err) => return CliError::new_std(err, 1),
     };
 
+    let schedule_param = match matches.value_of("schedule") {
+        Some(raw) => {
+            if !matches.is_present("permit_me") && !matches.is_present("permit_all") {
+                return CliError::new(
+                    "--schedule requires --permit-me or --permit-all",
+                    EXIT_USAGE,
+                );
+            }
+            match raw.parse::<api::Schedule>() {
+                Ok(s) => Some(s.to_param()),
+                Err(err) => return CliError::new(err, 1),
+            }
+        }
+        None => None,
+    };
+
     if matches.is_present("list_rules") {
         let ac = api::HSAPIClient::new();
         let mut ruleset = match ac.get_access_rules(&local_config.wdeployments[wd_index].id) {
@@ -750,7 +1226,7 @@ fn rules_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
         let ac = api::HSAPIClient::new();
         let wdid = &local_config.wdeployments[wd_index].id;
         let username = &local_config.wdeployments[wd_index].owner;
-        match ac.add_access_rule(wdid, username) {
+        match ac.add_access_rule(wdid, username, schedule_param) {
             Ok(_) => (),
             Err(err) => return CliError::new_std(err, 1),
         }
@@ -758,12 +1234,12 @@ fn rules_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
         confirm("Do you want to permit access by anyone? [y/N] ")?;
 
         let ac = api::HSAPIClient::new();
-        match ac.add_access_rule(&local_config.wdeployments[wd_index].id, "*") {
+        match ac.add_access_rule(&local_config.wdeployments[wd_index].id, "*", schedule_param) {
             Ok(_) => (),
             Err(err) => return CliError::new_std(err, 1),
         }
     } else {
-        return CliError::new("Use `hardshare rules` with a switch. For example, `hardshare rules -l`\nor to get a help message, enter\n\n    hardshare help rules", 1);
+        return CliError::new("Use `hardshare rules` with a switch. For example, `hardshare rules -l`\nor to get a help message, enter\n\n    hardshare help rules", EXIT_USAGE);
     }
 
     Ok(())
@@ -775,25 +1251,203 @@ fn ad_subcommand(matches: &clap::ArgMatches, bindaddr: &str) -> Result<(), CliEr
         Err(err) => return CliError::new_std(err, 1),
     };
 
-    let wd_index = match mgmt::find_id_prefix(&local_config, matches.value_of("id_prefix")) {
-        Ok(wi) => wi,
-        Err(err) => return CliError::new_std(err, 1),
-    };
+    let mut wdids = if matches.is_present("all") {
+        if local_config.wdeployments.is_empty() {
+            return CliError::new("no workspace deployment in local configuration.", 1);
+        }
+        local_config
+            .wdeployments
+            .iter()
+            .map(|wd| wd.id.clone())
+            .collect()
+    } else {
+        let id_prefixes = match matches.values_of("id_prefix") {
+            Some(v) => v.collect(),
+            None => vec![],
+        };
+        match mgmt::expand_id_prefixes(&local_config, &id_prefixes) {
+            Ok(w) => w,
+            Err(err) => return CliError::new_std(err, 1),
+        }
+    };
+    wdids.sort();
+    wdids.dedup();
+    if wdids.len() > 1 && matches.is_present("once") {
+        return CliError::new(
+            "--once can only be used with 1 workspace deployment",
+            EXIT_USAGE,
+        );
+    }
 
-    if local_config.wdeployments[wd_index].cprovider == CProvider::Proxy {
-        if let Err(err) = check::check_proxy(&local_config.wdeployments[wd_index]) {
-            return CliError::new(err, 1);
+    for wd in local_config.wdeployments.iter() {
+        if wd.cprovider == CProvider::Proxy && wdids.contains(&wd.id) {
+            if let Err(err) = check::check_proxy(wd) {
+                return CliError::new(err, 1);
+            }
         }
     }
 
+    if matches.is_present("detach") {
+        return detach_ad(matches.value_of("log_file"));
+    }
+
     let ac = api::HSAPIClient::new();
-    match ac.run(&local_config.wdeployments[wd_index].id, bindaddr) {
+    let res = ac.run(
+        &wdids,
+        bindaddr,
+        matches.is_present("metrics"),
+        matches.is_present("once"),
+    );
+    remove_pidfile();
+    match res {
         Ok(()) => Ok(()),
         Err(err) => CliError::new_std(err, 1),
     }
 }
 
-fn stop_ad_subcommand(matches: &clap::ArgMatches, bindaddr: &str) -> Result<(), CliError> {
+fn get_pidfile_path() -> Result<std::path::PathBuf, CliError> {
+    match mgmt::get_base_path() {
+        Some(base_path) => Ok(base_path.join("hardshare.pid")),
+        None => CliError::new(
+            "cannot determine home directory; set HOME or HARDSHARE_CONFIG_DIR",
+            1,
+        )
+        .map(|_| std::path::PathBuf::new()),
+    }
+}
+
+// Remove the pidfile if it was written for this process
+fn remove_pidfile() {
+    let pidfile_path = match get_pidfile_path() {
+        Ok(p) => p,
+        Err(_) => return,
+    };
+    if let Ok(pid) = std::fs::read_to_string(&pidfile_path) {
+        if pid.trim() == std::process::id().to_string() {
+            if let Err(err) = std::fs::remove_file(&pidfile_path) {
+                warn!("failed to remove pidfile: {}", err);
+            }
+        }
+    }
+}
+
+// Start `hardshare ad` again without --detach as a background process
+fn detach_ad(log_file: Option<&str>) -> Result<(), CliError> {
+    let self_path = match std::env::current_exe() {
+        Ok(p) => p,
+        Err(err) => return CliError::new_stdio(err, 1),
+    };
+    let args: Vec<std::ffi::OsString> = std::env::args_os()
+        .skip(1)
+        .filter(|arg| arg != "--detach")
+        .collect();
+
+    let (stdout, stderr) = match log_file {
+        Some(log_file) => {
+            let f = match std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(log_file)
+            {
+                Ok(f) => f,
+                Err(err) => return CliError::new_stdio(err, 1),
+            };
+            let g = match f.try_clone() {
+                Ok(g) => g,
+                Err(err) => return CliError::new_stdio(err, 1),
+            };
+            (Stdio::from(f), Stdio::from(g))
+        }
+        None => (Stdio::null(), Stdio::null()),
+    };
+
+    let mut cmd = Command::new(self_path);
+    cmd.args(args)
+        .stdin(Stdio::null())
+        .stdout(stdout)
+        .stderr(stderr);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        // Start a new session without a controlling terminal, so that the
+        // process is not signaled when the terminal or its shell exits.
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::setsid() == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        const DETACHED_PROCESS: u32 = 0x00000008;
+        const CREATE_NEW_PROCESS_GROUP: u32 = 0x00000200;
+        cmd.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
+    }
+
+    let pidfile_path = get_pidfile_path()?;
+    let child = match cmd.spawn() {
+        Ok(c) => c,
+        Err(err) => return CliError::new_stdio(err, 1),
+    };
+    if let Err(err) = std::fs::write(pidfile_path, format!("{}\n", child.id())) {
+        return CliError::new_stdio(err, 1);
+    }
+    println!("{}", child.id());
+
+    Ok(())
+}
+
+// Addresses of running daemons: the one at the given port or, if the port is 0
+// or not given, those that recorded their port when started with port 0 and
+// still answer. If the port is not given, the default port is included.
+fn daemon_addrs(daemonport: u16, port_given: bool) -> Result<Vec<String>, CliError> {
+    if port_given && daemonport != 0 {
+        return Ok(vec![format!("127.0.0.1:{}", daemonport)]);
+    }
+    let mut addrs = vec![];
+    if !port_given {
+        addrs.push(format!("127.0.0.1:{}", daemonport));
+    }
+    let ac = api::HSAPIClient::new();
+    for port in api::list_daemon_ports() {
+        let addr = format!("127.0.0.1:{}", port);
+        // Recorded ports are stale if a daemon did not exit cleanly
+        if port != daemonport && ac.get_local_status(&addr).is_ok() {
+            addrs.push(addr);
+        }
+    }
+    if addrs.is_empty() {
+        return CliError::new(
+            "no daemon that was started with port 0 is running\nIs the local hardshare client active?",
+            1,
+        )
+        .map(|_| vec![]);
+    }
+    Ok(addrs)
+}
+
+// Address of the daemon that advertises the given deployment, else the first
+fn find_daemon_advertising<'a>(
+    ac: &api::HSAPIClient,
+    daemonaddrs: &'a [String],
+    wdid: &str,
+) -> &'a str {
+    for addr in daemonaddrs.iter() {
+        if let Ok(r) = ac.get_local_status(addr) {
+            if r.ad_deployments().iter().any(|x| x == wdid) {
+                return addr;
+            }
+        }
+    }
+    &daemonaddrs[0]
+}
+
+fn stop_ad_subcommand(matches: &clap::ArgMatches, daemonaddrs: &[String]) -> Result<(), CliError> {
     let local_config = match mgmt::get_local_config(false, false) {
         Ok(lc) => lc,
         Err(err) => return CliError::new_std(err, 1),
@@ -804,16 +1458,20 @@ fn stop_ad_subcommand(matches: &clap::ArgMatches, bindaddr: &str) -> Result<(),
         Err(err) => return CliError::new_std(err, 1),
     };
 
+    let wdid = &local_config.wdeployments[wd_index].id;
     let ac = api::HSAPIClient::new();
-    match ac.stop(&local_config.wdeployments[wd_index].id, bindaddr) {
+    match ac.stop(wdid, find_daemon_advertising(&ac, daemonaddrs, wdid)) {
         Ok(()) => Ok(()),
         Err(err) => CliError::new_std(err, 1),
     }
 }
 
 fn register_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
-    let mut ac = api::HSAPIClient::new();
     let at_most_1 = !matches.is_present("permit_more");
+    if let Some(path) = matches.value_of("from_file") {
+        return register_from_file(path, at_most_1);
+    }
+    let mut ac = api::HSAPIClient::new();
     match ac.register_new(at_most_1) {
         Ok(new_wdid) => {
             println!("{}", new_wdid);
@@ -823,6 +1481,134 @@ fn register_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
     }
 }
 
+fn apply_batch_settings(
+    wd: &mut mgmt::WDeployment,
+    bd: &mgmt::BatchDeployment,
+) -> Result<(), CliError> {
+    if let Some(cprovider) = &bd.cprovider {
+        if wd.cprovider != *cprovider {
+            assign_cprovider(wd, cprovider.clone());
+        }
+    }
+    if let Some(image) = &bd.image {
+        check_image(&wd.cprovider, image)?;
+        wd.image = Some(image.clone());
+    }
+    if let Some(container_name) = &bd.container_name {
+        if let Err(err) = mgmt::validate_container_name(container_name, &wd.cprovider) {
+            return CliError::new_std(err, 1);
+        }
+        wd.container_name = container_name.clone();
+    }
+    if let Some(ssh_user) = &bd.ssh_user {
+        if ssh_user == "root" {
+            wd.ssh_user = None;
+        } else {
+            wd.ssh_user = Some(ssh_user.clone());
+        }
+    }
+    if let Some(command) = &bd.proxy_command {
+        if wd.cprovider != CProvider::Proxy {
+            let errmessage = format!("cannot use proxy_command for cprovider `{}`", wd.cprovider);
+            return CliError::new(errmessage.as_str(), 1);
+        }
+        check_proxy_command(command)?;
+        wd.cargs = command.split(' ').map(|x| x.to_string()).collect();
+    }
+    wd.init_inside.extend(bd.init_inside.iter().cloned());
+    wd.terminate.extend(bd.terminate.iter().cloned());
+    if bd.monitor.is_some() {
+        wd.monitor = bd.monitor.clone();
+    }
+    if bd.ready_check.is_some() {
+        wd.ready_check = bd.ready_check.clone();
+    }
+    Ok(())
+}
+
+fn register_from_file(path: &str, at_most_1: bool) -> Result<(), CliError> {
+    let batch = match mgmt::read_batch_file(path) {
+        Ok(b) => b,
+        Err(err) => return CliError::new_std(err, 1),
+    };
+    let original_config = match mgmt::get_local_config(false, false) {
+        Ok(lc) => lc,
+        Err(err) => return CliError::new_std(err, 1),
+    };
+
+    // Check all settings before registering anything
+    let mut templates = vec![];
+    for (j, bd) in batch.iter().enumerate() {
+        let mut h = std::collections::HashMap::new();
+        h.insert("id".into(), json!(""));
+        h.insert("owner".into(), json!(""));
+        let mut wd = mgmt::WDeployment::from_json(&h);
+        if let Err(err) = apply_batch_settings(&mut wd, bd) {
+            return CliError::new(format!("deployment {} in {}: {}", j, path, err), 1);
+        }
+        templates.push(wd);
+    }
+
+    let rollback = |new_wdids: &[String], err: String| -> Result<(), CliError> {
+        let mut errmessage = err;
+        if let Err(err) = mgmt::modify_local(&original_config) {
+            errmessage = format!(
+                "{}\nfailed to restore local configuration: {}",
+                errmessage, err
+            );
+        }
+        if !new_wdids.is_empty() {
+            errmessage = format!(
+                "{}\nlocal configuration was restored, but the following were already registered (to keep any, `hardshare config --declare ID`):\n{}",
+                errmessage,
+                new_wdids.join("\n")
+            );
+        }
+        CliError::new(errmessage, 1)
+    };
+
+    let mut ac = api::HSAPIClient::new();
+    let mut new_wdids = vec![];
+    for j in 0..templates.len() {
+        match ac.register_new(at_most_1 && j == 0) {
+            Ok(new_wdid) => new_wdids.push(new_wdid),
+            Err(err) => return rollback(&new_wdids, format!("{}", err)),
+        }
+    }
+
+    let mut local_config = match mgmt::get_local_config(false, false) {
+        Ok(lc) => lc,
+        Err(err) => return rollback(&new_wdids, format!("{}", err)),
+    };
+    for (new_wdid, template) in new_wdids.iter().zip(templates) {
+        let wd = match local_config
+            .wdeployments
+            .iter_mut()
+            .find(|wd| &wd.id == new_wdid)
+        {
+            Some(wd) => wd,
+            None => {
+                return rollback(
+                    &new_wdids,
+                    format!("{} not found in local configuration", new_wdid),
+                )
+            }
+        };
+        let owner = wd.owner.clone();
+        *wd = template;
+        wd.id = new_wdid.clone();
+        wd.owner = owner;
+    }
+    if let Err(err) = mgmt::modify_local(&local_config) {
+        return rollback(&new_wdids, format!("{}", err));
+    }
+
+    for new_wdid in new_wdids.iter() {
+        println!("{}", new_wdid);
+    }
+    Ok(())
+}
+
 fn declare_default_org_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
     let mut local_config = match mgmt::get_local_config(false, false) {
         Ok(lc) => lc,
@@ -870,28 +1656,366 @@ fn lock_wdeplyoment_subcommand(
     }
 }
 
-fn status_subcommand(bindaddr: &str, pformat: PrintingFormat) -> Result<(), CliError> {
+fn status_subcommand(
+    matches: &clap::ArgMatches,
+    daemonaddrs: &[String],
+    pformat: PrintingFormat,
+) -> Result<(), CliError> {
     let ac = api::HSAPIClient::new();
-    match ac.get_local_status(bindaddr) {
-        Ok(r) => {
-            if pformat == PrintingFormat::Json {
-                println!("{}", serde_json::to_string(&r).unwrap());
-            } else if pformat == PrintingFormat::Yaml {
-                println!("{}", serde_yaml::to_string(&r).unwrap());
-            } else {
-                println!("{}", r);
+    if !matches.is_present("watch") {
+        return match print_daemon_status(&ac, daemonaddrs, &pformat) {
+            Ok(()) => Ok(()),
+            Err(err) => CliError::new(format!("{}\nIs the local hardshare client active?", err), 1),
+        };
+    }
+
+    let interval = match matches.value_of("watch").unwrap_or("2").parse::<u64>() {
+        Ok(i) if i > 0 => i,
+        _ => return CliError::new("INTERVAL must be a positive integer (seconds)", EXIT_USAGE),
+    };
+    loop {
+        if pformat == PrintingFormat::Default {
+            // Clear the terminal and move cursor to top-left, to redraw
+            print!("\x1b[2J\x1b[H");
+            println!("{}", Utc::now());
+        }
+        if let Err(err) = print_daemon_status(&ac, daemonaddrs, &pformat) {
+            eprintln!("{}\nIs the local hardshare client active?", err);
+        }
+        std::io::stdout().flush().expect("failed to flush stdout");
+        std::thread::sleep(std::time::Duration::from_secs(interval));
+    }
+}
+
+// Print the status of each daemon that answers; if none does, return the
+// error from the first
+fn print_daemon_status(
+    ac: &api::HSAPIClient,
+    daemonaddrs: &[String],
+    pformat: &PrintingFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut first_err = None;
+    let mut answered = false;
+    for addr in daemonaddrs.iter() {
+        match ac.get_local_status(addr) {
+            Ok(r) => {
+                if daemonaddrs.len() > 1 && *pformat == PrintingFormat::Default {
+                    println!("daemon at {}:", addr);
+                }
+                print_status(&r, pformat);
+                answered = true;
+            }
+            Err(err) => {
+                if first_err.is_none() {
+                    first_err = Some(err);
+                }
+            }
+        }
+    }
+    match first_err {
+        Some(err) if !answered => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+fn print_status(r: &api::DaemonStatus, pformat: &PrintingFormat) {
+    if *pformat == PrintingFormat::Json {
+        println!("{}", serde_json::to_string(r).unwrap());
+    } else if *pformat == PrintingFormat::Yaml {
+        println!("{}", serde_yaml::to_string(r).unwrap());
+    } else {
+        println!("{}", r);
+    }
+}
+
+fn reload_subcommand(daemonaddrs: &[String]) -> Result<(), CliError> {
+    let ac = api::HSAPIClient::new();
+    let mut first_err = None;
+    let mut reloaded = false;
+    for addr in daemonaddrs.iter() {
+        match ac.req_reload_config(addr) {
+            Ok(()) => reloaded = true,
+            Err(err) => {
+                if first_err.is_none() {
+                    first_err = Some(err);
+                }
+            }
+        }
+    }
+    match first_err {
+        Some(err) if !reloaded => CliError::new_std(err, 1),
+        _ => Ok(()),
+    }
+}
+
+fn prune_containers_subcommand(
+    matches: &clap::ArgMatches,
+    daemonaddrs: &[String],
+) -> Result<(), CliError> {
+    let local_config = match mgmt::get_local_config(false, false) {
+        Ok(lc) => lc,
+        Err(err) => return CliError::new_std(err, 1),
+    };
+
+    // Containers of deployments that are being advertised may belong to a
+    // current instance, so they are not touched.
+    let ac = api::HSAPIClient::new();
+    let mut advertised = vec![];
+    for addr in daemonaddrs.iter() {
+        if let Ok(r) = ac.get_local_status(addr) {
+            advertised.extend_from_slice(r.ad_deployments());
+        }
+    }
+
+    // Other deployments may use the same container name prefix, so that
+    // containers of advertised deployments are also found through them.
+    let advertised_names: Vec<(Option<String>, &str)> = local_config
+        .wdeployments
+        .iter()
+        .filter(|wd| advertised.contains(&wd.id))
+        .map(|wd| (wd.cprovider.get_execname(), wd.container_name.as_str()))
+        .collect();
+
+    let mut seen = std::collections::HashSet::new();
+    let mut found = vec![];
+    for wd in local_config.wdeployments.iter() {
+        if wd.cprovider == CProvider::Proxy {
+            continue;
+        }
+        if advertised.contains(&wd.id) {
+            note(&format!(
+                "skipping {}, which is advertised by the local hardshare client (try `hardshare stop-ad`)",
+                wd.id
+            ));
+            continue;
+        }
+        let names = match control::CurrentInstance::list_local_containers(wd) {
+            Ok(n) => n,
+            Err(err) => {
+                note(&format!("skipping {}: {}", wd.id, err));
+                continue;
+            }
+        };
+        let execname = wd.cprovider.get_execname();
+        for name in names {
+            if advertised_names
+                .iter()
+                .any(|(e, base_name)| *e == execname && control::is_local_name(base_name, &name))
+            {
+                continue;
+            }
+            if seen.insert((execname.clone(), name.clone())) {
+                found.push((wd, name));
             }
-            Ok(())
         }
-        Err(err) => CliError::new(format!("{}\nIs the local hardshare client active?", err), 1),
     }
+
+    if found.is_empty() {
+        println!("no orphaned containers found");
+        return Ok(());
+    }
+    for (wd, name) in found.iter() {
+        println!("{}\t{}\t{}", wd.cprovider, name, wd.id);
+    }
+    if matches.is_present("dry_run") {
+        return Ok(());
+    }
+    if !matches.is_present("force") {
+        confirm(&format!("Remove {} containers? [y/N] ", found.len()))?;
+    }
+
+    let mut failures = 0;
+    for (wd, name) in found.iter() {
+        if let Err(err) = control::CurrentInstance::destroy_container(wd, name, None) {
+            eprintln!("failed to remove {}: {}", name, err);
+            failures += 1;
+        }
+    }
+    if failures > 0 {
+        return CliError::new(
+            format!("{} of {} containers not removed", failures, found.len()),
+            1,
+        );
+    }
+    Ok(())
 }
 
-fn reload_subcommand(bindaddr: &str) -> Result<(), CliError> {
+// Launch a real instance through the server, as a user would, and wait for
+// the local hardshare client to make it READY
+fn test_launch_subcommand(
+    matches: &clap::ArgMatches,
+    daemonaddrs: &[String],
+) -> Result<(), CliError> {
+    let local_config = match mgmt::get_local_config(false, false) {
+        Ok(lc) => lc,
+        Err(err) => return CliError::new_std(err, 1),
+    };
+
+    let wd_index = match mgmt::find_id_prefix(&local_config, matches.value_of("id_prefix")) {
+        Ok(wi) => wi,
+        Err(err) => return CliError::new_std(err, 1),
+    };
+    let wd = &local_config.wdeployments[wd_index];
+
+    let timeout = match matches.value_of("timeout").unwrap().parse::<u64>() {
+        Ok(t) if t > 0 => std::time::Duration::from_secs(t),
+        _ => return CliError::new("SECONDS must be a positive integer", EXIT_USAGE),
+    };
+
     let ac = api::HSAPIClient::new();
-    match ac.req_reload_config(bindaddr) {
+    match ac.get_local_status(find_daemon_advertising(&ac, daemonaddrs, &wd.id)) {
+        Ok(r) => {
+            if !r.ad_deployments().contains(&wd.id) {
+                return CliError::new(
+                    format!(
+                        "{} is not advertised by the local hardshare client\nStart it with `hardshare ad {}`",
+                        wd.id, wd.id
+                    ),
+                    1,
+                );
+            }
+        }
+        Err(err) => {
+            return CliError::new(format!("{}\nIs the local hardshare client active?", err), 1)
+        }
+    }
+
+    let (instance_id, sshkey) = match ac.new_instance(&wd.id) {
+        Ok(x) => x,
+        Err(err) => return CliError::new_std(err, 1),
+    };
+    note(&format!("requested instance {}", instance_id));
+    terminate_on_interrupt(&ac, &instance_id);
+
+    let res = wait_test_instance(&ac, &instance_id, timeout).and_then(|fwd| {
+        note("instance is READY");
+        match matches.value_of("smoke") {
+            Some(smoke) => run_smoke_command(&fwd, wd.get_ssh_user(), &sshkey, smoke),
+            None => Ok(()),
+        }
+    });
+
+    note(&format!("terminating instance {}", instance_id));
+    if let Err(err) = ac.terminate_instance(&instance_id) {
+        let msg = format!("failed to terminate instance {}: {}", instance_id, err);
+        return match res {
+            Ok(()) => CliError::new(msg, 1),
+            Err(res_err) => CliError::new(format!("{}\n{}", res_err, msg), 1),
+        };
+    }
+    match res {
         Ok(()) => Ok(()),
-        Err(err) => CliError::new_std(err, 1),
+        Err(err) => CliError::new(err, 1),
+    }
+}
+
+// If interrupted, e.g., by Ctrl-C, then terminate the instance before exiting,
+// so that it is not left running
+fn terminate_on_interrupt(ac: &api::HSAPIClient, instance_id: &str) {
+    let ac = ac.clone();
+    let instance_id = instance_id.to_string();
+    std::thread::spawn(move || {
+        let rt = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(rt) => rt,
+            Err(err) => {
+                warn!("failed to handle interrupts: {}", err);
+                return;
+            }
+        };
+        if rt.block_on(tokio::signal::ctrl_c()).is_err() {
+            return;
+        }
+        note(&format!(
+            "interrupted; terminating instance {}",
+            instance_id
+        ));
+        if let Err(err) = ac.terminate_instance(&instance_id) {
+            eprintln!("failed to terminate instance {}: {}", instance_id, err);
+        }
+        std::process::exit(EXIT_FAILURE);
+    });
+}
+
+// Poll the instance until it is READY, or until failure or timeout
+fn wait_test_instance(
+    ac: &api::HSAPIClient,
+    instance_id: &str,
+    timeout: std::time::Duration,
+) -> Result<api::InstanceFwd, String> {
+    let start = std::time::Instant::now();
+    let mut last_status = String::new();
+    loop {
+        let info = match ac.get_instance_info(instance_id) {
+            Ok(i) => i,
+            Err(err) => return Err(format!("failed to get instance status: {}", err)),
+        };
+        if info.status != last_status {
+            note(&format!("status: {}", info.status));
+            last_status = info.status.clone();
+        }
+        match info.status.as_str() {
+            "READY" => {
+                return match info.fwd {
+                    Some(fwd) => Ok(fwd),
+                    None => Err("instance is READY but has no ssh address".into()),
+                };
+            }
+            "INIT_FAIL" | "TERMINATING" | "TERMINATED" => {
+                return Err(format!("instance did not become READY: {}", info.status));
+            }
+            _ => (),
+        }
+        if start.elapsed() > timeout {
+            return Err(format!(
+                "instance did not become READY within {} s",
+                timeout.as_secs()
+            ));
+        }
+        std::thread::sleep(std::time::Duration::from_secs(2));
+    }
+}
+
+fn run_smoke_command(
+    fwd: &api::InstanceFwd,
+    ssh_user: &str,
+    sshkey: &str,
+    command: &str,
+) -> Result<(), String> {
+    // NamedTempFile is only readable by this user, as ssh requires of keys
+    let mut key_file = match tempfile::NamedTempFile::new() {
+        Ok(f) => f,
+        Err(err) => return Err(format!("failed to create key file: {}", err)),
+    };
+    if let Err(err) = key_file.write_all(sshkey.as_bytes()) {
+        return Err(format!("failed to write key file: {}", err));
+    }
+    note(&format!("running smoke command: {}", command));
+    let status = Command::new("ssh")
+        .args([
+            "-i",
+            &key_file.path().to_string_lossy(),
+            "-p",
+            &fwd.port.to_string(),
+            "-o",
+            "StrictHostKeyChecking=no",
+            "-o",
+            "UserKnownHostsFile=/dev/null",
+            "-o",
+            "BatchMode=yes",
+            &format!("{}@{}", ssh_user, fwd.ipv4),
+            command,
+        ])
+        .status();
+    match status {
+        Ok(s) if s.success() => Ok(()),
+        Ok(s) => Err(format!(
+            "smoke command failed with return code: {:?}",
+            s.code()
+        )),
+        Err(err) => Err(format!("error calling `ssh`: {}", err)),
     }
 }
 
@@ -922,6 +2046,20 @@ fn attach_camera_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError>
     #[cfg(not(any(target_os = "linux", target_os = "macos")))]
     return CliError::new("only Linux and Mac supported", 1);
 
+    if matches.is_present("list_devices") {
+        let devices = match camera::list_devices() {
+            Ok(d) => d,
+            Err(err) => return CliError::new_std(err, 1),
+        };
+        if devices.is_empty() {
+            note("no camera devices found");
+        }
+        for dev in devices.iter() {
+            println!("{}", dev);
+        }
+        return Ok(());
+    }
+
     if matches.values_of("id_prefix").is_some()
         && matches.values_of("attach_camera_crop_config").is_some()
     {
@@ -988,8 +2126,65 @@ fn attach_camera_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError>
         None => None,
     };
 
+    let recorder = match matches.value_of("record_dir") {
+        Some(dir) => {
+            let max_frames = match matches.value_of("record_max_frames") {
+                Some(n) => match n.parse::<u64>() {
+                    Ok(n) if n > 0 => n,
+                    _ => {
+                        return CliError::new(
+                            format!("--record-max-frames must be a positive integer: {}", n),
+                            EXIT_USAGE,
+                        )
+                    }
+                },
+                None => camera::DEFAULT_RECORD_MAX_FRAMES,
+            };
+            match camera::FrameRecorder::new(std::path::Path::new(dir), max_frames) {
+                Ok(r) => Some(r),
+                Err(err) => return CliError::new_std(err, 1),
+            }
+        }
+        None => None,
+    };
+
+    let on_unsupported_res = match matches.value_of("on_unsupported_res") {
+        Some(p) => match p.parse::<camera::ResolutionPolicy>() {
+            Ok(p) => p,
+            Err(err) => return CliError::new(err, EXIT_USAGE),
+        },
+        None => camera::ResolutionPolicy::default(),
+    };
+
+    let rotate = match matches.value_of("rotate") {
+        Some(r) => r.parse::<u16>().unwrap(),
+        None => 0,
+    };
+    let flip = match matches.value_of("flip") {
+        Some(f) => match f.parse::<camera::Flip>() {
+            Ok(f) => f,
+            Err(err) => return CliError::new(err, EXIT_USAGE),
+        },
+        None => camera::Flip::None,
+    };
+
+    let capture = camera::CaptureOptions {
+        dimensions: width_height,
+        on_unsupported_res,
+        recorder,
+        rotate,
+        flip,
+    };
+    let duration = match matches.value_of("duration") {
+        Some(d) => match d.parse::<u64>() {
+            Ok(d) if d > 0 => Some(std::time::Duration::from_secs(d)),
+            _ => return CliError::new("SECONDS must be a positive integer", EXIT_USAGE),
+        },
+        None => None,
+    };
+
     let ac = api::HSAPIClient::new();
-    match ac.attach_camera(camera_path, &wds, &width_height, &crop) {
+    match ac.attach_camera(camera_path, &wds, &crop, capture, duration) {
         Ok(()) => Ok(()),
         Err(err) => CliError::new_std(err, 1),
     }
@@ -1004,6 +2199,93 @@ fn stop_cameras_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
     }
 }
 
+fn camera_status_subcommand(pformat: PrintingFormat) -> Result<(), CliError> {
+    let ac = api::HSAPIClient::new();
+    let cameras = match ac.camera_status() {
+        Ok(c) => c,
+        Err(err) => return CliError::new_std(err, 1),
+    };
+    if pformat == PrintingFormat::Json {
+        println!("{}", serde_json::to_string(&cameras).unwrap());
+    } else if pformat == PrintingFormat::Yaml {
+        println!("{}", serde_yaml::to_string(&cameras).unwrap());
+    } else if cameras.is_empty() {
+        println!("no camera streams started on this host");
+    } else {
+        println!("CAMERA\tPID\tSTATUS\tFPS\tWDEPLOYMENTS");
+        for cam in cameras.iter() {
+            let status = if !cam.alive {
+                "dead"
+            } else if !cam.registered {
+                "unregistered"
+            } else {
+                "running"
+            };
+            let fps = match &cam.stats {
+                Some(stats) => format!("{:.1}", stats.fps),
+                None => "-".into(),
+            };
+            let wdeployments = if cam.wdeployments.is_empty() {
+                "-".into()
+            } else {
+                cam.wdeployments.join(",")
+            };
+            println!(
+                "{}\t{}\t{}\t{}\t{}",
+                cam.id, cam.pid, status, fps, wdeployments
+            );
+        }
+    }
+    Ok(())
+}
+
+fn version_subcommand(as_json: bool) {
+    if as_json {
+        println!(
+            "{}",
+            json!({
+                "version": crate_version!(),
+                "git_sha": env!("HARDSHARE_GIT_SHA"),
+                "rustc": env!("HARDSHARE_RUSTC_VERSION"),
+                "target": env!("HARDSHARE_TARGET"),
+            })
+        );
+    } else {
+        println!(crate_version!());
+    }
+}
+
+fn whoami_subcommand(pformat: PrintingFormat) -> Result<(), CliError> {
+    let ac = api::HSAPIClient::new();
+    let claims = match ac.get_token_claims() {
+        Ok(c) => c,
+        Err(err) => return CliError::new_std(err, 1),
+    };
+    if pformat == PrintingFormat::Default {
+        println!("subject: {}", claims.subject);
+        match &claims.organization {
+            Some(org) => println!("organization: {}", org),
+            None => println!("organization: (none)"),
+        }
+        match claims.expiration {
+            Some(exp) => println!("expiration: {}", Utc.timestamp_opt(exp as i64, 0).unwrap()),
+            None => println!("expiration: (none)"),
+        }
+    } else {
+        let r = json!({
+            "subject": claims.subject,
+            "organization": claims.organization,
+            "expiration": claims.expiration,
+        });
+        if pformat == PrintingFormat::Json {
+            println!("{}", serde_json::to_string(&r).unwrap());
+        } else {
+            println!("{}", serde_yaml::to_string(&r).unwrap());
+        }
+    }
+    Ok(())
+}
+
 fn init_subcommand() -> Result<(), CliError> {
     if mgmt::get_local_config(false, false).is_ok() {
         return CliError::new("Cannot init: local configuration already exists", 1);
@@ -1018,27 +2300,56 @@ fn init_subcommand() -> Result<(), CliError> {
 fn check_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
     let mut at_least_one_error = false;
 
-    let local_config = match mgmt::get_local_config(false, true) {
+    let camera_dims = match matches.value_of("camera_res") {
+        Some(wh) => match wh.parse::<CameraDimensions>() {
+            Ok(c) => Some(c),
+            Err(err) => return CliError::new(err, 1),
+        },
+        None => None,
+    };
+
+    let fix = matches.is_present("fix");
+
+    let mut local_config = match mgmt::get_local_config(false, true) {
         Ok(lc) => Some(lc),
         Err(_) => None,
     };
 
-    if let Some(config) = &local_config {
+    if local_config.is_none()
+        && fix
+        && ask_yes_no("local configuration not found. Create it? [y/N] ")?
+    {
+        local_config = match mgmt::get_local_config(true, true) {
+            Ok(lc) => Some(lc),
+            Err(err) => return CliError::new_std(err, 1),
+        };
+    }
+
+    if let Some(config) = &mut local_config {
         if let Some(err_tokens) = &config.err_api_tokens {
             if !err_tokens.is_empty() {
-                at_least_one_error = true;
                 println!("found possible API tokens with errors:");
             }
             for (err_token_path, err) in err_tokens {
                 println!("\t {}: {}", err, err_token_path);
             }
             if !err_tokens.is_empty() {
-                println!("remove these with the following command:\n\n    hardshare config -p\n");
+                if fix && ask_yes_no("Remove these? [y/N] ")? {
+                    for err_token_path in err_tokens.keys() {
+                        if let Err(err) = std::fs::remove_file(err_token_path) {
+                            return CliError::new_stdio(err, 1);
+                        }
+                    }
+                } else {
+                    at_least_one_error = true;
+                    note("remove these with the following command:\n\n    hardshare config -p\n");
+                }
             }
         }
         if config.api_tokens.is_empty() {
             at_least_one_error = true;
-            println!("no valid API tokens\nCreate and manage API tokens at\n\n    https://rerobots.net/tokens\n");
+            println!("no valid API tokens");
+            note("Create and manage API tokens at\n\n    https://rerobots.net/tokens\n");
         } else {
             let org_name = match &config.default_org {
                 Some(default_org) => default_org.as_str(),
@@ -1052,6 +2363,28 @@ fn check_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
                     format!(": {}", org_name)
                 };
                 println!("no valid API tokens for default org{}", suffix);
+            } else if let Some(remaining) = api::HSAPIClient::new().token_expiring_soon() {
+                println!("API token expires in {} minutes", remaining / 60);
+                note("Get a new token at\n\n    https://rerobots.net/tokens\n");
+            }
+        }
+
+        match mgmt::get_ssh_fingerprint(&config.ssh_key) {
+            Ok(fingerprint) => println!("SSH key fingerprint: {}", fingerprint),
+            Err(err) => {
+                println!("error reading SSH key {}: {}", config.ssh_key, err);
+                if fix && ask_yes_no("Generate a new SSH key for tunnels? [y/N] ")? {
+                    config.ssh_key = match mgmt::create_ssh_key(&mgmt::get_base_path().unwrap()) {
+                        Ok(p) => p,
+                        Err(err) => return CliError::new_std(err, 1),
+                    };
+                    if let Err(err) = mgmt::modify_local(config) {
+                        return CliError::new_std(err, 1);
+                    }
+                    println!("created SSH key {}", config.ssh_key);
+                } else {
+                    at_least_one_error = true;
+                }
             }
         }
 
@@ -1060,6 +2393,13 @@ fn check_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
         }
     }
 
+    let launch = check::LaunchOptions {
+        pull: matches.is_present("pull"),
+        repo: matches
+            .value_of("repo")
+            .map(|url| control::RepoInfo::new(url, matches.value_of("repo_cmd"))),
+    };
+
     if matches.value_of("id_prefix").is_some() {
         if local_config.is_none() {
             return CliError::new("given ID when local configuration is undefined", 1);
@@ -1074,9 +2414,11 @@ fn check_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
         match check::config(
             &local_config,
             matches.is_present("camera"),
+            &camera_dims,
             &local_config.wdeployments[wd_index].id,
             None,
             matches.is_present("fail_fast"),
+            &launch,
         ) {
             Ok(()) => {
                 if at_least_one_error {
@@ -1096,7 +2438,9 @@ fn check_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
         match check::all_configurations(
             &local_config,
             matches.is_present("camera"),
+            &camera_dims,
             matches.is_present("fail_fast"),
+            &launch,
         ) {
             Ok(()) => {
                 if at_least_one_error {
@@ -1110,7 +2454,9 @@ fn check_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
     } else {
         match check::defaults(
             matches.is_present("camera"),
+            &camera_dims,
             matches.is_present("fail_fast"),
+            &launch,
         ) {
             Ok(()) => {
                 if at_least_one_error {
@@ -1124,7 +2470,7 @@ fn check_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
     }
 }
 
-fn monitor_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
+fn monitor_subcommand(matches: &clap::ArgMatches, pformat: PrintingFormat) -> Result<(), CliError> {
     let local_config = match mgmt::get_local_config(false, false) {
         Ok(lc) => lc,
         Err(err) => return CliError::new_std(err, 1),
@@ -1135,6 +2481,15 @@ fn monitor_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
         Err(err) => return CliError::new_std(err, 1),
     };
 
+    let auto_lock = matches.is_present("auto_lock");
+    let report = |health: &monitor::Health| {
+        if pformat == PrintingFormat::Json {
+            println!("{}", serde_json::to_string(health).unwrap());
+        } else if pformat == PrintingFormat::Yaml {
+            println!("{}", serde_yaml::to_string(health).unwrap());
+        }
+    };
+
     if matches.is_present("loop") {
         let duration = match matches.value_of("loop").unwrap().parse::<u64>() {
             Ok(d) => d,
@@ -1144,25 +2499,38 @@ fn monitor_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
             &local_config,
             wd_index,
             std::time::Duration::from_secs(duration),
+            auto_lock,
+            report,
         ) {
             Ok(()) => Ok(()),
             Err(err) => CliError::new_std(err, 1),
         }
     } else {
         match monitor::run(&local_config, wd_index) {
-            Ok(()) => Ok(()),
+            Ok(health) => {
+                report(&health);
+                match health.error() {
+                    Some(err) => CliError::new_std(err, 1),
+                    None => Ok(()),
+                }
+            }
             Err(err) => CliError::new_std(err, 1),
         }
     }
 }
 
-pub fn main() -> Result<(), CliError> {
-    let app = clap::App::new("hardshare")
+fn build_cli() -> clap::App<'static, 'static> {
+    clap::App::new("hardshare")
         .bin_name("hardshare")
         .max_term_width(80)
         .about("Command-line interface for the hardshare client")
         .subcommand(SubCommand::with_name("version")
-                    .about("Prints version number and exits"))
+                    .about("Prints version number and exits")
+                    .arg(Arg::with_name("json")
+                         .long("json")
+                         .help("Prints build information as JSON, including git commit and target")))
+        .subcommand(SubCommand::with_name("whoami")
+                    .about("Prints user and organization of the API token in use"))
         .arg(Arg::with_name("version")
              .short("V")
              .long("version")
@@ -1171,14 +2539,43 @@ pub fn main() -> Result<(), CliError> {
              .short("v")
              .long("verbose")
              .help("Increases verboseness level of logs; ignored if RUST_LOG is defined"))
+        .arg(Arg::with_name("quiet")
+             .short("q")
+             .long("quiet")
+             .conflicts_with("verbose")
+             .help("Prints only results and errors; hints are not shown"))
         .arg(Arg::with_name("printformat")
              .long("format")
              .value_name("FORMAT")
              .help("special output formatting (default is no special formatting); options: YAML , JSON"))
+        .arg(Arg::with_name("configdir")
+             .long("config-dir")
+             .value_name("DIR")
+             .help("directory of local configuration and keys (default: ~/.rerobots); overrides HARDSHARE_CONFIG_DIR"))
+        .arg(Arg::with_name("api_origin")
+             .long("api-origin")
+             .value_name("URL")
+             .help("origin of the rerobots API, e.g., for staging; overrides REROBOTS_ORIGIN"))
+        .arg(Arg::with_name("cacert")
+             .long("cacert")
+             .value_name("FILE")
+             .help("PEM file of additional CA certificates to trust for connections to the rerobots API; overrides HARDSHARE_CACERT"))
+        .arg(Arg::with_name("pin_sha256")
+             .long("pin-sha256")
+             .value_name("HEX")
+             .help("SHA-256 fingerprints (comma-separated) of which the server certificate of the rerobots API must match one; overrides HARDSHARE_PIN_SHA256"))
+        .arg(Arg::with_name("request_timeout")
+             .long("timeout")
+             .value_name("SECONDS")
+             .help("maximum duration of connecting to and waiting for responses from servers (default: 30); overrides HARDSHARE_TIMEOUT"))
+        .arg(Arg::with_name("proxy")
+             .long("proxy")
+             .value_name("URL")
+             .help("proxy for connections to the rerobots API, e.g., http://HOST:PORT or socks5://HOST:PORT; overrides HTTPS_PROXY and ALL_PROXY"))
         .arg(Arg::with_name("daemonport")
              .long("port")
              .value_name("PORT")
-             .help("port for daemon")
+             .help("port for daemon; if 0, then any free port, which is recorded in the configuration directory so that other commands find the daemon")
              .default_value("6666"))
         .subcommand(SubCommand::with_name("init")
                     .about("Initialize local configuration"))
@@ -1190,11 +2587,29 @@ pub fn main() -> Result<(), CliError> {
                     .arg(Arg::with_name("onlylocalconfig")
                          .long("local")
                          .help("Only show local configuration data"))
+                    .arg(Arg::with_name("include_secrets")
+                         .long("include-secrets")
+                         .help("Include paths of SSH key and API tokens in JSON or YAML output; omitted by default"))
                     .arg(Arg::with_name("includedissolved")
                          .long("--include-dissolved")
-                         .help("Include configuration data of dissolved workspace deployments")))
+                         .help("Include configuration data of dissolved workspace deployments"))
+                    .arg(Arg::with_name("refresh")
+                         .long("refresh")
+                         .alias("no-cache")
+                         .conflicts_with("onlylocalconfig")
+                         .help("Get remote configuration from the server even if a recent copy is cached"))
+                    .arg(Arg::with_name("with_instances")
+                         .long("with-instances")
+                         .conflicts_with("onlylocalconfig")
+                         .help("Include active instances of each workspace deployment"))
+                    .arg(Arg::with_name("org")
+                         .long("org")
+                         .value_name("ORG")
+                         .conflicts_with("onlylocalconfig")
+                         .help("List deployments of this organization instead of the default organization; the default is not changed")))
         .subcommand(SubCommand::with_name("config")
                     .about("Manage local and remote configuration")
+                    .setting(AppSettings::ArgRequiredElseHelp)
                     .arg(Arg::with_name("new_api_token")
                          .long("add-token")
                          .value_name("FILE")
@@ -1206,26 +2621,74 @@ pub fn main() -> Result<(), CliError> {
                     .arg(Arg::with_name("cprovider")
                          .long("cprovider")
                          .value_name("CPROVIDER")
-                         .help("select a container provider: lxd, docker, docker-rootless, podman, proxy"))
+                         .help("select a container provider: lxd, docker, docker-rootless, podman, podman-rootful, proxy"))
                     .arg(Arg::with_name("cprovider_img")
                          .long("assign-image")
                          .value_name("IMG")
-                         .help("assign image for cprovider to use (advanced option)"))
+                         .help("assign image for cprovider to use (advanced option); pin a version with NAME@sha256:DIGEST"))
                     .arg(Arg::with_name("cprovider_cmd")
                          .long("assign-proxy-command")
                          .value_name("CMD"))
+                    .arg(Arg::with_name("clone_src")
+                         .long("clone")
+                         .value_name("SRC_ID")
+                         .help("copy settings from deployment SRC_ID (can be unique prefix) to ID"))
+                    .arg(Arg::with_name("force")
+                         .long("force")
+                         .help("with --clone, allow source with a different cprovider; with --import, replace local configuration without confirmation"))
+                    .arg(Arg::with_name("container_name")
+                         .long("container-name")
+                         .value_name("NAME")
+                         .help("base name of containers for instances (default: rrc)"))
                     .arg(Arg::with_name("raw_device_path")
                          .long("add-raw-device")
                          .value_name("PATH")
                          .help("add device file to present in container"))
+                    .arg(Arg::with_name("list_cargs")
+                         .long("list-cargs")
+                         .help("print each carg on its own numbered line"))
+                    .arg(Arg::with_name("clear_cargs")
+                         .long("clear-cargs")
+                         .help("remove all cargs after confirmation"))
                     .arg(Arg::with_name("remove_raw_device_path")
                          .long("rm-raw-device")
                          .value_name("PATH")
                          .help("remove device previously marked for inclusion in container"))
+                    .arg(Arg::with_name("label")
+                         .long("label")
+                         .value_name("LABEL")
+                         .help("set human-readable label of deployment; use `-` to remove label"))
+                    .arg(Arg::with_name("idle_timeout")
+                         .long("idle-timeout")
+                         .value_name("SECONDS")
+                         .help("terminate idle instances after SECONDS (rrhttp only); `-` for none"))
+                    .arg(Arg::with_name("max_session")
+                         .long("max-session")
+                         .value_name("SECONDS")
+                         .help("terminate instances SECONDS after READY; use `-` for no limit"))
+                    .arg(Arg::with_name("ssh_user")
+                         .long("ssh-user")
+                         .value_name("USER")
+                         .help("container user for SSH login by instance owners (default: root)"))
                     .arg(Arg::with_name("new_ssh_path")
                          .long("add-ssh-path")
                          .value_name("FILE")
                          .help("add path of SSH key pair (does not copy the key)"))
+                    .arg(Arg::with_name("show_ssh_key")
+                         .long("show-ssh-key")
+                         .help("print SSH key path, public key, and fingerprint"))
+                    .arg(Arg::with_name("export_path")
+                         .long("export")
+                         .value_name("FILE")
+                         .help("write local configuration to FILE; secrets only if --with-secrets"))
+                    .arg(Arg::with_name("with_secrets")
+                         .long("with-secrets")
+                         .requires("export_path")
+                         .help("with --export, include SSH key and API tokens"))
+                    .arg(Arg::with_name("import_path")
+                         .long("import")
+                         .value_name("FILE")
+                         .help("restore local configuration from FILE created by --export"))
                     .arg(Arg::with_name("declare_wdeployment_id")
                          .long("declare")
                          .value_name("ID")
@@ -1250,15 +2713,43 @@ pub fn main() -> Result<(), CliError> {
                         .long("monitor-prog")
                         .value_name("PROGRAM")
                         .help("declare program to run in a monitor cycle; use `-` to declare none"))
+                    .arg(Arg::with_name("ready_check")
+                        .long("ready-check")
+                        .value_name("PROGRAM")
+                        .help("must succeed inside container before READY; use `-` to declare none"))
+                    .arg(Arg::with_name("ready_timeout")
+                        .long("ready-timeout")
+                        .value_name("SECONDS")
+                        .help("time limit for ready check to pass (default: 120); `-` for default"))
+                    .arg(Arg::with_name("on_ready")
+                        .long("on-ready")
+                        .value_name("PROGRAM")
+                        .help("run on host after instance is READY; use `-` to declare none"))
+                    .arg(Arg::with_name("pre_launch")
+                        .long("pre-launch")
+                        .value_name("PROGRAM")
+                        .help("run on host before each launch; failure aborts; use `-` for none"))
+                    .arg(Arg::with_name("network")
+                        .long("network")
+                        .value_name("NAME")
+                        .help("attach containers to this Docker or Podman network; `-` for default"))
+                    .arg(Arg::with_name("ssh_tunnel_option")
+                        .long("ssh-tunnel-option")
+                        .value_name("KEY=VALUE")
+                        .help("set ssh tunnel option KEY=VALUE, where KEY is one of server-alive-interval, server-alive-count-max, connect-timeout, compression; VALUE `-` restores the default"))
                     .arg(Arg::with_name("hook_emails")
                         .long("hook-emails")
                         .value_name("ADDRESSES")
                         .help("specify email addresses to receive alerts; use `-` to indicate none"))
+                    .arg(Arg::with_name("hook_webhooks")
+                        .long("hook-webhooks")
+                        .value_name("URLS")
+                        .help("specify webhook URLs to receive alerts; use `-` to indicate none"))
                     .arg(Arg::with_name("id_prefix")
                          .value_name("ID")
                          .help("id of workspace deployment for configuration changes (can be unique prefix); this argument is not required if there is only 1 workspace deployment")))
         .subcommand(SubCommand::with_name("config-addon")
-                    .about("Manage add-ons (mistyproxy, vnc, ...)")
+                    .about("Manage add-ons (mistyproxy, webrtc, ...)")
                     .arg(Arg::with_name("id_prefix")
                          .value_name("ID")
                          .help("id of workspace deployment for add-ons (can be unique prefix)"))
@@ -1273,6 +2764,30 @@ pub fn main() -> Result<(), CliError> {
                          .long("ip")
                          .value_name("ADDR")
                          .help("mistyproxy: declare IP address of Misty robot"))
+                    .arg(Arg::with_name("stun")
+                         .long("stun")
+                         .value_name("URL")
+                         .multiple(true)
+                         .number_of_values(1)
+                         .help("webrtc: STUN server, e.g., stun:stun.example.com:3478; can be repeated"))
+                    .arg(Arg::with_name("turn")
+                         .long("turn")
+                         .value_name("URL")
+                         .help("webrtc: TURN server, e.g., turn:turn.example.com:3478"))
+                    .arg(Arg::with_name("turn_username")
+                         .long("turn-username")
+                         .value_name("USER")
+                         .requires("turn")
+                         .help("webrtc: username for the TURN server"))
+                    .arg(Arg::with_name("turn_credential")
+                         .long("turn-credential")
+                         .value_name("SECRET")
+                         .requires("turn")
+                         .help("webrtc: credential for the TURN server"))
+                    .arg(Arg::with_name("set_config")
+                         .long("set")
+                         .value_name("JSON")
+                         .help("replace configuration of add-on with the given JSON object"))
                     .arg(Arg::with_name("remove")
                          .long("rm")
                          .help("remove add-on from workspace deployment; instances will not be able to use the add-on specified with `-a`")))
@@ -1280,7 +2795,26 @@ pub fn main() -> Result<(), CliError> {
                     .about("Advertise availability, accept new instances")
                     .arg(Arg::with_name("id_prefix")
                          .value_name("ID")
-                         .help("id of workspace deployment to advertise (can be unique prefix); this argument is not required if there is only 1 workspace deployment")))
+                         .multiple(true)
+                         .help("id of workspace deployment to advertise (can be unique prefix); this argument is not required if there is only 1 workspace deployment; if more than 1 is given, then all are advertised by one daemon"))
+                    .arg(Arg::with_name("all")
+                         .long("all")
+                         .conflicts_with("id_prefix")
+                         .help("advertise all workspace deployments in the local configuration"))
+                    .arg(Arg::with_name("detach")
+                         .long("detach")
+                         .help("run in the background; the process id is printed and written to ~/.rerobots/hardshare.pid"))
+                    .arg(Arg::with_name("log_file")
+                         .long("log-file")
+                         .value_name("PATH")
+                         .requires("detach")
+                         .help("with --detach, append log messages to this file"))
+                    .arg(Arg::with_name("metrics")
+                         .long("metrics")
+                         .help("serve Prometheus metrics at /metrics of the daemon"))
+                    .arg(Arg::with_name("once")
+                         .long("once")
+                         .help("stop the daemon after one instance is done (destroyed or failed)")))
         .subcommand(SubCommand::with_name("rules")
                     .about("Modify access rules (also known as capabilities or permissions)")
                     .arg(Arg::with_name("id_prefix")
@@ -1298,7 +2832,11 @@ pub fn main() -> Result<(), CliError> {
                          .help("Permit instantiations by you (the owner)"))
                     .arg(Arg::with_name("permit_all")
                          .long("permit-all")
-                         .help("Permit instantiations by anyone")))
+                         .help("Permit instantiations by anyone"))
+                    .arg(Arg::with_name("schedule")
+                         .long("schedule")
+                         .value_name("SCHEDULE")
+                         .help("with --permit-me or --permit-all, only permit during the given times, e.g., \"Mon-Fri 09:00-17:00 UTC-08:00\"; time zone is UTC or an offset from it, and default is UTC")))
         .subcommand(SubCommand::with_name("lock")
                     .about("Lock a workspace deployment to prevent new instances")
                     .arg(Arg::with_name("id_prefix")
@@ -1318,13 +2856,24 @@ pub fn main() -> Result<(), CliError> {
                     .about("Register new workspace deployment")
                     .arg(Arg::with_name("permit_more")
                          .long("permit-more")
-                         .help("Permits registration of more than 1 wdeployment; default is to fail if local configuration already has wdeployment declared")))
+                         .help("Permits registration of more than 1 wdeployment; default is to fail if local configuration already has wdeployment declared"))
+                    .arg(Arg::with_name("from_file")
+                         .long("from-file")
+                         .value_name("FILE")
+                         .help("register every workspace deployment declared in YAML file, with settings as in `hardshare config`")))
         .subcommand(SubCommand::with_name("declare-org")
                     .about("Declare default organization for commands; for example, `register` will mark the owner as this organization or, if none, the user")
                     .arg(Arg::with_name("org_name")
                          .value_name("ORG")))
         .subcommand(SubCommand::with_name("status")
-                    .about("Get information about a running hardshare client, if present"))
+                    .about("Get information about a running hardshare client, if present")
+                    .arg(Arg::with_name("watch")
+                         .long("watch")
+                         .alias("follow")
+                         .value_name("INTERVAL")
+                         .min_values(0)
+                         .max_values(1)
+                         .help("Poll status every INTERVAL seconds (default 2) until interrupted")))
         .subcommand(SubCommand::with_name("reload")
                     .about("Reload configuration in a running hardshare client"))
         .subcommand(SubCommand::with_name("dissolve")
@@ -1344,10 +2893,42 @@ pub fn main() -> Result<(), CliError> {
                          .long("width-height")
                          .value_name("W,H")
                          .help("width and height of captured images; default depends on the supporting drivers"))
+                    .arg(Arg::with_name("on_unsupported_res")
+                         .long("on-unsupported-res")
+                         .value_name("POLICY")
+                         .possible_values(&["fail", "fallback"])
+                         .requires("attach_camera_res")
+                         .help("what to do if the camera does not support --width-height: fail, or fall back to a size that the camera offers; default is fallback"))
                     .arg(Arg::with_name("attach_camera_crop_config")
                          .long("crop")
                          .value_name("CROPCONFIG")
-                         .help("image crop configuration; default: all wdeployments get full images")))
+                         .help("image crop configuration; default: all wdeployments get full images"))
+                    .arg(Arg::with_name("rotate")
+                         .long("rotate")
+                         .value_name("DEGREES")
+                         .possible_values(&["0", "90", "180", "270"])
+                         .help("rotate images clockwise before upload; on Linux, this requires decoding and encoding each frame, which costs more CPU"))
+                    .arg(Arg::with_name("flip")
+                         .long("flip")
+                         .value_name("AXIS")
+                         .possible_values(&["none", "h", "v"])
+                         .help("flip images horizontally (h) or vertically (v) before upload, after any rotation; on Linux, this requires decoding and encoding each frame"))
+                    .arg(Arg::with_name("duration")
+                         .long("duration")
+                         .value_name("SECONDS")
+                         .help("stream for SECONDS and then stop and detach the camera; fail if no frames were sent"))
+                    .arg(Arg::with_name("list_devices")
+                         .long("list-devices")
+                         .help("list camera devices and supported resolutions; does not start a stream"))
+                    .arg(Arg::with_name("record_dir")
+                         .long("record-dir")
+                         .value_name("DIR")
+                         .help("also write captured JPEG frames to DIR, for debugging"))
+                    .arg(Arg::with_name("record_max_frames")
+                         .long("record-max-frames")
+                         .value_name("N")
+                         .requires("record_dir")
+                         .help("with --record-dir, stop recording after N frames; default is 100")))
         .subcommand(SubCommand::with_name("check")
                     .about("Check configuration, dependencies, runtime behavior")
                     .arg(Arg::with_name("all")
@@ -1359,9 +2940,53 @@ pub fn main() -> Result<(), CliError> {
                     .arg(Arg::with_name("camera")
                          .long("camera")
                          .help("check camera and image capture (not streaming)"))
+                    .arg(Arg::with_name("pull")
+                         .long("pull")
+                         .help("pull the container image if it is not present locally"))
+                    .arg(Arg::with_name("repo")
+                         .long("repo")
+                         .value_name("URL")
+                         .help("clone repository into the test container, as if given with a launch request"))
+                    .arg(Arg::with_name("repo_cmd")
+                         .long("repo-cmd")
+                         .value_name("CMD")
+                         .requires("repo")
+                         .help("with --repo, run CMD in the cloned repository inside the test container"))
+                    .arg(Arg::with_name("fix")
+                         .long("fix")
+                         .help("offer to create missing local configuration and SSH key, and to remove API tokens with errors"))
+                    .arg(Arg::with_name("camera_res")
+                         .long("width-height")
+                         .value_name("W,H")
+                         .requires("camera")
+                         .help("with --camera, check that the camera supports this width and height"))
                     .arg(Arg::with_name("id_prefix")
                          .value_name("ID")
                          .help("id of workspace deployment to check; if neither --all nor ID is given, then check whether a deployment with the default configuration has all requirements satisfied")))
+        .subcommand(SubCommand::with_name("test-launch")
+                    .about("Launch a real instance as a user would, wait for READY, then terminate it")
+                    .arg(Arg::with_name("id_prefix")
+                         .value_name("ID")
+                         .help("id of workspace deployment to test (can be unique prefix); this argument is not required if there is only 1 workspace deployment"))
+                    .arg(Arg::with_name("smoke")
+                         .long("smoke")
+                         .value_name("CMD")
+                         .help("when READY, run CMD in the instance via ssh"))
+                    .arg(Arg::with_name("timeout")
+                         .long("timeout")
+                         .value_name("SECONDS")
+                         .default_value("300")
+                         .help("maximum duration to wait for READY")))
+        .subcommand(SubCommand::with_name("camera-status")
+                    .about("List camera streams started on this host"))
+        .subcommand(SubCommand::with_name("prune-containers")
+                    .about("Remove containers left behind by previous instances")
+                    .arg(Arg::with_name("dry_run")
+                         .long("dry-run")
+                         .help("only list containers that would be removed"))
+                    .arg(Arg::with_name("force")
+                         .long("force")
+                         .help("remove without confirmation")))
         .subcommand(SubCommand::with_name("stop-cameras")
                     .about("Stop camera streams previously started by attach-camera")
                     .arg(Arg::with_name("all_cameras")
@@ -1376,12 +3001,43 @@ pub fn main() -> Result<(), CliError> {
                     .arg(Arg::with_name("loop")
                         .long("loop")
                         .value_name("DURATION")
-                        .help("Repeat monitor checks every DURATION seconds")))
-        ;
+                        .help("Repeat monitor checks every DURATION seconds"))
+                    .arg(Arg::with_name("auto_lock")
+                        .long("auto-lock")
+                        .requires("loop")
+                        .help("With --loop, if a check fails, lock the deployment and send an alert. Without --loop, this is always done.")))
+        .subcommand(SubCommand::with_name("completions")
+                    .about("Generate shell completion script and print it to stdout")
+                    .arg(Arg::with_name("shell")
+                         .value_name("SHELL")
+                         .required(true)
+                         .possible_values(&clap::Shell::variants())
+                         .help("shell for which to generate the completion script")))
+}
 
-    let matches = app.get_matches();
+fn completions_subcommand(matches: &clap::ArgMatches) -> Result<(), CliError> {
+    let shell = value_t!(matches, "shell", clap::Shell).unwrap();
+    build_cli().gen_completions_to("hardshare", shell, &mut std::io::stdout());
+    Ok(())
+}
+
+pub fn main() -> Result<(), CliError> {
+    let app = build_cli();
 
-    let default_loglevel = if matches.is_present("verbose") {
+    let matches = match app.get_matches_safe() {
+        Ok(m) => m,
+        Err(err) => {
+            if err.use_stderr() {
+                return CliError::new(err.message, EXIT_USAGE);
+            }
+            err.exit();
+        }
+    };
+
+    let default_loglevel = if matches.is_present("quiet") {
+        QUIET.store(true, Ordering::Relaxed);
+        "error"
+    } else if matches.is_present("verbose") {
         "info"
     } else {
         "warn"
@@ -1389,6 +3045,40 @@ pub fn main() -> Result<(), CliError> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default_loglevel))
         .init();
 
+    if let Some(origin) = matches.value_of("api_origin") {
+        if let Err(err) = api::validate_origin(origin) {
+            return CliError::new(err, EXIT_USAGE);
+        }
+        std::env::set_var("REROBOTS_ORIGIN", origin);
+    }
+    if let Some(timeout) = matches.value_of("request_timeout") {
+        match timeout.parse::<u64>() {
+            Ok(t) if t > 0 => std::env::set_var("HARDSHARE_TIMEOUT", timeout),
+            _ => return CliError::new("SECONDS must be a positive integer", EXIT_USAGE),
+        }
+    }
+    if let Some(proxy_url) = matches.value_of("proxy") {
+        if let Err(err) = proxy_url.parse::<proxy::Proxy>() {
+            return CliError::new(err, EXIT_USAGE);
+        }
+        std::env::set_var("HARDSHARE_PROXY", proxy_url);
+    }
+    if let Some(cacert) = matches.value_of("cacert") {
+        if let Err(err) = tls::load_ca_certs(cacert) {
+            return CliError::new(err, EXIT_USAGE);
+        }
+        std::env::set_var("HARDSHARE_CACERT", cacert);
+    }
+    if let Some(fingerprint) = matches.value_of("pin_sha256") {
+        if let Err(err) = tls::parse_pins(fingerprint) {
+            return CliError::new(err, EXIT_USAGE);
+        }
+        std::env::set_var("HARDSHARE_PIN_SHA256", fingerprint);
+    }
+    if let Some(config_dir) = matches.value_of("configdir") {
+        std::env::set_var("HARDSHARE_CONFIG_DIR", config_dir);
+    }
+
     let pformat = match matches.value_of("printformat") {
         Some(given_pformat) => {
             let given_pformat_lower = given_pformat.to_lowercase();
@@ -1399,17 +3089,30 @@ pub fn main() -> Result<(), CliError> {
             } else {
                 return CliError::new(
                     format!("unrecognized format: {}", given_pformat).as_str(),
-                    1,
+                    EXIT_USAGE,
                 );
             }
         }
         None => PrintingFormat::Default,
     };
 
-    let bindaddr = format!("127.0.0.1:{}", matches.value_of("daemonport").unwrap());
+    let daemonport = match matches.value_of("daemonport").unwrap().parse::<u16>() {
+        Ok(p) => p,
+        Err(_) => return CliError::new("PORT must be an integer from 0 to 65535", EXIT_USAGE),
+    };
+    // `ad` binds to the given port; other commands look for running daemons
+    let bindaddr = format!("127.0.0.1:{}", daemonport);
+    let port_given = matches.occurrences_of("daemonport") > 0;
+    let find_daemons = || daemon_addrs(daemonport, port_given);
 
     if matches.is_present("version") || matches.subcommand_matches("version").is_some() {
-        println!(crate_version!());
+        let as_json = pformat == PrintingFormat::Json
+            || matches
+                .subcommand_matches("version")
+                .is_some_and(|m| m.is_present("json"));
+        version_subcommand(as_json);
+    } else if matches.subcommand_matches("whoami").is_some() {
+        return whoami_subcommand(pformat);
     } else if matches.subcommand_matches("init").is_some() {
         return init_subcommand();
     } else if let Some(matches) = matches.subcommand_matches("list") {
@@ -1423,7 +3126,7 @@ pub fn main() -> Result<(), CliError> {
     } else if let Some(matches) = matches.subcommand_matches("ad") {
         return ad_subcommand(matches, &bindaddr);
     } else if let Some(matches) = matches.subcommand_matches("stop-ad") {
-        return stop_ad_subcommand(matches, &bindaddr);
+        return stop_ad_subcommand(matches, &find_daemons()?);
     } else if let Some(matches) = matches.subcommand_matches("register") {
         return register_subcommand(matches);
     } else if let Some(matches) = matches.subcommand_matches("declare-org") {
@@ -1432,22 +3135,30 @@ pub fn main() -> Result<(), CliError> {
         return lock_wdeplyoment_subcommand(matches, true);
     } else if let Some(matches) = matches.subcommand_matches("unlock") {
         return lock_wdeplyoment_subcommand(matches, false);
-    } else if matches.subcommand_matches("status").is_some() {
-        return status_subcommand(&bindaddr, pformat);
+    } else if let Some(matches) = matches.subcommand_matches("status") {
+        return status_subcommand(matches, &find_daemons()?, pformat);
     } else if let Some(matches) = matches.subcommand_matches("dissolve") {
         return dissolve_subcommand(matches);
     } else if matches.subcommand_matches("reload").is_some() {
-        return reload_subcommand(&bindaddr);
+        return reload_subcommand(&find_daemons()?);
     } else if let Some(matches) = matches.subcommand_matches("check") {
         return check_subcommand(matches);
     } else if let Some(matches) = matches.subcommand_matches("monitor") {
-        return monitor_subcommand(matches);
+        return monitor_subcommand(matches, pformat);
     } else if let Some(matches) = matches.subcommand_matches("attach-camera") {
         return attach_camera_subcommand(matches);
+    } else if matches.subcommand_matches("camera-status").is_some() {
+        return camera_status_subcommand(pformat);
+    } else if let Some(matches) = matches.subcommand_matches("test-launch") {
+        return test_launch_subcommand(matches, &find_daemons()?);
+    } else if let Some(matches) = matches.subcommand_matches("prune-containers") {
+        return prune_containers_subcommand(matches, &find_daemons()?);
     } else if let Some(matches) = matches.subcommand_matches("stop-cameras") {
         return stop_cameras_subcommand(matches);
+    } else if let Some(matches) = matches.subcommand_matches("completions") {
+        return completions_subcommand(matches);
     } else {
-        println!("No command given. Try `hardshare -h`");
+        note("No command given. Try `hardshare -h`");
     }
 
     Ok(())
@@ -1457,8 +3168,13 @@ pub fn main() -> Result<(), CliError> {
 mod tests {
     use tempfile::tempdir;
 
+    use super::describe_error;
     use super::print_config_w;
     use super::PrintingFormat;
+    use super::{
+        classify_exit_code, EXIT_AUTH, EXIT_CONFLICT, EXIT_FAILURE, EXIT_NETWORK, EXIT_USAGE,
+    };
+    use crate::error::HardshareError;
     use crate::mgmt;
 
     #[test]
@@ -1468,9 +3184,91 @@ mod tests {
         let lconf = mgmt::get_local_config_bp(&base_path, true, false).unwrap();
 
         let mut buf: Vec<u8> = vec![];
-        print_config_w(&mut buf, &lconf, &None, PrintingFormat::Json, true).unwrap();
+        print_config_w(&mut buf, &lconf, &None, PrintingFormat::Json, true, false).unwrap();
         let buf_parsing_result: Result<serde_json::Value, serde_json::Error> =
             serde_json::from_slice(&buf);
         assert!(buf_parsing_result.is_ok());
+        let listing = buf_parsing_result.unwrap();
+        assert!(listing.get("ssh_key").is_none());
+        assert!(listing.get("api_tokens").is_none());
+
+        let mut buf: Vec<u8> = vec![];
+        print_config_w(&mut buf, &lconf, &None, PrintingFormat::Json, true, true).unwrap();
+        let listing: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert!(listing["ssh_key"].is_string());
+    }
+
+    #[test]
+    fn exit_code_by_error_class() {
+        let err: Box<dyn std::error::Error> = Box::new(HardshareError::Auth("no API token".into()));
+        assert_eq!(classify_exit_code(err.as_ref(), EXIT_FAILURE), EXIT_AUTH);
+
+        let err: Box<dyn std::error::Error> = Box::new(HardshareError::Api {
+            status: 409,
+            message: "already locked".into(),
+        });
+        assert_eq!(
+            classify_exit_code(err.as_ref(), EXIT_FAILURE),
+            EXIT_CONFLICT
+        );
+
+        let err: Box<dyn std::error::Error> =
+            Box::new(std::io::Error::from(std::io::ErrorKind::ConnectionRefused));
+        assert_eq!(classify_exit_code(err.as_ref(), EXIT_FAILURE), EXIT_NETWORK);
+
+        let err: Box<dyn std::error::Error> = Box::new(HardshareError::Timeout);
+        assert_eq!(classify_exit_code(err.as_ref(), EXIT_FAILURE), EXIT_NETWORK);
+
+        let err: Box<dyn std::error::Error> =
+            Box::new(HardshareError::Invalid("user name cannot be empty".into()));
+        assert_eq!(classify_exit_code(err.as_ref(), EXIT_FAILURE), EXIT_USAGE);
+
+        let err: Box<dyn std::error::Error> = Box::new(HardshareError::Other("oops".into()));
+        assert_eq!(classify_exit_code(err.as_ref(), EXIT_FAILURE), EXIT_FAILURE);
+    }
+
+    #[test]
+    fn list_remote_instances() {
+        let td = tempdir().unwrap();
+        let base_path = td.path().join(".rerobots");
+        let lconf = mgmt::get_local_config_bp(&base_path, true, false).unwrap();
+        let remote = Some(json!({
+            "wdeployments": [{
+                "id": "68a1be97-9365-4007-b726-14c56bd69eef",
+                "date_created": "2026-01-05 21:09:24",
+                "desc": null,
+                "origin": null,
+                "dissolved": null,
+                "lockout": false,
+                "instances": [
+                    {"id": "2d5c0c52-3b0d-4a9e-8f21-6a5a1e7bd0c4", "status": "READY"},
+                    {"id": "8b0e6d1c-6f0a-4f53-9d2e-3c1a7e5b9f20", "status": "INIT"},
+                ],
+            }],
+        }));
+
+        let mut buf: Vec<u8> = vec![];
+        print_config_w(
+            &mut buf,
+            &lconf,
+            &remote,
+            PrintingFormat::Default,
+            true,
+            false,
+        )
+        .unwrap();
+        let listing = String::from_utf8(buf).unwrap();
+        assert!(listing.contains("\tactive instances: 2\n"));
+        assert!(listing.contains("\t\t2d5c0c52-3b0d-4a9e-8f21-6a5a1e7bd0c4: READY\n"));
+    }
+
+    #[test]
+    fn describe_timeout() {
+        let err: Box<dyn std::error::Error> = Box::new(awc::error::SendRequestError::Timeout);
+        assert!(describe_error(err.as_ref()).contains("timed out"));
+        assert_eq!(classify_exit_code(err.as_ref(), EXIT_FAILURE), EXIT_NETWORK);
+
+        let err: Box<dyn std::error::Error> = Box::new(HardshareError::Auth("no API token".into()));
+        assert_eq!(describe_error(err.as_ref()), "no API token");
     }
 }
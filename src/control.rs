@@ -13,11 +13,13 @@
 // limitations under the License.
 
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fs::File;
 use std::io::prelude::*;
+use std::net::ToSocketAddrs;
 use std::process::{Command, Stdio};
 use std::str::FromStr;
-use std::sync::atomic::{self, AtomicBool};
+use std::sync::atomic::{self, AtomicBool, AtomicU64};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 
@@ -28,7 +30,7 @@ use tempfile::NamedTempFile;
 
 use crate::api;
 use crate::check::Error;
-use crate::mgmt::{CProvider, WDeployment};
+use crate::mgmt::{self, CProvider, WDeployment};
 
 #[derive(PartialEq, Debug, Clone)]
 enum InstanceStatus {
@@ -51,6 +53,153 @@ impl std::fmt::Display for InstanceStatus {
     }
 }
 
+// Phases of an instance launch, reported while the status is INIT
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum LaunchPhase {
+    Pulling,
+    Container,
+    Keys,
+    HostKey,
+    Scripts,
+    Repo,
+    Tunnel,
+    ReadyCheck,
+}
+
+impl std::fmt::Display for LaunchPhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LaunchPhase::Pulling => write!(f, "INIT_PULLING"),
+            LaunchPhase::Container => write!(f, "INIT_CONTAINER"),
+            LaunchPhase::Keys => write!(f, "INIT_KEYS"),
+            LaunchPhase::HostKey => write!(f, "INIT_HOSTKEY"),
+            LaunchPhase::Scripts => write!(f, "INIT_SCRIPTS"),
+            LaunchPhase::Repo => write!(f, "INIT_REPO"),
+            LaunchPhase::Tunnel => write!(f, "INIT_TUNNEL"),
+            LaunchPhase::ReadyCheck => write!(f, "INIT_READY_CHECK"),
+        }
+    }
+}
+
+// Progress is sent with a newer message version than INSTANCE_STATUS, so
+// servers that only know version 0 ignore it and see INIT until READY.
+fn progress_message(phase: LaunchPhase) -> serde_json::Value {
+    json!({
+        "v": 1,
+        "cmd": "INSTANCE_STATUS",
+        "s": phase.to_string(),
+    })
+}
+
+// Counters and gauges of one workspace deployment, for the optional
+// Prometheus endpoint of the daemon
+#[derive(Default)]
+pub struct Metrics {
+    instances_launched: AtomicU64,
+    launch_failures: AtomicU64,
+    ws_reconnects: AtomicU64,
+    tunnel_restarts: AtomicU64,
+    status: Mutex<Option<InstanceStatus>>,
+}
+
+impl Metrics {
+    fn set_status(&self, new_status: Option<&InstanceStatus>) {
+        match new_status {
+            Some(InstanceStatus::Init) => {
+                self.instances_launched
+                    .fetch_add(1, atomic::Ordering::Relaxed);
+            }
+            Some(InstanceStatus::InitFail) => {
+                self.launch_failures.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+            _ => (),
+        }
+        *self.status.lock().unwrap() = new_status.cloned();
+    }
+
+    pub fn count_ws_reconnect(&self) {
+        self.ws_reconnects.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+
+    fn count_tunnel_restart(&self) {
+        self.tunnel_restarts.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+}
+
+// Name, help text, and value of a counter in the metrics
+type Counter = (&'static str, &'static str, fn(&Metrics) -> u64);
+
+// Write metrics of all given workspace deployments in Prometheus text format
+pub fn render_metrics(tab: &HashMap<String, Arc<Metrics>>) -> String {
+    let mut wdids: Vec<&String> = tab.keys().collect();
+    wdids.sort();
+    let mut out = String::new();
+
+    let counters: [Counter; 4] = [
+        (
+            "hardshare_instances_launched_total",
+            "Number of instances that were initialized",
+            |m: &Metrics| m.instances_launched.load(atomic::Ordering::Relaxed),
+        ),
+        (
+            "hardshare_launch_failures_total",
+            "Number of instances that failed to initialize",
+            |m: &Metrics| m.launch_failures.load(atomic::Ordering::Relaxed),
+        ),
+        (
+            "hardshare_ws_reconnects_total",
+            "Number of times the WebSocket to the server was reopened",
+            |m: &Metrics| m.ws_reconnects.load(atomic::Ordering::Relaxed),
+        ),
+        (
+            "hardshare_tunnel_reconnects_total",
+            "Number of times the ssh tunnel of an instance was restarted",
+            |m: &Metrics| m.tunnel_restarts.load(atomic::Ordering::Relaxed),
+        ),
+    ];
+    for (name, help, get) in counters.iter() {
+        out.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} counter\n",
+            name, help, name
+        ));
+        for wdid in wdids.iter() {
+            out.push_str(&format!(
+                "{}{{wdeployment=\"{}\"}} {}\n",
+                name,
+                wdid,
+                get(&tab[*wdid])
+            ));
+        }
+    }
+
+    let name = "hardshare_instance_status";
+    out.push_str(&format!(
+        "# HELP {} Status of the current instance, if any\n# TYPE {} gauge\n",
+        name, name
+    ));
+    let all_status = [
+        InstanceStatus::Init,
+        InstanceStatus::InitFail,
+        InstanceStatus::Ready,
+        InstanceStatus::Terminating,
+        InstanceStatus::Fault,
+    ];
+    for wdid in wdids.iter() {
+        let current = tab[*wdid].status.lock().unwrap().clone();
+        for s in all_status.iter() {
+            out.push_str(&format!(
+                "{}{{wdeployment=\"{}\",status=\"{}\"}} {}\n",
+                name,
+                wdid,
+                s,
+                if current.as_ref() == Some(s) { 1 } else { 0 }
+            ));
+        }
+    }
+
+    out
+}
+
 type Port = u32;
 
 #[derive(PartialEq, Debug, Clone)]
@@ -68,6 +217,7 @@ pub struct ContainerAddress {
 struct SshTunnel {
     proc: std::process::Child,
     container_addr: ContainerAddress,
+    info: TunnelInfo,
 }
 
 #[derive(Clone)]
@@ -79,6 +229,7 @@ pub struct CurrentInstance {
     main_actor_addr: Option<Addr<api::MainActor>>,
     responses: Arc<Mutex<HashMap<String, Option<CWorkerCommand>>>>,
     tunnel: Arc<Mutex<Option<SshTunnel>>>,
+    metrics: Arc<Metrics>,
 }
 
 impl CurrentInstance {
@@ -94,6 +245,7 @@ impl CurrentInstance {
             main_actor_addr: main_actor_addr.cloned(),
             responses: Arc::new(Mutex::new(HashMap::new())),
             tunnel: Arc::new(Mutex::new(None)),
+            metrics: Arc::new(Metrics::default()),
         }
     }
 
@@ -138,12 +290,16 @@ impl CurrentInstance {
                             serde_json::to_string(&if hostkey.is_some()
                                 && (*s == InstanceStatus::Ready || *s == InstanceStatus::Init)
                             {
-                                json!({
+                                let mut m = json!({
                                     "v": 0,
                                     "cmd": "INSTANCE_STATUS",
                                     "s": s.to_string(),
                                     "h": hostkey.unwrap(),
-                                })
+                                });
+                                if let Some(u) = &self.wdeployment.ssh_user {
+                                    m["u"] = json!(u);
+                                }
+                                m
                             } else {
                                 json!({
                                     "v": 0,
@@ -162,6 +318,19 @@ impl CurrentInstance {
         }
     }
 
+    fn send_progress(&self, phase: LaunchPhase) {
+        debug!("launch phase: {}", phase);
+        if self.status() != Some(InstanceStatus::Init) {
+            return;
+        }
+        if let Some(main_actor_addr) = &self.main_actor_addr {
+            main_actor_addr.do_send(api::ClientWorkerMessage {
+                mtype: CWorkerMessageType::WsSend,
+                body: Some(serde_json::to_string(&progress_message(phase)).unwrap()),
+            });
+        }
+    }
+
     fn send_create_sshtun(
         &self,
         tunnelkey_public: &str,
@@ -224,6 +393,7 @@ impl CurrentInstance {
             }
             None => {
                 *status = Some(InstanceStatus::Init);
+                self.metrics.set_status(status.as_ref());
                 self.id = Some(instance_id.into());
             }
         }
@@ -257,6 +427,7 @@ impl CurrentInstance {
 
     fn declare_status(&mut self, new_status: InstanceStatus) {
         let mut x = self.status.lock().unwrap();
+        self.metrics.set_status(Some(&new_status));
         *x = Some(new_status);
     }
 
@@ -264,12 +435,14 @@ impl CurrentInstance {
         let mut x = self.status.lock().unwrap();
         if *x != Some(InstanceStatus::Fault) {
             *x = None;
+            self.metrics.set_status(None);
         }
     }
 
     fn get_container_addr(
         cprovider: &CProvider,
         name: &str,
+        network: Option<&str>,
         timeout: u64,
     ) -> Result<String, String> {
         let execname = cprovider.get_execname().unwrap();
@@ -290,14 +463,8 @@ impl CurrentInstance {
                 Ok(o) => o,
                 Err(err) => return Err(format!("{}", err)),
             };
-            match r[0]["NetworkSettings"]["IPAddress"].as_str() {
-                Some(addr) => {
-                    if !addr.is_empty() {
-                        return Ok(addr.into());
-                    }
-                    warn!("waiting for address...");
-                    std::thread::sleep(sleep_time);
-                }
+            match addr_from_inspect(&r, network) {
+                Some(addr) => return Ok(addr),
                 None => {
                     warn!("waiting for address...");
                     std::thread::sleep(sleep_time);
@@ -307,6 +474,57 @@ impl CurrentInstance {
         Err("address not found".into())
     }
 
+    // Poll until the container no longer exists, retrying removal once
+    fn wait_container_absent(
+        cprovider: &CProvider,
+        name: &str,
+        timeout: u64,
+    ) -> Result<(), String> {
+        if *cprovider != CProvider::Docker
+            && *cprovider != CProvider::DockerRootless
+            && *cprovider != CProvider::Podman
+            && *cprovider != CProvider::PodmanRootful
+        {
+            return Ok(());
+        }
+        let execname = cprovider.get_execname().unwrap();
+        let max_duration = std::time::Duration::from_secs(timeout);
+        let sleep_time = std::time::Duration::from_secs(2);
+        let now = std::time::Instant::now();
+        let mut retried_rm = false;
+        loop {
+            let inspect_status = Command::new(&execname)
+                .args(["inspect", name])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status();
+            match inspect_status {
+                Ok(s) => {
+                    if !s.success() {
+                        return Ok(());
+                    }
+                }
+                Err(err) => return Err(format!("{}", err)),
+            }
+            if now.elapsed() > max_duration {
+                return Err(format!("container {} still exists after removal", name));
+            }
+            if !retried_rm && now.elapsed() > max_duration / 2 {
+                warn!("container {} still exists; trying to remove again", name);
+                retried_rm = true;
+                if let Err(err) = Command::new(&execname)
+                    .args(["rm", "-f", name])
+                    .stdout(Stdio::null())
+                    .status()
+                {
+                    warn!("{}", err);
+                }
+            }
+            warn!("waiting for container {} to be removed...", name);
+            std::thread::sleep(sleep_time);
+        }
+    }
+
     fn get_container_sshport(cprovider: &CProvider, name: &str) -> Result<Port, String> {
         let execname = cprovider.get_execname().unwrap();
         let mut run_command = Command::new(execname);
@@ -380,8 +598,8 @@ impl CurrentInstance {
         cargs: &[String],
         timeout: u64,
     ) -> Result<(std::process::Child, Port), Box<dyn std::error::Error>> {
-        if cargs[0] != "rrhttp" {
-            return Err("only rrhttp proxy supported".into());
+        if cargs[0] != "rrhttp" && cargs[0] != "rrtcp" {
+            return Err("only rrhttp and rrtcp proxies supported".into());
         }
         let mut child = Command::new(&cargs[0])
             .args(cargs[1..].iter())
@@ -447,27 +665,12 @@ impl CurrentInstance {
         }
         let tunnelinfo = tunnelinfo.unwrap();
 
-        let tunnel_process_args = [
-            "-o",
-            "ServerAliveInterval=10",
-            "-o",
-            "StrictHostKeyChecking=no",
-            "-o",
-            "ExitOnForwardFailure=yes",
-            "-T",
-            "-N",
-            "-R",
-            &format!(":2210:{}:{}", container_addr.ip, container_addr.port),
-            "-i",
+        let tunnel_process_args = sshtun_args(
+            &container_addr,
             tunnelkey_path,
-            "-p",
-            &format!("{thport}", thport = tunnelinfo.thport),
-            &format!(
-                "{thuser}@{addr}",
-                thuser = tunnelinfo.thuser,
-                addr = tunnelinfo.ipv4
-            ),
-        ];
+            &tunnelinfo,
+            &self.wdeployment.ssh_tunnel,
+        );
         info!("tunnel process args: {:?}", tunnel_process_args);
         let tunnel_process = Command::new("ssh").args(tunnel_process_args).spawn()?;
 
@@ -475,10 +678,75 @@ impl CurrentInstance {
         *tunnel = Some(SshTunnel {
             proc: tunnel_process,
             container_addr,
+            info: tunnelinfo,
         });
         Ok(())
     }
 
+    // Replace the ssh tunnel process, reusing the tunnel that the server created
+    fn restart_sshtun(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let tunnelkey_path = match &self.wdeployment.ssh_key {
+            Some(k) => k.clone(),
+            None => return Err(Error::new("no SSH key in configuration")),
+        };
+        let mut tunnel_ref = self.tunnel.lock().unwrap();
+        let tunnel = match tunnel_ref.as_mut() {
+            Some(t) => t,
+            None => return Err(Error::new("no ssh tunnel to restart")),
+        };
+        if let Err(err) = tunnel.proc.kill() {
+            debug!("tunnel kill: {}", err);
+        }
+        if let Err(err) = tunnel.proc.wait() {
+            warn!("{}", err);
+        }
+
+        let tunnel_process_args = sshtun_args(
+            &tunnel.container_addr,
+            &tunnelkey_path,
+            &tunnel.info,
+            &self.wdeployment.ssh_tunnel,
+        );
+        info!(
+            "restarting tunnel process with args: {:?}",
+            tunnel_process_args
+        );
+        tunnel.proc = Command::new("ssh").args(tunnel_process_args).spawn()?;
+        Ok(())
+    }
+
+    // Check that the ssh tunnel process is running and that the container
+    // accepts connections at the address that is forwarded
+    fn check_tunnel(&self) -> Result<(), String> {
+        let mut tunnel_ref = self.tunnel.lock().unwrap();
+        let tunnel = match tunnel_ref.as_mut() {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+        match tunnel.proc.try_wait() {
+            Ok(Some(s)) => return Err(format!("ssh tunnel process exited: {}", s)),
+            Ok(None) => (),
+            Err(err) => return Err(format!("failed to check ssh tunnel process: {}", err)),
+        }
+
+        let ip = tunnel.container_addr.ip.as_str();
+        let port = match u16::try_from(tunnel.container_addr.port) {
+            Ok(p) => p,
+            Err(err) => return Err(format!("invalid forwarded port: {}", err)),
+        };
+        let addr = match (ip, port).to_socket_addrs().map(|mut a| a.next()) {
+            Ok(Some(a)) => a,
+            Ok(None) | Err(_) => return Err(format!("cannot resolve forwarded address {}", ip)),
+        };
+        if let Err(err) = std::net::TcpStream::connect_timeout(&addr, TUNNEL_CONNECT_TIMEOUT) {
+            return Err(format!(
+                "forwarded address {} does not accept connections: {}",
+                addr, err
+            ));
+        }
+        Ok(())
+    }
+
     fn launch_sshtun(
         mut instance: CurrentInstance,
         public_key: &str,
@@ -487,16 +755,28 @@ impl CurrentInstance {
     ) {
         let base_name = instance.wdeployment.container_name.clone();
         let name = instance.generate_local_name(&base_name);
-        let container_addr = match Self::launch_container(&instance.wdeployment, &name, public_key)
-        {
-            Ok(ca) => ca,
-            Err(err) => {
-                error!("{}", err);
+
+        if let Some(pre_launch) = &instance.wdeployment.pre_launch {
+            if let Err(err) = instance.run_pre_launch(pre_launch, &name) {
+                error!("pre-launch program failed: {}", err);
                 instance.declare_status(InstanceStatus::InitFail);
                 instance.send_status();
                 return;
             }
-        };
+        }
+
+        let container_addr =
+            match Self::launch_container(&instance.wdeployment, &name, public_key, &|phase| {
+                instance.send_progress(phase)
+            }) {
+                Ok(ca) => ca,
+                Err(err) => {
+                    error!("{}", err);
+                    instance.declare_status(InstanceStatus::InitFail);
+                    instance.send_status();
+                    return;
+                }
+            };
         if abort_launch.load(atomic::Ordering::Relaxed) {
             error!("received request to abort launch");
             instance.declare_status(InstanceStatus::InitFail);
@@ -507,71 +787,208 @@ impl CurrentInstance {
         let tunnelkey_path = instance.wdeployment.ssh_key.clone().unwrap();
 
         if let Some(repo_info) = repo_args {
-            let cprovider_execname = instance.wdeployment.cprovider.get_execname().unwrap();
-            let status = Command::new(&cprovider_execname)
+            instance.send_progress(LaunchPhase::Repo);
+            if let Err(err) = Self::setup_repo(&instance.wdeployment, &name, &repo_info) {
+                error!("{}", err);
+                instance.declare_status(InstanceStatus::InitFail);
+                instance.send_status();
+                return;
+            }
+        }
+
+        instance.send_progress(LaunchPhase::Tunnel);
+        if let Err(err) = instance.start_sshtun(container_addr, &tunnelkey_path, 30) {
+            error!("{}", err);
+            instance.declare_status(InstanceStatus::InitFail);
+            instance.send_status();
+            return;
+        }
+
+        if let Some(ready_check) = &instance.wdeployment.ready_check {
+            instance.send_progress(LaunchPhase::ReadyCheck);
+            if let Err(err) = Self::wait_ready(
+                &instance.wdeployment,
+                &name,
+                ready_check,
+                instance.wdeployment.get_ready_timeout(),
+            ) {
+                error!("{}", err);
+                instance.declare_status(InstanceStatus::InitFail);
+                instance.send_status();
+                return;
+            }
+        }
+
+        instance.declare_status(InstanceStatus::Ready);
+        instance.send_status();
+
+        if let Some(on_ready) = &instance.wdeployment.on_ready {
+            if let Err(err) = instance.run_on_ready(on_ready, &name) {
+                error!("on-ready program failed: {}", err);
+            }
+        }
+    }
+
+    // Home directory of `user` inside the container, as the user sees it
+    fn get_container_home(
+        cprovider_execname: &str,
+        name: &str,
+        user: &str,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let output = Command::new(cprovider_execname)
+            .args(["exec", "-u", user, name, "printenv", "HOME"])
+            .output()?;
+        let home_dir = String::from_utf8(output.stdout)?.trim().to_string();
+        if !output.status.success() || home_dir.is_empty() {
+            return Err(Error::new(format!(
+                "failed to find home directory of {} in container",
+                user
+            )));
+        }
+        Ok(home_dir)
+    }
+
+    // Clone the repository into the container, and run its command, if any.
+    // Neither the URL nor the command is given to a shell.
+    pub fn setup_repo(
+        wdeployment: &WDeployment,
+        name: &str,
+        repo_info: &RepoInfo,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let cprovider_execname = match wdeployment.cprovider.get_execname() {
+            Some(e) => e,
+            None => {
+                return Err(Error::new(format!(
+                    "repository not supported with cprovider `{}`",
+                    wdeployment.cprovider
+                )))
+            }
+        };
+        check_repo_url(&repo_info.url)?;
+        let repo_command = match &repo_info.path {
+            Some(path) => Some(parse_repo_command(path)?),
+            None => None,
+        };
+        let ssh_user = wdeployment.get_ssh_user().to_string();
+
+        let home_dir = CurrentInstance::get_container_home(&cprovider_execname, name, &ssh_user)?;
+
+        let status = Command::new(&cprovider_execname)
+            .args(["exec", "-u", &ssh_user, "-w", &home_dir, name])
+            .args(["git", "clone", "--", &repo_info.url, "m"])
+            .status();
+        match status {
+            Ok(clone_result) => {
+                if !clone_result.success() {
+                    return Err(Error::new(format!(
+                        "clone of {:?} failed: {}",
+                        repo_info, clone_result
+                    )));
+                }
+            }
+            Err(err) => {
+                return Err(Error::new(format!(
+                    "clone of {:?} failed: {}",
+                    repo_info, err
+                )));
+            }
+        }
+
+        if let Some(repo_command) = repo_command {
+            let path = repo_info.path.as_ref().unwrap();
+            let status = Command::new(cprovider_execname)
                 .args([
                     "exec",
-                    &name,
-                    "/bin/sh",
-                    "-c",
-                    &format!("cd $HOME && git clone {} m", repo_info.url),
+                    "-u",
+                    &ssh_user,
+                    "-w",
+                    &format!("{}/m", home_dir),
+                    name,
                 ])
+                .args(&repo_command)
                 .status();
             match status {
-                Ok(clone_result) => {
-                    if !clone_result.success() {
-                        error!("clone of {:?} failed: {}", repo_info, clone_result);
-                        instance.declare_status(InstanceStatus::InitFail);
-                        instance.send_status();
-                        return;
+                Ok(exec_result) => {
+                    if !exec_result.success() {
+                        return Err(Error::new(format!(
+                            "exec of {} failed: {}",
+                            path, exec_result
+                        )));
                     }
                 }
                 Err(err) => {
-                    error!("clone of {:?} failed: {}", repo_info, err);
-                    instance.declare_status(InstanceStatus::InitFail);
-                    instance.send_status();
-                    return;
+                    return Err(Error::new(format!("exec of {} failed: {}", path, err)));
                 }
             }
+        }
+        Ok(())
+    }
 
-            if let Some(path) = repo_info.path {
-                let status = Command::new(cprovider_execname)
-                    .args([
-                        "exec",
-                        &name,
-                        "/bin/sh",
-                        "-c",
-                        &format!("cd $HOME/m && {}", path),
-                    ])
-                    .status();
-                match status {
-                    Ok(exec_result) => {
-                        if !exec_result.success() {
-                            error!("exec of {} failed: {}", path, exec_result);
-                            instance.declare_status(InstanceStatus::InitFail);
-                            instance.send_status();
-                            return;
-                        }
-                    }
-                    Err(err) => {
-                        error!("exec of {} failed: {}", path, err);
-                        instance.declare_status(InstanceStatus::InitFail);
-                        instance.send_status();
-                        return;
+    // Run the pre-launch program on the host, with details of the instance
+    // given in environment variables
+    fn run_pre_launch(&self, program: &str, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut command = Command::new("/bin/sh");
+        command
+            .args(["-c", program])
+            .env("HARDSHARE_WDEPLOYMENT_ID", &self.wdeployment.id)
+            .env("HARDSHARE_INSTANCE_ID", self.id.as_deref().unwrap_or(""))
+            .env("HARDSHARE_CONTAINER_NAME", name);
+        run_script(&mut command, program, SCRIPT_TIMEOUT)
+    }
+
+    // Run the on-ready program on the host, with details of the instance and
+    // its tunnel given in environment variables
+    fn run_on_ready(&self, program: &str, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut command = Command::new("/bin/sh");
+        command
+            .args(["-c", program])
+            .env("HARDSHARE_WDEPLOYMENT_ID", &self.wdeployment.id)
+            .env("HARDSHARE_INSTANCE_ID", self.id.as_deref().unwrap_or(""))
+            .env("HARDSHARE_CONTAINER_NAME", name);
+        if let Some(tunnel) = self.tunnel.lock().unwrap().as_ref() {
+            command
+                .env("HARDSHARE_CONTAINER_ADDR", &tunnel.container_addr.ip)
+                .env(
+                    "HARDSHARE_CONTAINER_PORT",
+                    tunnel.container_addr.port.to_string(),
+                )
+                .env("HARDSHARE_TUNNEL_HOST", &tunnel.info.ipv4)
+                .env("HARDSHARE_TUNNEL_PORT", tunnel.info.thport.to_string());
+        }
+        run_script(&mut command, program, SCRIPT_TIMEOUT)
+    }
+
+    // Poll the ready check inside the container until it succeeds. Each
+    // attempt may use what remains of `max_duration`.
+    fn wait_ready(
+        wdeployment: &WDeployment,
+        name: &str,
+        ready_check: &str,
+        max_duration: std::time::Duration,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let execname = match wdeployment.cprovider.get_execname() {
+            Some(e) => e,
+            None => return Err(Error::new("ready check requires a container provider")),
+        };
+        let sleep_time = std::time::Duration::from_secs(2);
+        let now = std::time::Instant::now();
+        loop {
+            let mut check_command = Command::new(&execname);
+            check_command.args(["exec", name, "/bin/sh", "-c", ready_check]);
+            let remaining = max_duration.saturating_sub(now.elapsed());
+            let res = run_script(&mut check_command, ready_check, remaining);
+            match res {
+                Ok(()) => return Ok(()),
+                Err(err) => {
+                    if now.elapsed() + sleep_time >= max_duration {
+                        return Err(Error::new(format!("ready check never passed: {}", err)));
                     }
+                    debug!("ready check did not pass: {}", err);
                 }
             }
+            warn!("waiting for ready check to pass...");
+            std::thread::sleep(sleep_time);
         }
-
-        if let Err(err) = instance.start_sshtun(container_addr, &tunnelkey_path, 30) {
-            error!("{}", err);
-            instance.declare_status(InstanceStatus::InitFail);
-            instance.send_status();
-            return;
-        }
-
-        instance.declare_status(InstanceStatus::Ready);
-        instance.send_status();
     }
 
     fn terminate(&mut self) -> Result<(), String> {
@@ -586,6 +1003,7 @@ impl CurrentInstance {
                     return Err(format!("cannot terminate when status is {}", s));
                 }
                 *status = Some(InstanceStatus::Terminating);
+                self.metrics.set_status(status.as_ref());
             }
             None => {
                 return Err("terminate() called when no active instance".into());
@@ -616,64 +1034,119 @@ impl CurrentInstance {
         }
     }
 
-    fn stop_tunnel(&self) {
+    // Stop the ssh tunnel process and, for the proxy cprovider, the proxy
+    // process. Return descriptions of anything that could not be released.
+    fn stop_tunnel(&self) -> Vec<String> {
+        let mut leaks = vec![];
         let mut tunnel_ref = self.tunnel.lock().unwrap();
         if let Some(tunnel) = tunnel_ref.as_mut() {
             debug!("killing ssh tunnel process: {:?}", tunnel.proc);
-            if let Err(err) = tunnel.proc.kill() {
-                warn!("tunnel kill: : {}", err);
-            }
-            match tunnel.proc.wait() {
-                Ok(s) => {
-                    if !s.success() {
-                        warn!("exit code: {:?}", s.code());
-                    }
-                }
-                Err(err) => {
-                    error!("{}", err);
-                }
+            if let Err(err) = reap_process(&mut tunnel.proc) {
+                leaks.push(format!("ssh tunnel process {}: {}", tunnel.proc.id(), err));
             }
 
             if self.wdeployment.cprovider == CProvider::Proxy {
                 if let Some(subprocess) = tunnel.container_addr.subprocess.as_mut() {
                     debug!("killing proxy process: {:?}", subprocess);
-                    if let Err(err) = subprocess.kill() {
-                        warn!("proxy kill: : {}", err);
+                    if let Err(err) = reap_process(subprocess) {
+                        leaks.push(format!("proxy process {}: {}", subprocess.id(), err));
                     }
-                    match subprocess.wait() {
-                        Ok(s) => {
-                            if !s.success() {
-                                warn!("exit code: {:?}", s.code());
-                            }
-                        }
-                        Err(err) => {
-                            error!("{}", err);
-                        }
+                }
+
+                // Something else, e.g., a child of the proxy, may still hold the port
+                let addr = (
+                    tunnel.container_addr.ip.as_str(),
+                    tunnel.container_addr.port as u16,
+                )
+                    .to_socket_addrs()
+                    .ok()
+                    .and_then(|mut a| a.next());
+                if let Some(addr) = addr {
+                    if std::net::TcpStream::connect_timeout(&addr, TUNNEL_CONNECT_TIMEOUT).is_ok() {
+                        leaks.push(format!("proxy address {} still accepts connections", addr));
                     }
                 }
             }
         }
         *tunnel_ref = None;
+        leaks
     }
 
     fn destroy(mut instance: CurrentInstance) {
-        instance.stop_tunnel();
+        let leaks = instance.stop_tunnel();
+        if !leaks.is_empty() {
+            instance.alert_leaks(&leaks);
+        }
 
         let name = instance.get_local_name().unwrap();
-        if let Err(err) = Self::destroy_container(&instance.wdeployment, &name) {
+        if let Err(err) =
+            Self::destroy_container(&instance.wdeployment, &name, instance.id.as_deref())
+        {
             error!("Deployment fault! Caught from destroy_container(): {}", err);
             instance.declare_status(InstanceStatus::Fault);
             return;
         }
+        if let Err(err) = Self::wait_container_absent(&instance.wdeployment.cprovider, &name, 30) {
+            error!("Deployment fault! {}", err);
+            instance.declare_status(InstanceStatus::Fault);
+            return;
+        }
 
         instance.clear_status();
         instance.send_destroy_done();
     }
 
+    fn alert_leaks(&self, leaks: &[String]) {
+        let instance_id = self.id.as_deref().unwrap_or("(unknown)");
+        for leak in leaks.iter() {
+            error!(
+                "Deployment fault! Not released after instance {}: {}",
+                instance_id, leak
+            );
+        }
+        let message = format!(
+            "After instance {} on deployment {}, the following were not released: {}",
+            instance_id,
+            self.wdeployment.id,
+            leaks.join("; ")
+        );
+        let ac = api::HSAPIClient::new();
+        if let Err(err) = ac.send_alert(&self.wdeployment.id, &message) {
+            error!("failed to send alert: {}", err);
+        }
+    }
+
+    // Destroy the current instance, if any, and block until done or timeout
+    fn shutdown(&mut self, timeout: std::time::Duration) {
+        let now = std::time::Instant::now();
+        match self.status() {
+            None | Some(InstanceStatus::Fault) => (),
+            Some(InstanceStatus::Terminating) => {
+                info!("waiting for current instance to terminate before exit...");
+                while self.exists() && self.status() != Some(InstanceStatus::Fault) {
+                    if now.elapsed() > timeout {
+                        warn!("timed out waiting for current instance to terminate");
+                        return;
+                    }
+                    std::thread::sleep(std::time::Duration::from_secs(1));
+                }
+            }
+            Some(s) => {
+                if self.get_local_name().is_none() {
+                    return;
+                }
+                info!("destroying current instance (status {}) before exit", s);
+                self.declare_status(InstanceStatus::Terminating);
+                CurrentInstance::destroy(self.clone());
+            }
+        }
+    }
+
     pub fn launch_container(
         wdeployment: &WDeployment,
         name: &str,
         public_key: &str,
+        progress: &dyn Fn(LaunchPhase),
     ) -> Result<ContainerAddress, Box<dyn std::error::Error>> {
         let cprovider = wdeployment.cprovider.clone();
         let ip: String;
@@ -683,6 +1156,7 @@ impl CurrentInstance {
         if cprovider == CProvider::Docker
             || cprovider == CProvider::DockerRootless
             || cprovider == CProvider::Podman
+            || cprovider == CProvider::PodmanRootful
         {
             let cprovider_execname = cprovider.get_execname().unwrap();
             let image = match &wdeployment.image {
@@ -692,6 +1166,18 @@ impl CurrentInstance {
                 }
             };
 
+            let image_present = Command::new(&cprovider_execname)
+                .args(["image", "inspect", &image])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .map(|s| s.success())
+                .unwrap_or(false);
+            if !image_present {
+                progress(LaunchPhase::Pulling);
+            }
+            progress(LaunchPhase::Container);
+
             let mut run_command = Command::new(&cprovider_execname);
             let mut run_command = run_command.args([
                 "run",
@@ -706,6 +1192,9 @@ impl CurrentInstance {
             if cprovider != CProvider::Docker {
                 run_command = run_command.args(["--cap-add=CAP_SYS_CHROOT"]);
             }
+            if let Some(network) = &wdeployment.network {
+                run_command = run_command.args(["--network", network.as_str()]);
+            }
             run_command = run_command.args(&wdeployment.cargs);
             if cprovider == CProvider::Podman || cprovider == CProvider::DockerRootless {
                 run_command = run_command.args(["-p", "127.0.0.1::22"]);
@@ -730,7 +1219,12 @@ impl CurrentInstance {
             ip = if cprovider == CProvider::Podman || cprovider == CProvider::DockerRootless {
                 "127.0.0.1".into()
             } else {
-                match CurrentInstance::get_container_addr(&cprovider, name, 10) {
+                match CurrentInstance::get_container_addr(
+                    &cprovider,
+                    name,
+                    wdeployment.network.as_deref(),
+                    10,
+                ) {
                     Ok(a) => a,
                     Err(err) => {
                         return Err(Error::new(err));
@@ -738,7 +1232,7 @@ impl CurrentInstance {
                 }
             };
 
-            port = if cprovider == CProvider::Docker {
+            port = if cprovider == CProvider::Docker || cprovider == CProvider::PodmanRootful {
                 22
             } else {
                 match CurrentInstance::get_container_sshport(&cprovider, name) {
@@ -771,8 +1265,15 @@ impl CurrentInstance {
                 }
             };
 
+            progress(LaunchPhase::Keys);
+            let home_dir = CurrentInstance::get_container_home(
+                &cprovider_execname,
+                name,
+                wdeployment.get_ssh_user(),
+            )?;
+            let ssh_dir = format!("{}/.ssh", home_dir);
             let mkdir_result = Command::new(&cprovider_execname)
-                .args(["exec", name, "/bin/mkdir", "-p", "/root/.ssh"])
+                .args(["exec", name, "/bin/mkdir", "-p", &ssh_dir])
                 .status()
                 .unwrap();
             if !mkdir_result.success() {
@@ -786,7 +1287,7 @@ impl CurrentInstance {
                 .args([
                     "cp",
                     public_key_file.path().to_str().unwrap(),
-                    &(name.to_string() + ":/root/.ssh/authorized_keys"),
+                    &format!("{}:{}/authorized_keys", name, ssh_dir),
                 ])
                 .status()
                 .unwrap();
@@ -794,14 +1295,12 @@ impl CurrentInstance {
                 return Err(Error::new(format!("cp command failed: {:?}", cp_result)));
             }
 
+            let owner = match &wdeployment.ssh_user {
+                Some(u) => format!("{}:", u),
+                None => "0:0".into(),
+            };
             let chown_result = Command::new(&cprovider_execname)
-                .args([
-                    "exec",
-                    name,
-                    "/bin/chown",
-                    "0:0",
-                    "/root/.ssh/authorized_keys",
-                ])
+                .args(["exec", name, "/bin/chown", "-R", &owner, &ssh_dir])
                 .status()
                 .unwrap();
             if !chown_result.success() {
@@ -811,6 +1310,7 @@ impl CurrentInstance {
                 )));
             }
 
+            progress(LaunchPhase::HostKey);
             hostkey = match CurrentInstance::get_container_hostkey(&cprovider, name, 20) {
                 Ok(k) => k,
                 Err(err) => {
@@ -818,28 +1318,32 @@ impl CurrentInstance {
                 }
             };
 
+            if !wdeployment.init_inside.is_empty() {
+                progress(LaunchPhase::Scripts);
+            }
             for script in wdeployment.init_inside.iter() {
-                let status = Command::new(&cprovider_execname)
-                    .args(["exec", name, "/bin/sh", "-c", script])
-                    .status();
-                match status {
-                    Ok(script_result) => {
-                        if !script_result.success() {
-                            return Err(Error::new(format!(
-                                "`{script}` failed: {}",
-                                script_result
-                            )));
-                        }
-                    }
-                    Err(err) => {
-                        return Err(Error::new(format!("`{script}` failed: {}", err)));
-                    }
-                }
+                let mut script_command = Command::new(&cprovider_execname);
+                script_command.args(["exec", name, "/bin/sh", "-c", script]);
+                run_script(&mut script_command, script, SCRIPT_TIMEOUT)?;
             }
         } else if cprovider == CProvider::Lxd {
             return Err(Error::new("lxd cprovider not implemented yet"));
         } else if cprovider == CProvider::Proxy {
-            let res = CurrentInstance::start_proxy(&wdeployment.cargs, 5)?;
+            let mut cargs = wdeployment.cargs.clone();
+            if wdeployment.idle_timeout_secs.is_some() && observes_activity(wdeployment) {
+                if let Some(path) = get_activity_file_path(&wdeployment.id) {
+                    // Activity of a previous instance must not count toward this one
+                    if let Err(err) = std::fs::remove_file(&path) {
+                        if err.kind() != std::io::ErrorKind::NotFound {
+                            warn!("failed to remove activity file: {}", err);
+                        }
+                    }
+                    cargs.push("--activity-file".into());
+                    cargs.push(path.to_string_lossy().to_string());
+                }
+            }
+            progress(LaunchPhase::Container);
+            let res = CurrentInstance::start_proxy(&cargs, 5)?;
             port = res.1;
             ip = "127.0.0.1".into();
             hostkey = "".into();
@@ -859,10 +1363,12 @@ impl CurrentInstance {
     pub fn destroy_container(
         wdeployment: &WDeployment,
         name: &str,
+        instance_id: Option<&str>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         if wdeployment.cprovider == CProvider::Docker
             || wdeployment.cprovider == CProvider::DockerRootless
             || wdeployment.cprovider == CProvider::Podman
+            || wdeployment.cprovider == CProvider::PodmanRootful
         {
             let cprovider_execname = wdeployment.cprovider.get_execname().unwrap();
             let mut run_command = Command::new(cprovider_execname);
@@ -883,39 +1389,445 @@ impl CurrentInstance {
             }
         }
 
+        // Run every terminate script, even if some fail, so that as much as
+        // possible is cleaned up
+        let mut failures = 0;
         for script in wdeployment.terminate.iter() {
-            match Command::new("/bin/sh").args(["-c", script]).status() {
-                Ok(script_result) => {
-                    if !script_result.success() {
-                        return Err(Error::new(format!("`{script}` failed: {}", script_result)));
-                    }
-                }
-                Err(err) => {
-                    return Err(Error::new(format!("`{script}` failed: {}", err)));
-                }
+            let mut script_command = Command::new("/bin/sh");
+            script_command
+                .args(["-c", script])
+                .env("HARDSHARE_WDEPLOYMENT_ID", &wdeployment.id)
+                .env("HARDSHARE_INSTANCE_ID", instance_id.unwrap_or(""))
+                .env("HARDSHARE_CONTAINER_NAME", name);
+            if let Err(err) = run_script(&mut script_command, script, SCRIPT_TIMEOUT) {
+                error!("terminate program failed: {}", err);
+                failures += 1;
             }
         }
+        if failures > 0 {
+            return Err(Error::new(format!(
+                "{} of {} terminate programs failed",
+                failures,
+                wdeployment.terminate.len()
+            )));
+        }
 
         Ok(())
     }
+
+    // Names of containers on this host that were created for instances of
+    // the workspace deployment, whether or not they are still running
+    pub fn list_local_containers(
+        wdeployment: &WDeployment,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        if wdeployment.cprovider == CProvider::Lxd {
+            return Err(Error::new("lxd cprovider not implemented yet"));
+        }
+        let cprovider_execname = match wdeployment.cprovider.get_execname() {
+            Some(e) => e,
+            None => return Ok(vec![]),
+        };
+        let output = Command::new(&cprovider_execname)
+            .args(["ps", "-a", "--format", "{{.Names}}"])
+            .output()?;
+        if !output.status.success() {
+            return Err(Error::new(format!(
+                "{} ps failed: {}",
+                cprovider_execname,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from_utf8(output.stdout)?
+            .lines()
+            .map(|x| x.trim())
+            .filter(|x| is_local_name(&wdeployment.container_name, x))
+            .map(|x| x.to_string())
+            .collect())
+    }
 } // impl CurrentInstance
 
+// Whether a container name is of the form given by generate_local_name()
+pub fn is_local_name(base_name: &str, name: &str) -> bool {
+    match name.strip_prefix(base_name) {
+        Some(suffix) => !suffix.is_empty() && suffix.chars().all(|c| c.is_ascii_digit()),
+        None => false,
+    }
+}
+
+// Maximum duration of each init_inside or terminate script
+const SCRIPT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(300);
+
+// Run the command of a script, killing it if it does not finish before the
+// timeout. If it fails, the error includes the captured stdout and stderr.
+fn run_script(
+    command: &mut Command,
+    script: &str,
+    timeout: std::time::Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut child = match command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(c) => c,
+        Err(err) => return Err(Error::new(format!("`{script}` failed: {}", err))),
+    };
+
+    // Read output in separate threads so that the child cannot block on a
+    // full pipe while we wait for it
+    let read_all = |mut r: Box<dyn Read + Send>| {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = r.read_to_end(&mut buf);
+            let _ = tx.send(buf);
+        });
+        rx
+    };
+    let stdout_rx = read_all(Box::new(child.stdout.take().unwrap()));
+    let stderr_rx = read_all(Box::new(child.stderr.take().unwrap()));
+
+    let start = std::time::Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(s)) => break Some(s),
+            Ok(None) => {
+                if start.elapsed() > timeout {
+                    warn!(
+                        "killing `{}` after timeout of {} s",
+                        script,
+                        timeout.as_secs()
+                    );
+                    if let Err(err) = child.kill() {
+                        warn!("script kill: {}", err);
+                    }
+                    if let Err(err) = child.wait() {
+                        error!("{}", err);
+                    }
+                    break None;
+                }
+                thread::sleep(std::time::Duration::from_millis(100));
+            }
+            Err(err) => return Err(Error::new(format!("`{script}` failed: {}", err))),
+        }
+    };
+
+    let reason = match status {
+        Some(s) => {
+            if s.success() {
+                return Ok(());
+            }
+            format!("{}", s)
+        }
+        None => format!("timed out after {} s", timeout.as_secs()),
+    };
+
+    // Processes started by the script may hold the pipes open, so do not
+    // wait long for the output to be closed
+    let grace = std::time::Duration::from_secs(1);
+    let stdout =
+        String::from_utf8_lossy(&stdout_rx.recv_timeout(grace).unwrap_or_default()).to_string();
+    let stderr =
+        String::from_utf8_lossy(&stderr_rx.recv_timeout(grace).unwrap_or_default()).to_string();
+    Err(Error::new(format!(
+        "`{script}` failed: {}\nstdout:\n{}\nstderr:\n{}",
+        reason, stdout, stderr
+    )))
+}
+
+// Period of checking whether the current instance exceeded max_session_secs
+// or idle_timeout_secs
+const SESSION_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+// Whether traffic of instances is observable, as required for idle_timeout_secs.
+// Only rrhttp reports activity, so only the proxy cprovider with rrhttp qualifies.
+pub fn observes_activity(wdeployment: &WDeployment) -> bool {
+    wdeployment.cprovider == CProvider::Proxy
+        && !wdeployment.cargs.is_empty()
+        && wdeployment.cargs[0] == "rrhttp"
+}
+
+// File that rrhttp writes upon each forwarded request; its modification time
+// is the most recent activity
+fn get_activity_file_path(wdid: &str) -> Option<std::path::PathBuf> {
+    mgmt::get_base_path().map(|base| base.join(format!("activity-{}", wdid)))
+}
+
+// Duration since the most recent activity, or since `ready_since` if there was none after it
+fn idle_duration(wdid: &str, ready_since: std::time::Instant) -> std::time::Duration {
+    let since_ready = ready_since.elapsed();
+    let modified = get_activity_file_path(wdid)
+        .and_then(|path| std::fs::metadata(path).ok())
+        .and_then(|md| md.modified().ok())
+        .and_then(|mtime| mtime.elapsed().ok());
+    match modified {
+        Some(since_activity) => std::cmp::min(since_ready, since_activity),
+        None => since_ready,
+    }
+}
+
+// How often to check that the ssh tunnel of a READY instance is working
+const TUNNEL_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+// Time to wait for the container to accept a connection during tunnel checks
+const TUNNEL_CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+// Number of attempts to restart a broken tunnel before declaring a fault
+const MAX_TUNNEL_RESTARTS: u32 = 3;
+
+// Health of the ssh tunnel of the current instance
+#[derive(Default)]
+struct TunnelWatch {
+    last_check: Option<std::time::Instant>,
+    restarts: u32,
+}
+
+// Periodically check the ssh tunnel of a READY instance. If it is broken,
+// restart the ssh process; after MAX_TUNNEL_RESTARTS, declare a fault and alert.
+fn watch_tunnel(
+    current_instance: &mut CurrentInstance,
+    wdeployment: &WDeployment,
+    watch: &mut TunnelWatch,
+) {
+    if current_instance.status() != Some(InstanceStatus::Ready) {
+        *watch = TunnelWatch::default();
+        return;
+    }
+    if let Some(last_check) = watch.last_check {
+        if last_check.elapsed() < TUNNEL_CHECK_INTERVAL {
+            return;
+        }
+    }
+    watch.last_check = Some(std::time::Instant::now());
+    let err = match current_instance.check_tunnel() {
+        Ok(()) => {
+            // Only consecutive failures count toward MAX_TUNNEL_RESTARTS
+            watch.restarts = 0;
+            return;
+        }
+        Err(err) => err,
+    };
+    let instance_id = current_instance
+        .id
+        .as_deref()
+        .unwrap_or("(unknown)")
+        .to_string();
+    warn!("tunnel of instance {} is broken: {}", instance_id, err);
+
+    if watch.restarts < MAX_TUNNEL_RESTARTS {
+        watch.restarts += 1;
+        current_instance.metrics.count_tunnel_restart();
+        match current_instance.restart_sshtun() {
+            Ok(()) => {
+                info!(
+                    "restarted tunnel of instance {} (attempt {} of {})",
+                    instance_id, watch.restarts, MAX_TUNNEL_RESTARTS
+                );
+                return;
+            }
+            Err(err) => error!("failed to restart tunnel: {}", err),
+        }
+    }
+
+    error!(
+        "Deployment fault! Tunnel of instance {} could not be recovered",
+        instance_id
+    );
+    current_instance.declare_status(InstanceStatus::Fault);
+    current_instance.send_status();
+    let message = format!(
+        "The ssh tunnel of instance {} on deployment {} failed and could not be restarted: {}",
+        instance_id, wdeployment.id, err
+    );
+    let ac = api::HSAPIClient::new();
+    if let Err(err) = ac.send_alert(&wdeployment.id, &message) {
+        error!("failed to send alert: {}", err);
+    }
+}
+
+// Terminate the current instance if it has been READY for longer than
+// max_session_secs, or if it has been idle for longer than idle_timeout_secs.
+// `ready_since` is the first instant it was observed READY.
+fn enforce_session_limits(
+    current_instance: &mut CurrentInstance,
+    wdeployment: &WDeployment,
+    ready_since: &mut Option<std::time::Instant>,
+) {
+    if current_instance.status() != Some(InstanceStatus::Ready) {
+        *ready_since = None;
+        return;
+    }
+    let since = *ready_since.get_or_insert_with(std::time::Instant::now);
+    let mut reason = None;
+    if let Some(max_session_secs) = wdeployment.max_session_secs {
+        if since.elapsed() >= std::time::Duration::from_secs(max_session_secs) {
+            reason = Some(format!(
+                "maximum session duration of {} s",
+                max_session_secs
+            ));
+        }
+    }
+    if let Some(idle_timeout_secs) = wdeployment.idle_timeout_secs {
+        if reason.is_none()
+            && observes_activity(wdeployment)
+            && idle_duration(&wdeployment.id, since)
+                >= std::time::Duration::from_secs(idle_timeout_secs)
+        {
+            reason = Some(format!("no activity for {} s", idle_timeout_secs));
+        }
+    }
+    if let Some(reason) = reason {
+        warn!(
+            "forcing termination of instance {} after {}",
+            current_instance.id.as_deref().unwrap_or("(unknown)"),
+            reason
+        );
+        *ready_since = None;
+        if let Err(err) = current_instance.terminate() {
+            error!("forced termination failed: {}", err);
+        }
+    }
+}
+
+// Minimum time between alerts about launches rejected due to lockout
+const LOCKOUT_ALERT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(900);
+
+// Rate limiting of alerts about launches rejected due to lockout, so that a
+// flood of launch attempts does not send a flood of alerts
+#[derive(Default)]
+struct LockoutAlerts {
+    last_sent: Option<std::time::Instant>,
+    suppressed: u32,
+}
+
+impl LockoutAlerts {
+    // If an alert should be sent now, return the number of rejections since
+    // the previous alert that were not reported.
+    fn try_send(&mut self, now: std::time::Instant) -> Option<u32> {
+        if let Some(last_sent) = self.last_sent {
+            if now.duration_since(last_sent) < LOCKOUT_ALERT_INTERVAL {
+                self.suppressed += 1;
+                return None;
+            }
+        }
+        self.last_sent = Some(now);
+        let suppressed = self.suppressed;
+        self.suppressed = 0;
+        Some(suppressed)
+    }
+}
+
+fn reject_if_locked_out(
+    wdeployment: &WDeployment,
+    instance_id: &str,
+    alerts: &mut LockoutAlerts,
+) -> bool {
+    let ac = api::HSAPIClient::new();
+    match ac.is_locked_out(&wdeployment.id) {
+        Ok(false) => return false,
+        Ok(true) => {}
+        Err(err) => {
+            warn!(
+                "failed to determine whether deployment is locked out: {}",
+                err
+            );
+            return false;
+        }
+    }
+    warn!(
+        "rejecting launch of instance {} because deployment is locked out",
+        instance_id
+    );
+    if let Some(suppressed) = alerts.try_send(std::time::Instant::now()) {
+        let mut message = format!(
+            "Launch of instance {} was rejected because deployment {} is locked out.",
+            instance_id, wdeployment.id
+        );
+        if suppressed > 0 {
+            message.push_str(&format!(
+                " {} other launches were rejected since the previous alert.",
+                suppressed
+            ));
+        }
+        if let Err(err) = ac.send_alert(&wdeployment.id, &message) {
+            error!("failed to send alert: {}", err);
+        }
+    }
+    true
+}
+
+// Whether the instance has reached a terminal state: destroyed, or failed
+fn instance_is_done(current_instance: &CurrentInstance) -> bool {
+    matches!(
+        current_instance.status(),
+        None | Some(InstanceStatus::InitFail) | Some(InstanceStatus::Fault)
+    )
+}
+
 pub fn cworker(
     wsclient_req: mpsc::Receiver<CWorkerCommand>,
     main_actor_addr: Addr<api::MainActor>,
     wdeployment: Arc<WDeployment>,
+    metrics: Arc<Metrics>,
+    once: bool,
 ) {
     let mut current_instance = CurrentInstance::new(&wdeployment, Some(&main_actor_addr));
+    current_instance.metrics = metrics;
+    let mut lockout_alerts = LockoutAlerts::default();
+    let mut tunnel_watch = TunnelWatch::default();
+    let mut ready_since = None;
+    if wdeployment.idle_timeout_secs.is_some() && !observes_activity(&wdeployment) {
+        warn!("idle timeout requires the proxy cprovider with rrhttp; ignoring it");
+    }
+    let session_limits =
+        wdeployment.max_session_secs.is_some() || wdeployment.idle_timeout_secs.is_some();
+
+    // With `once`, stop the daemon after the first launched instance is done
+    let mut launched = false;
+    let mut stop_requested = false;
 
     loop {
-        let req = match wsclient_req.recv() {
-            Ok(m) => m,
-            Err(_) => return,
+        if once && launched && !stop_requested && instance_is_done(&current_instance) {
+            info!("instance is done; stopping daemon");
+            main_actor_addr.do_send(api::ClientWorkerMessage {
+                mtype: CWorkerMessageType::StopDaemon,
+                body: None,
+            });
+            stop_requested = true;
+        }
+        let req = if session_limits || (once && !stop_requested) || current_instance.exists() {
+            enforce_session_limits(&mut current_instance, &wdeployment, &mut ready_since);
+            watch_tunnel(&mut current_instance, &wdeployment, &mut tunnel_watch);
+            match wsclient_req.recv_timeout(SESSION_POLL_INTERVAL) {
+                Ok(m) => m,
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            }
+        } else {
+            match wsclient_req.recv() {
+                Ok(m) => m,
+                Err(_) => return,
+            }
         };
         debug!("cworker rx: {:?}", req);
 
         match req.command {
             CWorkerCommandType::InstanceLaunch => {
+                if reject_if_locked_out(&wdeployment, &req.instance_id, &mut lockout_alerts) {
+                    main_actor_addr.do_send(api::ClientWorkerMessage {
+                        mtype: CWorkerMessageType::WsSend,
+                        body: Some(
+                            serde_json::to_string(&json!({
+                                "v": 0,
+                                "cmd": "NACK",
+                                "mi": req.message_id,
+                            }))
+                            .unwrap(),
+                        ),
+                    });
+                    continue;
+                }
                 match current_instance.init(
                     &req.instance_id,
                     req.conntype.unwrap(),
@@ -923,6 +1835,7 @@ pub fn cworker(
                     req.repo_args,
                 ) {
                     Ok(_) => {
+                        launched = true;
                         main_actor_addr.do_send(api::ClientWorkerMessage {
                             mtype: CWorkerMessageType::WsSend,
                             body: Some(
@@ -1041,6 +1954,15 @@ pub fn cworker(
                     }
                 };
             }
+            CWorkerCommandType::Shutdown => {
+                current_instance.shutdown(std::time::Duration::from_secs(30));
+                if let Some(done) = req.done {
+                    if done.unbounded_send(()).is_err() {
+                        warn!("shutdown requester is gone");
+                    }
+                }
+                return;
+            }
             CWorkerCommandType::CreateSshTunDone => {
                 if current_instance.exists() {
                     if let Err(err) = current_instance.handle_response(&req) {
@@ -1060,6 +1982,122 @@ enum CWorkerCommandType {
     InstanceDestroy,
     InstanceStatus,
     CreateSshTunDone,
+    Shutdown,
+}
+
+// Address of a container in output from `inspect`. The legacy top-level field
+// is empty on IPv6-only or custom networks, so otherwise take the first
+// address under Networks, preferring IPv4. If `network` is given, only
+// addresses on it are considered.
+fn addr_from_inspect(inspect: &serde_json::Value, network: Option<&str>) -> Option<String> {
+    let settings = &inspect[0]["NetworkSettings"];
+    let nonempty =
+        |v: &serde_json::Value| v.as_str().filter(|a| !a.is_empty()).map(|a| a.to_string());
+    if let Some(network) = network {
+        let entry = &settings["Networks"][network];
+        return nonempty(&entry["IPAddress"]).or_else(|| nonempty(&entry["GlobalIPv6Address"]));
+    }
+    if let Some(addr) = nonempty(&settings["IPAddress"]) {
+        return Some(addr);
+    }
+    if let Some(networks) = settings["Networks"].as_object() {
+        for field in ["IPAddress", "GlobalIPv6Address"] {
+            for network in networks.values() {
+                if let Some(addr) = nonempty(&network[field]) {
+                    return Some(addr);
+                }
+            }
+        }
+    }
+    nonempty(&settings["GlobalIPv6Address"])
+}
+
+// Host part of an ssh forward specification, in which IPv6 addresses
+// must be enclosed in brackets
+fn forward_host(ip: &str) -> String {
+    if ip.contains(':') {
+        format!("[{}]", ip)
+    } else {
+        ip.to_string()
+    }
+}
+
+// Kill the process, if it is still running, and wait for it to exit
+fn reap_process(proc: &mut std::process::Child) -> Result<(), String> {
+    if let Ok(Some(s)) = proc.try_wait() {
+        debug!("process {} already exited: {}", proc.id(), s);
+        return Ok(());
+    }
+    if let Err(err) = proc.kill() {
+        warn!("kill: {}", err);
+    }
+    match proc.wait() {
+        Ok(s) => {
+            if !s.success() {
+                debug!("exit code: {:?}", s.code());
+            }
+        }
+        Err(err) => return Err(format!("failed to wait for exit: {}", err)),
+    }
+    match proc.try_wait() {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err("still running".into()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+// Arguments of the ssh process that forwards the tunnel to the container
+fn sshtun_args(
+    container_addr: &ContainerAddress,
+    tunnelkey_path: &str,
+    tunnelinfo: &TunnelInfo,
+    options: &mgmt::SshTunnelOptions,
+) -> Vec<String> {
+    let mut args: Vec<String> = vec![
+        "-o".into(),
+        format!("ServerAliveInterval={}", options.server_alive_interval),
+        "-o".into(),
+        format!("ServerAliveCountMax={}", options.server_alive_count_max),
+    ];
+    if let Some(t) = options.connect_timeout {
+        args.extend(["-o".into(), format!("ConnectTimeout={}", t)]);
+    }
+    if options.compression {
+        args.push("-C".into());
+    }
+    args.extend([
+        "-o".into(),
+        "StrictHostKeyChecking=no".into(),
+        "-o".into(),
+        "ExitOnForwardFailure=yes".into(),
+        "-T".into(),
+        "-N".into(),
+        "-R".into(),
+        format!(
+            ":{}:{}:{}",
+            tunnelinfo.rport,
+            forward_host(&container_addr.ip),
+            container_addr.port
+        ),
+        "-i".into(),
+        tunnelkey_path.into(),
+        "-p".into(),
+        format!("{thport}", thport = tunnelinfo.thport),
+        format!(
+            "{thuser}@{addr}",
+            thuser = tunnelinfo.thuser,
+            addr = tunnelinfo.ipv4
+        ),
+    ]);
+    args
+}
+
+// Port on the tunnel host to which the container is forwarded, if the server
+// does not direct otherwise
+const DEFAULT_TUNNEL_REMOTE_PORT: Port = 2210;
+
+fn default_tunnel_remote_port() -> Port {
+    DEFAULT_TUNNEL_REMOTE_PORT
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -1069,6 +2107,75 @@ pub struct TunnelInfo {
     port: Port,
     thport: Port,
     thuser: String,
+
+    // Remote bind port of the reverse forward on the tunnel host
+    #[serde(default = "default_tunnel_remote_port")]
+    rport: Port,
+}
+
+const REPO_URL_SCHEMES: [&str; 3] = ["https://", "git://", "ssh://"];
+
+// Check that a repository URL from an instance launch request is safe to give
+// to `git clone`
+fn check_repo_url(url: &str) -> Result<(), String> {
+    if !REPO_URL_SCHEMES
+        .iter()
+        .any(|scheme| url.starts_with(scheme))
+    {
+        return Err(format!(
+            "repository URL must begin with one of {}: {}",
+            REPO_URL_SCHEMES.join(", "),
+            url
+        ));
+    }
+    let rest = url.split_once("://").unwrap().1;
+    if rest.is_empty() || rest.starts_with('/') {
+        return Err(format!("repository URL has no host: {}", url));
+    }
+    if rest.starts_with('-') {
+        return Err(format!(
+            "repository URL host must not begin with '-': {}",
+            url
+        ));
+    }
+    if url.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(format!(
+            "repository URL must not contain whitespace or control characters: {:?}",
+            url
+        ));
+    }
+    Ok(())
+}
+
+// Split the command to run in a cloned repository into a program and its
+// arguments. Only plain words are accepted, so anything that would need a
+// shell to interpret (pipes, substitutions, redirects, ...) is rejected.
+fn parse_repo_command(path: &str) -> Result<Vec<String>, String> {
+    let argv: Vec<String> = path.split_whitespace().map(|x| x.to_string()).collect();
+    if argv.is_empty() {
+        return Err("repository command is empty".into());
+    }
+    if let Some(c) = path.chars().find(|c| {
+        !(c.is_ascii_alphanumeric() || c.is_ascii_whitespace() || "-_./=:,+@%".contains(*c))
+    }) {
+        return Err(format!(
+            "repository command must not contain {:?}: {}",
+            c, path
+        ));
+    }
+    if argv[0].starts_with('-') {
+        return Err(format!(
+            "repository command must begin with a program: {}",
+            path
+        ));
+    }
+    if argv[0].starts_with('/') || argv[0].split('/').any(|part| part == "..") {
+        return Err(format!(
+            "repository command must be in the repository or on PATH: {}",
+            path
+        ));
+    }
+    Ok(argv)
 }
 
 #[derive(Debug, Clone)]
@@ -1077,6 +2184,15 @@ pub struct RepoInfo {
     path: Option<String>,
 }
 
+impl RepoInfo {
+    pub fn new(url: &str, path: Option<&str>) -> RepoInfo {
+        RepoInfo {
+            url: url.to_string(),
+            path: path.map(|x| x.to_string()),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct CWorkerCommand {
     command: CWorkerCommandType,
@@ -1086,6 +2202,9 @@ pub struct CWorkerCommand {
     tunnelinfo: Option<TunnelInfo>,
     message_id: Option<String>,
     repo_args: Option<RepoInfo>,
+
+    // Notified after Shutdown is handled
+    done: Option<futures::channel::mpsc::UnboundedSender<()>>,
 }
 
 impl CWorkerCommand {
@@ -1098,6 +2217,7 @@ impl CWorkerCommand {
             tunnelinfo: None,
             message_id: Some(String::from(message_id)),
             repo_args: None,
+            done: None,
         }
     }
 
@@ -1109,10 +2229,7 @@ impl CWorkerCommand {
         repo_url: Option<&str>,
         repo_path: Option<&str>,
     ) -> CWorkerCommand {
-        let repo_args = repo_url.map(|u| RepoInfo {
-            url: u.to_string(),
-            path: repo_path.map(|x| x.to_string()),
-        });
+        let repo_args = repo_url.map(|u| RepoInfo::new(u, repo_path));
         CWorkerCommand {
             command: CWorkerCommandType::InstanceLaunch,
             instance_id: String::from(instance_id),
@@ -1121,6 +2238,7 @@ impl CWorkerCommand {
             tunnelinfo: None,
             message_id: Some(String::from(message_id)),
             repo_args,
+            done: None,
         }
     }
 
@@ -1133,6 +2251,7 @@ impl CWorkerCommand {
             tunnelinfo: None,
             message_id: Some(String::from(message_id)),
             repo_args: None,
+            done: None,
         }
     }
 
@@ -1149,6 +2268,20 @@ impl CWorkerCommand {
             tunnelinfo: Some(tunnelinfo.clone()),
             message_id: Some(String::from(message_id)),
             repo_args: None,
+            done: None,
+        }
+    }
+
+    pub fn shutdown(done: futures::channel::mpsc::UnboundedSender<()>) -> Self {
+        Self {
+            command: CWorkerCommandType::Shutdown,
+            instance_id: String::new(),
+            conntype: None,
+            publickey: None,
+            tunnelinfo: None,
+            message_id: None,
+            repo_args: None,
+            done: Some(done),
         }
     }
 }
@@ -1156,14 +2289,18 @@ impl CWorkerCommand {
 #[derive(PartialEq, Debug, Clone)]
 pub enum CWorkerMessageType {
     WsSend,
+    StopDaemon,
 }
 
 #[cfg(test)]
 mod tests {
     use std::sync::{atomic, Arc};
 
-    use super::{ConnType, CurrentInstance};
-    use crate::mgmt::WDeployment;
+    use super::{addr_from_inspect, sshtun_args, ContainerAddress, SshTunnel, TunnelInfo};
+    use super::{check_repo_url, is_local_name, parse_repo_command, progress_message, LaunchPhase};
+    use super::{instance_is_done, LockoutAlerts, LOCKOUT_ALERT_INTERVAL};
+    use super::{render_metrics, run_script, ConnType, CurrentInstance, InstanceStatus, Metrics};
+    use crate::mgmt::{SshTunnelOptions, WDeployment};
 
     fn create_example_wdeployment() -> WDeployment {
         serde_json::from_str(
@@ -1218,7 +2355,7 @@ mod tests {
         abort_launch.store(true, atomic::Ordering::Relaxed);
         thread_handle.join().unwrap();
         let name = current_instance.get_local_name().unwrap();
-        if let Err(err) = CurrentInstance::destroy_container(&wdeployment, &name) {
+        if let Err(err) = CurrentInstance::destroy_container(&wdeployment, &name, None) {
             panic!("{}", err);
         }
     }
@@ -1233,4 +2370,403 @@ mod tests {
         let second = instance.generate_local_name("base");
         assert_ne!(first, second);
     }
+
+    #[test]
+    fn detect_broken_tunnel() {
+        let wdeployment = create_example_wdeployment();
+        let instance = CurrentInstance::new(&Arc::new(wdeployment), None);
+        assert!(instance.check_tunnel().is_ok());
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let proc = std::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+        *instance.tunnel.lock().unwrap() = Some(SshTunnel {
+            proc,
+            container_addr: ContainerAddress {
+                ip: "127.0.0.1".into(),
+                port: port as u32,
+                hostkey: "".into(),
+                subprocess: None,
+            },
+            info: TunnelInfo {
+                hostkey: "".into(),
+                ipv4: "127.0.0.1".into(),
+                port: 2210,
+                thport: 22,
+                thuser: "hs".into(),
+                rport: 2210,
+            },
+        });
+        assert!(instance.check_tunnel().is_ok());
+
+        // Nothing can listen on port 0, whereas the port of a closed listener
+        // may be taken by another test running in parallel
+        if let Some(tunnel) = instance.tunnel.lock().unwrap().as_mut() {
+            tunnel.container_addr.port = 0;
+        }
+        let err = instance.check_tunnel().unwrap_err();
+        assert!(err.contains("does not accept connections"));
+
+        assert!(instance.stop_tunnel().is_empty());
+        assert!(instance.check_tunnel().is_ok());
+    }
+
+    #[test]
+    fn tunnel_remote_port() {
+        let container_addr = ContainerAddress {
+            ip: "172.17.0.2".into(),
+            port: 22,
+            hostkey: "".into(),
+            subprocess: None,
+        };
+        let tunnelinfo: TunnelInfo = serde_json::from_str(
+            r#"{"hostkey": "k", "ipv4": "192.0.2.1", "port": 2222, "thport": 2200, "thuser": "hs"}"#,
+        )
+        .unwrap();
+        let options = SshTunnelOptions::default();
+        let args = sshtun_args(&container_addr, "/tmp/tun", &tunnelinfo, &options);
+        assert!(args.contains(&":2210:172.17.0.2:22".to_string()));
+
+        let tunnelinfo: TunnelInfo = serde_json::from_str(
+            r#"{"hostkey": "k", "ipv4": "192.0.2.1", "port": 2222, "thport": 2200, "thuser": "hs", "rport": 2211}"#,
+        )
+        .unwrap();
+        let args = sshtun_args(&container_addr, "/tmp/tun", &tunnelinfo, &options);
+        assert!(args.contains(&":2211:172.17.0.2:22".to_string()));
+    }
+
+    #[test]
+    fn container_addr_from_inspect() {
+        let legacy = json!([{"NetworkSettings": {
+            "IPAddress": "172.17.0.2",
+            "Networks": {"bridge": {"IPAddress": "172.17.0.2"}},
+        }}]);
+        assert_eq!(addr_from_inspect(&legacy, None).unwrap(), "172.17.0.2");
+
+        let custom = json!([{"NetworkSettings": {
+            "IPAddress": "",
+            "Networks": {"lab": {"IPAddress": "10.1.0.5", "GlobalIPv6Address": "fd00::5"}},
+        }}]);
+        assert_eq!(addr_from_inspect(&custom, None).unwrap(), "10.1.0.5");
+
+        let ipv6_only = json!([{"NetworkSettings": {
+            "IPAddress": "",
+            "Networks": {"lab6": {"IPAddress": "", "GlobalIPv6Address": "fd00::5"}},
+        }}]);
+        assert_eq!(addr_from_inspect(&ipv6_only, None).unwrap(), "fd00::5");
+
+        let pending = json!([{"NetworkSettings": {
+            "IPAddress": "",
+            "Networks": {"bridge": {"IPAddress": ""}},
+        }}]);
+        assert!(addr_from_inspect(&pending, None).is_none());
+
+        let macvlan = json!([{"NetworkSettings": {
+            "IPAddress": "",
+            "Networks": {
+                "bridge": {"IPAddress": "172.17.0.2"},
+                "lan": {"IPAddress": "192.168.1.40"},
+            },
+        }}]);
+        assert_eq!(
+            addr_from_inspect(&macvlan, Some("lan")).unwrap(),
+            "192.168.1.40"
+        );
+        assert!(addr_from_inspect(&macvlan, Some("other")).is_none());
+
+        let container_addr = ContainerAddress {
+            ip: "fd00::5".into(),
+            port: 22,
+            hostkey: "".into(),
+            subprocess: None,
+        };
+        let tunnelinfo: TunnelInfo = serde_json::from_str(
+            r#"{"hostkey": "k", "ipv4": "192.0.2.1", "port": 2222, "thport": 2200, "thuser": "hs"}"#,
+        )
+        .unwrap();
+        let args = sshtun_args(
+            &container_addr,
+            "/tmp/tun",
+            &tunnelinfo,
+            &SshTunnelOptions::default(),
+        );
+        assert!(args.contains(&":2210:[fd00::5]:22".to_string()));
+    }
+
+    #[test]
+    fn tunnel_ssh_options() {
+        let container_addr = ContainerAddress {
+            ip: "172.17.0.2".into(),
+            port: 22,
+            hostkey: "".into(),
+            subprocess: None,
+        };
+        let tunnelinfo: TunnelInfo = serde_json::from_str(
+            r#"{"hostkey": "k", "ipv4": "192.0.2.1", "port": 2222, "thport": 2200, "thuser": "hs"}"#,
+        )
+        .unwrap();
+
+        let options = SshTunnelOptions::default();
+        let args = sshtun_args(&container_addr, "/tmp/tun", &tunnelinfo, &options);
+        assert!(args.contains(&"ServerAliveInterval=10".to_string()));
+        assert!(!args.iter().any(|a| a.starts_with("ConnectTimeout")));
+        assert!(!args.contains(&"-C".to_string()));
+
+        let mut options = SshTunnelOptions::default();
+        options.set("server-alive-count-max=6").unwrap();
+        options.set("connect-timeout=15").unwrap();
+        options.set("compression=yes").unwrap();
+        let args = sshtun_args(&container_addr, "/tmp/tun", &tunnelinfo, &options);
+        assert!(args.contains(&"ServerAliveCountMax=6".to_string()));
+        assert!(args.contains(&"ConnectTimeout=15".to_string()));
+        assert!(args.contains(&"-C".to_string()));
+        assert_eq!(args.last().unwrap(), "hs@192.0.2.1");
+
+        options.set("connect-timeout=-").unwrap();
+        assert_eq!(options.connect_timeout, None);
+        assert!(options.set("server-alive-interval=0").is_err());
+        assert!(options.set("compression=maybe").is_err());
+        assert!(options.set("cipher=aes").is_err());
+        assert!(options.set("compression").is_err());
+    }
+
+    #[test]
+    fn stop_tunnel_reports_held_proxy_port() {
+        let wdeployment = create_example_proxy_wdeployment();
+        let instance = CurrentInstance::new(&Arc::new(wdeployment), None);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let proc = std::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+        let subprocess = std::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+        *instance.tunnel.lock().unwrap() = Some(SshTunnel {
+            proc,
+            container_addr: ContainerAddress {
+                ip: "127.0.0.1".into(),
+                port: port as u32,
+                hostkey: "".into(),
+                subprocess: Some(subprocess),
+            },
+            info: TunnelInfo {
+                hostkey: "".into(),
+                ipv4: "127.0.0.1".into(),
+                port: 2210,
+                thport: 22,
+                thuser: "hs".into(),
+                rport: 2210,
+            },
+        });
+        let leaks = instance.stop_tunnel();
+        assert_eq!(leaks.len(), 1);
+        assert!(leaks[0].contains("still accepts connections"));
+        assert!(instance.tunnel.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn detect_exited_tunnel_process() {
+        let wdeployment = create_example_wdeployment();
+        let instance = CurrentInstance::new(&Arc::new(wdeployment), None);
+        let mut proc = std::process::Command::new("true").spawn().unwrap();
+        proc.wait().unwrap();
+        *instance.tunnel.lock().unwrap() = Some(SshTunnel {
+            proc,
+            container_addr: ContainerAddress {
+                ip: "127.0.0.1".into(),
+                port: 22,
+                hostkey: "".into(),
+                subprocess: None,
+            },
+            info: TunnelInfo {
+                hostkey: "".into(),
+                ipv4: "127.0.0.1".into(),
+                port: 2210,
+                thport: 22,
+                thuser: "hs".into(),
+                rport: 2210,
+            },
+        });
+        let err = instance.check_tunnel().unwrap_err();
+        assert!(err.contains("exited"));
+    }
+
+    #[test]
+    fn pre_launch_environment() {
+        let wdeployment = create_example_wdeployment();
+        let instance = CurrentInstance::new(&Arc::new(wdeployment), None);
+        assert!(instance
+            .run_pre_launch("test \"$HARDSHARE_CONTAINER_NAME\" = rrc123", "rrc123")
+            .is_ok());
+        assert!(instance.run_pre_launch("exit 1", "rrc123").is_err());
+    }
+
+    #[test]
+    fn terminate_programs_get_context() {
+        let mut wdeployment = create_example_proxy_wdeployment();
+        wdeployment.terminate = vec![
+            "test \"$HARDSHARE_CONTAINER_NAME\" = rrc42".into(),
+            "test \"$HARDSHARE_INSTANCE_ID\" = e5fcf112".into(),
+        ];
+        assert!(
+            CurrentInstance::destroy_container(&wdeployment, "rrc42", Some("e5fcf112")).is_ok()
+        );
+
+        wdeployment.terminate.insert(0, "exit 1".into());
+        let err = CurrentInstance::destroy_container(&wdeployment, "rrc42", Some("e5fcf112"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("1 of 3"));
+    }
+
+    #[test]
+    fn instance_done_states() {
+        let wdeployment = create_example_wdeployment();
+        let mut instance = CurrentInstance::new(&Arc::new(wdeployment), None);
+        assert!(instance_is_done(&instance));
+        instance.declare_status(InstanceStatus::Ready);
+        assert!(!instance_is_done(&instance));
+        instance.declare_status(InstanceStatus::Terminating);
+        assert!(!instance_is_done(&instance));
+        instance.declare_status(InstanceStatus::Fault);
+        assert!(instance_is_done(&instance));
+    }
+
+    #[test]
+    fn script_output_in_error() {
+        let script = "echo hello; echo oops >&2; exit 3";
+        let mut command = std::process::Command::new("/bin/sh");
+        command.args(["-c", script]);
+        let err = run_script(&mut command, script, std::time::Duration::from_secs(10))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("hello"));
+        assert!(err.contains("oops"));
+    }
+
+    #[test]
+    fn script_timeout() {
+        let script = "sleep 10";
+        let mut command = std::process::Command::new("/bin/sh");
+        command.args(["-c", script]);
+        let start = std::time::Instant::now();
+        let err = run_script(&mut command, script, std::time::Duration::from_secs(1))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("timed out"));
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+    }
+
+    #[test]
+    fn metrics_text_format() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.set_status(Some(&InstanceStatus::Init));
+        metrics.set_status(Some(&InstanceStatus::Ready));
+        metrics.count_ws_reconnect();
+        metrics.count_tunnel_restart();
+        metrics.count_tunnel_restart();
+        let mut tab = std::collections::HashMap::new();
+        tab.insert("68a1be97".to_string(), metrics);
+        let out = render_metrics(&tab);
+        assert!(out.contains("# TYPE hardshare_instances_launched_total counter\n"));
+        assert!(out.contains("hardshare_instances_launched_total{wdeployment=\"68a1be97\"} 1\n"));
+        assert!(out.contains("hardshare_launch_failures_total{wdeployment=\"68a1be97\"} 0\n"));
+        assert!(out.contains("hardshare_ws_reconnects_total{wdeployment=\"68a1be97\"} 1\n"));
+        assert!(out.contains("hardshare_tunnel_reconnects_total{wdeployment=\"68a1be97\"} 2\n"));
+        assert!(out
+            .contains("hardshare_instance_status{wdeployment=\"68a1be97\",status=\"READY\"} 1\n"));
+        assert!(
+            out.contains("hardshare_instance_status{wdeployment=\"68a1be97\",status=\"INIT\"} 0\n")
+        );
+    }
+
+    #[test]
+    fn lockout_alerts_rate_limited() {
+        let mut alerts = LockoutAlerts::default();
+        let t0 = std::time::Instant::now();
+        assert_eq!(alerts.try_send(t0), Some(0));
+        assert_eq!(
+            alerts.try_send(t0 + std::time::Duration::from_secs(1)),
+            None
+        );
+        assert_eq!(
+            alerts.try_send(t0 + std::time::Duration::from_secs(2)),
+            None
+        );
+        assert_eq!(alerts.try_send(t0 + LOCKOUT_ALERT_INTERVAL), Some(2));
+        assert_eq!(alerts.try_send(t0 + LOCKOUT_ALERT_INTERVAL), None);
+    }
+
+    #[test]
+    fn repo_url_validation() {
+        assert!(check_repo_url("https://github.com/rerobots/hardshare.git").is_ok());
+        assert!(check_repo_url("git://example.com/repo").is_ok());
+        assert!(check_repo_url("ssh://git@example.com/repo.git").is_ok());
+
+        for url in [
+            "http://example.com/repo",
+            "file:///etc",
+            "ext::sh -c touch% /tmp/pwned",
+            "--upload-pack=touch /tmp/pwned",
+            "https://",
+            "ssh://-oProxyCommand=touch /tmp/pwned/repo",
+            "https://example.com/repo m; rm -rf /",
+            "https://example.com/repo\nrm -rf /",
+        ] {
+            assert!(check_repo_url(url).is_err(), "accepted {}", url);
+        }
+    }
+
+    #[test]
+    fn repo_command_validation() {
+        assert_eq!(
+            parse_repo_command("./setup.sh --quiet").unwrap(),
+            vec!["./setup.sh", "--quiet"]
+        );
+        assert_eq!(
+            parse_repo_command("make  install").unwrap(),
+            vec!["make", "install"]
+        );
+
+        for path in [
+            "",
+            "  ",
+            "./setup.sh; rm -rf /",
+            "./setup.sh && curl example.com",
+            "echo $(id)",
+            "echo `id`",
+            "cat secrets > /tmp/x",
+            "./setup.sh | sh",
+            "--help",
+            "/bin/sh setup.sh",
+            "../../bin/sh",
+        ] {
+            assert!(parse_repo_command(path).is_err(), "accepted {:?}", path);
+        }
+    }
+
+    #[test]
+    fn launch_progress_message() {
+        let m = progress_message(LaunchPhase::Pulling);
+        assert_eq!(m["cmd"], "INSTANCE_STATUS");
+        assert_eq!(m["s"], "INIT_PULLING");
+        assert_ne!(m["v"], 0);
+        assert_eq!(LaunchPhase::Tunnel.to_string(), "INIT_TUNNEL");
+    }
+
+    #[test]
+    fn local_container_names() {
+        assert!(is_local_name("rrc", "rrc12345"));
+        assert!(is_local_name("rrc", "rrc0"));
+        assert!(!is_local_name("rrc", "rrc"));
+        assert!(!is_local_name("rrc", "rrcache"));
+        assert!(!is_local_name("rrc", "myrrc123"));
+        assert!(!is_local_name("rrc", "rrc123-db"));
+    }
 }
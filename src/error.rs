@@ -0,0 +1,167 @@
+// Copyright (C) 2026 rerobots, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Errors from api and mgmt are returned as HardshareError. Errors from
+// dependencies are converted with `?` to the variant that classifies them.
+// Elsewhere errors are Box<dyn std::error::Error>, from which callers can
+// recover the variant with HardshareError::find().
+#[derive(Debug)]
+pub enum HardshareError {
+    // Error response from the rerobots API
+    Api {
+        status: u16,
+        message: String,
+    },
+
+    NotFound(String),
+
+    // Given id prefix matches more than 1 workspace deployment
+    Ambiguous {
+        message: String,
+        candidates: Vec<String>,
+    },
+
+    // Missing or invalid local configuration
+    Config(String),
+
+    // Given value is not valid, e.g., a container name or a batch file
+    Invalid(String),
+
+    Io(std::io::Error),
+
+    Json(serde_json::Error),
+
+    Yaml(serde_yaml::Error),
+
+    // Cannot connect to, or receive a response from, rerobots servers or the
+    // local daemon
+    Network(String),
+
+    // Request did not complete before the timeout (`hardshare --timeout`)
+    Timeout,
+
+    // Missing, expired, or rejected API token
+    Auth(String),
+
+    Other(String),
+}
+
+impl HardshareError {
+    pub fn find<'a>(err: &'a (dyn std::error::Error + 'static)) -> Option<&'a HardshareError> {
+        err.downcast_ref::<HardshareError>()
+    }
+}
+
+impl std::error::Error for HardshareError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HardshareError::Io(err) => Some(err),
+            HardshareError::Json(err) => Some(err),
+            HardshareError::Yaml(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for HardshareError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HardshareError::Api { message, .. } => write!(f, "{}", message),
+            HardshareError::NotFound(msg) => write!(f, "{}", msg),
+            HardshareError::Ambiguous {
+                message,
+                candidates,
+            } => {
+                write!(f, "{}", message)?;
+                for candidate in candidates.iter() {
+                    write!(f, "\n\t{}", candidate)?;
+                }
+                Ok(())
+            }
+            HardshareError::Config(msg) => write!(f, "{}", msg),
+            HardshareError::Invalid(msg) => write!(f, "{}", msg),
+            HardshareError::Io(err) => write!(f, "{}", err),
+            HardshareError::Json(err) => write!(f, "{}", err),
+            HardshareError::Yaml(err) => write!(f, "{}", err),
+            HardshareError::Network(msg) => write!(f, "{}", msg),
+            HardshareError::Timeout => write!(f, "request timed out"),
+            HardshareError::Auth(msg) => write!(f, "{}", msg),
+            HardshareError::Other(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl From<std::io::Error> for HardshareError {
+    fn from(err: std::io::Error) -> Self {
+        HardshareError::Io(err)
+    }
+}
+
+// For errors from modules that return Box<dyn std::error::Error>, e.g., camera
+impl From<Box<dyn std::error::Error>> for HardshareError {
+    fn from(err: Box<dyn std::error::Error>) -> Self {
+        match err.downcast::<HardshareError>() {
+            Ok(err) => *err,
+            Err(err) => match err.downcast::<std::io::Error>() {
+                Ok(err) => HardshareError::Io(*err),
+                Err(err) => HardshareError::Other(err.to_string()),
+            },
+        }
+    }
+}
+
+impl From<serde_json::Error> for HardshareError {
+    fn from(err: serde_json::Error) -> Self {
+        HardshareError::Json(err)
+    }
+}
+
+impl From<serde_yaml::Error> for HardshareError {
+    fn from(err: serde_yaml::Error) -> Self {
+        HardshareError::Yaml(err)
+    }
+}
+
+impl From<awc::error::SendRequestError> for HardshareError {
+    fn from(err: awc::error::SendRequestError) -> Self {
+        match err {
+            awc::error::SendRequestError::Timeout
+            | awc::error::SendRequestError::Connect(awc::error::ConnectError::Timeout) => {
+                HardshareError::Timeout
+            }
+            _ => HardshareError::Network(err.to_string()),
+        }
+    }
+}
+
+impl From<awc::error::WsClientError> for HardshareError {
+    fn from(err: awc::error::WsClientError) -> Self {
+        match err {
+            awc::error::WsClientError::SendRequest(err) => HardshareError::from(err),
+            _ => HardshareError::Network(err.to_string()),
+        }
+    }
+}
+
+impl From<awc::error::PayloadError> for HardshareError {
+    fn from(err: awc::error::PayloadError) -> Self {
+        HardshareError::Network(err.to_string())
+    }
+}
+
+impl From<awc::error::JsonPayloadError> for HardshareError {
+    fn from(err: awc::error::JsonPayloadError) -> Self {
+        HardshareError::Network(err.to_string())
+    }
+}
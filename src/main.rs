@@ -26,8 +26,12 @@ mod camera;
 mod check;
 mod cli;
 mod control;
+mod error;
 mod mgmt;
 mod monitor;
+mod proxy;
+mod tls;
+mod util;
 
 fn main() {
     rerobots::init();
@@ -24,27 +24,26 @@ extern crate home;
 
 use rerobots::client::TokenClaims;
 
-struct MgmtError {
-    msg: String,
+use crate::error::HardshareError;
+
+fn config_error<T>(msg: &str) -> Result<T, HardshareError> {
+    Err(HardshareError::Config(String::from(msg)))
 }
-impl std::error::Error for MgmtError {}
 
-impl std::fmt::Display for MgmtError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.msg)
-    }
+fn invalid<T>(msg: &str) -> Result<T, HardshareError> {
+    Err(HardshareError::Invalid(String::from(msg)))
 }
 
-impl std::fmt::Debug for MgmtError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.msg)
-    }
+fn not_found<T>(msg: &str) -> Result<T, HardshareError> {
+    Err(HardshareError::NotFound(String::from(msg)))
 }
 
-fn error<T>(msg: &str) -> Result<T, Box<dyn std::error::Error>> {
-    Err(Box::new(MgmtError {
-        msg: String::from(msg),
-    }))
+fn auth_error<T>(msg: &str) -> Result<T, HardshareError> {
+    Err(HardshareError::Auth(String::from(msg)))
+}
+
+fn other_error<T>(msg: &str) -> Result<T, HardshareError> {
+    Err(HardshareError::Other(String::from(msg)))
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -54,6 +53,7 @@ pub enum CProvider {
     DockerRootless,
     Lxd,
     Podman,
+    PodmanRootful,
     Proxy,
 }
 
@@ -61,12 +61,25 @@ impl CProvider {
     pub fn get_execname(&self) -> Option<String> {
         if self == &Self::DockerRootless {
             Some("docker".into())
+        } else if self == &Self::PodmanRootful {
+            Some("podman".into())
         } else if self == &Self::Proxy {
             None
         } else {
             Some(self.to_string())
         }
     }
+
+    // Image assigned when a deployment switches to this cprovider
+    pub fn default_image(&self) -> Option<&'static str> {
+        match self {
+            Self::Docker | Self::DockerRootless | Self::Podman | Self::PodmanRootful => {
+                Some("rerobots/hs-generic")
+            }
+            Self::Lxd => Some("images:ubuntu/22.04"),
+            Self::Proxy => None,
+        }
+    }
 }
 
 impl TryFrom<&str> for CProvider {
@@ -78,8 +91,9 @@ impl TryFrom<&str> for CProvider {
             "docker-rootless" => Ok(Self::DockerRootless),
             "lxd" => Ok(Self::Lxd),
             "podman" => Ok(Self::Podman),
+            "podman-rootful" => Ok(Self::PodmanRootful),
             "proxy" => Ok(Self::Proxy),
-            _ => Err("error: cprovider must be one of the following: docker, docker-rootless, lxd, podman, proxy"),
+            _ => Err("error: cprovider must be one of the following: docker, docker-rootless, lxd, podman, podman-rootful, proxy"),
         }
     }
 }
@@ -91,11 +105,15 @@ impl std::fmt::Display for CProvider {
             Self::DockerRootless => write!(f, "docker-rootless"),
             Self::Lxd => write!(f, "lxd"),
             Self::Podman => write!(f, "podman"),
+            Self::PodmanRootful => write!(f, "podman-rootful"),
             Self::Proxy => write!(f, "proxy"),
         }
     }
 }
 
+// Seconds for the ready check of an instance to pass, unless configured otherwise
+pub const DEFAULT_READY_TIMEOUT_SECS: u64 = 120;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct WDeployment {
     pub id: String,
@@ -115,6 +133,140 @@ pub struct WDeployment {
 
     #[serde(default)]
     pub ssh_key: Option<String>,
+
+    #[serde(default)]
+    pub ssh_user: Option<String>,
+
+    #[serde(default)]
+    pub ready_check: Option<String>,
+
+    // Seconds for ready_check to pass; None implies DEFAULT_READY_TIMEOUT_SECS
+    #[serde(default)]
+    pub ready_timeout_secs: Option<u64>,
+
+    // Run on the host after an instance becomes READY
+    #[serde(default)]
+    pub on_ready: Option<String>,
+
+    // Run on the host before the container of an instance is created; if it
+    // fails, then the launch is aborted
+    #[serde(default)]
+    pub pre_launch: Option<String>,
+
+    // Human-readable label, only used locally
+    #[serde(default)]
+    pub label: Option<String>,
+
+    // Instances are terminated this many seconds after READY; None implies no limit
+    #[serde(default)]
+    pub max_session_secs: Option<u64>,
+
+    // Instances are terminated after this many seconds without traffic. Only
+    // effective if traffic is observable, i.e., proxy cprovider with rrhttp.
+    #[serde(default)]
+    pub idle_timeout_secs: Option<u64>,
+
+    #[serde(default)]
+    pub ssh_tunnel: SshTunnelOptions,
+
+    // Docker or Podman network of containers; None implies the default bridge
+    #[serde(default)]
+    pub network: Option<String>,
+}
+
+// Options of the ssh process that keeps the tunnel of an instance open
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct SshTunnelOptions {
+    pub server_alive_interval: u32,
+    pub server_alive_count_max: u32,
+
+    // Seconds; None implies the default of ssh
+    pub connect_timeout: Option<u32>,
+
+    pub compression: bool,
+}
+
+impl Default for SshTunnelOptions {
+    fn default() -> Self {
+        SshTunnelOptions {
+            server_alive_interval: 10,
+            server_alive_count_max: 3,
+            connect_timeout: None,
+            compression: false,
+        }
+    }
+}
+
+impl SshTunnelOptions {
+    // Set option from `KEY=VALUE`, where VALUE `-` restores the default
+    pub fn set(&mut self, assignment: &str) -> Result<(), String> {
+        let (key, value) = match assignment.split_once('=') {
+            Some(kv) => kv,
+            None => return Err(format!("expected KEY=VALUE, got `{}`", assignment)),
+        };
+        let default = Self::default();
+        let parse_secs = |v: &str| match v.parse::<u32>() {
+            Ok(x) if x > 0 => Ok(x),
+            _ => Err(format!("{} must be a positive integer or `-`", key)),
+        };
+        match key {
+            "server-alive-interval" => {
+                self.server_alive_interval = if value == "-" {
+                    default.server_alive_interval
+                } else {
+                    parse_secs(value)?
+                };
+            }
+            "server-alive-count-max" => {
+                self.server_alive_count_max = if value == "-" {
+                    default.server_alive_count_max
+                } else {
+                    parse_secs(value)?
+                };
+            }
+            "connect-timeout" => {
+                self.connect_timeout = if value == "-" {
+                    default.connect_timeout
+                } else {
+                    Some(parse_secs(value)?)
+                };
+            }
+            "compression" => {
+                self.compression = match value {
+                    "-" => default.compression,
+                    "yes" => true,
+                    "no" => false,
+                    _ => return Err("compression must be yes, no, or `-`".into()),
+                };
+            }
+            _ => {
+                return Err(format!(
+                    "unknown ssh tunnel option: {}; expected one of server-alive-interval, server-alive-count-max, connect-timeout, compression",
+                    key
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for SshTunnelOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "server-alive-interval={}, server-alive-count-max={}",
+            self.server_alive_interval, self.server_alive_count_max
+        )?;
+        if let Some(t) = self.connect_timeout {
+            write!(f, ", connect-timeout={}", t)?;
+        }
+        write!(
+            f,
+            ", compression={}",
+            if self.compression { "yes" } else { "no" }
+        )
+    }
 }
 
 impl WDeployment {
@@ -180,8 +332,54 @@ impl WDeployment {
 
         let image = if h.contains_key("image") {
             Some(h["image"].as_str().unwrap().into())
-        } else if cprovider != CProvider::Proxy {
-            Some("rerobots/hs-generic".into())
+        } else {
+            cprovider.default_image().map(String::from)
+        };
+
+        let ssh_user: Option<String> = if h.contains_key("ssh_user") {
+            h["ssh_user"].as_str().map(|u| u.into())
+        } else {
+            None
+        };
+
+        let ready_check = if h.contains_key("ready_check") {
+            h["ready_check"].as_str().map(|r| r.into())
+        } else {
+            None
+        };
+
+        let ready_timeout_secs: Option<u64> = if h.contains_key("ready_timeout_secs") {
+            h["ready_timeout_secs"].as_u64()
+        } else {
+            None
+        };
+
+        let on_ready = if h.contains_key("on_ready") {
+            h["on_ready"].as_str().map(|r| r.into())
+        } else {
+            None
+        };
+
+        let pre_launch = if h.contains_key("pre_launch") {
+            h["pre_launch"].as_str().map(|r| r.into())
+        } else {
+            None
+        };
+
+        let label: Option<String> = if h.contains_key("label") {
+            h["label"].as_str().map(|l| l.into())
+        } else {
+            None
+        };
+
+        let max_session_secs: Option<u64> = if h.contains_key("max_session_secs") {
+            h["max_session_secs"].as_u64()
+        } else {
+            None
+        };
+
+        let idle_timeout_secs: Option<u64> = if h.contains_key("idle_timeout_secs") {
+            h["idle_timeout_secs"].as_u64()
         } else {
             None
         };
@@ -192,6 +390,18 @@ impl WDeployment {
             None
         };
 
+        let network: Option<String> = if h.contains_key("network") {
+            h["network"].as_str().map(|n| n.into())
+        } else {
+            None
+        };
+
+        let ssh_tunnel = if h.contains_key("ssh_tunnel") {
+            serde_json::from_value(h["ssh_tunnel"].clone()).unwrap_or_default()
+        } else {
+            SshTunnelOptions::default()
+        };
+
         WDeployment {
             id: h["id"].as_str().unwrap().into(),
             owner: h["owner"].as_str().unwrap().into(),
@@ -205,6 +415,32 @@ impl WDeployment {
             url,
 
             ssh_key: None,
+            ssh_user,
+            ready_check,
+            ready_timeout_secs,
+            on_ready,
+            pre_launch,
+            label,
+            max_session_secs,
+            idle_timeout_secs,
+            ssh_tunnel,
+            network,
+        }
+    }
+
+    // Time limit for ready_check to pass
+    pub fn get_ready_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(
+            self.ready_timeout_secs
+                .unwrap_or(DEFAULT_READY_TIMEOUT_SECS),
+        )
+    }
+
+    // User that instance owners log in as over SSH
+    pub fn get_ssh_user(&self) -> &str {
+        match &self.ssh_user {
+            Some(u) => u.as_str(),
+            None => "root",
         }
     }
 }
@@ -234,10 +470,64 @@ pub struct Config {
     pub known_orgs: Vec<String>,
 }
 
+// Version of the local configuration format. When it is incremented, add a
+// function to MIGRATIONS that upgrades from the previous version.
+const CONFIG_VERSION: u16 = 1;
+
+type Migration = fn(&mut serde_json::Value) -> Result<(), HardshareError>;
+
+// MIGRATIONS[i] upgrades from version i to version i + 1
+const MIGRATIONS: [Migration; CONFIG_VERSION as usize] = [migrate_v0];
+
+// Version 0 files from early releases can omit lists and names that are now
+// required for each workspace deployment.
+fn migrate_v0(config: &mut serde_json::Value) -> Result<(), HardshareError> {
+    let wdeployments = match config["wdeployments"].as_array_mut() {
+        Some(w) => w,
+        None => return config_error("configuration does not have list of workspace deployments"),
+    };
+    for wd in wdeployments.iter_mut() {
+        let wd = match wd.as_object_mut() {
+            Some(w) => w,
+            None => return config_error("workspace deployment in configuration is not an object"),
+        };
+        for key in ["cargs", "init_inside", "terminate"].iter() {
+            wd.entry(*key).or_insert_with(|| json!([]));
+        }
+        wd.entry("container_name").or_insert_with(|| json!("rrc"));
+        wd.entry("cprovider").or_insert_with(|| json!("docker"));
+    }
+    Ok(())
+}
+
+// Upgrade the configuration in place, returning whether it was changed
+fn migrate_config(config: &mut serde_json::Value) -> Result<bool, HardshareError> {
+    let version = config["version"].as_u64().unwrap_or(0);
+    if version > CONFIG_VERSION as u64 {
+        return config_error(&format!(
+            "configuration version {} is newer than supported ({}); upgrade hardshare",
+            version, CONFIG_VERSION
+        ));
+    }
+    if version == CONFIG_VERSION as u64 {
+        return Ok(false);
+    }
+    for (from_version, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        info!(
+            "migrating local configuration from version {} to {}",
+            from_version,
+            from_version + 1
+        );
+        migration(config)?;
+    }
+    config["version"] = json!(CONFIG_VERSION);
+    Ok(true)
+}
+
 impl Config {
     pub fn new() -> Config {
         Config {
-            version: 0,
+            version: CONFIG_VERSION,
             wdeployments: vec![],
             ssh_key: "".to_string(),
             api_tokens: HashMap::new(),
@@ -249,7 +539,15 @@ impl Config {
     }
 }
 
+// Directory of local configuration, API tokens, SSH keys, etc. The default is
+// ~/.rerobots, which can be overridden by the environment variable
+// HARDSHARE_CONFIG_DIR (or `hardshare --config-dir`, which sets it).
 pub fn get_base_path() -> Option<std::path::PathBuf> {
+    if let Some(config_dir) = std::env::var_os("HARDSHARE_CONFIG_DIR") {
+        if !config_dir.is_empty() {
+            return Some(std::path::PathBuf::from(config_dir));
+        }
+    }
     let home_dir = match home::home_dir() {
         Some(s) => s,
         None => return None,
@@ -263,9 +561,7 @@ type APITokensInfo = (
     HashMap<String, String>,
 );
 
-pub fn list_local_api_tokens(
-    collect_errors: bool,
-) -> Result<APITokensInfo, Box<dyn std::error::Error>> {
+pub fn list_local_api_tokens(collect_errors: bool) -> Result<APITokensInfo, HardshareError> {
     let base_path = get_base_path().unwrap();
     list_local_api_tokens_bp(&base_path, collect_errors)
 }
@@ -273,7 +569,7 @@ pub fn list_local_api_tokens(
 fn list_local_api_tokens_bp(
     base_path: &std::path::Path,
     collect_errors: bool,
-) -> Result<APITokensInfo, Box<dyn std::error::Error>> {
+) -> Result<APITokensInfo, HardshareError> {
     let mut likely_tokens = HashMap::new();
     let mut likely_tokens_data = HashMap::new();
     let mut errored_tokens = HashMap::new();
@@ -330,10 +626,41 @@ fn list_local_api_tokens_bp(
     Ok((likely_tokens, likely_tokens_data, errored_tokens))
 }
 
+// Local configuration is saved as JSON in the file `main` or as YAML in the
+// file `main.yaml`. If both exist, then `main.yaml` is used.
+fn get_config_path(base_path: &std::path::Path) -> std::path::PathBuf {
+    let yaml_path = base_path.join("main.yaml");
+    if yaml_path.exists() {
+        yaml_path
+    } else {
+        base_path.join("main")
+    }
+}
+
+fn is_yaml_path(path: &std::path::Path) -> bool {
+    path.extension().map(|x| x == "yaml").unwrap_or(false)
+}
+
+fn parse_config(path: &std::path::Path, raw: &str) -> Result<serde_json::Value, HardshareError> {
+    if is_yaml_path(path) {
+        Ok(serde_yaml::from_str(raw)?)
+    } else {
+        Ok(serde_json::from_str(raw)?)
+    }
+}
+
+fn serialize_config(path: &std::path::Path, config: &Config) -> Result<String, HardshareError> {
+    if is_yaml_path(path) {
+        Ok(serde_yaml::to_string(config)?)
+    } else {
+        Ok(serde_json::to_string(config)?)
+    }
+}
+
 pub fn get_local_config(
     create_if_empty: bool,
     collect_errors: bool,
-) -> Result<Config, Box<dyn std::error::Error>> {
+) -> Result<Config, HardshareError> {
     let base_path = get_base_path().unwrap();
     get_local_config_bp(&base_path, create_if_empty, collect_errors)
 }
@@ -342,42 +669,33 @@ pub fn get_local_config_bp(
     base_path: &std::path::Path,
     create_if_empty: bool,
     collect_errors: bool,
-) -> Result<Config, Box<dyn std::error::Error>> {
+) -> Result<Config, HardshareError> {
     if !base_path.exists() {
         if create_if_empty {
             std::fs::create_dir(base_path)?;
             std::fs::create_dir(base_path.join("tokens"))?;
             std::fs::create_dir(base_path.join("ssh"))?;
         } else {
-            return error("no configuration data found");
+            return config_error("no configuration data found");
         }
     }
-    let path = base_path.join("main");
+    let path = get_config_path(base_path);
     if !path.exists() {
         if create_if_empty {
             let mut init = Config::new();
-            let sshpath = base_path.join("ssh").join("tun");
-            let exitcode = Command::new("ssh-keygen")
-                .arg("-N")
-                .arg("")
-                .arg("-f")
-                .arg(&sshpath)
-                .stdout(Stdio::piped())
-                .spawn()
-                .expect("failed to call ssh-keygen")
-                .wait()
-                .expect("failed to wait on ssh-keygen");
-            if !exitcode.success() {
-                return error("failed to create SSH keys");
-            }
-            init.ssh_key = String::from(sshpath.to_str().unwrap());
+            init.ssh_key = create_ssh_key(base_path)?;
             std::fs::write(&path, serde_json::to_string(&init)?)?;
         } else {
-            return error("no configuration data found");
+            return config_error("no configuration data found");
         }
     }
-    let config_raw = std::fs::read_to_string(path)?;
-    let mut config: Config = serde_json::from_str(config_raw.as_str())?;
+    let config_raw = std::fs::read_to_string(&path)?;
+    let mut config_value = parse_config(&path, &config_raw)?;
+    let migrated = migrate_config(&mut config_value)?;
+    let mut config: Config = serde_json::from_value(config_value)?;
+    if migrated {
+        std::fs::write(&path, serialize_config(&path, &config)?)?;
+    }
     let res = list_local_api_tokens(collect_errors)?;
     config.api_tokens = res.0;
     config.api_tokens_data = res.1;
@@ -387,6 +705,35 @@ pub fn get_local_config_bp(
     Ok(config)
 }
 
+// Generate a new key pair for SSH tunnels under base_path, and return the path
+// of the secret key. Existing keys are not overwritten.
+pub fn create_ssh_key(base_path: &std::path::Path) -> Result<String, HardshareError> {
+    let ssh_dir = base_path.join("ssh");
+    if !ssh_dir.exists() {
+        std::fs::create_dir(&ssh_dir)?;
+    }
+    let mut sshpath = ssh_dir.join("tun");
+    let mut counter = 0;
+    while sshpath.exists() || get_ssh_public_path(sshpath.to_str().unwrap()).exists() {
+        sshpath = ssh_dir.join(format!("tun-{}", counter));
+        counter += 1;
+    }
+    let exitcode = Command::new("ssh-keygen")
+        .arg("-N")
+        .arg("")
+        .arg("-f")
+        .arg(&sshpath)
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to call ssh-keygen")
+        .wait()
+        .expect("failed to wait on ssh-keygen");
+    if !exitcode.success() {
+        return other_error("failed to create SSH keys");
+    }
+    Ok(String::from(sshpath.to_str().unwrap()))
+}
+
 pub fn append_urls(config: &mut Config) {
     let prefix = "https://rerobots.net/workspace/";
     for wd in config.wdeployments.iter_mut() {
@@ -396,16 +743,19 @@ pub fn append_urls(config: &mut Config) {
     }
 }
 
-pub fn add_token_file(path: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
-    let rawtok = String::from(String::from_utf8(std::fs::read(path)?)?.trim());
+pub fn add_token_file(path: &str) -> Result<Option<String>, HardshareError> {
+    let rawtok = match String::from_utf8(std::fs::read(path)?) {
+        Ok(t) => String::from(t.trim()),
+        Err(_) => return auth_error("API token is not valid UTF-8"),
+    };
     let org = match TokenClaims::new(&rawtok) {
         Ok(claims) => {
             if claims.is_expired() {
-                return error("expired");
+                return auth_error("expired");
             }
             claims.organization
         }
-        Err(err) => return error(err),
+        Err(err) => return auth_error(err),
     };
 
     let base_path = get_base_path().unwrap();
@@ -417,7 +767,8 @@ pub fn add_token_file(path: &str) -> Result<Option<String>, Box<dyn std::error::
     let mut target_path = tokens_dir.join(from_filename);
     if target_path.exists() {
         let utime = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)?
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_err(|err| HardshareError::Other(err.to_string()))?
             .as_secs();
         let candidate = format!("{}-{}", target_path.to_str().unwrap(), utime);
         target_path = std::path::PathBuf::from(candidate);
@@ -439,17 +790,17 @@ pub fn add_token_file(path: &str) -> Result<Option<String>, Box<dyn std::error::
     Ok(org)
 }
 
-pub fn add_ssh_path(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+pub fn add_ssh_path(path: &str) -> Result<(), HardshareError> {
     let target = std::path::Path::new(path).canonicalize()?;
     if !target.exists() {
-        return error("file does not exist");
+        return not_found("file does not exist");
     }
     let target_public = target.with_extension("pub");
     if target_public == target {
-        return error("public key file cannot be same as secret key file");
+        return invalid("public key file cannot be same as secret key file");
     }
     if !target_public.exists() {
-        return error("public key file does not exist");
+        return not_found("public key file does not exist");
     }
     let mut local_config = match get_local_config(false, false) {
         Ok(lc) => lc,
@@ -457,15 +808,150 @@ pub fn add_ssh_path(path: &str) -> Result<(), Box<dyn std::error::Error>> {
     };
     local_config.ssh_key = match target.to_str() {
         Some(s) => s.into(),
-        None => return error("path not given in UTF-8"),
+        None => return invalid("path not given in UTF-8"),
     };
     modify_local(&local_config)
 }
 
-pub fn find_id_prefix(
-    config: &Config,
-    id_prefix: Option<&str>,
-) -> Result<usize, Box<dyn std::error::Error>> {
+// As created by ssh-keygen, i.e., with .pub appended to the private key path
+pub fn get_ssh_public_path(key_path: &str) -> std::path::PathBuf {
+    std::path::PathBuf::from(format!("{}.pub", key_path))
+}
+
+pub fn get_ssh_fingerprint(key_path: &str) -> Result<String, HardshareError> {
+    let output = Command::new("ssh-keygen")
+        .arg("-l")
+        .arg("-f")
+        .arg(get_ssh_public_path(key_path))
+        .output()?;
+    if !output.status.success() {
+        return other_error(&format!(
+            "ssh-keygen failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    match String::from_utf8(output.stdout) {
+        Ok(fingerprint) => Ok(fingerprint.trim().to_string()),
+        Err(_) => other_error("output of ssh-keygen is not valid UTF-8"),
+    }
+}
+
+// Contents of a file created by `hardshare config --export`
+#[derive(Serialize, Deserialize)]
+struct ExportBundle {
+    config: serde_json::Value,
+
+    #[serde(default)]
+    ssh_key: Option<SshKeyPair>,
+
+    // file name -> API token
+    #[serde(default)]
+    api_tokens: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SshKeyPair {
+    secret: String,
+    public: String,
+}
+
+// Write a file that is readable only by the current user
+fn write_private(path: &std::path::Path, data: &str) -> Result<(), HardshareError> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut f = options.open(path)?;
+    std::io::Write::write_all(&mut f, data.as_bytes())?;
+    Ok(())
+}
+
+pub fn export_config(path: &str, with_secrets: bool) -> Result<(), HardshareError> {
+    let mut config = get_local_config(false, false)?;
+    let mut bundle = ExportBundle {
+        config: serde_json::Value::Null,
+        ssh_key: None,
+        api_tokens: HashMap::new(),
+    };
+    if with_secrets {
+        bundle.ssh_key = Some(SshKeyPair {
+            secret: std::fs::read_to_string(&config.ssh_key)?,
+            public: std::fs::read_to_string(get_ssh_public_path(&config.ssh_key))?,
+        });
+        for token_path in config.api_tokens.values().flatten() {
+            let name = match std::path::Path::new(token_path).file_name() {
+                Some(n) => n.to_string_lossy().to_string(),
+                None => continue,
+            };
+            let token = std::fs::read_to_string(token_path)?;
+            bundle.api_tokens.insert(name, token.trim().to_string());
+        }
+    }
+    config.api_tokens = HashMap::new();
+    bundle.config = serde_json::to_value(&config)?;
+    write_private(
+        std::path::Path::new(path),
+        &serde_json::to_string_pretty(&bundle)?,
+    )
+}
+
+// Replace the local configuration with that of a file created by export_config
+pub fn import_config(path: &str) -> Result<(), HardshareError> {
+    let mut bundle: ExportBundle = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    migrate_config(&mut bundle.config)?;
+    let mut config: Config = serde_json::from_value(bundle.config)?;
+
+    // Create the configuration directory and a new SSH key pair if needed
+    let local_config = get_local_config(true, false)?;
+    let base_path = match get_base_path() {
+        Some(p) => p,
+        None => {
+            return config_error(
+                "cannot determine home directory; set HOME or HARDSHARE_CONFIG_DIR",
+            )
+        }
+    };
+
+    match &bundle.ssh_key {
+        Some(key) => {
+            let utime = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_err(|err| HardshareError::Other(err.to_string()))?
+                .as_secs();
+            let key_path = base_path.join("ssh").join(format!("imported-{}", utime));
+            write_private(&key_path, &key.secret)?;
+            std::fs::write(get_ssh_public_path(key_path.to_str().unwrap()), &key.public)?;
+            config.ssh_key = key_path.to_str().unwrap().into();
+        }
+        None => {
+            config.ssh_key = local_config.ssh_key.clone();
+        }
+    }
+
+    if !bundle.api_tokens.is_empty() {
+        let td = tempfile::tempdir()?;
+        for (name, token) in bundle.api_tokens.iter() {
+            let token_path = td.path().join(name);
+            write_private(&token_path, token)?;
+            match add_token_file(token_path.to_str().unwrap()) {
+                Ok(Some(org_name)) => {
+                    if !config.known_orgs.contains(&org_name) {
+                        config.known_orgs.push(org_name);
+                    }
+                }
+                Ok(None) => {}
+                Err(err) => warn!("skipping API token {}: {}", name, err),
+            }
+        }
+    }
+
+    modify_local(&config)
+}
+
+pub fn find_id_prefix(config: &Config, id_prefix: Option<&str>) -> Result<usize, HardshareError> {
     if let Some(id_prefix) = id_prefix {
         let mut candidates = vec![];
 
@@ -476,31 +962,35 @@ pub fn find_id_prefix(
         }
         if candidates.len() > 1 {
             let candidates: Vec<String> = candidates.iter().map(|val| val.1.clone()).collect();
-            error(
-                format!(
-                    "given prefix matches more than 1 workspace deployment: {}",
-                    candidates.join(", ")
-                )
-                .as_str(),
-            )
+            Err(HardshareError::Ambiguous {
+                message: "given prefix matches more than 1 workspace deployment:".into(),
+                candidates,
+            })
         } else if candidates.is_empty() {
-            error("given prefix does not match any workspace deployments")
+            Err(HardshareError::NotFound(
+                "given prefix does not match any workspace deployments".into(),
+            ))
         } else {
             Ok(candidates[0].0)
         }
     } else if config.wdeployments.len() == 1 {
         Ok(0)
     } else if config.wdeployments.is_empty() {
-        error("no workspace deployment in local configuration.")
+        Err(HardshareError::NotFound(
+            "no workspace deployment in local configuration.".into(),
+        ))
     } else {
-        error("ambiguous command: more than 1 workspace deployment defined.")
+        Err(HardshareError::Ambiguous {
+            message: "ambiguous command: more than 1 workspace deployment defined:".into(),
+            candidates: config.wdeployments.iter().map(|wd| wd.id.clone()).collect(),
+        })
     }
 }
 
 pub fn expand_id_prefixes(
     config: &Config,
     id_prefixes: &[&str],
-) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+) -> Result<Vec<String>, HardshareError> {
     if id_prefixes.is_empty() {
         let index = find_id_prefix(config, None)?;
         return Ok(vec![config.wdeployments[index].id.clone()]);
@@ -513,34 +1003,196 @@ pub fn expand_id_prefixes(
     Ok(expansion)
 }
 
-pub fn modify_local(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+pub fn modify_local(config: &Config) -> Result<(), HardshareError> {
     let base_path = get_base_path().unwrap();
     if !base_path.exists() {
-        return error("no configuration data found");
+        return config_error("no configuration data found");
     }
-    let path = base_path.join("main");
+    let path = get_config_path(&base_path);
     if !path.exists() {
-        return error("no configuration data found");
+        return config_error("no configuration data found");
+    }
+    std::fs::write(&path, serialize_config(&path, config)?)?;
+    Ok(())
+}
+
+// Check that containers can be named with the given base name. Docker and
+// Podman accept [a-zA-Z0-9][a-zA-Z0-9_.-]*, and LXD requires a valid
+// hostname. A random numeric suffix of up to 5 digits is appended at launch.
+pub fn validate_container_name(name: &str, cprovider: &CProvider) -> Result<(), HardshareError> {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) => {
+            if !c.is_ascii_alphanumeric() || (*cprovider == CProvider::Lxd && c.is_ascii_digit()) {
+                return invalid("container name must begin with a letter or digit (LXD: letter)");
+            }
+        }
+        None => return invalid("container name cannot be empty"),
+    }
+    let is_allowed = |c: char| {
+        if *cprovider == CProvider::Lxd {
+            c.is_ascii_alphanumeric() || c == '-'
+        } else {
+            c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-'
+        }
+    };
+    if !chars.all(is_allowed) {
+        return invalid("container name has characters that are not allowed by the cprovider");
+    }
+    if name.len() > 58 {
+        return invalid("container name must be at most 58 characters");
+    }
+    Ok(())
+}
+
+pub fn validate_ssh_user(name: &str) -> Result<(), HardshareError> {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) => {
+            if !(c.is_ascii_lowercase() || c == '_') {
+                return invalid("user name must begin with a lowercase letter or _");
+            }
+        }
+        None => return invalid("user name cannot be empty"),
+    }
+    if !chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-') {
+        return invalid("user name can only have lowercase letters, digits, _, or -");
+    }
+    if name.len() > 32 {
+        return invalid("user name must be at most 32 characters");
     }
-    std::fs::write(&path, serde_json::to_string(&config)?)?;
     Ok(())
 }
 
-pub fn get_username(token_path: &str) -> Result<String, Box<dyn std::error::Error>> {
+// Digest of an image that is pinned with a reference like
+// rerobots/hs-generic@sha256:..., if any
+pub fn image_digest(image: &str) -> Option<&str> {
+    image.find('@').map(|sep| &image[(sep + 1)..])
+}
+
+// Check that a digest pin, if the image has one, is well formed. Tags are
+// not checked here; the cprovider is asked whether it knows them.
+pub fn validate_image_ref(image: &str, cprovider: &CProvider) -> Result<(), HardshareError> {
+    let digest = match image_digest(image) {
+        Some(d) => d,
+        None => return Ok(()),
+    };
+    if *cprovider == CProvider::Lxd {
+        return invalid("image digests are not supported for cprovider `lxd`");
+    }
+    if image.starts_with('@') {
+        return invalid("image digest must follow a name, as in NAME@sha256:DIGEST");
+    }
+    let hex = match digest.strip_prefix("sha256:") {
+        Some(h) => h,
+        None => return invalid("image digest must begin with sha256:"),
+    };
+    if hex.len() != 64
+        || !hex
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+    {
+        return invalid("sha256 image digest must be 64 lowercase hexadecimal digits");
+    }
+    Ok(())
+}
+
+// Settings for one workspace deployment in a file given to
+// `hardshare register --from-file`. Omitted fields keep the defaults of a
+// newly registered workspace deployment.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BatchDeployment {
+    #[serde(default)]
+    pub cprovider: Option<CProvider>,
+
+    #[serde(default)]
+    pub image: Option<String>,
+
+    #[serde(default)]
+    pub container_name: Option<String>,
+
+    #[serde(default)]
+    pub ssh_user: Option<String>,
+
+    #[serde(default)]
+    pub proxy_command: Option<String>,
+
+    #[serde(default)]
+    pub init_inside: Vec<String>,
+
+    #[serde(default)]
+    pub terminate: Vec<String>,
+
+    #[serde(default)]
+    pub monitor: Option<String>,
+
+    #[serde(default)]
+    pub ready_check: Option<String>,
+}
+
+pub fn read_batch_file(path: &str) -> Result<Vec<BatchDeployment>, HardshareError> {
+    let raw = std::fs::read_to_string(path)?;
+    parse_batch(&raw)
+}
+
+fn parse_batch(raw: &str) -> Result<Vec<BatchDeployment>, HardshareError> {
+    let batch: Vec<BatchDeployment> = serde_yaml::from_str(raw)?;
+    if batch.is_empty() {
+        return invalid("no workspace deployments declared in file");
+    }
+    for bd in batch.iter() {
+        if let Some(name) = &bd.container_name {
+            validate_container_name(name, bd.cprovider.as_ref().unwrap_or(&CProvider::Docker))?;
+        }
+        if let Some(user) = &bd.ssh_user {
+            if user != "root" {
+                validate_ssh_user(user)?;
+            }
+        }
+    }
+    Ok(batch)
+}
+
+pub fn get_username(token_path: &str) -> Result<String, HardshareError> {
     let token = std::fs::read(token_path)?;
-    let token = String::from_utf8(token)?.trim().to_string();
-    let claims = TokenClaims::new(&token)?;
-    Ok(claims.subject)
+    let token = match String::from_utf8(token) {
+        Ok(t) => t.trim().to_string(),
+        Err(_) => return auth_error("API token is not valid UTF-8"),
+    };
+    match TokenClaims::new(&token) {
+        Ok(claims) => Ok(claims.subject),
+        Err(err) => auth_error(err),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use tempfile::tempdir;
 
-    use super::find_id_prefix;
+    use super::create_ssh_key;
     use super::get_local_config_bp;
+    use super::get_ssh_public_path;
     use super::list_local_api_tokens_bp;
-    use super::Config;
+    use super::parse_batch;
+    use super::validate_container_name;
+    use super::CONFIG_VERSION;
+    use super::{expand_id_prefixes, find_id_prefix};
+    use super::{image_digest, validate_image_ref};
+    use super::{CProvider, Config};
+    use crate::error::HardshareError;
+
+    #[test]
+    fn ssh_public_key_path() {
+        assert_eq!(
+            get_ssh_public_path("/home/scott/.rerobots/ssh/tun"),
+            std::path::PathBuf::from("/home/scott/.rerobots/ssh/tun.pub")
+        );
+        assert_eq!(
+            get_ssh_public_path("/home/scott/.rerobots/ssh/tun.key"),
+            std::path::PathBuf::from("/home/scott/.rerobots/ssh/tun.key.pub")
+        );
+    }
 
     #[test]
     fn configuration_directory_suffix() {
@@ -588,6 +1240,41 @@ mod tests {
         assert_eq!(wd_index, 0);
         let wd_index = find_id_prefix(&local_config, Some("6")).unwrap();
         assert_eq!(wd_index, 1);
+
+        let err = find_id_prefix(&local_config, None).unwrap_err();
+        match &err {
+            HardshareError::Ambiguous { candidates, .. } => assert_eq!(candidates.len(), 2),
+            _ => panic!("expected ambiguity error"),
+        }
+        assert!(err.to_string().ends_with(
+            ":\n\t2d6039bc-7c83-4d46-8567-c8df4711c386\n\t68a1be97-9365-4007-b726-14c56bd69eef"
+        ));
+        let err = find_id_prefix(&local_config, Some("a")).unwrap_err();
+        assert!(matches!(err, HardshareError::NotFound(_)));
+
+        let wdids = expand_id_prefixes(&local_config, &["6", "2d"]).unwrap();
+        assert_eq!(
+            wdids,
+            vec![
+                "68a1be97-9365-4007-b726-14c56bd69eef",
+                "2d6039bc-7c83-4d46-8567-c8df4711c386"
+            ]
+        );
+        assert!(expand_id_prefixes(&local_config, &[]).is_err());
+        assert!(expand_id_prefixes(&local_config, &["2", "a"]).is_err());
+    }
+
+    #[test]
+    fn default_image_per_cprovider() {
+        assert_eq!(
+            CProvider::Podman.default_image(),
+            CProvider::Docker.default_image()
+        );
+        assert_ne!(
+            CProvider::Lxd.default_image(),
+            CProvider::Docker.default_image()
+        );
+        assert!(CProvider::Proxy.default_image().is_none());
     }
 
     #[test]
@@ -606,6 +1293,17 @@ mod tests {
         assert_ne!(lconf.ssh_key.len(), 0);
     }
 
+    #[test]
+    fn create_ssh_key_keeps_existing() {
+        let td = tempdir().unwrap();
+        let base_path = td.path().join(".rerobots");
+        let lconf = get_local_config_bp(&base_path, true, false).unwrap();
+        let new_key = create_ssh_key(&base_path).unwrap();
+        assert_ne!(new_key, lconf.ssh_key);
+        assert!(std::path::Path::new(&lconf.ssh_key).exists());
+        assert!(std::path::Path::new(&new_key).exists());
+    }
+
     #[test]
     fn no_saved_api_tokens() {
         let td = tempdir().unwrap();
@@ -616,4 +1314,151 @@ mod tests {
         assert_eq!(likely_tokens_data.len(), 0);
         assert_eq!(errored_tokens.len(), 0);
     }
+
+    #[test]
+    fn container_names() {
+        assert!(validate_container_name("rrc", &CProvider::Docker).is_ok());
+        assert!(validate_container_name("lab_1.arm-2", &CProvider::Podman).is_ok());
+        assert!(matches!(
+            validate_container_name("", &CProvider::Docker),
+            Err(HardshareError::Invalid(_))
+        ));
+        assert!(validate_container_name("-rrc", &CProvider::Docker).is_err());
+        assert!(validate_container_name("rr c", &CProvider::Docker).is_err());
+        assert!(validate_container_name("lab-1", &CProvider::Lxd).is_ok());
+        assert!(validate_container_name("lab_1", &CProvider::Lxd).is_err());
+        assert!(validate_container_name("1lab", &CProvider::Lxd).is_err());
+    }
+
+    #[test]
+    fn batch_file() {
+        let batch = parse_batch(
+            r#"
+- cprovider: podman
+  image: rerobots/hs-generic
+  init_inside:
+    - /sbin/init-lab.sh
+- cprovider: proxy
+  proxy_command: rrhttp 127.0.0.1:8080
+"#,
+        )
+        .unwrap();
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch[0].cprovider, Some(CProvider::Podman));
+        assert_eq!(batch[0].init_inside, vec!["/sbin/init-lab.sh"]);
+        assert_eq!(batch[1].cprovider, Some(CProvider::Proxy));
+        assert!(batch[1].image.is_none());
+
+        assert!(parse_batch("[]").is_err());
+        assert!(parse_batch("- cprovider: vbox").is_err());
+        assert!(parse_batch("- imag: rerobots/hs-generic").is_err());
+        assert!(parse_batch("- container_name: -rrc").is_err());
+    }
+
+    #[test]
+    fn yaml_config_preferred() {
+        let td = tempdir().unwrap();
+        let base_path = td.path().join(".rerobots");
+        let lconf = get_local_config_bp(&base_path, true, false).unwrap();
+        assert_eq!(lconf.wdeployments.len(), 0);
+
+        std::fs::write(
+            base_path.join("main.yaml"),
+            r#"
+# comments are allowed in YAML
+version: 0
+wdeployments:
+  - id: 68a1be97-9365-4007-b726-14c56bd69eef
+    owner: bilbo
+    cprovider: podman
+    cargs: []
+    image: rerobots/hs-generic
+    terminate: []
+    init_inside:
+      - |
+        apt-get update
+        apt-get -y install curl
+    container_name: rrc
+    monitor: null
+ssh_key: /home/bilbo/.rerobots/ssh/tun
+"#,
+        )
+        .unwrap();
+        let lconf = get_local_config_bp(&base_path, false, false).unwrap();
+        assert_eq!(lconf.wdeployments.len(), 1);
+        assert_eq!(lconf.wdeployments[0].cprovider, CProvider::Podman);
+        assert!(lconf.wdeployments[0].init_inside[0].contains('\n'));
+    }
+
+    #[test]
+    fn migrate_v0_config() {
+        let td = tempdir().unwrap();
+        let base_path = td.path().join(".rerobots");
+        std::fs::create_dir_all(base_path.join("tokens")).unwrap();
+        std::fs::write(
+            base_path.join("main"),
+            r#"
+            {
+                "version": 0,
+                "wdeployments": [
+                    {
+                        "id": "2d6039bc-7c83-4d46-8567-c8df4711c386",
+                        "owner": "scott",
+                        "cprovider": "lxd",
+                        "monitor": null
+                    }
+                ],
+                "ssh_key": "/home/scott/.rerobots/ssh/tun"
+            }"#,
+        )
+        .unwrap();
+
+        let lconf = get_local_config_bp(&base_path, false, false).unwrap();
+        assert_eq!(lconf.version, CONFIG_VERSION);
+        assert_eq!(lconf.wdeployments[0].cprovider, CProvider::Lxd);
+        assert_eq!(lconf.wdeployments[0].container_name, "rrc");
+        assert!(lconf.wdeployments[0].terminate.is_empty());
+
+        let on_disk: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(base_path.join("main")).unwrap())
+                .unwrap();
+        assert_eq!(on_disk["version"], CONFIG_VERSION);
+        assert_eq!(on_disk["wdeployments"][0]["container_name"], "rrc");
+    }
+
+    #[test]
+    fn reject_newer_config() {
+        let td = tempdir().unwrap();
+        let base_path = td.path().join(".rerobots");
+        std::fs::create_dir_all(&base_path).unwrap();
+        std::fs::write(
+            base_path.join("main"),
+            r#"{"version": 65535, "wdeployments": [], "ssh_key": ""}"#,
+        )
+        .unwrap();
+        assert!(get_local_config_bp(&base_path, false, false).is_err());
+    }
+
+    #[test]
+    fn image_digest_pins() {
+        let digest = format!("sha256:{}", "0123456789abcdef".repeat(4));
+        let pinned = format!("rerobots/hs-generic@{}", digest);
+        assert_eq!(image_digest(&pinned), Some(digest.as_str()));
+        assert_eq!(image_digest("rerobots/hs-generic:latest"), None);
+        assert!(validate_image_ref(&pinned, &CProvider::Docker).is_ok());
+        assert!(validate_image_ref("rerobots/hs-generic", &CProvider::Podman).is_ok());
+
+        assert!(validate_image_ref(&pinned, &CProvider::Lxd).is_err());
+        assert!(validate_image_ref(&format!("@{}", digest), &CProvider::Docker).is_err());
+        assert!(validate_image_ref("rerobots/hs-generic@md5:00", &CProvider::Docker).is_err());
+        assert!(validate_image_ref("rerobots/hs-generic@sha256:abc", &CProvider::Docker).is_err());
+        assert!(validate_image_ref(
+            &format!(
+                "rerobots/hs-generic@sha256:{}",
+                "0123456789ABCDEF".repeat(4)
+            ),
+            &CProvider::Docker
+        )
+        .is_err());
+    }
 }
@@ -1,61 +1,148 @@
 use std::process::Command;
 use std::thread::sleep;
 
+use serde::Serialize;
+
 use crate::api;
 use crate::check::Error;
 use crate::mgmt::Config;
 
+// Number of characters at the end of monitor output to include in alerts
+const MAX_ALERT_OUTPUT: usize = 1000;
+
+#[derive(Serialize, Debug)]
+pub struct CheckResult {
+    pub name: String,
+    pub ok: bool,
+    pub detail: Option<String>,
+    pub exit_code: Option<i32>,
+    pub output: Option<String>,
+}
+
+// Result of one monitor cycle for a workspace deployment
+#[derive(Serialize, Debug)]
+pub struct Health {
+    pub wdeployment_id: String,
+    pub date: String,
+    pub ok: bool,
+    pub checks: Vec<CheckResult>,
+}
+
+impl Health {
+    pub fn error(&self) -> Option<Box<dyn std::error::Error>> {
+        self.checks.iter().find(|c| !c.ok).map(|c| {
+            Error::new(c.detail.as_deref().unwrap_or("monitor: check failed"))
+                as Box<dyn std::error::Error>
+        })
+    }
+}
+
 fn run_opt(
     local_config: &Config,
     wd_index: usize,
     handle_errors: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<Health, Box<dyn std::error::Error>> {
+    let mut checks = vec![];
     if let Some(prog) = &local_config.wdeployments[wd_index].monitor {
-        let result = match Command::new("/bin/sh").args(["-c", prog.as_str()]).status() {
+        let check = match Command::new("/bin/sh").args(["-c", prog.as_str()]).output() {
             Ok(result) => {
-                if !result.success() {
-                    let msg = format!("monitor: `{prog}` failed: {result}");
-                    warn!("{}", msg);
-                    Err(Error::new(msg))
-                } else {
-                    Ok(())
+                let mut output = String::from_utf8_lossy(&result.stdout).to_string();
+                output.push_str(&String::from_utf8_lossy(&result.stderr));
+                let output = output.trim().to_string();
+                CheckResult {
+                    name: prog.clone(),
+                    ok: result.status.success(),
+                    detail: if result.status.success() {
+                        None
+                    } else {
+                        Some(format!("monitor: `{prog}` failed: {}", result.status))
+                    },
+                    exit_code: result.status.code(),
+                    output: if output.is_empty() {
+                        None
+                    } else {
+                        Some(output)
+                    },
                 }
             }
-            Err(err) => {
-                let msg = format!("monitor: `{prog}` failed: {err}");
-                warn!("{}", msg);
-                Err(Error::new(msg))
-            }
+            Err(err) => CheckResult {
+                name: prog.clone(),
+                ok: false,
+                detail: Some(format!("monitor: `{prog}` failed: {err}")),
+                exit_code: None,
+                output: None,
+            },
         };
+        if let Some(msg) = &check.detail {
+            warn!("{}", msg);
+            if let Some(output) = &check.output {
+                warn!("monitor output:\n{}", output);
+            }
+        }
+        checks.push(check);
+    }
 
-        if result.is_err() {
-            if handle_errors {
-                let ac = api::HSAPIClient::new();
-                ac.toggle_lockout(&local_config.wdeployments[wd_index].id, true)?;
-                ac.send_alert(&local_config.wdeployments[wd_index].id,
-                    "hardshare monitor detected an error. The deployment has been locked to prevent new instances.")?;
+    let health = Health {
+        wdeployment_id: local_config.wdeployments[wd_index].id.clone(),
+        date: chrono::Utc::now().to_rfc3339(),
+        ok: checks.iter().all(|c| c.ok),
+        checks,
+    };
+
+    if !health.ok && handle_errors {
+        let mut message = String::from(
+            "hardshare monitor detected an error. The deployment has been locked to prevent new instances.",
+        );
+        for c in health.checks.iter().filter(|c| !c.ok) {
+            if let Some(detail) = &c.detail {
+                message.push_str(&format!("\n\n{}", detail));
+            }
+            if let Some(output) = &c.output {
+                let start = output
+                    .char_indices()
+                    .rev()
+                    .nth(MAX_ALERT_OUTPUT - 1)
+                    .map(|(i, _)| i)
+                    .unwrap_or(0);
+                message.push_str(&format!("\noutput:\n{}", &output[start..]));
             }
-            return Ok(result?);
         }
+        let ac = api::HSAPIClient::new();
+        ac.toggle_lockout(&local_config.wdeployments[wd_index].id, true)?;
+        ac.send_alert(&local_config.wdeployments[wd_index].id, &message)?;
     }
-    Ok(())
+    Ok(health)
 }
 
 pub fn run_dry(local_config: &Config, wd_index: usize) -> Result<(), Box<dyn std::error::Error>> {
-    run_opt(local_config, wd_index, false)
+    match run_opt(local_config, wd_index, false)?.error() {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
 }
 
-pub fn run(local_config: &Config, wd_index: usize) -> Result<(), Box<dyn std::error::Error>> {
+// Lock the deployment and send an alert when a check fails
+pub fn run(local_config: &Config, wd_index: usize) -> Result<Health, Box<dyn std::error::Error>> {
     run_opt(local_config, wd_index, true)
 }
 
-pub fn run_loop(
+// Unlike `run`, lock the deployment and send an alert only if auto_lock
+pub fn run_loop<F>(
     local_config: &Config,
     wd_index: usize,
     duration: std::time::Duration,
-) -> Result<(), Box<dyn std::error::Error>> {
+    auto_lock: bool,
+    report: F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: Fn(&Health),
+{
     loop {
-        run(local_config, wd_index)?;
+        let health = run_opt(local_config, wd_index, auto_lock)?;
+        report(&health);
+        if let Some(err) = health.error() {
+            return Err(err);
+        }
         sleep(duration);
     }
 }
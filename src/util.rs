@@ -0,0 +1,47 @@
+// Copyright (C) 2026 rerobots, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashSet;
+use std::hash::Hash;
+
+// Whether u and v have at least one element in common. The shorter slice is
+// hashed, so this is linear in the total length.
+pub fn intersects<T>(u: &[T], v: &[T]) -> bool
+where
+    T: Eq + Hash,
+{
+    let (short, long) = if u.len() <= v.len() { (u, v) } else { (v, u) };
+    let short: HashSet<&T> = short.iter().collect();
+    long.iter().any(|x| short.contains(x))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::intersects;
+
+    #[test]
+    fn intersection_is_nonempty() {
+        let empty: Vec<String> = vec![];
+        let wds: Vec<String> = vec!["a".into(), "b".into(), "c".into()];
+        assert!(intersects(&wds, &["c".to_string()]));
+        assert!(intersects(&["c".to_string(), "z".to_string()], &wds));
+        assert!(!intersects(&wds, &["d".to_string()]));
+        assert!(!intersects(&wds, &empty));
+        assert!(!intersects(&empty, &empty));
+
+        let large: Vec<u32> = (0..1000).collect();
+        assert!(intersects(&large, &[999]));
+        assert!(!intersects(&large, &[1000, 1001]));
+    }
+}
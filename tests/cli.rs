@@ -24,6 +24,40 @@ fn prints_version() {
         .success();
 }
 
+#[test]
+fn prints_version_json() {
+    let mut cmd = Command::cargo_bin("hardshare").unwrap();
+    let assert = cmd.arg("version").arg("--json").assert();
+    let output = assert.get_output().clone();
+    assert.success();
+    let info: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+    assert!(info["git_sha"].is_string());
+    assert!(info["target"].is_string());
+}
+
+#[test]
+fn quiet_suppresses_hints() {
+    let mut cmd = Command::cargo_bin("hardshare").unwrap();
+    cmd.assert()
+        .stdout("")
+        .stderr("No command given. Try `hardshare -h`\n");
+
+    let mut cmd = Command::cargo_bin("hardshare").unwrap();
+    cmd.arg("--quiet").assert().stdout("").stderr("");
+}
+
+#[test]
+fn prints_completions_bash() {
+    let mut cmd = Command::cargo_bin("hardshare").unwrap();
+    let assert = cmd.arg("completions").arg("bash").assert();
+    let output = assert.get_output().clone();
+    assert.success();
+    let script = String::from_utf8(output.stdout).unwrap();
+    assert!(script.contains("hardshare"));
+    assert!(script.contains("attach-camera"));
+}
+
 #[test]
 fn prints_help() {
     let mut cmd = Command::cargo_bin("hardshare").unwrap();
@@ -112,7 +146,7 @@ fn prints_help_register() {
 fn config_requires_arg() {
     let mut cmd = Command::cargo_bin("hardshare").unwrap();
     let assert = cmd.arg("config").assert();
-    assert.failure().code(1);
+    assert.failure().code(2);
 }
 
 #[test]
@@ -121,14 +155,24 @@ fn add_token_does_not_exist() {
     let mut cmd = Command::cargo_bin("hardshare").unwrap();
     let assert = cmd
         .arg("config")
-        .arg("--add-key")
+        .arg("--add-token")
         .arg(ntf.path().join("notexist"))
         .assert();
     assert.failure().code(1);
 }
 
+// Unknown switches are usage errors, which exit with 2 (previously 1)
 #[test]
-fn list_config_does_not_exist() {
+fn config_unknown_switch() {
+    let ntf = NamedTempFile::new().unwrap();
+    let mut cmd = Command::cargo_bin("hardshare").unwrap();
+    let assert = cmd
+        .arg("config")
+        .arg("--add-key")
+        .arg(ntf.path().join("notexist"))
+        .assert();
+    assert.failure().code(2);
+
     let tmphome = tempdir().unwrap();
     let mut cmd = Command::cargo_bin("hardshare").unwrap();
     let assert = cmd
@@ -137,5 +181,17 @@ fn list_config_does_not_exist() {
         .arg("--local")
         .arg("-l")
         .assert();
+    assert.failure().code(2);
+}
+
+#[test]
+fn list_config_does_not_exist() {
+    let tmphome = tempdir().unwrap();
+    let mut cmd = Command::cargo_bin("hardshare").unwrap();
+    let assert = cmd
+        .env("HOME", tmphome.path())
+        .arg("list")
+        .arg("--local")
+        .assert();
     assert.failure().code(1);
 }